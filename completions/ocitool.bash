@@ -0,0 +1,223 @@
+# Bash completion for ocitool
+#
+# Install by sourcing this file, or by copying it to
+# /usr/share/bash-completion/completions/ocitool
+
+_ocitool_images() {
+    local IFS=$'\n'
+    COMPREPLY=($(ocitool complete-image "$cur" 2>/dev/null))
+    __ltrim_colon_completions "$cur"
+}
+
+_ocitool() {
+    local cur prev words cword
+    _init_completion -n : || return
+
+    local commands="compose upload plan run rm stop attach extract cat assert referrers inspect digest diff tags copy push-layout load save prune retag delete manifest artifact complete-image state cache login logout config cleanup"
+    local command="" i
+
+    for ((i = 1; i < cword; i++)); do
+        case "${words[i]}" in
+            --host | -u | --username | -p | --password | --registry-auth | --cache-max-size | --proxy | --connect-timeout | --read-timeout | --max-connections-per-host | --progress | --protocol | --insecure-registry | --registry-ca | --registry-cert | --record | --replay)
+                ((i++))
+                ;;
+            -*) ;;
+            *)
+                command="${words[i]}"
+                break
+                ;;
+        esac
+    done
+
+    if [[ $prev == --progress ]]; then
+        COMPREPLY=($(compgen -W "text json" -- "$cur"))
+        return
+    fi
+
+    if [[ -z $command ]]; then
+        COMPREPLY=($(compgen -W "$commands --host --username --password --password-stdin --registry-auth --no-cache --isolated-cache --cache-max-size --profile-io --proxy --connect-timeout --read-timeout --max-connections-per-host --protocol --http1 --insecure-registry --registry-ca --registry-cert --respect-rate-limits --skip-foreign-layers --progress --record --replay --help" -- "$cur"))
+        return
+    fi
+
+    case "$command" in
+        run)
+            if [[ $prev == -i || $prev == --image ]]; then
+                _ocitool_images
+                return
+            fi
+            if [[ $prev == --backend ]]; then
+                COMPREPLY=($(compgen -W "proot bwrap chroot-overlay runc crun" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --env-file || $prev == --socket ]]; then
+                _filedir
+                return
+            fi
+            COMPREPLY=($(compgen -W "--image --volume --read-only --tmpfs --entrypoint --cmd --workdir --env --env-file --user --backend --via-containerd --socket --snapshotter --no-mount-system --no-ensure-dns --dns --dns-search --jobs --name --keep --detach --tty --stop-timeout" -- "$cur"))
+            ;;
+        extract)
+            if [[ $prev == -o || $prev == --output ]]; then
+                _filedir
+                return
+            fi
+            if [[ $prev == --platform ]]; then
+                return
+            fi
+            if [[ $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            COMPREPLY=($(compgen -W "--output --platform" -- "$cur"))
+            ;;
+        cat)
+            if [[ $prev == --platform ]]; then
+                return
+            fi
+            if [[ $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            COMPREPLY=($(compgen -W "--platform" -- "$cur"))
+            ;;
+        assert | referrers | inspect | digest | diff | tags | copy | retag | delete)
+            if [[ $prev == --format ]]; then
+                COMPREPLY=($(compgen -W "text json" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --platform || $prev == -o || $prev == --output ]]; then
+                return
+            fi
+            if [[ $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            ;;
+        push-layout)
+            if [[ $prev == --platform ]]; then
+                return
+            fi
+            if [[ $cur == -* ]]; then
+                COMPREPLY=($(compgen -W "--platform" -- "$cur"))
+            fi
+            ;;
+        load)
+            if [[ $prev == -i || $prev == --input || $prev == -s || $prev == --socket ]]; then
+                _filedir
+                return
+            fi
+            if [[ $prev == --repository || $prev == --platform || $prev == --snapshotter ]]; then
+                return
+            fi
+            COMPREPLY=($(compgen -W "--input --repository --platform --socket --snapshotter" -- "$cur"))
+            ;;
+        stop | attach)
+            local names
+            names=$(ls "${XDG_CACHE_HOME:-$HOME/.cache}/ocitool/detached" 2>/dev/null | sed -n 's/\.log$//p')
+            COMPREPLY=($(compgen -W "$names" -- "$cur"))
+            ;;
+        rm)
+            local names
+            names=$(sed -n '/"workspaces"/,/^  }/s/^    "\([^"]*\)": {/\1/p' "${XDG_CACHE_HOME:-$HOME/.cache}/ocitool/state.json" 2>/dev/null)
+            COMPREPLY=($(compgen -W "$names" -- "$cur"))
+            ;;
+        save)
+            if [[ $prev == -o || $prev == --output || $prev == -s || $prev == --socket ]]; then
+                _filedir
+                return
+            fi
+            if [[ $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            COMPREPLY=($(compgen -W "--output --socket" -- "$cur"))
+            ;;
+        prune)
+            if [[ $prev == -s || $prev == --socket ]]; then
+                _filedir
+                return
+            fi
+            if [[ $prev == -n || $prev == --namespace ]]; then
+                return
+            fi
+            COMPREPLY=($(compgen -W "--socket --namespace --yes" -- "$cur"))
+            ;;
+        manifest)
+            if [[ $prev == manifest ]]; then
+                COMPREPLY=($(compgen -W "create annotate" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --platform || $prev == --os-version ]]; then
+                return
+            fi
+            if [[ $prev == --add || $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            COMPREPLY=($(compgen -W "--add --platform --os-version" -- "$cur"))
+            ;;
+        cleanup)
+            if [[ $prev == cleanup && $cur != -* ]]; then
+                COMPREPLY=($(compgen -W "restore" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --layout ]]; then
+                COMPREPLY=($(compgen -W "distribution harbor zot" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --format ]]; then
+                COMPREPLY=($(compgen -W "text json" -- "$cur"))
+                return
+            fi
+            if [[ $prev == -d || $prev == --dir || $prev == --quarantine || $prev == restore ]]; then
+                _filedir -d
+                return
+            fi
+            COMPREPLY=($(compgen -W "--dir --remote --layout --commits --indexes --layers --blobs --uploads --upload-age --all --yes --keep-last --repo --keep-tags --dry-run --format --check --quarantine" -- "$cur"))
+            ;;
+        artifact)
+            if [[ $prev == artifact ]]; then
+                COMPREPLY=($(compgen -W "list download" -- "$cur"))
+                return
+            fi
+            if [[ $prev == -o || $prev == --output ]]; then
+                _filedir -d
+                return
+            fi
+            if [[ $cur != -* ]]; then
+                _ocitool_images
+                return
+            fi
+            COMPREPLY=($(compgen -W "--output" -- "$cur"))
+            ;;
+        compose)
+            COMPREPLY=($(compgen -W "pull up estimate" -- "$cur"))
+            ;;
+        plan)
+            COMPREPLY=($(compgen -W "deps update" -- "$cur"))
+            ;;
+        state)
+            COMPREPLY=($(compgen -W "ls gc" -- "$cur"))
+            ;;
+        cache)
+            COMPREPLY=($(compgen -W "info clear prune --older-than" -- "$cur"))
+            ;;
+        login)
+            if [[ $cur == -* ]]; then
+                COMPREPLY=($(compgen -W "--username --password --password-stdin" -- "$cur"))
+            fi
+            ;;
+        config)
+            if [[ $prev == config ]]; then
+                COMPREPLY=($(compgen -W "show" -- "$cur"))
+                return
+            fi
+            if [[ $prev == --format ]]; then
+                COMPREPLY=($(compgen -W "text json" -- "$cur"))
+                return
+            fi
+            COMPREPLY=($(compgen -W "--format" -- "$cur"))
+            ;;
+    esac
+}
+
+complete -F _ocitool ocitool
@@ -1,10 +1,10 @@
+use crate::report::exit;
 use nix::unistd::{execvp, getuid};
 use std::env::args;
 use std::ffi::CString;
 use std::fmt::{Display, Formatter};
 use std::io::ErrorKind;
 use std::os::unix::net::UnixStream;
-use std::process::exit;
 
 #[derive(Debug)]
 pub enum SocketAccessError {
@@ -1,4 +1,9 @@
-use std::fmt;
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use sha2::{Digest, Sha256};
 
 use crate::spec::enums::MediaType;
 
@@ -19,6 +24,8 @@ impl std::error::Error for DetectError {}
 /// Detection rules:
 /// - If the buffer starts with gzip magic (0x1F 0x8B) => TarGz
 /// - If the buffer starts with zstd frame magic (0x28 B5 2F FD) => TarZstd
+/// - If the buffer starts with a zstd skippable frame (0x5? 2A 4D 18), as
+///   some nerdctl-produced layers do => TarZstd
 /// - If the buffer contains the tar "ustar" magic at offset 257 => Tar
 /// - Otherwise returns an error.
 ///
@@ -37,6 +44,16 @@ pub fn detect_media_type<B: AsRef<[u8]>>(buf: B) -> Result<MediaType, DetectErro
         return Ok(MediaType::OciImageLayerV1TarZstd);
     }
 
+    // zstd skippable frame magic: 0x184D2A50 to 0x184D2A5F (little-endian)
+    if data.len() >= 4
+        && data[0] & 0xF0 == 0x50
+        && data[1] == 0x2A
+        && data[2] == 0x4D
+        && data[3] == 0x18
+    {
+        return Ok(MediaType::OciImageLayerV1TarZstd);
+    }
+
     // tar ustar magic at offset 257 (POSIX tar)
     if data.len() > 257 + 5 && &data[257..257 + 5] == b"ustar" {
         return Ok(MediaType::OciImageLayerV1Tar);
@@ -44,3 +61,147 @@ pub fn detect_media_type<B: AsRef<[u8]>>(buf: B) -> Result<MediaType, DetectErro
 
     Err(DetectError)
 }
+
+/// Hashes everything written into it
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum DigesterState {
+    /// Not enough of the layer has arrived to tell its compression apart
+    Detecting(Vec<u8>),
+    Tar(HashWriter),
+    Gzip(Box<flate2::write::GzDecoder<HashWriter>>),
+    Zstd(zstd::stream::write::Decoder<'static, HashWriter>),
+}
+
+/// Computes the digest of a layer's uncompressed tar, its diff ID, while the
+/// layer is streamed in chunks. The compression is detected from the data
+/// rather than the media type, so gzip, zstd and uncompressed layers work
+/// under both the OCI and the Docker media types
+pub struct UncompressedDigester {
+    state: DigesterState,
+}
+
+impl Default for UncompressedDigester {
+    fn default() -> Self {
+        UncompressedDigester {
+            state: DigesterState::Detecting(Vec::new()),
+        }
+    }
+}
+
+impl UncompressedDigester {
+    pub fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.state {
+            DigesterState::Detecting(pending) => {
+                pending.extend_from_slice(data);
+
+                // Four bytes are enough to recognize gzip and zstd
+                if pending.len() >= 4 {
+                    let pending = std::mem::take(pending);
+                    self.start(&pending)?;
+                }
+
+                Ok(())
+            }
+            DigesterState::Tar(writer) => writer.write_all(data),
+            DigesterState::Gzip(decoder) => decoder.write_all(data),
+            DigesterState::Zstd(decoder) => decoder.write_all(data),
+        }
+    }
+
+    fn start(&mut self, pending: &[u8]) -> io::Result<()> {
+        let writer = HashWriter(Sha256::new());
+
+        self.state = match detect_media_type(pending) {
+            Ok(MediaType::OciImageLayerV1TarGzip) => {
+                DigesterState::Gzip(Box::new(flate2::write::GzDecoder::new(writer)))
+            }
+            Ok(MediaType::OciImageLayerV1TarZstd) => {
+                DigesterState::Zstd(zstd::stream::write::Decoder::new(writer)?)
+            }
+            // Tarballs are only recognized after 262 bytes, so anything that is
+            // not compressed is taken as a tarball
+            _ => DigesterState::Tar(writer),
+        };
+
+        self.update(pending)
+    }
+
+    /// Returns the diff ID, as sha256:<hex>
+    pub fn finish(mut self) -> io::Result<String> {
+        if let DigesterState::Detecting(pending) = &mut self.state {
+            let pending = std::mem::take(pending);
+            self.start(&pending)?;
+        }
+
+        let writer = match self.state {
+            DigesterState::Detecting(_) => unreachable!(),
+            DigesterState::Tar(writer) => writer,
+            DigesterState::Gzip(decoder) => decoder.finish()?,
+            DigesterState::Zstd(mut decoder) => {
+                decoder.flush()?;
+                decoder.into_inner()
+            }
+        };
+
+        Ok(format!("sha256:{:x}", writer.0.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_digest;
+
+    fn tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "etc/hostname", &b"layer"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_uncompressed_digester() -> io::Result<()> {
+        let tar = tarball();
+        let diff_id = sha256_digest(&tar);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&tar)?;
+        let gzip = gzip.finish()?;
+
+        // A skippable frame in front, like zstd:chunked layers may carry
+        let mut zstd = vec![0x50, 0x2A, 0x4D, 0x18, 3, 0, 0, 0, 1, 2, 3];
+        zstd.extend(zstd::stream::encode_all(&tar[..], 3)?);
+
+        for layer in [&tar, &gzip, &zstd] {
+            assert!(detect_media_type(layer).is_ok());
+
+            let mut digester = UncompressedDigester::default();
+
+            // Odd chunk sizes split the magic bytes and the frames
+            for chunk in layer.chunks(3) {
+                digester.update(chunk)?;
+            }
+
+            assert_eq!(digester.finish()?, diff_id);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    cat::normalize,
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::{IndexResponse, OciDownloader},
+    format::format_size,
+    parser::FullImageWithTag,
+    report::Context,
+    spec::manifest::{Descriptor, ImageManifest},
+    Artifact, ArtifactCmd,
+};
+
+/// The annotation ORAS names the file of a layer with
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// The file name of a layer, from its title, or else its digest. Titles are
+/// kept inside the output directory
+fn layer_file_name(layer: &Descriptor) -> PathBuf {
+    let title = layer
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(TITLE_ANNOTATION))
+        .map(|title| normalize(Path::new(title)))
+        .filter(|title| !title.as_os_str().is_empty());
+
+    title.unwrap_or_else(|| PathBuf::from(layer.digest.replacen(':', "-", 1)))
+}
+
+/// The type of an artifact, which older artifacts only set on their config
+fn artifact_type(manifest: &ImageManifest) -> &str {
+    manifest
+        .artifact_type
+        .as_deref()
+        .unwrap_or(manifest.config.media_type.to_string())
+}
+
+async fn download_artifact(
+    downloader: &OciDownloader,
+    image: &FullImageWithTag,
+) -> Result<ImageManifest, Box<dyn Error>> {
+    match downloader
+        .download_index(image.clone())
+        .await
+        .with_context(|| format!("fetching {}", image.tag))?
+        .0
+    {
+        IndexResponse::ImageManifest(manifest) => Ok(*manifest),
+        IndexResponse::ImageIndex(_) => Err(format!(
+            "{} is an index, name one of its manifests by digest",
+            image.tag
+        )
+        .into()),
+    }
+}
+
+pub async fn artifact_command(
+    args: &Artifact,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let name = match &args.subcommand {
+        ArtifactCmd::List(list) => &list.image,
+        ArtifactCmd::Download(download) => &download.image,
+    };
+
+    let image = FullImageWithTag::parse(name, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let manifest = download_artifact(&downloader, &image).await?;
+
+    match &args.subcommand {
+        ArtifactCmd::List(_) => {
+            println!("Artifact type: {}", artifact_type(&manifest));
+
+            for layer in &manifest.layers {
+                println!(
+                    "{}\t{}\t{}",
+                    layer_file_name(layer).display(),
+                    layer.media_type.to_string(),
+                    format_size(layer.size)
+                );
+            }
+        }
+        ArtifactCmd::Download(download) => {
+            let output = download
+                .output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("."));
+
+            for layer in &manifest.layers {
+                let data = downloader
+                    .download_layer(image.image.clone(), &layer.digest)
+                    .await
+                    .with_context(|| format!("downloading {}", layer.digest))?;
+
+                if sha256_digest(&data) != layer.digest {
+                    return Err(format!("{} does not match its digest", layer.digest).into());
+                }
+
+                let path = output.join(layer_file_name(layer));
+
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+
+                fs::write(&path, &data).with_context(|| format!("writing {}", path.display()))?;
+                println!(
+                    "Downloaded {} ({})",
+                    path.display(),
+                    format_size(layer.size)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oras_manifest() {
+        // As pushed by `oras push --artifact-type application/vnd.example+type`
+        let manifest: ImageManifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.example+type",
+                "config": {
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                    "size": 2,
+                    "data": "e30="
+                },
+                "layers": [
+                    {
+                        "mediaType": "application/vnd.example.report.v1+json",
+                        "digest": "sha256:5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03",
+                        "size": 6,
+                        "annotations": {"org.opencontainers.image.title": "../reports/hello.json"}
+                    },
+                    {
+                        "mediaType": "application/octet-stream",
+                        "digest": "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                        "size": 0
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(artifact_type(&manifest), "application/vnd.example+type");
+        assert_eq!(
+            manifest.layers[0].media_type.to_string(),
+            "application/vnd.example.report.v1+json"
+        );
+        assert_eq!(
+            layer_file_name(&manifest.layers[0]),
+            PathBuf::from("reports/hello.json")
+        );
+        assert_eq!(
+            layer_file_name(&manifest.layers[1]),
+            PathBuf::from(
+                "sha256-e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+            )
+        );
+
+        // Custom media types and annotations survive a round trip unchanged
+        let json = String::from_utf8(manifest.to_json()).unwrap();
+        assert!(json.contains(r#""mediaType":"application/vnd.example.report.v1+json""#));
+        assert!(json.contains(r#""org.opencontainers.image.title":"../reports/hello.json""#));
+    }
+}
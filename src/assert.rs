@@ -0,0 +1,178 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::{IndexResponse, OciDownloader},
+    parser::FullImageWithTag,
+    platform::PlatformMatcher,
+    spec::config::Config,
+    Assert,
+};
+
+/// Compares the config of an image against the expectations given on the command line,
+/// returning a description of every mismatch
+pub fn check_config(config: &Config, args: &Assert) -> Vec<String> {
+    let mut failures = vec![];
+
+    if !args.entrypoint.is_empty() && config.entrypoint.as_ref() != Some(&args.entrypoint) {
+        failures.push(format!(
+            "Expected entrypoint {:?}, found {:?}",
+            args.entrypoint,
+            config.entrypoint.clone().unwrap_or_default()
+        ));
+    }
+
+    if !args.cmd.is_empty() && config.cmd.as_ref() != Some(&args.cmd) {
+        failures.push(format!(
+            "Expected cmd {:?}, found {:?}",
+            args.cmd,
+            config.cmd.clone().unwrap_or_default()
+        ));
+    }
+
+    let env = config.env.clone().unwrap_or_default();
+
+    for expected in &args.has_env {
+        // KEY only checks that the variable is set, KEY=VALUE also checks its value
+        let found = if expected.contains('=') {
+            env.iter().any(|variable| variable == expected)
+        } else {
+            env.iter()
+                .any(|variable| variable.split('=').next() == Some(expected.as_str()))
+        };
+
+        if !found {
+            failures.push(format!("Expected environment variable {}", expected));
+        }
+    }
+
+    if let Some(user) = &args.user {
+        if config.user.as_deref().unwrap_or_default() != user {
+            failures.push(format!(
+                "Expected user {}, found {}",
+                user,
+                config.user.as_deref().unwrap_or("<unset>")
+            ));
+        }
+    }
+
+    if let Some(workdir) = &args.workdir {
+        if config.working_dir.as_deref().unwrap_or_default() != workdir {
+            failures.push(format!(
+                "Expected working directory {}, found {}",
+                workdir,
+                config.working_dir.as_deref().unwrap_or("<unset>")
+            ));
+        }
+    }
+
+    let exposed_ports = config.exposed_ports.clone().unwrap_or_default();
+
+    for port in &args.port {
+        // Ports without a protocol default to tcp, like EXPOSE in a Dockerfile
+        let port = if port.contains('/') {
+            port.clone()
+        } else {
+            format!("{}/tcp", port)
+        };
+
+        if !exposed_ports.contains_key(&port) {
+            failures.push(format!("Expected exposed port {}", port));
+        }
+    }
+
+    failures
+}
+
+pub async fn assert_command(
+    args: &Assert,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let platform_matcher = PlatformMatcher::new();
+    let manifest = match downloader.download_index(image.clone()).await?.0 {
+        IndexResponse::ImageIndex(index) => {
+            let manifest = platform_matcher
+                .find_manifest(&index.manifests)
+                .ok_or("No matching platform found")?;
+
+            downloader
+                .download_manifest(image.image.clone(), &manifest.digest)
+                .await?
+                .0
+        }
+        IndexResponse::ImageManifest(manifest) => *manifest,
+    };
+
+    let config = downloader
+        .download_config(image.image.clone(), &manifest.config.digest)
+        .await?
+        .0
+        .config
+        .ok_or("Image has no runtime config")?;
+
+    let failures = check_config(&config, args);
+
+    if failures.is_empty() {
+        println!("All assertions passed for {}", args.image);
+        return Ok(());
+    }
+
+    for failure in &failures {
+        println!("{}", failure);
+    }
+
+    Err(format!("{} assertion(s) failed for {}", failures.len(), args.image).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_args() -> Assert {
+        Assert {
+            image: "app:latest".to_string(),
+            entrypoint: vec![],
+            cmd: vec![],
+            has_env: vec![],
+            user: None,
+            workdir: None,
+            port: vec![],
+        }
+    }
+
+    #[test]
+    fn test_check_config() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "User": "1000",
+                "Env": ["PATH=/usr/bin", "APP_MODE=prod"],
+                "Entrypoint": ["/app"],
+                "ExposedPorts": { "8080/tcp": {} }
+            }"#,
+        )
+        .unwrap();
+
+        let mut args = assert_args();
+        args.entrypoint = vec!["/app".to_string()];
+        args.has_env = vec!["PATH".to_string(), "APP_MODE=prod".to_string()];
+        args.user = Some("1000".to_string());
+        args.port = vec!["8080".to_string()];
+        assert!(check_config(&config, &args).is_empty());
+
+        args.has_env = vec!["APP_MODE=dev".to_string(), "HOME".to_string()];
+        args.port = vec!["8080/udp".to_string()];
+        args.workdir = Some("/srv".to_string());
+        assert_eq!(check_config(&config, &args).len(), 4);
+    }
+}
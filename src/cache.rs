@@ -0,0 +1,412 @@
+use std::{
+    env,
+    error::Error,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    format::{format_duration, format_size, parse_duration},
+    Cache as CacheArgs, CacheCmd,
+};
+
+/// The job directory blobs are cached in with `--isolated-cache`
+static ISOLATED_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// The size the blob cache is kept under with `--cache-max-size`
+static MAX_SIZE: OnceLock<u64> = OnceLock::new();
+
+/// The directory ocitool keeps its blob cache and state in
+pub fn cache_dir() -> PathBuf {
+    match dirs::cache_dir() {
+        Some(dir) => dir.join("ocitool"),
+        None => PathBuf::from("/tmp/ocitool"),
+    }
+}
+
+/// Names the CI job this process runs in, so that every invocation of the
+/// same job shares an isolated cache. Outside of CI, only this process does
+fn job_id(var: impl Fn(&str) -> Option<String>) -> String {
+    let github = match (var("GITHUB_RUN_ID"), var("GITHUB_JOB")) {
+        (Some(run), Some(job)) => Some(format!(
+            "github-{}-{}-{}",
+            run,
+            var("GITHUB_RUN_ATTEMPT").unwrap_or_else(|| "1".to_string()),
+            job
+        )),
+        _ => None,
+    };
+
+    github
+        .or_else(|| var("CI_JOB_ID").map(|id| format!("gitlab-{}", id)))
+        .or_else(|| var("BUILDKITE_JOB_ID").map(|id| format!("buildkite-{}", id)))
+        .unwrap_or_else(|| format!("pid-{}", std::process::id()))
+        .replace(['/', '\\'], "_")
+}
+
+/// Caches blobs in a directory of their own for the current job, instead of
+/// the one shared by every job on the host
+pub fn set_isolated(isolated: bool) {
+    if isolated {
+        let job = job_id(|name| env::var(name).ok().filter(|value| !value.is_empty()));
+        ISOLATED_DIR.get_or_init(|| cache_dir().join("jobs").join(job));
+    }
+}
+
+pub fn blob_dir() -> PathBuf {
+    match ISOLATED_DIR.get() {
+        Some(dir) => dir.join("blobs"),
+        None => cache_dir().join("blobs"),
+    }
+}
+
+/// Removes an isolated cache that no later invocation can find again, as it
+/// is named after this process
+pub fn remove_isolated() {
+    if let Some(dir) = ISOLATED_DIR.get() {
+        if dir
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("pid-"))
+        {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// An exclusive lock on a file, held across processes until dropped
+pub struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Waits for the lock, creating the lock file if needed
+    pub fn acquire(path: &Path) -> io::Result<FileLock> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock()?;
+
+        Ok(FileLock { _file: file })
+    }
+
+    /// Takes the lock if no one holds it, None if someone does
+    pub fn try_acquire(path: &Path) -> io::Result<Option<FileLock>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Some(FileLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+pub fn set_max_size(bytes: u64) {
+    let _ = MAX_SIZE.set(bytes);
+}
+
+pub fn max_size() -> Option<u64> {
+    MAX_SIZE.get().copied()
+}
+
+/// A blob in the cache, with when it was last used
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedBlob {
+    pub path: PathBuf,
+    pub size: u64,
+    pub used: SystemTime,
+}
+
+/// Lists the blobs of a cache directory, least recently used first. The
+/// locks and the temporary files of writes in progress are left out
+pub fn list_blobs(dir: &Path) -> io::Result<Vec<CachedBlob>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut blobs = vec![];
+
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if !metadata.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        blobs.push(CachedBlob {
+            path: entry.path(),
+            size: metadata.len(),
+            used: metadata.modified()?,
+        });
+    }
+
+    blobs.sort_by_key(|blob| blob.used);
+    Ok(blobs)
+}
+
+/// Marks a blob as used, so that it is evicted after the blobs that were
+/// not. Caches on read-only mounts are left as they are
+pub fn touch(path: &Path) {
+    if let Ok(file) = OpenOptions::new().write(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// The blobs to evict for a cache to fit in max_size, least recently used
+/// first. The blob at `keep`, such as the one just written, is never evicted
+pub fn lru_evictions(
+    blobs: Vec<CachedBlob>,
+    max_size: u64,
+    keep: Option<&Path>,
+) -> Vec<CachedBlob> {
+    let mut total: u64 = blobs.iter().map(|blob| blob.size).sum();
+
+    blobs
+        .into_iter()
+        .filter(|blob| Some(blob.path.as_path()) != keep)
+        .take_while(|blob| {
+            let evict = total > max_size;
+            total = total.saturating_sub(blob.size);
+            evict
+        })
+        .collect()
+}
+
+/// Removes blobs from the cache. Blobs another process removed first are
+/// not an error
+pub fn remove_blobs(blobs: &[CachedBlob]) -> io::Result<()> {
+    for blob in blobs {
+        match fs::remove_file(&blob.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Evicts the least recently used blobs once the cache grows past
+/// `--cache-max-size`, keeping the blob just written
+pub fn enforce_max_size(dir: &Path, written: &Path) -> io::Result<()> {
+    match max_size() {
+        Some(max_size) => remove_blobs(&lru_evictions(list_blobs(dir)?, max_size, Some(written))),
+        None => Ok(()),
+    }
+}
+
+fn total_size(blobs: &[CachedBlob]) -> u64 {
+    blobs.iter().map(|blob| blob.size).sum()
+}
+
+/// Removes blobs, or only lists them in a dry run
+fn remove_reported(blobs: &[CachedBlob], dry_run: bool) -> io::Result<()> {
+    match dry_run {
+        true => {
+            for blob in blobs {
+                println!(
+                    "Would remove {} ({})",
+                    blob.path.display(),
+                    format_size(blob.size)
+                );
+            }
+        }
+        false => {
+            remove_blobs(blobs)?;
+            println!(
+                "Removed {} blobs ({})",
+                blobs.len(),
+                format_size(total_size(blobs))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn cache_command(args: &CacheArgs, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let dir = blob_dir();
+    let blobs = list_blobs(&dir)?;
+
+    match &args.subcommand {
+        CacheCmd::Info(_) => {
+            println!("Blob cache: {}", dir.display());
+            println!(
+                "Blobs: {} ({})",
+                blobs.len(),
+                format_size(total_size(&blobs))
+            );
+            println!(
+                "Maximum size: {}",
+                max_size()
+                    .map(format_size)
+                    .unwrap_or_else(|| "none".to_string())
+            );
+
+            if let Some(oldest) = blobs.first() {
+                let unused = SystemTime::now()
+                    .duration_since(oldest.used)
+                    .unwrap_or_default();
+                println!(
+                    "Least recently used: {} ago",
+                    format_duration(unused.as_secs())
+                );
+            }
+        }
+        CacheCmd::Clear(_) => remove_reported(&blobs, dry_run)?,
+        CacheCmd::Evict(evict) => {
+            let older_than = match &evict.older_than {
+                Some(older_than) => Some(parse_duration(older_than).ok_or_else(|| {
+                    format!("Invalid duration: {}, such as 12h or 7d", older_than)
+                })?),
+                None => None,
+            };
+
+            let evictions = match (older_than, max_size()) {
+                (Some(older_than), _) => {
+                    let cutoff = SystemTime::now() - Duration::from_secs(older_than);
+                    blobs
+                        .into_iter()
+                        .filter(|blob| blob.used < cutoff)
+                        .collect()
+                }
+                (None, Some(max_size)) => lru_evictions(blobs, max_size, None),
+                (None, None) => {
+                    return Err("Pass --older-than, or --cache-max-size to prune down to".into())
+                }
+            };
+
+            remove_reported(&evictions, dry_run)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a file by renaming a complete copy over it, so that readers and
+/// mappings of the previous file never see it half written
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(data)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_job_id() {
+        let vars = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let github = vars(&[("GITHUB_RUN_ID", "42"), ("GITHUB_JOB", "build/arm")]);
+        assert_eq!(
+            job_id(|name| github.get(name).cloned()),
+            "github-42-1-build_arm"
+        );
+
+        let gitlab = vars(&[("CI_JOB_ID", "7")]);
+        assert_eq!(job_id(|name| gitlab.get(name).cloned()), "gitlab-7");
+
+        assert!(job_id(|_| None).starts_with("pid-"));
+    }
+
+    #[test]
+    fn test_file_lock() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("locks").join("blob.lock");
+
+        let lock = FileLock::acquire(&path)?;
+        let other = OpenOptions::new().write(true).open(&path)?;
+        assert!(other.try_lock().is_err());
+
+        drop(lock);
+        assert!(other.try_lock().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lru_evictions() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let now = SystemTime::now();
+
+        for (name, size, age) in [
+            ("sha256-a", 300, 30),
+            ("sha256-b", 200, 20),
+            ("sha256-c", 100, 10),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, vec![0; size])?;
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(now - Duration::from_secs(age))?;
+        }
+
+        fs::write(dir.path().join(".tmpXYZ"), b"partial")?;
+        fs::create_dir(dir.path().join("locks"))?;
+
+        let blobs = list_blobs(dir.path())?;
+        assert_eq!(blobs.len(), 3);
+        assert_eq!(blobs[0].path, dir.path().join("sha256-a"));
+
+        let names = |blobs: Vec<CachedBlob>| -> Vec<String> {
+            blobs
+                .iter()
+                .map(|blob| blob.path.file_name().unwrap().to_string_lossy().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            names(lru_evictions(blobs.clone(), 600, None)),
+            Vec::<String>::new()
+        );
+        assert_eq!(names(lru_evictions(blobs.clone(), 300, None)), ["sha256-a"]);
+        assert_eq!(
+            names(lru_evictions(blobs.clone(), 250, None)),
+            ["sha256-a", "sha256-b"]
+        );
+
+        // The blob just written stays, even if it is the least recently used
+        let keep = dir.path().join("sha256-a");
+        assert_eq!(
+            names(lru_evictions(blobs.clone(), 300, Some(&keep))),
+            ["sha256-b", "sha256-c"]
+        );
+
+        touch(&keep);
+        assert_eq!(list_blobs(dir.path())?.last().unwrap().path, keep);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+
+use crate::{
+    archive::detect_media_type,
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    parser::FullImageWithTag,
+    report::Context,
+    spec::enums::MediaType,
+    Cat,
+};
+
+/// How many symlinks are followed before giving up, like the kernel's limit
+const MAX_LINKS: usize = 40;
+
+/// What a single layer says about a path
+#[derive(Debug, PartialEq)]
+enum Lookup {
+    File(Vec<u8>),
+    Link(PathBuf),
+    Directory,
+    /// Removed by a whiteout, so older layers must not be searched
+    Deleted,
+    Missing,
+}
+
+/// Turns an absolute or tar entry path into a root-relative one, resolving `.` and `..`
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+
+    normalized
+}
+
+pub fn layer_reader<'a>(data: &'a [u8]) -> Result<Box<dyn Read + 'a>, Box<dyn Error>> {
+    Ok(match detect_media_type(data)? {
+        MediaType::OciImageLayerV1TarGzip => Box::new(GzDecoder::new(data)),
+        MediaType::OciImageLayerV1TarZstd => Box::new(zstd::stream::Decoder::new(data)?),
+        _ => Box::new(data),
+    })
+}
+
+/// Looks a path up in a single layer tarball, streaming through it. A
+/// symlink in place of one of the directories of the path is followed, as
+/// with /bin/sh in images where /bin links to /usr/bin
+fn find_in_layer<R: Read>(reader: R, path: &Path) -> io::Result<Lookup> {
+    let mut deleted = false;
+
+    for entry in Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        let entry_path = normalize(&entry.path()?);
+        let file_name = entry_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let parent = entry_path.parent().unwrap_or(Path::new(""));
+
+        if file_name == ".wh..wh..opq" {
+            // An opaque directory hides everything below it in older layers
+            deleted |= path.starts_with(parent) && path != parent;
+            continue;
+        }
+
+        if let Some(name) = file_name.strip_prefix(".wh.") {
+            deleted |= path.starts_with(parent.join(name));
+            continue;
+        }
+
+        // What is left of the path below the entry
+        let Ok(rest) = path.strip_prefix(&entry_path) else {
+            continue;
+        };
+
+        let header = entry.header();
+
+        if rest != Path::new("") && header.entry_type() != EntryType::Symlink {
+            continue;
+        }
+
+        return Ok(match header.entry_type() {
+            EntryType::Directory => Lookup::Directory,
+            EntryType::Symlink => {
+                let target = header.link_name()?.unwrap_or_default().into_owned();
+
+                if target.is_absolute() {
+                    Lookup::Link(normalize(&target.join(rest)))
+                } else {
+                    Lookup::Link(normalize(&parent.join(target).join(rest)))
+                }
+            }
+            EntryType::Link => Lookup::Link(normalize(&header.link_name()?.unwrap_or_default())),
+            _ => {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                Lookup::File(content)
+            }
+        });
+    }
+
+    Ok(if deleted {
+        Lookup::Deleted
+    } else {
+        Lookup::Missing
+    })
+}
+
+pub async fn cat_command(
+    args: &Cat,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let (manifest, _) = downloader
+        .download_image(image.clone(), args.platform.as_deref())
+        .await
+        .with_context(|| format!("fetching {}", args.image))?;
+
+    let mut path = normalize(&args.path);
+    let mut links = 0;
+
+    // Layers are searched newest first, and searched again when a link is followed
+    'resolve: loop {
+        for layer in manifest.layers.iter().rev() {
+            let data = downloader
+                .download_layer(image.image.clone(), &layer.digest)
+                .await
+                .with_context(|| format!("downloading layer {}", layer.digest))?;
+
+            let lookup = find_in_layer(layer_reader(&data)?, &path)?;
+
+            match lookup {
+                Lookup::File(content) => {
+                    io::stdout().write_all(&content)?;
+                    return Ok(());
+                }
+                Lookup::Link(target) => {
+                    links += 1;
+
+                    if links > MAX_LINKS {
+                        return Err(
+                            format!("Too many links resolving {}", args.path.display()).into()
+                        );
+                    }
+
+                    path = target;
+                    continue 'resolve;
+                }
+                Lookup::Directory => {
+                    return Err(format!("/{} is a directory", path.display()).into())
+                }
+                Lookup::Deleted => break,
+                Lookup::Missing => {}
+            }
+        }
+
+        return Err(format!("/{} does not exist in {}", path.display(), args.image).into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+
+    fn layer(files: &[(&str, &str)], symlinks: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for (path, content) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+
+        for (path, target) in symlinks {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_find_in_layer() {
+        let data = layer(
+            &[("./etc/os-release", "ID=alpine\n"), ("usr/.wh.share", "")],
+            &[
+                ("etc/alpine-release", "../usr/lib/release"),
+                ("bin", "usr/bin"),
+                ("etc/ssl/certs", "/usr/share/ca-certificates"),
+            ],
+        );
+        let find = |path: &str| find_in_layer(&data[..], Path::new(path)).unwrap();
+
+        assert_eq!(
+            find("etc/os-release"),
+            Lookup::File(b"ID=alpine\n".to_vec())
+        );
+        assert_eq!(
+            find("etc/alpine-release"),
+            Lookup::Link(PathBuf::from("usr/lib/release"))
+        );
+        assert_eq!(find("usr/share/man/cat.1"), Lookup::Deleted);
+        assert_eq!(find("usr/bin/cat"), Lookup::Missing);
+
+        // Links in place of directories are followed for the rest of the path
+        assert_eq!(find("bin/sh"), Lookup::Link(PathBuf::from("usr/bin/sh")));
+        assert_eq!(
+            find("etc/ssl/certs/ca.pem"),
+            Lookup::Link(PathBuf::from("usr/share/ca-certificates/ca.pem"))
+        );
+        assert_eq!(find("etc"), Lookup::Missing);
+    }
+
+    #[test]
+    fn test_opaque_directory() {
+        let data = layer(&[("etc/.wh..wh..opq", ""), ("etc/hostname", "box")], &[]);
+        let find = |path: &str| find_in_layer(&data[..], Path::new(path)).unwrap();
+
+        assert_eq!(find("etc/hostname"), Lookup::File(b"box".to_vec()));
+        assert_eq!(find("etc/passwd"), Lookup::Deleted);
+        assert_eq!(find("etc"), Lookup::Missing);
+    }
+}
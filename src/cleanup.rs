@@ -1,12 +1,88 @@
+use crate::cache::write_atomic;
+use crate::format::{format_size, raw_sizes};
+use crate::layout::REF_NAME_ANNOTATION;
+use crate::quarantine::Quarantine;
+use crate::report::exit;
+use crate::storage_check::{check_storage, print_check_report};
 use crate::Cleanup;
+use indicatif::{ProgressBar, ProgressStyle};
+use regex_lite::Regex;
+use serde::Serialize;
 use serde_json::Value;
 use std::fs;
+use std::time::{Duration, SystemTime};
 use std::{
     collections::{HashMap, HashSet},
     io::stdin,
-    path::PathBuf,
-    process::exit,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
 };
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// How a registry lays out its storage directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageLayout {
+    /// registry:2 and other distribution deployments, with docker/registry/v2
+    /// holding blobs and owner/name repositories of link files
+    Distribution,
+    /// Harbor, which runs distribution under its data directory, next to its
+    /// database, with repositories nested in projects at any depth
+    Harbor,
+    /// Zot, which stores every repository as an OCI image layout
+    Zot,
+}
+
+impl StorageLayout {
+    pub fn parse(name: &str) -> Result<StorageLayout, String> {
+        match name {
+            "distribution" => Ok(StorageLayout::Distribution),
+            "harbor" => Ok(StorageLayout::Harbor),
+            "zot" => Ok(StorageLayout::Zot),
+            _ => Err(format!(
+                "Unknown storage layout {}, expected distribution, harbor or zot",
+                name
+            )),
+        }
+    }
+
+    /// Guesses the layout of a storage directory. Harbor's data directory
+    /// has its database and secrets next to the registry, and Zot stores no
+    /// repositories directory, but OCI layouts
+    pub fn detect(dir: &Path) -> StorageLayout {
+        let has_dir = |name: &str| dir.join(name).is_dir();
+
+        if has_dir("registry") && (has_dir("database") || has_dir("secret")) {
+            return StorageLayout::Harbor;
+        }
+
+        if find_dir(&dir.to_path_buf(), "repositories").is_err() && !find_layouts(dir, 4).is_empty()
+        {
+            return StorageLayout::Zot;
+        }
+
+        StorageLayout::Distribution
+    }
+}
+
+/// Where the content of blobs is stored, by digest
+#[derive(Debug, Clone)]
+pub enum DigestStore {
+    /// blobs/sha256/<first two>/<digest>/data, as distribution stores them
+    Sharded(PathBuf),
+    /// blobs/sha256/<digest>, as in an OCI image layout
+    Flat(PathBuf),
+}
+
+impl DigestStore {
+    /// The file holding the content of a blob
+    pub fn data_path(&self, digest: &str) -> PathBuf {
+        match self {
+            DigestStore::Sharded(dir) => dir.join(&digest[..2]).join(digest).join("data"),
+            DigestStore::Flat(dir) => dir.join(digest),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Repository {
@@ -16,6 +92,19 @@ pub struct Repository {
     pub layer_dir: PathBuf,
     pub tag_dir: PathBuf,
     pub revision_dir: PathBuf,
+    /// Where the registry keeps the state of chunked uploads in progress
+    pub upload_dir: PathBuf,
+}
+
+impl Repository {
+    /// The repository as it is pulled, without an owner for top level ones
+    pub fn path_name(&self) -> String {
+        if self.owner.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.owner, self.name)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -24,14 +113,61 @@ pub struct DockerRepository {
     pub repositories: Vec<Repository>,
 }
 
+impl DockerRepository {
+    pub fn store(&self) -> DigestStore {
+        DigestStore::Sharded(self.blobs_dir.clone())
+    }
+}
+
+/// Limits a cleanup to some repositories, and keeps tags it would remove
+#[derive(Debug, Clone, Default)]
+pub struct CleanupFilter {
+    /// The repositories to clean up, or all if empty
+    pub repositories: Vec<String>,
+    /// Tags matching any of these are never removed
+    pub keep_tags: Vec<Regex>,
+}
+
+impl CleanupFilter {
+    pub fn new(cleanup: &Cleanup) -> Result<CleanupFilter, String> {
+        let keep_tags = cleanup
+            .keep_tags
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| format!("Invalid --keep-tags {}: {}", pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(CleanupFilter {
+            repositories: cleanup.repo.clone(),
+            keep_tags,
+        })
+    }
+
+    pub fn selects(&self, repo: &Repository) -> bool {
+        self.repositories.is_empty() || self.repositories.contains(&repo.path_name())
+    }
+
+    pub fn keeps_tag(&self, tag: &str) -> bool {
+        self.keep_tags.iter().any(|pattern| pattern.is_match(tag))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CleanupPlan<'a> {
     pub cleanup: &'a Cleanup,
+    pub filter: CleanupFilter,
     pub repository: &'a DockerRepository,
     pub cleanup_commits: HashMap<&'a Repository, HashSet<PathBuf>>,
     pub cleanup_indexes: HashMap<&'a Repository, HashSet<PathBuf>>,
     pub cleanup_revisions: HashMap<&'a Repository, HashSet<PathBuf>>,
     pub cleanup_layers: HashMap<&'a Repository, HashSet<String>>,
+    /// Tags to drop from the index.json of Zot repositories
+    pub cleanup_tags: HashMap<&'a Repository, HashSet<String>>,
+    /// Tag directories beyond the --keep-last most recent ones
+    pub cleanup_expired_tags: HashMap<&'a Repository, HashSet<PathBuf>>,
+    /// Uploads abandoned for longer than --upload-age
+    pub cleanup_uploads: HashMap<&'a Repository, HashSet<PathBuf>>,
     pub cleanup_blobs: HashSet<String>,
 }
 
@@ -39,14 +175,23 @@ impl<'a> CleanupPlan<'a> {
     pub fn new(cleanup: &'a Cleanup, repository: &'a DockerRepository) -> Self {
         CleanupPlan {
             cleanup,
+            filter: CleanupFilter::default(),
             repository,
             cleanup_commits: HashMap::new(),
             cleanup_indexes: HashMap::new(),
             cleanup_revisions: HashMap::new(),
             cleanup_layers: HashMap::new(),
+            cleanup_tags: HashMap::new(),
+            cleanup_expired_tags: HashMap::new(),
+            cleanup_uploads: HashMap::new(),
             cleanup_blobs: HashSet::new(),
         }
     }
+
+    pub fn with_filter(mut self, filter: CleanupFilter) -> Self {
+        self.filter = filter;
+        self
+    }
 }
 
 pub fn strip_sha256_prefix(name: &str) -> String {
@@ -110,90 +255,188 @@ pub fn find_commit_dirs(dir: &PathBuf) -> Result<HashSet<PathBuf>, String> {
     Ok(commit_dirs)
 }
 
-pub fn get_repository(dir: PathBuf) -> Result<DockerRepository, String> {
-    let blobs_dir = find_dir(&dir, "sha256")?;
-    let repositories_dir = find_dir(&dir, "repositories")?;
-    /*let blob_dirs = fs::read_dir(&blobs_dir)
-    .map_err(|e| e.to_string())?
-    .flatten()
-    .filter_map(|entry| {
-        if entry.file_type().map_or(false, |ft| ft.is_dir()) {
-            Some(entry.path())
+/// Finds the OCI image layouts under a directory, as Zot stores its
+/// repositories, skipping Zot's own directories such as _trivy
+pub fn find_layouts(dir: &Path, max_depth: usize) -> Vec<PathBuf> {
+    if dir.join("oci-layout").is_file() && dir.join("index.json").is_file() {
+        return vec![dir.to_path_buf()];
+    }
+
+    if max_depth == 0 {
+        return vec![];
+    }
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut entries = entries.flatten().collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            entry.file_type().is_ok_and(|ft| ft.is_dir())
+                && !name.starts_with('_')
+                && !name.starts_with('.')
+        })
+        .flat_map(|entry| find_layouts(&entry.path(), max_depth - 1))
+        .collect()
+}
+
+/// Splits a repository path into its owner, everything up to the last
+/// component, and its name
+fn split_repository_path(path: &Path) -> (String, String) {
+    let path = path.to_string_lossy().replace('\\', "/");
+
+    match path.rsplit_once('/') {
+        Some((owner, name)) => (owner.to_string(), name.to_string()),
+        None => (String::new(), path),
+    }
+}
+
+/// Reads a distribution repository, if it has the link directories cleanup needs
+fn distribution_repository(owner: String, name: String, repo_path: PathBuf) -> Option<Repository> {
+    let layer_dir = repo_path.join("_layers/sha256");
+    let manifest_dir = repo_path.join("_manifests");
+    let tag_dir = manifest_dir.join("tags");
+    let revision_dir = manifest_dir.join("revisions/sha256");
+
+    if !layer_dir.exists() {
+        eprintln!(
+            "Layer directory does not exist for repository: {}",
+            repo_path.display()
+        );
+        return None;
+    }
+
+    if !tag_dir.exists() {
+        eprintln!(
+            "Tag directory does not exist for repository: {}",
+            repo_path.display()
+        );
+        return None;
+    }
+
+    if !revision_dir.exists() {
+        eprintln!(
+            "Tag directory does not exist for repository: {}",
+            repo_path.display()
+        );
+        return None;
+    }
+
+    Some(Repository {
+        owner,
+        name,
+        upload_dir: repo_path.join("_uploads"),
+        dir: repo_path,
+        layer_dir,
+        tag_dir,
+        revision_dir,
+    })
+}
+
+/// Finds the repositories of a Harbor project at any depth, as every
+/// directory holding _manifests
+fn find_nested_repositories(repositories_dir: &Path, dir: &Path, found: &mut Vec<Repository>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if !entry.file_type().is_ok_and(|ft| ft.is_dir()) || name.starts_with('_') {
+            continue;
+        }
+
+        let path = entry.path();
+
+        if path.join("_manifests").is_dir() {
+            let relative = path.strip_prefix(repositories_dir).unwrap_or(&path);
+            let (owner, name) = split_repository_path(relative);
+            found.extend(distribution_repository(owner, name, path.clone()));
         } else {
-            None
+            find_nested_repositories(repositories_dir, &path, found);
         }
-    })
-    .collect::<Vec<_>>();*/
+    }
+}
 
-    let repositories = fs::read_dir(&repositories_dir)
-        .map_err(|e| e.to_string())?
-        .flatten()
-        .filter_map(|owner_entry| {
-            if !owner_entry.file_type().map_or(false, |ft| ft.is_dir()) {
-                return None;
-            }
+pub fn get_repository(dir: PathBuf, layout: StorageLayout) -> Result<DockerRepository, String> {
+    if layout == StorageLayout::Zot {
+        let repositories = find_layouts(&dir, 8)
+            .into_iter()
+            .map(|repo_path| {
+                let relative = repo_path.strip_prefix(&dir).unwrap_or(&repo_path);
+                let (owner, name) = split_repository_path(relative);
+                let blobs_dir = repo_path.join("blobs/sha256");
+
+                Repository {
+                    owner,
+                    name,
+                    dir: repo_path.clone(),
+                    layer_dir: blobs_dir.clone(),
+                    upload_dir: repo_path.join(".uploads"),
+                    tag_dir: repo_path,
+                    revision_dir: blobs_dir,
+                }
+            })
+            .collect();
 
-            let owner = owner_entry.file_name().to_string_lossy().to_string();
-            let owner_path = owner_entry.path();
-            // Iterate over names inside the owner directory
-            let repos = fs::read_dir(&owner_path)
-                .map_err(|e| e.to_string())
-                .ok()?
-                .flatten()
-                .filter_map(|repo_entry| {
-                    if repo_entry.file_type().map_or(false, |ft| ft.is_dir()) {
-                        let name = repo_entry.file_name().to_string_lossy().to_string();
-                        let repo_path = repo_entry.path();
-                        let layer_dir = repo_path.join("_layers/sha256");
-                        let manifest_dir = repo_path.join("_manifests");
-                        let tag_dir = manifest_dir.join("tags");
-                        let revision_dir = manifest_dir.join("revisions/sha256");
-
-                        if !layer_dir.exists() {
-                            eprintln!(
-                                "Layer directory does not exist for repository: {}",
-                                repo_path.display()
-                            );
-                            return None;
-                        }
+        return Ok(DockerRepository {
+            blobs_dir: dir,
+            repositories,
+        });
+    }
 
-                        if !tag_dir.exists() {
-                            eprintln!(
-                                "Tag directory does not exist for repository: {}",
-                                repo_path.display()
-                            );
-                            return None;
-                        }
+    // Harbor keeps distribution's storage in the registry directory
+    let dir = match layout {
+        StorageLayout::Harbor if dir.join("registry").is_dir() => dir.join("registry"),
+        _ => dir,
+    };
 
-                        if !revision_dir.exists() {
-                            eprintln!(
-                                "Tag directory does not exist for repository: {}",
-                                repo_path.display()
-                            );
-                            return None;
-                        }
+    let blobs_dir = find_dir(&dir, "sha256")?;
+    let repositories_dir = find_dir(&dir, "repositories")?;
 
-                        Some(Repository {
-                            owner: owner.clone(),
-                            name,
-                            dir: repo_path,
-                            layer_dir,
-                            tag_dir,
-                            revision_dir,
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
+    let repositories = if layout == StorageLayout::Harbor {
+        let mut repositories = vec![];
+        find_nested_repositories(&repositories_dir, &repositories_dir, &mut repositories);
+        repositories
+    } else {
+        fs::read_dir(&repositories_dir)
+            .map_err(|e| e.to_string())?
+            .flatten()
+            .filter_map(|owner_entry| {
+                if !owner_entry.file_type().map_or(false, |ft| ft.is_dir()) {
+                    return None;
+                }
 
-            Some(repos)
-        })
-        .flatten()
-        .collect::<Vec<_>>();
+                let owner = owner_entry.file_name().to_string_lossy().to_string();
+                let owner_path = owner_entry.path();
+                // Iterate over names inside the owner directory
+                let repos = fs::read_dir(&owner_path)
+                    .map_err(|e| e.to_string())
+                    .ok()?
+                    .flatten()
+                    .filter_map(|repo_entry| {
+                        if repo_entry.file_type().map_or(false, |ft| ft.is_dir()) {
+                            let name = repo_entry.file_name().to_string_lossy().to_string();
+                            distribution_repository(owner.clone(), name, repo_entry.path())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                Some(repos)
+            })
+            .flatten()
+            .collect::<Vec<_>>()
+    };
 
     Ok(DockerRepository {
-        //blob_dirs,
         blobs_dir,
         repositories,
     })
@@ -216,7 +459,7 @@ pub fn handle_digest(
 
 pub fn handle_manifest_file(
     data_path: &PathBuf,
-    repository: &DockerRepository,
+    store: &DigestStore,
     existing_blobs: &mut HashSet<String>,
     existing_layers: &mut HashSet<String>,
 ) {
@@ -227,18 +470,13 @@ pub fn handle_manifest_file(
                     for manifest in manifests {
                         if let Some(digest) = manifest.get("digest").and_then(|d| d.as_str()) {
                             let index_blob = strip_sha256_prefix(digest);
-                            let index_first_two = &index_blob[..2];
-                            let index_blob_path = repository
-                                .blobs_dir
-                                .join(index_first_two)
-                                .join(index_blob.clone())
-                                .join("data");
+                            let index_blob_path = store.data_path(&index_blob);
                             existing_blobs.insert(index_blob.clone());
                             existing_layers.insert(index_blob.clone());
 
                             handle_manifest_file(
                                 &index_blob_path,
-                                repository,
+                                store,
                                 existing_blobs,
                                 existing_layers,
                             )
@@ -294,6 +532,413 @@ pub fn handle_manifest_file(
     }
 }
 
+/// How long an upload has to be abandoned for before --uploads removes it,
+/// unless --upload-age is given
+pub const DEFAULT_UPLOAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When an upload was started: the startedat file distribution writes, or
+/// else the last change of its directory or file
+fn upload_started(path: &Path) -> Option<SystemTime> {
+    let started_at = fs::read_to_string(path.join("startedat"))
+        .ok()
+        .and_then(|started_at| OffsetDateTime::parse(started_at.trim(), &Rfc3339).ok())
+        .map(SystemTime::from);
+
+    started_at.or_else(|| {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+}
+
+/// The uploads in a directory of uploads that were started longer than
+/// `max_age` before `now`
+pub fn stale_uploads(upload_dir: &Path, max_age: Duration, now: SystemTime) -> HashSet<PathBuf> {
+    fs::read_dir(upload_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            upload_started(path)
+                .and_then(|started| now.duration_since(started).ok())
+                .is_some_and(|age| age > max_age)
+        })
+        .collect()
+}
+
+/// Plans removing the uploads abandoned in the selected repositories
+pub fn plan_upload_cleanup<'a>(
+    cleanup_plan: &mut CleanupPlan<'a>,
+    repository: &'a DockerRepository,
+) {
+    let cleanup = cleanup_plan.cleanup;
+
+    if !cleanup.all && !cleanup.uploads {
+        return;
+    }
+
+    let max_age = cleanup.upload_age.map_or(DEFAULT_UPLOAD_AGE, |hours| {
+        Duration::from_secs(hours * 60 * 60)
+    });
+    let now = SystemTime::now();
+
+    for repo in &repository.repositories {
+        if !cleanup_plan.filter.selects(repo) {
+            continue;
+        }
+
+        let uploads = stale_uploads(&repo.upload_dir, max_age, now);
+
+        if !uploads.is_empty() {
+            cleanup_plan.cleanup_uploads.insert(repo, uploads);
+        }
+    }
+}
+
+/// When the image of a manifest was created, from its config. An index is
+/// as old as the first manifest in it
+pub fn manifest_created(store: &DigestStore, digest: &str) -> Option<OffsetDateTime> {
+    let read_json = |digest: &str| -> Option<Value> {
+        serde_json::from_slice(&fs::read(store.data_path(digest)).ok()?).ok()
+    };
+
+    let manifest = read_json(digest)?;
+
+    if let Some(first) = manifest
+        .get("manifests")
+        .and_then(|manifests| manifests.as_array())
+        .and_then(|manifests| manifests.first())
+    {
+        return manifest_created(store, &strip_sha256_prefix(first.get("digest")?.as_str()?));
+    }
+
+    let config = read_json(&strip_sha256_prefix(
+        manifest.get("config")?.get("digest")?.as_str()?,
+    ))?;
+
+    OffsetDateTime::parse(config.get("created")?.as_str()?, &Rfc3339).ok()
+}
+
+/// The tags beyond the `keep` most recently created ones. Tags whose age is
+/// unknown count as the oldest
+pub fn expired_tags(mut tags: Vec<(String, Option<OffsetDateTime>)>, keep: usize) -> Vec<String> {
+    tags.sort_by(|(name_a, created_a), (name_b, created_b)| {
+        created_b.cmp(created_a).then_with(|| name_a.cmp(name_b))
+    });
+
+    tags.into_iter().skip(keep).map(|(name, _)| name).collect()
+}
+
+/// Plans the cleanup of Zot repositories. Tags named after commits are
+/// dropped from index.json, and the blobs that no remaining manifest
+/// refers to are removed from the repository's layout
+pub fn plan_zot_cleanup<'a>(cleanup_plan: &mut CleanupPlan<'a>, repository: &'a DockerRepository) {
+    let cleanup = cleanup_plan.cleanup;
+    let filter = cleanup_plan.filter.clone();
+
+    for repo in &repository.repositories {
+        if !filter.selects(repo) {
+            continue;
+        }
+
+        let index_path = repo.dir.join("index.json");
+        let index = match fs::read(&index_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+        {
+            Some(index) => index,
+            None => {
+                eprintln!("Could not read index file at {}", index_path.display());
+                continue;
+            }
+        };
+
+        let store = DigestStore::Flat(repo.layer_dir.clone());
+        let mut existing_blobs = HashSet::new();
+        let mut existing_layers = HashSet::new();
+        let manifests = index.get("manifests").and_then(|m| m.as_array());
+        let tag_of = |manifest: &Value| {
+            manifest
+                .get("annotations")
+                .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+                .and_then(|tag| tag.as_str())
+                .map(str::to_string)
+        };
+        let is_removed_commit = |tag: &str| {
+            (cleanup.all || cleanup.commits) && is_commit(tag) && !filter.keeps_tag(tag)
+        };
+
+        let expired: HashSet<String> = match cleanup.keep_last {
+            Some(keep) => {
+                let tags = manifests
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|manifest| {
+                        let tag = tag_of(manifest)
+                            .filter(|tag| !is_removed_commit(tag) && !filter.keeps_tag(tag))?;
+                        let digest = strip_sha256_prefix(manifest.get("digest")?.as_str()?);
+                        Some((tag, manifest_created(&store, &digest)))
+                    })
+                    .collect();
+
+                expired_tags(tags, keep).into_iter().collect()
+            }
+            None => HashSet::new(),
+        };
+
+        for manifest in manifests.into_iter().flatten() {
+            if let Some(tag) = tag_of(manifest) {
+                if is_removed_commit(&tag) || expired.contains(&tag) {
+                    cleanup_plan
+                        .cleanup_tags
+                        .entry(repo)
+                        .or_default()
+                        .insert(tag);
+                    continue;
+                }
+            }
+
+            if let Some(digest) = handle_digest(manifest, &mut existing_blobs, &mut existing_layers)
+            {
+                handle_manifest_file(
+                    &store.data_path(&digest),
+                    &store,
+                    &mut existing_blobs,
+                    &mut existing_layers,
+                );
+            }
+        }
+
+        if cleanup.all || cleanup.layers || cleanup.blobs {
+            let Ok(entries) = fs::read_dir(&repo.layer_dir) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let blob_name = entry.file_name().to_string_lossy().to_string();
+
+                if entry.file_type().is_ok_and(|ft| ft.is_file())
+                    && !existing_blobs.contains(&blob_name)
+                {
+                    cleanup_plan
+                        .cleanup_layers
+                        .entry(repo)
+                        .or_default()
+                        .insert(blob_name);
+                }
+            }
+        }
+    }
+}
+
+/// Drops tags from the index.json of an OCI image layout
+/// Removes tags from the index.json of a layout, returning the entries of
+/// the removed tags
+fn remove_layout_tags(repo: &Repository, tags: &HashSet<String>) -> Result<Vec<Value>, String> {
+    let index_path = repo.dir.join("index.json");
+    let data = fs::read(&index_path).map_err(|e| e.to_string())?;
+    let mut index: Value = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+    let mut removed = vec![];
+
+    if let Some(manifests) = index.get_mut("manifests").and_then(|m| m.as_array_mut()) {
+        let (kept, untagged) = manifests.drain(..).partition(|manifest| {
+            manifest
+                .get("annotations")
+                .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+                .and_then(|tag| tag.as_str())
+                .is_none_or(|tag| !tags.contains(tag))
+        });
+        *manifests = kept;
+        removed = untagged;
+    }
+
+    let data = serde_json::to_vec(&index).map_err(|e| e.to_string())?;
+    write_atomic(&index_path, &data).map_err(|e| e.to_string())?;
+    Ok(removed)
+}
+
+/// What a cleanup would remove from one repository
+#[derive(Serialize, Debug)]
+pub struct RepositoryReport {
+    pub repository: String,
+    /// Directories and files that would be removed
+    pub paths: Vec<PathBuf>,
+    /// Tags that would be removed, from the index.json of a Zot repository
+    /// or from a remote registry
+    pub tags: Vec<String>,
+    /// Manifests that would be deleted from a remote registry, by digest
+    pub manifests: Vec<String>,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BlobReport {
+    pub digest: String,
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Everything a cleanup would remove, for --dry-run
+#[derive(Serialize, Debug)]
+pub struct CleanupReport {
+    pub repositories: Vec<RepositoryReport>,
+    pub blobs: Vec<BlobReport>,
+    #[serde(rename = "totalBytes")]
+    pub total_bytes: u64,
+}
+
+/// The bytes taken by the files under a path, not following links
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| disk_usage(&entry.path()))
+        .sum()
+}
+
+/// The path of a blob in the shared blob store of distribution
+fn blob_dir(blobs_dir: &Path, digest: &str) -> PathBuf {
+    blobs_dir.join(&digest[..2]).join(digest)
+}
+
+fn repository_report<'r>(
+    reports: &'r mut HashMap<String, RepositoryReport>,
+    repo: &Repository,
+) -> &'r mut RepositoryReport {
+    let name = repo.path_name();
+
+    reports
+        .entry(name.clone())
+        .or_insert_with(|| RepositoryReport {
+            repository: name,
+            paths: vec![],
+            tags: vec![],
+            manifests: vec![],
+            bytes: 0,
+        })
+}
+
+impl CleanupPlan<'_> {
+    pub fn report(&self) -> CleanupReport {
+        let mut repositories: HashMap<String, RepositoryReport> = HashMap::new();
+
+        for (repo, dirs) in self
+            .cleanup_commits
+            .iter()
+            .chain(&self.cleanup_expired_tags)
+            .chain(&self.cleanup_uploads)
+            .chain(&self.cleanup_indexes)
+            .chain(&self.cleanup_revisions)
+        {
+            repository_report(&mut repositories, repo)
+                .paths
+                .extend(dirs.iter().cloned());
+        }
+
+        for (repo, layers) in &self.cleanup_layers {
+            let paths = layers.iter().map(|layer| repo.layer_dir.join(layer));
+            repository_report(&mut repositories, repo)
+                .paths
+                .extend(paths);
+        }
+
+        for (repo, tags) in &self.cleanup_tags {
+            repository_report(&mut repositories, repo)
+                .tags
+                .extend(tags.iter().cloned());
+        }
+
+        let mut repositories: Vec<_> = repositories
+            .into_values()
+            .map(|mut report| {
+                report.paths.sort();
+                report.tags.sort();
+                report.bytes = report.paths.iter().map(|path| disk_usage(path)).sum();
+                report
+            })
+            .collect();
+        repositories.sort_by(|a, b| a.repository.cmp(&b.repository));
+
+        let mut blobs: Vec<_> = self
+            .cleanup_blobs
+            .iter()
+            .map(|digest| {
+                let path = blob_dir(&self.repository.blobs_dir, digest);
+
+                BlobReport {
+                    digest: format!("sha256:{}", digest),
+                    bytes: disk_usage(&path),
+                    path,
+                }
+            })
+            .collect();
+        blobs.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+        let total_bytes = repositories.iter().map(|repo| repo.bytes).sum::<u64>()
+            + blobs.iter().map(|blob| blob.bytes).sum::<u64>();
+
+        CleanupReport {
+            repositories,
+            blobs,
+            total_bytes,
+        }
+    }
+}
+
+fn size(bytes: u64) -> String {
+    match raw_sizes() {
+        true => bytes.to_string(),
+        false => format_size(bytes),
+    }
+}
+
+/// Prints every path a cleanup would remove, for --dry-run
+pub fn print_report(report: &CleanupReport) {
+    for repo in &report.repositories {
+        println!("{} ({}):", repo.repository, size(repo.bytes));
+
+        for path in &repo.paths {
+            println!("  {}", path.display());
+        }
+
+        for tag in &repo.tags {
+            println!("  tag {}", tag);
+        }
+
+        for manifest in &repo.manifests {
+            println!("  manifest {}", manifest);
+        }
+    }
+
+    if !report.blobs.is_empty() {
+        println!("Blobs:");
+    }
+
+    for blob in &report.blobs {
+        println!(
+            "  {} ({}) {}",
+            blob.digest,
+            size(blob.bytes),
+            blob.path.display()
+        );
+    }
+
+    println!(
+        "Total space that would be freed: {}",
+        size(report.total_bytes)
+    );
+}
+
 pub fn preview_plan(cleanup_plan: &CleanupPlan) {
     let cleanup = cleanup_plan.cleanup;
 
@@ -309,10 +954,58 @@ pub fn preview_plan(cleanup_plan: &CleanupPlan) {
 
     for (repo, commit_dirs) in cleanup_commits_vec {
         println!(
-            "Would clean up {} commits for repository: {}/{}",
+            "Would clean up {} commits for repository: {}",
             commit_dirs.len(),
-            repo.owner,
-            repo.name
+            repo.path_name()
+        );
+    }
+
+    let mut cleanup_tags_vec: Vec<_> = cleanup_plan.cleanup_tags.iter().collect();
+    cleanup_tags_vec.sort_by(|(repo_a, tags_a), (repo_b, tags_b)| {
+        tags_b
+            .len()
+            .cmp(&tags_a.len())
+            .then_with(|| repo_a.name.cmp(&repo_b.name))
+    });
+
+    for (repo, tags) in cleanup_tags_vec {
+        println!(
+            "Would clean up {} tags for repository: {}",
+            tags.len(),
+            repo.path_name()
+        );
+    }
+
+    let mut cleanup_expired_vec: Vec<_> = cleanup_plan.cleanup_expired_tags.iter().collect();
+    cleanup_expired_vec.sort_by(|(repo_a, dirs_a), (repo_b, dirs_b)| {
+        dirs_b
+            .len()
+            .cmp(&dirs_a.len())
+            .then_with(|| repo_a.name.cmp(&repo_b.name))
+    });
+
+    for (repo, dirs) in cleanup_expired_vec {
+        println!(
+            "Would untag {} tags beyond the last {} for repository: {}",
+            dirs.len(),
+            cleanup.keep_last.unwrap_or_default(),
+            repo.path_name()
+        );
+    }
+
+    let mut cleanup_uploads_vec: Vec<_> = cleanup_plan.cleanup_uploads.iter().collect();
+    cleanup_uploads_vec.sort_by(|(repo_a, uploads_a), (repo_b, uploads_b)| {
+        uploads_b
+            .len()
+            .cmp(&uploads_a.len())
+            .then_with(|| repo_a.name.cmp(&repo_b.name))
+    });
+
+    for (repo, uploads) in cleanup_uploads_vec {
+        println!(
+            "Would clean up {} stale uploads for repository: {}",
+            uploads.len(),
+            repo.path_name()
         );
     }
 
@@ -328,10 +1021,9 @@ pub fn preview_plan(cleanup_plan: &CleanupPlan) {
 
     for (repo, dirs) in cleanup_indexes_vec {
         println!(
-            "Would clean up {} indices for repository: {}/{}",
+            "Would clean up {} indices for repository: {}",
             dirs.len(),
-            repo.owner,
-            repo.name
+            repo.path_name()
         );
     }
 
@@ -347,10 +1039,9 @@ pub fn preview_plan(cleanup_plan: &CleanupPlan) {
 
     for (repo, dirs) in cleanup_revisions_vec {
         println!(
-            "Would clean up {} revisions for repository: {}/{}",
+            "Would clean up {} revisions for repository: {}",
             dirs.len(),
-            repo.owner,
-            repo.name
+            repo.path_name()
         );
     }
 
@@ -366,10 +1057,9 @@ pub fn preview_plan(cleanup_plan: &CleanupPlan) {
 
     for (repo, layers) in cleanup_layers_vec {
         println!(
-            "Would clean up {} layers for repository: {}/{}",
+            "Would clean up {} layers for repository: {}",
             layers.len(),
-            repo.owner,
-            repo.name
+            repo.path_name()
         );
     }
 
@@ -378,118 +1068,299 @@ pub fn preview_plan(cleanup_plan: &CleanupPlan) {
         let mut total_bytes = 0u64;
 
         for blob_name in &cleanup_plan.cleanup_blobs {
-            let first_two = &blob_name[..2];
-            let blob_path = cleanup_plan
-                .repository
-                .blobs_dir
-                .join(first_two)
-                .join(blob_name)
-                .join("data");
+            let blob_path = cleanup_plan.repository.store().data_path(blob_name);
             if let Ok(metadata) = fs::metadata(&blob_path) {
                 total_bytes += metadata.len();
             }
         }
 
-        println!(
-            "Total space that would be freed: {} ({} bytes)",
-            humansize::SizeFormatter::new(total_bytes, humansize::BINARY),
-            total_bytes
-        );
+        if raw_sizes() {
+            println!("Total space that would be freed: {}", total_bytes);
+        } else {
+            println!(
+                "Total space that would be freed: {} ({} bytes)",
+                format_size(total_bytes),
+                total_bytes
+            );
+        }
     }
 }
 
-pub fn execute_plan(cleanup_plan: &CleanupPlan) {
-    for (_repo, commit_dirs) in &cleanup_plan.cleanup_commits {
-        for commit_dir in commit_dirs {
-            if let Err(e) = fs::remove_dir_all(&commit_dir) {
-                eprintln!(
-                    "Failed to remove commit directory {}: {}",
-                    commit_dir.display(),
-                    e
-                );
-            }
-        }
-    }
+/// A path the cleanup removes, and what it is for error messages
+struct Removal {
+    path: PathBuf,
+    kind: &'static str,
+}
 
-    for (_repo, dirs) in &cleanup_plan.cleanup_indexes {
-        for dir in dirs {
-            if let Err(e) = fs::remove_dir_all(&dir) {
-                eprintln!("Failed to remove index directory {}: {}", dir.display(), e);
-            }
-        }
+/// What removing the paths of a plan did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RemovalSummary {
+    pub removed: u64,
+    pub failed: u64,
+    /// Measured on the disk, so a directory that was only partly removed
+    /// counts with what is gone from it
+    pub bytes: u64,
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    // Zot stores blobs as files, distribution as directories of links
+    match fs::symlink_metadata(path)?.is_dir() {
+        true => fs::remove_dir_all(path),
+        false => fs::remove_file(path),
     }
+}
 
-    for (_repo, dirs) in &cleanup_plan.cleanup_revisions {
-        for dir in dirs {
-            if let Err(e) = fs::remove_dir_all(&dir) {
-                eprintln!(
-                    "Failed to remove revision directory {}: {}",
-                    dir.display(),
-                    e
+/// Removes paths on a thread per CPU, as large registries can have hundreds
+/// of thousands of blobs to remove
+fn remove_all(
+    removals: &[Removal],
+    quarantine: Option<&Quarantine>,
+    progress: &ProgressBar,
+) -> RemovalSummary {
+    let next_removal = AtomicUsize::new(0);
+    let removed = AtomicU64::new(0);
+    let failed = AtomicU64::new(0);
+    let bytes = AtomicU64::new(0);
+    let workers = num_cpus::get().min(removals.len()).max(1);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next_removal.fetch_add(1, Ordering::Relaxed);
+
+                let Some(removal) = removals.get(index) else {
+                    break;
+                };
+
+                let before = disk_usage(&removal.path);
+                let result = match quarantine {
+                    Some(quarantine) => quarantine.keep(&removal.path),
+                    None => remove_path(&removal.path),
+                };
+                bytes.fetch_add(
+                    before.saturating_sub(disk_usage(&removal.path)),
+                    Ordering::Relaxed,
                 );
-            }
+
+                match result {
+                    Ok(()) => removed.fetch_add(1, Ordering::Relaxed),
+                    Err(e) => {
+                        progress.suspend(|| {
+                            eprintln!(
+                                "Failed to remove {} {}: {}",
+                                removal.kind,
+                                removal.path.display(),
+                                e
+                            )
+                        });
+                        failed.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+
+                progress.inc(1);
+            });
         }
+    });
+
+    RemovalSummary {
+        removed: removed.into_inner(),
+        failed: failed.into_inner(),
+        bytes: bytes.into_inner(),
     }
+}
 
-    for (repo, layers) in &cleanup_plan.cleanup_layers {
-        for layer in layers {
-            let layer_path = repo.layer_dir.join(&layer);
-            if let Err(e) = fs::remove_dir_all(&layer_path) {
-                eprintln!(
-                    "Failed to remove layer directory {}: {}",
-                    layer_path.display(),
-                    e
-                );
-            }
+pub fn execute_plan(cleanup_plan: &CleanupPlan, quarantine: Option<&Quarantine>) -> RemovalSummary {
+    let removal = |kind| {
+        move |path: &PathBuf| Removal {
+            path: path.clone(),
+            kind,
         }
-    }
+    };
+
+    // Everything that refers to layers and blobs goes first, so that an
+    // interrupted cleanup leaves no tag behind without its data
+    let references: Vec<Removal> = cleanup_plan
+        .cleanup_commits
+        .values()
+        .flatten()
+        .map(removal("commit directory"))
+        .chain(
+            cleanup_plan
+                .cleanup_uploads
+                .values()
+                .flatten()
+                .map(removal("upload")),
+        )
+        .chain(
+            cleanup_plan
+                .cleanup_expired_tags
+                .values()
+                .flatten()
+                .map(removal("tag directory")),
+        )
+        .chain(
+            cleanup_plan
+                .cleanup_indexes
+                .values()
+                .flatten()
+                .map(removal("index directory")),
+        )
+        .chain(
+            cleanup_plan
+                .cleanup_revisions
+                .values()
+                .flatten()
+                .map(removal("revision directory")),
+        )
+        .collect();
 
     let blobs_dir = &cleanup_plan.repository.blobs_dir;
+    let data: Vec<Removal> = cleanup_plan
+        .cleanup_layers
+        .iter()
+        .flat_map(|(repo, layers)| {
+            layers.iter().map(|layer| Removal {
+                path: repo.layer_dir.join(layer),
+                kind: "layer",
+            })
+        })
+        .chain(cleanup_plan.cleanup_blobs.iter().map(|blob_name| Removal {
+            path: blob_dir(blobs_dir, blob_name),
+            kind: "blob directory",
+        }))
+        .collect();
+
+    let progress = ProgressBar::new((references.len() + data.len()) as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} paths ({per_sec})")
+            .expect("Failed to set progress bar style")
+            .progress_chars("#>-"),
+    );
+
+    let mut summary = remove_all(&references, quarantine, &progress);
+
+    for (repo, tags) in &cleanup_plan.cleanup_tags {
+        let index_path = repo.dir.join("index.json");
+        let removed = remove_layout_tags(repo, tags).and_then(|entries| match quarantine {
+            Some(quarantine) => quarantine
+                .keep_index_entries(&index_path, entries)
+                .map_err(|e| e.to_string()),
+            None => Ok(()),
+        });
 
-    for blob_name in &cleanup_plan.cleanup_blobs {
-        let first_two = &blob_name[..2];
-        let blob_path = blobs_dir.join(first_two).join(blob_name);
-        if let Err(e) = fs::remove_dir_all(&blob_path) {
-            eprintln!(
-                "Failed to remove blob directory {}: {}",
-                blob_path.display(),
-                e
-            );
+        if let Err(e) = removed {
+            progress.suspend(|| {
+                eprintln!("Failed to remove tags from {}: {}", index_path.display(), e)
+            });
         }
     }
+
+    let data_summary = remove_all(&data, quarantine, &progress);
+    progress.finish_and_clear();
+
+    summary.removed += data_summary.removed;
+    summary.failed += data_summary.failed;
+    summary.bytes += data_summary.bytes;
+    summary
 }
 
-pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error>> {
-    let dir = &cleanup.dir;
+pub fn cleanup_command(cleanup: Cleanup, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(dir) = &cleanup.dir else {
+        return Err(
+            "Give the storage directory to clean up with --dir, or a registry with --remote".into(),
+        );
+    };
+
+    check_format(&cleanup, dry_run)?;
 
     if !dir.exists() {
         eprintln!("Directory does not exist: {}", dir.display());
         exit(1);
     }
 
-    if !cleanup.all && !cleanup.commits && !cleanup.indexes && !cleanup.layers && !cleanup.blobs {
+    if !cleanup.all
+        && !cleanup.commits
+        && !cleanup.indexes
+        && !cleanup.layers
+        && !cleanup.blobs
+        && !cleanup.uploads
+        && cleanup.keep_last.is_none()
+        && !cleanup.check
+    {
         eprintln!(
-            "No cleanup options specified. Use --all, --commits or --indexes or --layers or --blobs."
+            "No cleanup options specified. Use --all, --commits or --indexes or --layers or --blobs or --uploads or --keep-last, or --check."
         );
         exit(1);
     }
 
-    let repository = get_repository(dir.clone()).unwrap_or_else(|e| {
+    let layout = match &cleanup.layout {
+        Some(layout) => StorageLayout::parse(layout)?,
+        None => StorageLayout::detect(dir),
+    };
+
+    let repository = get_repository(dir.clone(), layout).unwrap_or_else(|e| {
         eprintln!("Error finding repository: {}", e);
         exit(1);
     });
 
+    let filter = CleanupFilter::new(&cleanup)?;
+
+    for name in &filter.repositories {
+        if !repository
+            .repositories
+            .iter()
+            .any(|repo| &repo.path_name() == name)
+        {
+            return Err(format!("No repository named {} in {}", name, dir.display()).into());
+        }
+    }
+
+    if cleanup.check {
+        let report = check_storage(&repository, layout, &filter);
+
+        match cleanup.format.as_deref() {
+            Some("json") => println!("{}", serde_json::to_string_pretty(&report)?),
+            _ => print_check_report(&report),
+        }
+
+        if !report.problems.is_empty() {
+            return Err(format!(
+                "Found {} problems in {}",
+                report.problems.len(),
+                dir.display()
+            )
+            .into());
+        }
+
+        return Ok(());
+    }
+
+    if layout == StorageLayout::Zot {
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &repository).with_filter(filter);
+        plan_zot_cleanup(&mut cleanup_plan, &repository);
+        plan_upload_cleanup(&mut cleanup_plan, &repository);
+        return run_plan(&cleanup_plan, dry_run);
+    }
+
     let mut cleaned_up_tags = HashMap::<&Repository, HashSet<String>>::new();
     let mut existing_blobs = HashSet::<String>::new();
     let mut existing_blobs_by_repo = HashMap::<&Repository, HashSet<String>>::new();
-    let mut cleanup_plan = CleanupPlan::new(&cleanup, &repository);
+    let mut cleanup_plan = CleanupPlan::new(&cleanup, &repository).with_filter(filter.clone());
+
+    // Blobs are shared by every repository, so all of them are read to
+    // know which blobs are still used, but only the selected ones change
+    let selected = |repo: &Repository| filter.selects(repo);
 
     if cleanup.all || cleanup.commits {
-        for repo in &repository.repositories {
-            let commit_dirs = find_commit_dirs(&repo.tag_dir).unwrap_or_else(|e| {
+        for repo in repository.repositories.iter().filter(|repo| selected(repo)) {
+            let mut commit_dirs = find_commit_dirs(&repo.tag_dir).unwrap_or_else(|e| {
                 eprintln!("Error finding commit directories: {}", e);
                 exit(1);
             });
+            commit_dirs.retain(|dir| {
+                !filter.keeps_tag(&dir.file_name().unwrap_or_default().to_string_lossy())
+            });
 
             if !commit_dirs.is_empty() {
                 for commit_dir in &commit_dirs {
@@ -506,6 +1377,37 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
         }
     }
 
+    if let Some(keep) = cleanup.keep_last {
+        for repo in repository.repositories.iter().filter(|repo| selected(repo)) {
+            let cleaned_up_tags_in_repo = cleaned_up_tags.entry(repo).or_default();
+            let tags = fs::read_dir(&repo.tag_dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|ft| ft.is_dir()))
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|tag| !cleaned_up_tags_in_repo.contains(tag) && !filter.keeps_tag(tag))
+                .map(|tag| {
+                    let link = repo.tag_dir.join(&tag).join("current/link");
+                    let created = fs::read_to_string(link).ok().and_then(|link| {
+                        manifest_created(&repository.store(), &strip_sha256_prefix(link.trim()))
+                    });
+
+                    (tag, created)
+                })
+                .collect();
+
+            for tag in expired_tags(tags, keep) {
+                cleanup_plan
+                    .cleanup_expired_tags
+                    .entry(repo)
+                    .or_default()
+                    .insert(repo.tag_dir.join(&tag));
+                cleaned_up_tags_in_repo.insert(tag);
+            }
+        }
+    }
+
     for repo in &repository.repositories {
         let tag_dirs = fs::read_dir(&repo.tag_dir)
             .map_err(|e| e.to_string())
@@ -522,7 +1424,7 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
 
                 if cleaned_up_tags_in_repo.contains(&tag_name) {
                     // This tag was cleaned up in the previous step, we can't use it anymore
-                    println!(
+                    eprintln!(
                         "Skipping cleaned up tag: {}",
                         entry.file_name().to_string_lossy()
                     );
@@ -546,28 +1448,24 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
                             if entry.file_type().map_or(false, |ft| ft.is_dir()) {
                                 let revision_name = entry.file_name().to_string_lossy().to_string();
 
-                                if cleanup.all || cleanup.indexes {
-                                    if !existing_blobs_in_repo.contains(&revision_name) {
-                                        cleanup_plan
-                                            .cleanup_indexes
-                                            .entry(repo)
-                                            .or_default()
-                                            .insert(entry.path());
-                                        continue;
-                                    }
+                                if (cleanup.all || cleanup.indexes)
+                                    && selected(repo)
+                                    && !existing_blobs_in_repo.contains(&revision_name)
+                                {
+                                    cleanup_plan
+                                        .cleanup_indexes
+                                        .entry(repo)
+                                        .or_default()
+                                        .insert(entry.path());
+                                    continue;
                                 }
 
-                                let first_two = &revision_name[..2];
-                                let data_path = repository
-                                    .blobs_dir
-                                    .join(first_two)
-                                    .join(&revision_name)
-                                    .join("data");
+                                let data_path = repository.store().data_path(&revision_name);
                                 existing_blobs.insert(revision_name.clone());
 
                                 handle_manifest_file(
                                     &data_path,
-                                    &repository,
+                                    &repository.store(),
                                     &mut existing_blobs,
                                     existing_blobs_in_repo,
                                 );
@@ -579,7 +1477,7 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
         }
     }
 
-    for repo in &repository.repositories {
+    for repo in repository.repositories.iter().filter(|repo| selected(repo)) {
         let existing_blobs_in_repo = existing_blobs_by_repo.entry(repo).or_default();
 
         if cleanup.all || cleanup.layers {
@@ -605,7 +1503,7 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
         }
     }
 
-    for repo in &repository.repositories {
+    for repo in repository.repositories.iter().filter(|repo| selected(repo)) {
         let existing_blobs_in_repo = existing_blobs_by_repo.entry(repo).or_default();
 
         if cleanup.all || cleanup.layers {
@@ -657,6 +1555,18 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
     }
 
     if cleanup.all || cleanup.blobs {
+        // Limited to the repositories, only blobs they link to are removed
+        let selected_blobs = (!filter.repositories.is_empty()).then(|| {
+            repository
+                .repositories
+                .iter()
+                .filter(|repo| selected(repo))
+                .flat_map(|repo| [&repo.layer_dir, &repo.revision_dir])
+                .flat_map(|dir| fs::read_dir(dir).into_iter().flatten().flatten())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect::<HashSet<_>>()
+        });
+
         let blob_dirs = fs::read_dir(&repository.blobs_dir)
             .map_err(|e| e.to_string())
             .unwrap_or_else(|e| {
@@ -679,7 +1589,11 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
                             if blob_entry.file_type().map_or(false, |ft| ft.is_dir()) {
                                 let blob_name =
                                     blob_entry.file_name().to_string_lossy().to_string();
-                                if !existing_blobs.contains(&blob_name) {
+                                if !existing_blobs.contains(&blob_name)
+                                    && selected_blobs
+                                        .as_ref()
+                                        .is_none_or(|blobs| blobs.contains(&blob_name))
+                                {
                                     cleanup_plan.cleanup_blobs.insert(blob_name);
                                 }
                             }
@@ -690,21 +1604,87 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
         }
     }
 
-    preview_plan(&cleanup_plan);
+    plan_upload_cleanup(&mut cleanup_plan, &repository);
+    run_plan(&cleanup_plan, dry_run)
+}
 
-    if !cleanup.yes {
-        println!("Do you want to proceed with the cleanup? (y/N)");
+/// Checks --format, which only formats the --dry-run and --check reports
+pub fn check_format(cleanup: &Cleanup, dry_run: bool) -> Result<(), String> {
+    match cleanup.format.as_deref() {
+        None | Some("text") => Ok(()),
+        Some("json") if dry_run || cleanup.check => Ok(()),
+        Some("json") => Err("--format json needs --dry-run or --check".to_string()),
+        Some(format) => Err(format!("Unknown format: {}", format)),
+    }
+}
 
-        let mut input = String::new();
-        stdin().read_line(&mut input).expect("Failed to read line");
+/// Prints the --dry-run report in the format asked for
+pub fn print_dry_run(
+    cleanup: &Cleanup,
+    report: &CleanupReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match cleanup.format.as_deref() {
+        Some("json") => println!("{}", serde_json::to_string_pretty(report)?),
+        _ => print_report(report),
+    }
 
-        if !input.trim().eq_ignore_ascii_case("y") {
-            println!("Cleanup aborted.");
-            return Ok(());
-        }
+    Ok(())
+}
+
+/// Asks whether to go ahead with the cleanup, unless --yes agreed already
+pub fn confirm(cleanup: &Cleanup) -> bool {
+    if cleanup.yes {
+        return true;
+    }
+
+    println!("Do you want to proceed with the cleanup? (y/N)");
+
+    let mut input = String::new();
+    stdin().read_line(&mut input).expect("Failed to read line");
+
+    if !input.trim().eq_ignore_ascii_case("y") {
+        println!("Cleanup aborted.");
+        return false;
     }
 
-    execute_plan(&cleanup_plan);
+    true
+}
+
+/// Previews a plan, and carries it out once confirmed
+fn run_plan(cleanup_plan: &CleanupPlan, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if dry_run {
+        return print_dry_run(cleanup_plan.cleanup, &cleanup_plan.report());
+    }
+
+    preview_plan(cleanup_plan);
+
+    if !confirm(cleanup_plan.cleanup) {
+        return Ok(());
+    }
+
+    let quarantine = match (&cleanup_plan.cleanup.quarantine, &cleanup_plan.cleanup.dir) {
+        (Some(quarantine), Some(dir)) => Some(Quarantine::new(dir, quarantine)?),
+        _ => None,
+    };
+    let summary = execute_plan(cleanup_plan, quarantine.as_ref());
+
+    match &quarantine {
+        Some(quarantine) => println!(
+            "Moved {} paths to the quarantine {}, reclaiming {}",
+            summary.removed,
+            quarantine.dir().display(),
+            size(summary.bytes)
+        ),
+        None => println!(
+            "Removed {} paths, reclaiming {}",
+            summary.removed,
+            size(summary.bytes)
+        ),
+    }
+
+    if summary.failed > 0 {
+        return Err(format!("Failed to remove {} paths", summary.failed).into());
+    }
 
     Ok(())
 }
@@ -712,6 +1692,7 @@ pub fn cleanup_command(cleanup: Cleanup) -> Result<(), Box<dyn std::error::Error
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{CleanupCmd, Remove};
     use std::fs::{self, File};
     use std::io::Write;
     use std::path::PathBuf;
@@ -777,7 +1758,7 @@ mod tests {
         let path = dir.path().to_path_buf();
         create_test_repo(&path);
 
-        let docker_repo = get_repository(path.clone()).unwrap();
+        let docker_repo = get_repository(path.clone(), StorageLayout::Distribution).unwrap();
 
         assert_eq!(docker_repo.repositories.len(), 1);
         let repo = &docker_repo.repositories[0];
@@ -818,7 +1799,7 @@ mod tests {
         let dir = tempdir().unwrap();
         let path = dir.path().to_path_buf();
         create_test_repo(&path);
-        let docker_repo = get_repository(path.clone()).unwrap();
+        let docker_repo = get_repository(path.clone(), StorageLayout::Distribution).unwrap();
 
         let manifest_path = path.join("manifest.json");
         let mut file = File::create(&manifest_path).unwrap();
@@ -841,7 +1822,7 @@ mod tests {
 
         handle_manifest_file(
             &manifest_path,
-            &docker_repo,
+            &docker_repo.store(),
             &mut existing_blobs,
             &mut existing_layers,
         );
@@ -851,4 +1832,395 @@ mod tests {
         assert!(existing_layers.contains("abcdef123456"));
         assert!(existing_layers.contains("fedcba654321"));
     }
+
+    #[test]
+    fn test_detect_layout() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        create_test_repo(&path);
+        assert_eq!(StorageLayout::detect(&path), StorageLayout::Distribution);
+
+        let harbor = tempdir().unwrap();
+        let harbor_path = harbor.path().to_path_buf();
+        create_test_repo(&harbor_path.join("registry"));
+        fs::create_dir_all(harbor_path.join("database")).unwrap();
+        assert_eq!(StorageLayout::detect(&harbor_path), StorageLayout::Harbor);
+
+        let zot = tempdir().unwrap();
+        let repo = zot.path().join("team/app");
+        fs::create_dir_all(repo.join("blobs/sha256")).unwrap();
+        fs::write(repo.join("oci-layout"), "{}").unwrap();
+        fs::write(repo.join("index.json"), "{}").unwrap();
+        assert_eq!(StorageLayout::detect(zot.path()), StorageLayout::Zot);
+    }
+
+    #[test]
+    fn test_get_repository_harbor() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        let repositories = path.join("registry/docker/registry/v2/repositories");
+
+        for repo in ["library/nginx", "team/tools/builder"] {
+            fs::create_dir_all(repositories.join(repo).join("_layers/sha256")).unwrap();
+            fs::create_dir_all(repositories.join(repo).join("_manifests/tags")).unwrap();
+            fs::create_dir_all(repositories.join(repo).join("_manifests/revisions/sha256"))
+                .unwrap();
+        }
+
+        fs::create_dir_all(path.join("registry/docker/registry/v2/blobs/sha256")).unwrap();
+
+        let docker_repo = get_repository(path, StorageLayout::Harbor).unwrap();
+        let mut names: Vec<_> = docker_repo
+            .repositories
+            .iter()
+            .map(Repository::path_name)
+            .collect();
+        names.sort();
+
+        assert_eq!(names, vec!["library/nginx", "team/tools/builder"]);
+    }
+
+    #[test]
+    fn test_plan_zot_cleanup() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("app");
+        let blobs = repo_path.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        fs::write(repo_path.join("oci-layout"), "{}").unwrap();
+
+        let commit = "da5929574d2d37543b174334c73def59a5aa724b";
+        let manifest = |config: &str| {
+            format!(
+                r#"{{"config":{{"digest":"sha256:{}"}},"layers":[]}}"#,
+                config
+            )
+        };
+
+        fs::write(blobs.join("aa11"), manifest("cc11")).unwrap();
+        fs::write(blobs.join("cc11"), "{}").unwrap();
+        fs::write(blobs.join("bb22"), manifest("dd22")).unwrap();
+        fs::write(blobs.join("dd22"), "{}").unwrap();
+        fs::write(blobs.join("ee33"), "stray").unwrap();
+        fs::write(
+            repo_path.join("index.json"),
+            format!(
+                r#"{{"manifests":[
+                    {{"digest":"sha256:aa11","annotations":{{"org.opencontainers.image.ref.name":"latest"}}}},
+                    {{"digest":"sha256:bb22","annotations":{{"org.opencontainers.image.ref.name":"{}"}}}}
+                ]}}"#,
+                commit
+            ),
+        )
+        .unwrap();
+
+        let cleanup = Cleanup {
+            dir: Some(dir.path().to_path_buf()),
+            remote: None,
+            layout: None,
+            commits: false,
+            indexes: false,
+            layers: false,
+            blobs: false,
+            all: true,
+            yes: true,
+            format: None,
+            keep_last: None,
+            uploads: false,
+            upload_age: None,
+            quarantine: None,
+            check: false,
+            subcommand: CleanupCmd::Remove(Remove {}),
+            repo: vec![],
+            keep_tags: vec![],
+        };
+        let docker_repo = get_repository(dir.path().to_path_buf(), StorageLayout::Zot).unwrap();
+        assert_eq!(docker_repo.repositories[0].path_name(), "app");
+
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &docker_repo);
+        plan_zot_cleanup(&mut cleanup_plan, &docker_repo);
+
+        let repo = &docker_repo.repositories[0];
+        assert_eq!(
+            cleanup_plan.cleanup_tags[repo],
+            HashSet::from([commit.to_string()])
+        );
+        assert_eq!(
+            cleanup_plan.cleanup_layers[repo],
+            HashSet::from(["bb22".to_string(), "dd22".to_string(), "ee33".to_string()])
+        );
+
+        let report = cleanup_plan.report();
+        assert_eq!(report.repositories.len(), 1);
+        assert_eq!(report.repositories[0].repository, "app");
+        assert_eq!(report.repositories[0].tags, vec![commit.to_string()]);
+        assert_eq!(
+            report.repositories[0].paths,
+            vec![blobs.join("bb22"), blobs.join("dd22"), blobs.join("ee33")]
+        );
+        let freed = manifest("dd22").len() as u64 + 2 + 5;
+        assert_eq!(report.repositories[0].bytes, freed);
+        assert_eq!(report.total_bytes, freed);
+
+        execute_plan(&cleanup_plan, None);
+
+        let index = fs::read_to_string(repo_path.join("index.json")).unwrap();
+        assert!(!index.contains(commit));
+        assert!(blobs.join("aa11").exists());
+        assert!(!blobs.join("ee33").exists());
+    }
+
+    #[test]
+    fn test_report_blobs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        create_test_repo(&path);
+
+        let blob = "abcdef123456";
+        let blob_dir = path.join("docker/registry/v2/blobs/sha256/ab").join(blob);
+        fs::create_dir_all(&blob_dir).unwrap();
+        fs::write(blob_dir.join("data"), "0123456789").unwrap();
+
+        let cleanup = Cleanup {
+            dir: Some(path.clone()),
+            remote: None,
+            layout: None,
+            commits: false,
+            indexes: false,
+            layers: false,
+            blobs: true,
+            all: false,
+            yes: false,
+            format: Some("json".to_string()),
+            keep_last: None,
+            uploads: false,
+            upload_age: None,
+            quarantine: None,
+            check: false,
+            subcommand: CleanupCmd::Remove(Remove {}),
+            repo: vec![],
+            keep_tags: vec![],
+        };
+        let docker_repo = get_repository(path, StorageLayout::Distribution).unwrap();
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &docker_repo);
+        cleanup_plan.cleanup_blobs.insert(blob.to_string());
+
+        let report = cleanup_plan.report();
+        assert!(report.repositories.is_empty());
+        assert_eq!(report.blobs.len(), 1);
+        assert_eq!(report.blobs[0].digest, "sha256:abcdef123456");
+        assert_eq!(report.blobs[0].path, blob_dir);
+        assert_eq!(report.blobs[0].bytes, 10);
+        assert_eq!(report.total_bytes, 10);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["totalBytes"], 10);
+    }
+
+    #[test]
+    fn test_expired_tags() {
+        let at = |secs| OffsetDateTime::from_unix_timestamp(secs).ok();
+        let tags = vec![
+            ("old".to_string(), at(100)),
+            ("unknown".to_string(), None),
+            ("new".to_string(), at(300)),
+            ("mid".to_string(), at(200)),
+        ];
+
+        assert_eq!(expired_tags(tags.clone(), 2), vec!["old", "unknown"]);
+        assert_eq!(expired_tags(tags.clone(), 0).len(), 4);
+        assert!(expired_tags(tags, 10).is_empty());
+    }
+
+    #[test]
+    fn test_remove_all() {
+        let dir = tempdir().unwrap();
+
+        let blob = dir.path().join("blob");
+        fs::create_dir_all(&blob).unwrap();
+        fs::write(blob.join("data"), vec![0; 100]).unwrap();
+
+        let layer = dir.path().join("layer");
+        fs::write(&layer, vec![0; 20]).unwrap();
+
+        let removals: Vec<Removal> = [blob.clone(), layer.clone(), dir.path().join("missing")]
+            .into_iter()
+            .map(|path| Removal { path, kind: "blob" })
+            .collect();
+
+        let summary = remove_all(&removals, None, &ProgressBar::hidden());
+        assert_eq!(
+            summary,
+            RemovalSummary {
+                removed: 2,
+                failed: 1,
+                bytes: 120,
+            }
+        );
+        assert!(!blob.exists());
+        assert!(!layer.exists());
+    }
+
+    #[test]
+    fn test_stale_uploads() {
+        let dir = tempdir().unwrap();
+        let uploads = dir.path().join("_uploads");
+
+        let old = uploads.join("old");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("startedat"), "2024-01-01T00:00:00Z").unwrap();
+
+        let recent = uploads.join("recent");
+        fs::create_dir_all(&recent).unwrap();
+        fs::write(recent.join("startedat"), "2024-01-02T23:00:00Z").unwrap();
+
+        // Without startedat, as Zot keeps its uploads, the age is the mtime
+        let file = uploads.join("file");
+        File::create(&file).unwrap();
+
+        let now =
+            SystemTime::from(OffsetDateTime::parse("2024-01-03T00:00:00Z", &Rfc3339).unwrap());
+        let stale = stale_uploads(&uploads, DEFAULT_UPLOAD_AGE, now);
+        assert_eq!(stale, HashSet::from([old.clone()]));
+
+        let stale = stale_uploads(
+            &uploads,
+            Duration::ZERO,
+            SystemTime::now() + DEFAULT_UPLOAD_AGE,
+        );
+        assert_eq!(stale, HashSet::from([old, recent, file]));
+
+        assert!(stale_uploads(&dir.path().join("missing"), Duration::ZERO, now).is_empty());
+    }
+
+    #[test]
+    fn test_plan_zot_keep_last() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("app");
+        let blobs = repo_path.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        fs::write(repo_path.join("oci-layout"), "{}").unwrap();
+
+        let mut manifests = vec![];
+        for (tag, day) in [("v1", 1), ("v2", 2), ("v3", 3)] {
+            let config = format!("c{}", day);
+            let manifest = format!("m{}", day);
+            fs::write(
+                blobs.join(&config),
+                format!(r#"{{"created":"2024-01-0{}T00:00:00Z"}}"#, day),
+            )
+            .unwrap();
+            fs::write(
+                blobs.join(&manifest),
+                format!(
+                    r#"{{"config":{{"digest":"sha256:{}"}},"layers":[]}}"#,
+                    config
+                ),
+            )
+            .unwrap();
+            manifests.push(format!(
+                r#"{{"digest":"sha256:{}","annotations":{{"org.opencontainers.image.ref.name":"{}"}}}}"#,
+                manifest, tag
+            ));
+        }
+        fs::write(
+            repo_path.join("index.json"),
+            format!(r#"{{"manifests":[{}]}}"#, manifests.join(",")),
+        )
+        .unwrap();
+
+        let store = DigestStore::Flat(blobs.clone());
+        assert_eq!(
+            manifest_created(&store, "m2"),
+            OffsetDateTime::parse("2024-01-02T00:00:00Z", &Rfc3339).ok()
+        );
+
+        let cleanup = Cleanup {
+            dir: Some(dir.path().to_path_buf()),
+            remote: None,
+            layout: None,
+            commits: false,
+            indexes: false,
+            layers: true,
+            blobs: false,
+            all: false,
+            yes: true,
+            format: None,
+            keep_last: Some(2),
+            uploads: false,
+            upload_age: None,
+            quarantine: None,
+            check: false,
+            subcommand: CleanupCmd::Remove(Remove {}),
+            repo: vec![],
+            keep_tags: vec![],
+        };
+        let docker_repo = get_repository(dir.path().to_path_buf(), StorageLayout::Zot).unwrap();
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &docker_repo);
+        plan_zot_cleanup(&mut cleanup_plan, &docker_repo);
+
+        let repo = &docker_repo.repositories[0];
+        assert_eq!(
+            cleanup_plan.cleanup_tags[repo],
+            HashSet::from(["v1".to_string()])
+        );
+        assert_eq!(
+            cleanup_plan.cleanup_layers[repo],
+            HashSet::from(["m1".to_string(), "c1".to_string()])
+        );
+
+        // A protected tag neither expires nor counts towards the kept ones
+        let cleanup = Cleanup {
+            keep_last: Some(1),
+            keep_tags: vec!["^v1$".to_string()],
+            ..cleanup
+        };
+        let filter = CleanupFilter::new(&cleanup).unwrap();
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &docker_repo).with_filter(filter);
+        plan_zot_cleanup(&mut cleanup_plan, &docker_repo);
+        assert_eq!(
+            cleanup_plan.cleanup_tags[repo],
+            HashSet::from(["v2".to_string()])
+        );
+
+        let cleanup = Cleanup {
+            repo: vec!["other".to_string()],
+            ..cleanup
+        };
+        let filter = CleanupFilter::new(&cleanup).unwrap();
+        let mut cleanup_plan = CleanupPlan::new(&cleanup, &docker_repo).with_filter(filter);
+        plan_zot_cleanup(&mut cleanup_plan, &docker_repo);
+        assert!(cleanup_plan.cleanup_tags.is_empty());
+        assert!(cleanup_plan.cleanup_layers.is_empty());
+    }
+
+    #[test]
+    fn test_cleanup_filter() {
+        let repo = |owner: &str, name: &str| Repository {
+            owner: owner.to_string(),
+            name: name.to_string(),
+            dir: PathBuf::new(),
+            layer_dir: PathBuf::new(),
+            tag_dir: PathBuf::new(),
+            revision_dir: PathBuf::new(),
+            upload_dir: PathBuf::new(),
+        };
+
+        let filter = CleanupFilter::default();
+        assert!(filter.selects(&repo("team", "app")));
+        assert!(!filter.keeps_tag("latest"));
+
+        let filter = CleanupFilter {
+            repositories: vec!["team/app".to_string()],
+            keep_tags: vec![
+                Regex::new(r"^v\d+\.\d+\.\d+$").unwrap(),
+                Regex::new("^latest$").unwrap(),
+            ],
+        };
+        assert!(filter.selects(&repo("team", "app")));
+        assert!(!filter.selects(&repo("team", "web")));
+        assert!(filter.keeps_tag("v1.2.3"));
+        assert!(filter.keeps_tag("latest"));
+        assert!(!filter.keeps_tag("v1.2"));
+        assert!(!filter.keeps_tag("latest-dev"));
+    }
 }
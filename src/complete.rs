@@ -0,0 +1,158 @@
+use std::{collections::BTreeSet, error::Error, path::Path, sync::Arc, time::Duration};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    compose::containerd::client::{services::v1::ListImagesRequest, Client},
+    downloader::OciDownloader,
+    parser::FullImage,
+    state::State,
+    with_namespace, CompleteImage,
+};
+use tonic::Request;
+
+/// Completion must stay responsive, even when a registry is slow or unreachable
+const REGISTRY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Returns the ways an image can be spelled, from the fully qualified
+/// reference down to the short Docker Hub form
+fn image_aliases(image: &str) -> Vec<String> {
+    let image = image
+        .strip_prefix("registry-1.docker.io/")
+        .map(|rest| format!("docker.io/{}", rest))
+        .unwrap_or_else(|| image.to_string());
+    let mut aliases = vec![image.clone()];
+
+    if let Some(rest) = image.strip_prefix("docker.io/") {
+        aliases.push(rest.to_string());
+
+        if let Some(rest) = rest.strip_prefix("library/") {
+            aliases.push(rest.to_string());
+        }
+    }
+
+    aliases
+}
+
+/// Filters the known images down to the suggestions matching a prefix
+pub fn suggestions(prefix: &str, images: &[String]) -> BTreeSet<String> {
+    images
+        .iter()
+        .flat_map(|image| image_aliases(image))
+        .filter(|alias| alias.starts_with(prefix))
+        .collect()
+}
+
+/// Lists the images in the local containerd store, if it is reachable
+async fn containerd_images(socket: &Path) -> Vec<String> {
+    let Ok(client) = Client::from_path(socket).await else {
+        return vec![];
+    };
+
+    match client
+        .images()
+        .list(with_namespace!(
+            ListImagesRequest { filters: vec![] },
+            "default"
+        ))
+        .await
+    {
+        Ok(response) => response
+            .into_inner()
+            .images
+            .into_iter()
+            .map(|image| image.name)
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Lists the tags of the repository being completed, once the prefix names one
+async fn registry_tags(prefix: &str, client: Arc<OciClient>) -> Vec<String> {
+    let Some((repository, _)) = prefix.rsplit_once(':') else {
+        return vec![];
+    };
+
+    if repository.is_empty() || repository.contains('@') || prefix.ends_with('/') {
+        return vec![];
+    }
+
+    let Ok(image) = FullImage::parse(repository, client.settings()) else {
+        return vec![];
+    };
+
+    let tags = async {
+        client
+            .login(&[ImagePermission {
+                full_image: image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await
+            .ok()?;
+
+        OciDownloader::new(client.clone(), true)
+            .list_tags(image)
+            .await
+            .ok()
+    };
+
+    match tokio::time::timeout(REGISTRY_TIMEOUT, tags).await {
+        Ok(Some(tags)) => tags
+            .into_iter()
+            .map(|tag| format!("{}:{}", repository, tag))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub async fn complete_image_command(
+    args: &CompleteImage,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let prefix = args.prefix.clone().unwrap_or_default();
+    let socket = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| "/run/containerd/containerd.sock".into());
+
+    let mut images: Vec<String> = State::load(&State::path()).images.into_keys().collect();
+    images.extend(containerd_images(&socket).await);
+    images.extend(registry_tags(&prefix, client).await);
+
+    for suggestion in suggestions(&prefix, &images) {
+        println!("{}", suggestion);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggestions() {
+        let images = vec![
+            "registry-1.docker.io/library/nginx:alpine".to_string(),
+            "docker.io/library/nginx:1.27".to_string(),
+            "ghcr.io/example/app:latest".to_string(),
+        ];
+
+        assert_eq!(
+            suggestions("ngi", &images).into_iter().collect::<Vec<_>>(),
+            vec!["nginx:1.27", "nginx:alpine"]
+        );
+        assert_eq!(
+            suggestions("library/nginx:a", &images)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["library/nginx:alpine"]
+        );
+        assert_eq!(
+            suggestions("ghcr.io/", &images)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["ghcr.io/example/app:latest"]
+        );
+        assert!(suggestions("redis", &images).is_empty());
+    }
+}
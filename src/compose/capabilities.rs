@@ -0,0 +1,193 @@
+use crate::{
+    compose::containerd::client::{
+        services::v1::{Plugin, PluginsRequest},
+        Client,
+    },
+    macros::impl_error,
+};
+use tonic::{Code, Request, Status};
+
+impl_error!(ContainerdError);
+
+/// The oldest containerd release ocitool pulls into
+const MIN_VERSION: (u64, u64) = (1, 5);
+
+/// The gRPC services a pull writes content, images and leases through
+const REQUIRED_SERVICES: [&str; 3] = ["content", "images", "leases"];
+
+const GRPC_PLUGIN: &str = "io.containerd.grpc.v1";
+const SNAPSHOTTER_PLUGIN: &str = "io.containerd.snapshotter.v1";
+
+/// Parses a containerd version such as v1.7.2 or 2.0.0-rc.1 into its major and minor parts
+fn parse_version(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.trim_start_matches('v').split(['.', '-', '+']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+
+    Some((major, minor))
+}
+
+fn describe_status(status: &Status) -> String {
+    if status.message().is_empty() {
+        format!("{:?}", status.code())
+    } else {
+        status.message().to_string()
+    }
+}
+
+/// Checks that the plugins a pull relies on are loaded, returning warnings
+/// for the ones that are only needed later on
+fn check_plugins(version: &str, plugins: &[Plugin]) -> Result<Vec<String>, ContainerdError> {
+    for service in REQUIRED_SERVICES {
+        let plugin = plugins
+            .iter()
+            .find(|plugin| plugin.r#type == GRPC_PLUGIN && plugin.id == service);
+
+        match plugin {
+            None => {
+                return Err(ContainerdError(format!(
+                    "containerd {} does not provide the {} service. Is {}.{} listed in disabled_plugins of its config?",
+                    version, service, GRPC_PLUGIN, service
+                )))
+            }
+            Some(Plugin {
+                init_err: Some(err),
+                ..
+            }) => {
+                return Err(ContainerdError(format!(
+                    "the {} service of containerd {} failed to start: {}",
+                    service, version, err.message
+                )))
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut warnings = Vec::new();
+
+    if !plugins
+        .iter()
+        .any(|plugin| plugin.r#type == SNAPSHOTTER_PLUGIN && plugin.init_err.is_none())
+    {
+        warnings.push(format!(
+            "containerd {} has no working snapshotter, so pulled images cannot be unpacked",
+            version
+        ));
+    }
+
+    Ok(warnings)
+}
+
+/// Asks containerd for its version and plugins before anything is written,
+/// so that an unsupported daemon is reported up front instead of failing
+/// halfway through a pull with a bare gRPC status
+pub async fn negotiate(client: &Client) -> Result<(), ContainerdError> {
+    let version = match client.version().version(Request::new(())).await {
+        Ok(response) => response.into_inner().version,
+        Err(status) if status.code() == Code::Unimplemented => {
+            return Err(ContainerdError(format!(
+                "containerd does not implement the version service. Versions older than {}.{} are not supported",
+                MIN_VERSION.0, MIN_VERSION.1
+            )))
+        }
+        Err(status) => {
+            return Err(ContainerdError(format!(
+                "containerd did not report its version: {}",
+                describe_status(&status)
+            )))
+        }
+    };
+
+    match parse_version(&version) {
+        Some(parsed) if parsed < MIN_VERSION => {
+            return Err(ContainerdError(format!(
+                "containerd {} is not supported, {}.{} or newer is required",
+                version, MIN_VERSION.0, MIN_VERSION.1
+            )))
+        }
+        Some(_) => {}
+        // Development builds report a commit instead of a release
+        None => eprintln!(
+            "Unknown containerd version {}, assuming it is recent",
+            version
+        ),
+    }
+
+    let plugins = match client
+        .introspection()
+        .plugins(Request::new(PluginsRequest { filters: vec![] }))
+        .await
+    {
+        Ok(response) => response.into_inner().plugins,
+        Err(status) => {
+            eprintln!(
+                "Could not list the plugins of containerd {}: {}",
+                version,
+                describe_status(&status)
+            );
+            return Ok(());
+        }
+    };
+
+    for warning in check_plugins(&version, &plugins)? {
+        eprintln!("Warning: {}", warning);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::containerd::client::google::rpc::Status as RpcStatus;
+
+    fn plugin(r#type: &str, id: &str, init_err: Option<&str>) -> Plugin {
+        Plugin {
+            r#type: r#type.to_string(),
+            id: id.to_string(),
+            init_err: init_err.map(|message| RpcStatus {
+                code: 2,
+                message: message.to_string(),
+                details: vec![],
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v1.7.2"), Some((1, 7)));
+        assert_eq!(parse_version("2.0.0-rc.1"), Some((2, 0)));
+        assert_eq!(parse_version("1.4"), Some((1, 4)));
+        assert_eq!(parse_version("a1b2c3d"), None);
+        assert!(parse_version("v1.4.13").unwrap() < MIN_VERSION);
+    }
+
+    #[test]
+    fn test_check_plugins() {
+        let mut plugins = vec![
+            plugin(GRPC_PLUGIN, "content", None),
+            plugin(GRPC_PLUGIN, "images", None),
+            plugin(GRPC_PLUGIN, "leases", None),
+            plugin(SNAPSHOTTER_PLUGIN, "overlayfs", None),
+        ];
+
+        assert!(check_plugins("v1.7.2", &plugins).unwrap().is_empty());
+
+        plugins[3] = plugin(SNAPSHOTTER_PLUGIN, "overlayfs", Some("not supported"));
+        assert_eq!(check_plugins("v1.7.2", &plugins).unwrap().len(), 1);
+
+        plugins[1] = plugin(GRPC_PLUGIN, "images", Some("bolt is locked"));
+        assert!(check_plugins("v1.7.2", &plugins)
+            .unwrap_err()
+            .0
+            .contains("bolt is locked"));
+
+        plugins.remove(2);
+        plugins.remove(1);
+        assert!(check_plugins("v1.7.2", &plugins)
+            .unwrap_err()
+            .0
+            .contains("images service"));
+    }
+}
@@ -150,7 +150,6 @@ impl Client {
     /// Task service client.
     #[inline]
     pub fn tasks(&self) -> TasksClient<Channel> {
-        println!("log: tasks client created");
         TasksClient::new(self.channel())
     }
 
@@ -1,14 +1,43 @@
 use crate::{
     access::ensure_socket_access,
+    compose::capabilities::negotiate,
     compose::containerd::client::{
         services::v1::{CreateRequest, DeleteRequest},
         Client,
     },
     with_namespace,
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tonic::Request;
 
+/// How long containerd keeps a lease that ocitool could not release,
+/// for example because it was killed. Matches containerd's own default
+const LEASE_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+async fn create_lease(
+    client: &Client,
+    namespace: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let expires_at = (OffsetDateTime::now_utc() + LEASE_EXPIRY).format(&Rfc3339)?;
+    let lease = client
+        .leases()
+        .create(with_namespace!(
+            CreateRequest {
+                id: "".to_string(),
+                labels: HashMap::from([("containerd.io/gc.expire".to_string(), expires_at)]),
+            },
+            namespace
+        ))
+        .await?
+        .into_inner();
+
+    match lease.lease {
+        None => Err("Failed to create lease".into()),
+        Some(lease) => Ok(lease.id),
+    }
+}
+
 pub struct LeasedClient {
     client: Arc<Client>,
     lease_id: String,
@@ -23,26 +52,27 @@ impl LeasedClient {
         ensure_socket_access(path);
 
         let client = Client::from_path(path).await?;
-        let lease = client
-            .leases()
-            .create(with_namespace!(
-                CreateRequest {
-                    id: "".to_string(),
-                    labels: HashMap::new(),
-                },
-                namespace
-            ))
-            .await?
-            .into_inner();
-
-        match lease.lease {
-            None => Err("Failed to create lease".into()),
-            Some(lease) => Ok(Self {
-                client: Arc::new(client),
-                lease_id: lease.id,
-                namespace,
-            }),
-        }
+        negotiate(&client).await?;
+
+        let lease_id = create_lease(&client, &namespace).await?;
+
+        Ok(Self {
+            client: Arc::new(client),
+            lease_id,
+            namespace,
+        })
+    }
+
+    /// Creates another lease on the same connection, so that content can be
+    /// protected from garbage collection, and released, in smaller batches
+    pub async fn new_lease(&self) -> Result<Self, Box<dyn std::error::Error>> {
+        let lease_id = create_lease(&self.client, &self.namespace).await?;
+
+        Ok(Self {
+            client: Arc::clone(&self.client),
+            lease_id,
+            namespace: self.namespace.clone(),
+        })
     }
 
     pub fn lease_id(&self) -> &str {
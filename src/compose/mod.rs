@@ -1,3 +1,4 @@
+pub mod capabilities;
 pub mod containerd;
 pub mod docker_compose_finder;
 pub mod lease;
@@ -1,19 +1,30 @@
 use crate::compose::containerd::client::services::v1::{
-    CreateImageRequest, Image, ListContentRequest, UpdateImageRequest, WriteAction,
-    WriteContentRequest,
+    CreateImageRequest, Image, Info, ListContentRequest, UpdateImageRequest, UpdateRequest,
+    WriteAction, WriteContentRequest,
 };
 use crate::compose::containerd::client::types;
 use crate::compose::lease::LeasedClient;
-use crate::parser::FullImageWithTag;
+use crate::parser::{FullImage, FullImageWithTag};
 use crate::with_client;
-use prost_types::Timestamp;
-use std::collections::{HashMap, HashSet};
+use prost_types::{FieldMask, Timestamp};
+use std::collections::HashMap;
 use std::sync::Arc;
 use tonic::{Code, Request};
 
-pub async fn get_existing_digests_from_containerd(
+/// The prefix of the labels containerd keeps the repositories a blob was
+/// pulled from in, one label per registry, as a comma separated list
+pub const DISTRIBUTION_SOURCE_PREFIX: &str = "containerd.io/distribution.source.";
+
+/// The distribution source label of the registry of an image, such as
+/// containerd.io/distribution.source.ghcr.io
+pub fn distribution_source_label(image: &FullImage) -> String {
+    format!("{}{}", DISTRIBUTION_SOURCE_PREFIX, image.host())
+}
+
+/// Lists the content in containerd along with the labels of each blob
+pub async fn get_existing_content_from_containerd(
     container_client: Arc<LeasedClient>,
-) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+) -> Result<HashMap<String, HashMap<String, String>>, Box<dyn std::error::Error>> {
     let list_content_request =
         with_client!(ListContentRequest { filters: vec![] }, container_client);
     let content = container_client
@@ -30,14 +41,77 @@ pub async fn get_existing_digests_from_containerd(
         }
     };
 
-    let mut existing_digests = HashSet::<String>::new();
+    let mut existing_content = HashMap::new();
     while let Some(item) = stream.message().await? {
-        for info in &item.info {
-            existing_digests.insert(info.digest.clone());
+        for info in item.info {
+            existing_content.insert(info.digest, info.labels);
         }
     }
 
-    Ok(existing_digests)
+    Ok(existing_content)
+}
+
+/// Returns the labels that have to be written for a blob to carry the wanted
+/// ones. The distribution source is a list, so a repository is appended to it
+/// rather than replacing the repositories already recorded
+pub fn missing_labels(
+    existing: &HashMap<String, String>,
+    wanted: HashMap<String, String>,
+) -> HashMap<String, String> {
+    wanted
+        .into_iter()
+        .filter_map(|(key, value)| match existing.get(&key) {
+            Some(current) if key.starts_with(DISTRIBUTION_SOURCE_PREFIX) => {
+                if current.split(',').any(|source| source == value) {
+                    None
+                } else {
+                    Some((key, format!("{},{}", current, value)))
+                }
+            }
+            Some(current) if *current == value => None,
+            _ => Some((key, value)),
+        })
+        .collect()
+}
+
+/// Adds the labels a blob still needs to its pending update. The update is
+/// diffed against the labels the blob has with the pending ones applied, so
+/// that images sharing the blob add to each other's distribution sources
+pub fn merge_label_update(
+    existing: &HashMap<String, String>,
+    pending: &mut HashMap<String, String>,
+    wanted: HashMap<String, String>,
+) {
+    let mut current = existing.clone();
+    current.extend(pending.clone());
+    pending.extend(missing_labels(&current, wanted));
+}
+
+/// Sets labels on a blob that already exists, leaving its other labels alone
+pub async fn update_content_labels(
+    container_client: Arc<LeasedClient>,
+    digest: &str,
+    labels: HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let paths = labels.keys().map(|key| format!("labels.{}", key)).collect();
+
+    container_client
+        .client()
+        .content()
+        .update(with_client!(
+            UpdateRequest {
+                info: Some(Info {
+                    digest: digest.to_string(),
+                    labels,
+                    ..Default::default()
+                }),
+                update_mask: Some(FieldMask { paths }),
+            },
+            container_client
+        ))
+        .await?;
+
+    Ok(())
 }
 
 pub async fn upload_content_to_containerd(
@@ -102,10 +176,7 @@ pub async fn create_image_in_containerd(
         .create(with_client!(
             CreateImageRequest {
                 image: Some(Image {
-                    name: format!(
-                        "docker.io/{}:{}",
-                        full_image.image.library_name, full_image.tag
-                    ),
+                    name: full_image.canonical_name(),
                     labels: HashMap::new(),
                     target: Some(types::Descriptor {
                         media_type: media_type.clone(),
@@ -131,10 +202,7 @@ pub async fn create_image_in_containerd(
                     .update(with_client!(
                         UpdateImageRequest {
                             image: Some(Image {
-                                name: format!(
-                                    "docker.io/{}:{}",
-                                    full_image.image.library_name, full_image.tag
-                                ),
+                                name: full_image.canonical_name(),
                                 labels: HashMap::new(),
                                 target: Some(types::Descriptor {
                                     media_type,
@@ -175,12 +243,103 @@ mod tests {
     use crate::parser::{FullImage, FullImageWithTag};
     use crate::test::tests::{create_test_client, ContainerdTestEnv};
 
+    const SOURCE: &str = "containerd.io/distribution.source.docker.io";
+
+    #[test]
+    fn test_merge_label_update() {
+        let existing = HashMap::from([(SOURCE.to_string(), "library/alpine".to_string())]);
+        let mut pending = HashMap::new();
+
+        // Two images sharing a blob both end up in its sources
+        for repository in ["library/nginx", "library/redis", "library/nginx"] {
+            merge_label_update(
+                &existing,
+                &mut pending,
+                HashMap::from([(SOURCE.to_string(), repository.to_string())]),
+            );
+        }
+
+        assert_eq!(
+            pending,
+            HashMap::from([(
+                SOURCE.to_string(),
+                "library/alpine,library/nginx,library/redis".to_string()
+            )])
+        );
+
+        let ghcr = FullImage::from_image_name("ghcr.io/example/app").unwrap();
+        assert_eq!(
+            distribution_source_label(&ghcr),
+            "containerd.io/distribution.source.ghcr.io"
+        );
+        let hub = FullImage::from_image_name("nginx").unwrap();
+        assert_eq!(distribution_source_label(&hub), SOURCE);
+    }
+
+    #[test]
+    fn test_missing_labels() {
+        let existing = HashMap::from([
+            (
+                SOURCE.to_string(),
+                "library/alpine,library/nginx".to_string(),
+            ),
+            (
+                "containerd.io/uncompressed".to_string(),
+                "sha256:a".to_string(),
+            ),
+        ]);
+
+        let missing = missing_labels(
+            &existing,
+            HashMap::from([
+                (SOURCE.to_string(), "library/nginx".to_string()),
+                (
+                    "containerd.io/uncompressed".to_string(),
+                    "sha256:a".to_string(),
+                ),
+            ]),
+        );
+        assert!(missing.is_empty());
+
+        let missing = missing_labels(
+            &existing,
+            HashMap::from([
+                (SOURCE.to_string(), "library/redis".to_string()),
+                (
+                    "containerd.io/uncompressed".to_string(),
+                    "sha256:b".to_string(),
+                ),
+                (
+                    "containerd.io/gc.ref.content.config".to_string(),
+                    "sha256:c".to_string(),
+                ),
+            ]),
+        );
+        assert_eq!(
+            missing,
+            HashMap::from([
+                (
+                    SOURCE.to_string(),
+                    "library/alpine,library/nginx,library/redis".to_string()
+                ),
+                (
+                    "containerd.io/uncompressed".to_string(),
+                    "sha256:b".to_string()
+                ),
+                (
+                    "containerd.io/gc.ref.content.config".to_string(),
+                    "sha256:c".to_string()
+                ),
+            ])
+        );
+    }
+
     #[tokio::test]
-    async fn test_get_existing_digests_from_containerd() -> Result<(), Box<dyn Error>> {
+    async fn test_get_existing_content_from_containerd() -> Result<(), Box<dyn Error>> {
         let env = ContainerdTestEnv::new().await?;
         let client = create_test_client(&env.socket_path).await?;
 
-        let digests = get_existing_digests_from_containerd(client.clone()).await?;
+        let digests = get_existing_content_from_containerd(client.clone()).await?;
         assert!(digests.is_empty());
 
         let test_digest = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
@@ -188,8 +347,8 @@ mod tests {
         upload_content_to_containerd(client.clone(), test_digest, test_data, HashMap::new())
             .await?;
 
-        let digests = get_existing_digests_from_containerd(client.clone()).await?;
-        assert!(digests.contains(test_digest));
+        let digests = get_existing_content_from_containerd(client.clone()).await?;
+        assert!(digests.contains_key(test_digest));
 
         Ok(())
     }
@@ -208,8 +367,8 @@ mod tests {
         assert!(result.is_ok());
 
         // Verify that the content is there
-        let digests = get_existing_digests_from_containerd(client.clone()).await?;
-        assert!(digests.contains(test_digest));
+        let digests = get_existing_content_from_containerd(client.clone()).await?;
+        assert!(digests.contains_key(test_digest));
 
         Ok(())
     }
@@ -1,22 +1,31 @@
-mod containerd_utils;
+pub mod containerd_utils;
+pub mod unpack;
 
+use crate::client::{count_retries, env_login};
 use crate::compose::lease::LeasedClient;
-use crate::downloader::{IndexResponse, OciDownloader};
+use crate::dashboard::{new_workers, print_summary, set_worker, Dashboard, Tracked, Transfer};
+use crate::downloader::{skip_foreign_layers, IndexResponse, OciDownloader};
+use crate::format::{format_duration, format_size, raw_sizes};
+use crate::metrics::{ImagePullMetrics, PullMetrics};
 use crate::platform::PlatformMatcher;
-use crate::spec::manifest::Descriptor;
+use crate::spec::manifest::{Descriptor, ImageManifest};
 use crate::{
-    client::{ImagePermission, ImagePermissions, OciClient},
+    client::{HttpProtocol, ImagePermission, ImagePermissions, LoginCredentials, OciClient},
     compose::docker_compose_finder::find_and_parse_docker_composes,
     parser::FullImageWithTag,
+    registry_config::RegistrySettings,
+    state::{self, image_key, State},
     system_login::get_system_login,
-    Compose,
+    Compose, ComposeCmd, Pull,
 };
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use containerd_utils::distribution_source_label;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use sha256::digest;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use unpack::{unpack_image, UnpackTarget, DEFAULT_SNAPSHOTTER};
 
 #[derive(Debug, Clone)]
 pub struct DownloadableIndex {
@@ -41,6 +50,7 @@ pub struct DownloadableLayer {
     pub full_image: FullImageWithTag,
     pub digest: String,
     pub uncompressed_digest: String,
+    pub size: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -51,18 +61,152 @@ pub enum Downloadable {
     Layer(DownloadableLayer),
 }
 
+pub fn source_labels(full_image: &FullImageWithTag) -> HashMap<String, String> {
+    HashMap::from([(
+        distribution_source_label(&full_image.image),
+        full_image.image.library_name.clone(),
+    )])
+}
+
+/// Labels a manifest with the config and layers it refers to, so that
+/// garbage collection keeps them as long as the manifest exists
+pub fn manifest_labels(
+    full_image: &FullImageWithTag,
+    manifest: &ImageManifest,
+) -> HashMap<String, String> {
+    let mut labels = source_labels(full_image);
+    labels.insert(
+        "containerd.io/gc.ref.content.config".to_string(),
+        manifest.config.digest.clone(),
+    );
+    for (idx, layer) in manifest.layers.iter().enumerate() {
+        labels.insert(
+            format!("containerd.io/gc.ref.content.l.{}", idx),
+            layer.digest.clone(),
+        );
+    }
+
+    labels
+}
+
+pub fn index_labels(
+    full_image: &FullImageWithTag,
+    index_response: &IndexResponse,
+) -> HashMap<String, String> {
+    match index_response {
+        IndexResponse::ImageIndex(image_index) => {
+            let mut labels = source_labels(full_image);
+            for (idx, manifest) in image_index.manifests.iter().enumerate() {
+                labels.insert(
+                    format!("containerd.io/gc.ref.content.m.{}", idx),
+                    manifest.digest.clone(),
+                );
+            }
+
+            labels
+        }
+        IndexResponse::ImageManifest(manifest) => manifest_labels(full_image, manifest),
+    }
+}
+
+/// The labels a blob that is not downloaded again should carry. Manifests
+/// are not read back from containerd, so only their source is known
+fn expected_labels(downloadable: &Downloadable) -> HashMap<String, String> {
+    match downloadable {
+        Downloadable::Index(index) => source_labels(&index.full_image),
+        Downloadable::Manifest(manifest) => source_labels(&manifest.full_image),
+        Downloadable::Config(config) => source_labels(&config.full_image),
+        Downloadable::Layer(layer) => {
+            let mut labels = source_labels(&layer.full_image);
+            labels.insert(
+                "containerd.io/uncompressed".to_string(),
+                layer.uncompressed_digest.clone(),
+            );
+            labels
+        }
+    }
+}
+
 pub struct PullInstance {
     pub container_client: Arc<LeasedClient>,
+    /// One lease per image, released as soon as the image is complete, so
+    /// that content is only shielded from garbage collection while needed
+    pub image_leases: Arc<Mutex<HashMap<FullImageWithTag, Arc<LeasedClient>>>>,
     pub existing_digests: Arc<Mutex<HashSet<String>>>,
+    /// Labels of the content that was in containerd before the pull started
+    pub existing_labels: Arc<HashMap<String, HashMap<String, String>>>,
+    /// Labels to add to existing content, which is skipped rather than written again
+    pub label_updates: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
     pub download_queue: Arc<Mutex<Vec<Downloadable>>>,
     pub total_bytes_to_download: Arc<Mutex<u64>>,
     pub downloaded_bytes: Arc<Mutex<u64>>,
 
     pub digest_to_image: Arc<Mutex<HashMap<String, FullImageWithTag>>>,
+
+    pub resolved_digests: Arc<Mutex<HashMap<FullImageWithTag, String>>>,
+    pub failed_images: Arc<Mutex<HashSet<FullImageWithTag>>>,
+
+    /// Images whose layers are applied into snapshots once everything is downloaded
+    pub unpack_targets: Arc<Mutex<Vec<UnpackTarget>>>,
+
+    /// Resolves the images without writing to containerd, collecting
+    /// the layers that would be downloaded instead
+    pub dry_run: bool,
+    pub planned_layers: Arc<Mutex<Vec<DownloadableLayer>>>,
+
+    /// Shows a full screen dashboard instead of a spinner per image
+    pub tui: bool,
+
+    /// The logins of the registries and the fallback login, like every other command
+    pub hostname_to_login: HashMap<String, LoginCredentials>,
+    pub default_login: Option<LoginCredentials>,
+    /// The HTTP versions forced per registry by --protocol and the configuration file
+    pub protocols: HashMap<String, HttpProtocol>,
+    pub settings: Arc<RegistrySettings>,
+}
+
+/// The style of the bar counting the bytes of a whole transfer
+pub fn transfer_style() -> ProgressStyle {
+    let template = if raw_sizes() {
+        "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}"
+    } else {
+        "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}"
+    };
+
+    ProgressStyle::default_bar()
+        .template(template)
+        .expect("Failed to set progress bar style")
+        .progress_chars("#>-")
+}
+
+/// The client of a pull, logging in with the dockerlogin= kernel command line
+/// option where neither the other logins nor the environment name the registry
+fn pull_client(
+    settings: Arc<RegistrySettings>,
+    kernel_logins: HashMap<String, LoginCredentials>,
+    hostname_to_login: &HashMap<String, LoginCredentials>,
+    default_login: Option<LoginCredentials>,
+    protocols: HashMap<String, HttpProtocol>,
+    var: impl Fn(&str) -> Option<String>,
+) -> OciClient {
+    let mut logins: HashMap<String, LoginCredentials> = kernel_logins
+        .into_iter()
+        .filter(|(registry, _)| env_login(registry, &var).is_none())
+        .collect();
+    logins.extend(hostname_to_login.clone());
+
+    OciClient::from_settings(settings, logins, default_login).with_protocol_overrides(protocols)
 }
 
 pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::error::Error>> {
-    let client = Arc::new(OciClient::new(get_system_login(), None));
+    let client = Arc::new(pull_client(
+        pull_instance.settings.clone(),
+        get_system_login(&pull_instance.settings),
+        &pull_instance.hostname_to_login,
+        pull_instance.default_login.clone(),
+        pull_instance.protocols.clone(),
+        |name| std::env::var(name).ok(),
+    ));
 
     let image_permissions = {
         let queue = pull_instance.download_queue.lock().await;
@@ -83,7 +227,11 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
 
     client.login(&image_permissions).await?;
 
-    let m = MultiProgress::new();
+    // The dashboard reads the spinners and progress bar instead of drawing them
+    let m = match pull_instance.tui {
+        true => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        false => MultiProgress::new(),
+    };
     let images = {
         let queue = pull_instance.download_queue.lock().await;
 
@@ -105,7 +253,7 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
     let spinners: HashMap<FullImageWithTag, ProgressBar> = images
         .iter()
         .map(|image| {
-            let full_name = format!("{}:{}", image.image.library_name, image.tag);
+            let full_name = image.name();
             let progress_bar = m.add(ProgressBar::new(0));
             progress_bar.set_style(
                 ProgressStyle::default_spinner()
@@ -118,33 +266,87 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
         })
         .collect();
     let spinners = Arc::new(spinners);
+    let tracked: Arc<HashMap<FullImageWithTag, Tracked>> = Arc::new(
+        images
+            .iter()
+            .map(|image| {
+                let tracked = Tracked::new(image.name(), spinners[image].clone());
+                (image.clone(), tracked)
+            })
+            .collect(),
+    );
 
     let progress_bar = m.add(ProgressBar::new(0));
-    progress_bar.set_style(ProgressStyle::default_bar()
-        .template("{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}")
-        .expect("Failed to set progress bar style")
-        .progress_chars("#>-"));
+    progress_bar.set_style(transfer_style());
+
+    let workers = new_workers(8);
+    let dashboard = pull_instance.tui.then(|| {
+        let rows = images.iter().map(|image| tracked[image].clone()).collect();
+        Dashboard::spawn(Transfer::Pull, rows, progress_bar.clone(), workers.clone())
+    });
 
     let downloader = Arc::new(OciDownloader::new(client.clone(), true));
     let total_bytes_to_download = pull_instance.total_bytes_to_download.clone();
     let downloaded_bytes = pull_instance.downloaded_bytes.clone();
     let mut tasks = vec![];
 
-    for _ in 0..8 {
+    for worker in 0..8 {
         let downloader = downloader.clone();
+        let workers = workers.clone();
         let download_queue = pull_instance.download_queue.clone();
         let existing_digests = pull_instance.existing_digests.clone();
+        let existing_labels = pull_instance.existing_labels.clone();
+        let label_updates = pull_instance.label_updates.clone();
         let container_client = pull_instance.container_client.clone();
+        let image_leases = pull_instance.image_leases.clone();
         let progress_bar = progress_bar.clone();
         let total_bytes_to_download = total_bytes_to_download.clone();
         let downloaded_bytes = downloaded_bytes.clone();
         let digest_to_image = pull_instance.digest_to_image.clone();
         let spinners = spinners.clone();
+        let tracked = tracked.clone();
+        let resolved_digests = pull_instance.resolved_digests.clone();
+        let failed_images = pull_instance.failed_images.clone();
+        let unpack_targets = pull_instance.unpack_targets.clone();
+        let dry_run = pull_instance.dry_run;
+        let planned_layers = pull_instance.planned_layers.clone();
 
         let task = tokio::spawn(async move {
             let platform_matcher = PlatformMatcher::new();
 
+            let lease_for = async |full_image: &FullImageWithTag| {
+                let mut image_leases = image_leases.lock().await;
+
+                if let Some(lease) = image_leases.get(full_image) {
+                    return lease.clone();
+                }
+
+                match container_client.new_lease().await {
+                    Ok(lease) => {
+                        let lease = Arc::new(lease);
+                        image_leases.insert(full_image.clone(), lease.clone());
+                        lease
+                    }
+                    Err(e) => {
+                        // The lease of the whole pull still protects the content
+                        eprintln!("Failed to create lease: {}", e);
+                        container_client.clone()
+                    }
+                }
+            };
+
+            let release_lease = async |full_image: &FullImageWithTag| {
+                let lease = image_leases.lock().await.remove(full_image);
+
+                if let Some(lease) = lease {
+                    lease.delete_lease().await;
+                }
+            };
+
             let download_failed = async |full_image: FullImageWithTag, error: String| {
+                failed_images.lock().await.insert(full_image.clone());
+                release_lease(&full_image).await;
+
                 if let Some(spinner) = spinners.get(&full_image) {
                     if !spinner.is_finished() {
                         spinner.finish_with_message(format!(
@@ -169,11 +371,19 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
                     };
 
                     if is_complete {
+                        // The image now refers to all of its content
+                        release_lease(&full_image_clone).await;
+
                         if let Some(spinner) = spinners.get(&full_image_clone) {
                             if !spinner.is_finished() {
                                 spinner.finish_with_message(format!(
-                                    "{}: \x1b[32mComplete\x1b[0m",
-                                    spinner.message()
+                                    "{}: {}",
+                                    spinner.message(),
+                                    if dry_run {
+                                        "\x1b[36mWould pull\x1b[0m"
+                                    } else {
+                                        "\x1b[32mComplete\x1b[0m"
+                                    }
                                 ));
                             }
                         }
@@ -191,11 +401,29 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
                     }
                 };
 
+            let reconcile_labels = async |digest: &str, wanted: HashMap<String, String>| {
+                // Content written earlier in this pull already has its labels
+                if let Some(existing) = existing_labels.get(digest) {
+                    if dry_run {
+                        return;
+                    }
+
+                    let mut label_updates = label_updates.lock().await;
+                    let pending = label_updates.entry(digest.to_string()).or_default();
+                    containerd_utils::merge_label_update(existing, pending, wanted);
+
+                    if pending.is_empty() {
+                        label_updates.remove(digest);
+                    }
+                }
+            };
+
             let queue_if_not_download =
                 async |digest: &str, something, full_image: FullImageWithTag, size| {
                     let mut existing_digests = existing_digests.lock().await;
 
                     if existing_digests.contains(digest) {
+                        reconcile_labels(digest, expected_labels(&something)).await;
                         false
                     } else {
                         digest_to_image
@@ -216,327 +444,320 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
                 let mut queue = download_queue.lock().await;
                 queue.pop()
             } {
-                match downloadable {
-                    Downloadable::Index(index_to_download) => {
-                        match downloader
-                            .download_index(index_to_download.full_image.clone())
-                            .await
-                        {
-                            Ok((index_response, image_json)) => {
-                                let image_json_len = image_json.len();
-                                let image_digest = format!("sha256:{}", digest(&image_json));
-
-                                *total_bytes_to_download.lock().await += image_json_len as u64;
-                                *downloaded_bytes.lock().await += image_json_len as u64;
-                                progress_bar.set_length(*total_bytes_to_download.lock().await);
-                                progress_bar.set_position(*downloaded_bytes.lock().await);
-
-                                if !existing_digests.lock().await.contains(&image_digest) {
-                                    containerd_utils::upload_content_to_containerd(
-                                        container_client.clone(),
-                                        &image_digest,
-                                        image_json.into_bytes(),
-                                        {
-                                            let mut labels = HashMap::new();
-                                            labels.insert(
-                                                "containerd.io/distribution.source.docker.io"
-                                                    .to_string(),
-                                                index_to_download
-                                                    .full_image
-                                                    .image
-                                                    .library_name
-                                                    .clone(),
-                                            );
+                set_worker(&workers, worker, describe(&downloadable));
+
+                // Retried requests are counted towards the image they are for
+                let retries = tracked
+                    .get(downloadable.full_image())
+                    .map(|tracked| tracked.retries.clone())
+                    .unwrap_or_default();
+
+                count_retries(retries, async {
+                    match downloadable {
+                        Downloadable::Index(index_to_download) => {
+                            match downloader
+                                .download_index(index_to_download.full_image.clone())
+                                .await
+                            {
+                                Ok((index_response, image_json)) => {
+                                    let image_json_len = image_json.len();
+                                    let image_digest = format!("sha256:{}", digest(&image_json));
+
+                                    *total_bytes_to_download.lock().await += image_json_len as u64;
+                                    *downloaded_bytes.lock().await += image_json_len as u64;
+                                    progress_bar.set_length(*total_bytes_to_download.lock().await);
+                                    progress_bar.set_position(*downloaded_bytes.lock().await);
+
+                                    let labels =
+                                        index_labels(&index_to_download.full_image, &index_response);
+
+                                    if existing_digests.lock().await.contains(&image_digest) {
+                                        reconcile_labels(&image_digest, labels).await;
+                                    } else if !dry_run {
+                                        containerd_utils::upload_content_to_containerd(
+                                            lease_for(&index_to_download.full_image).await,
+                                            &image_digest,
+                                            image_json.into_bytes(),
+                                            labels,
+                                        )
+                                        .await
+                                        .expect("Failed to upload index to containerd");
+                                        *downloaded_bytes.lock().await += image_json_len as u64;
+                                        progress_bar.set_position(*downloaded_bytes.lock().await);
+                                    }
+
+                                    if !dry_run {
+                                        containerd_utils::create_image_in_containerd(
+                                            lease_for(&index_to_download.full_image).await,
+                                            &index_to_download.full_image,
+                                            image_digest.clone(),
+                                            image_json_len as i64,
                                             match index_response {
-                                                IndexResponse::ImageIndex(ref image_index) => {
-                                                    for (idx, manifest) in
-                                                        image_index.manifests.iter().enumerate()
-                                                    {
-                                                        labels.insert(
-                                                            format!(
-                                                                "containerd.io/gc.ref.content.m.{}",
-                                                                idx
-                                                            ),
-                                                            manifest.digest.clone(),
-                                                        );
-                                                    }
+                                                IndexResponse::ImageIndex(ref index) => {
+                                                    index.media_type.to_string().into()
                                                 }
                                                 IndexResponse::ImageManifest(ref manifest) => {
-                                                    labels.insert(
-                                                        "containerd.io/gc.ref.content.config"
-                                                            .to_string(),
-                                                        manifest.config.digest.clone(),
-                                                    );
-                                                    for (idx, layer) in
-                                                        manifest.layers.iter().enumerate()
-                                                    {
-                                                        labels.insert(
-                                                            format!(
-                                                                "containerd.io/gc.ref.content.l.{}",
-                                                                idx
-                                                            ),
-                                                            layer.digest.clone(),
-                                                        );
-                                                    }
+                                                    manifest.media_type.to_string().into()
                                                 }
-                                            }
-                                            labels
-                                        },
-                                    )
-                                    .await
-                                    .expect("Failed to upload index to containerd");
-                                    *downloaded_bytes.lock().await += image_json_len as u64;
-                                    progress_bar.set_position(*downloaded_bytes.lock().await);
-                                }
+                                            },
+                                        )
+                                        .await
+                                        .expect("Failed to create image in containerd");
+                                    }
 
-                                containerd_utils::create_image_in_containerd(
-                                    container_client.clone(),
-                                    &index_to_download.full_image,
-                                    image_digest.clone(),
-                                    image_json_len as i64,
-                                    match index_response {
-                                        IndexResponse::ImageIndex(ref index) => {
-                                            index.media_type.to_string().into()
-                                        }
-                                        IndexResponse::ImageManifest(ref manifest) => {
-                                            manifest.media_type.to_string().into()
+                                    resolved_digests.lock().await.insert(
+                                        index_to_download.full_image.clone(),
+                                        image_digest.clone(),
+                                    );
+
+                                    let downloading = match index_response {
+                                        IndexResponse::ImageIndex(ref image_index) => {
+                                            let manifest =
+                                                platform_matcher.find_manifest(&image_index.manifests);
+                                            if let Some(manifest) = manifest {
+                                                // Check if the manifest digest is already in the download queue
+                                                queue_if_not_download(
+                                                    &manifest.digest,
+                                                    Downloadable::Manifest(DownloadableManifest {
+                                                        digest: manifest.digest.clone(),
+                                                        full_image: index_to_download
+                                                            .full_image
+                                                            .clone(),
+                                                    }),
+                                                    index_to_download.full_image.clone(),
+                                                    manifest.size,
+                                                )
+                                                .await
+                                            } else {
+                                                println!("\x1b[33mNo matching platform found for image: {}\x1b[0m",
+                                                    index_to_download.full_image.name());
+                                                false
+                                            }
                                         }
-                                    },
-                                )
-                                .await
-                                .expect("Failed to create image in containerd");
-
-                                let downloading = match index_response {
-                                    IndexResponse::ImageIndex(ref image_index) => {
-                                        let manifest =
-                                            platform_matcher.find_manifest(&image_index.manifests);
-                                        if let Some(manifest) = manifest {
-                                            // Check if the manifest digest is already in the download queue
+                                        IndexResponse::ImageManifest(manifest) => {
                                             queue_if_not_download(
-                                                &manifest.digest,
-                                                Downloadable::Manifest(DownloadableManifest {
-                                                    digest: manifest.digest.clone(),
-                                                    full_image: index_to_download
-                                                        .full_image
-                                                        .clone(),
+                                                &manifest.config.digest,
+                                                Downloadable::Config(DownloadableConfig {
+                                                    full_image: index_to_download.full_image.clone(),
+                                                    layers: manifest.layers.clone(),
+                                                    digest: manifest.config.digest.clone(),
                                                 }),
                                                 index_to_download.full_image.clone(),
-                                                manifest.size,
+                                                manifest.config.size,
                                             )
                                             .await
-                                        } else {
-                                            println!("\x1b[33mNo matching platform found for image: {}:{}\x1b[0m",
-                                                index_to_download.full_image.image.library_name, index_to_download.full_image.tag);
-                                            false
                                         }
-                                    }
-                                    IndexResponse::ImageManifest(manifest) => {
-                                        queue_if_not_download(
-                                            &manifest.config.digest,
-                                            Downloadable::Config(DownloadableConfig {
-                                                full_image: index_to_download.full_image.clone(),
-                                                layers: manifest.layers.clone(),
-                                                digest: manifest.config.digest.clone(),
-                                            }),
-                                            index_to_download.full_image.clone(),
-                                            manifest.config.size,
-                                        )
-                                        .await
-                                    }
-                                };
-
-                                if !downloading {
-                                    if let Some(spinner) =
-                                        spinners.get(&index_to_download.full_image)
-                                    {
-                                        spinner.finish_with_message(format!(
-                                            "{}: \x1b[33mUnchanged\x1b[0m",
-                                            spinner.message()
-                                        ));
+                                    };
+
+                                    if !downloading {
+                                        release_lease(&index_to_download.full_image).await;
+
+                                        if let Some(spinner) =
+                                            spinners.get(&index_to_download.full_image)
+                                        {
+                                            spinner.finish_with_message(format!(
+                                                "{}: \x1b[33mUnchanged\x1b[0m",
+                                                spinner.message()
+                                            ));
+                                        }
                                     }
                                 }
-                            }
-                            Err(e) => {
-                                download_failed(
-                                    index_to_download.full_image.clone(),
-                                    e.to_string(),
-                                )
-                                .await;
+                                Err(e) => {
+                                    download_failed(
+                                        index_to_download.full_image.clone(),
+                                        e.to_string(),
+                                    )
+                                    .await;
+                                }
                             }
                         }
-                    }
-                    Downloadable::Manifest(manifest_to_download) => {
-                        match downloader
-                            .download_manifest(
-                                manifest_to_download.full_image.image.clone(),
-                                &manifest_to_download.digest,
-                            )
-                            .await
-                        {
-                            Ok((manifest, manifest_json)) => {
-                                // UPLOADING A MANIFEST //
-                                containerd_utils::upload_content_to_containerd(
-                                    container_client.clone(),
+                        Downloadable::Manifest(manifest_to_download) => {
+                            match downloader
+                                .download_manifest(
+                                    manifest_to_download.full_image.image.clone(),
                                     &manifest_to_download.digest,
-                                    manifest_json.clone().into(),
-                                    {
-                                        let mut labels = HashMap::new();
-                                        labels.insert(
-                                            "containerd.io/distribution.source.docker.io"
-                                                .to_string(),
-                                            manifest_to_download
-                                                .full_image
-                                                .image
-                                                .library_name
-                                                .clone(),
-                                        );
-                                        labels.insert(
-                                            "containerd.io/gc.ref.content.config".to_string(),
-                                            manifest.config.digest.clone(),
-                                        );
-                                        for (idx, layer) in manifest.layers.iter().enumerate() {
-                                            labels.insert(
-                                                format!("containerd.io/gc.ref.content.l.{}", idx),
-                                                layer.digest.clone(),
-                                            );
-                                        }
-
-                                        labels
-                                    },
                                 )
                                 .await
-                                .expect("Failed to upload manifest to containerd");
-                                *downloaded_bytes.lock().await += manifest_json.len() as u64;
-                                progress_bar.set_position(*downloaded_bytes.lock().await);
-
-                                queue_if_not_download(
-                                    &manifest.config.digest,
-                                    Downloadable::Config(DownloadableConfig {
-                                        full_image: manifest_to_download.full_image.clone(),
-                                        layers: manifest.layers.clone(),
-                                        digest: manifest.config.digest.clone(),
-                                    }),
-                                    manifest_to_download.full_image.clone(),
-                                    manifest.config.size,
-                                )
-                                .await;
+                            {
+                                Ok((manifest, manifest_json)) => {
+                                    // UPLOADING A MANIFEST //
+                                    if !dry_run {
+                                        containerd_utils::upload_content_to_containerd(
+                                            lease_for(&manifest_to_download.full_image).await,
+                                            &manifest_to_download.digest,
+                                            manifest_json.clone().into(),
+                                            manifest_labels(
+                                                &manifest_to_download.full_image,
+                                                &manifest,
+                                            ),
+                                        )
+                                        .await
+                                        .expect("Failed to upload manifest to containerd");
+                                    }
+                                    *downloaded_bytes.lock().await += manifest_json.len() as u64;
+                                    progress_bar.set_position(*downloaded_bytes.lock().await);
 
-                                download_complete(
-                                    manifest_to_download.full_image.clone(),
-                                    manifest_to_download.digest.clone(),
-                                    manifest_json.len() as u64,
-                                )
-                                .await;
-                            }
-                            Err(e) => {
-                                download_failed(
-                                    manifest_to_download.full_image.clone(),
-                                    e.to_string(),
-                                )
-                                .await;
+                                    queue_if_not_download(
+                                        &manifest.config.digest,
+                                        Downloadable::Config(DownloadableConfig {
+                                            full_image: manifest_to_download.full_image.clone(),
+                                            layers: manifest.layers.clone(),
+                                            digest: manifest.config.digest.clone(),
+                                        }),
+                                        manifest_to_download.full_image.clone(),
+                                        manifest.config.size,
+                                    )
+                                    .await;
+
+                                    download_complete(
+                                        manifest_to_download.full_image.clone(),
+                                        manifest_to_download.digest.clone(),
+                                        manifest_json.len() as u64,
+                                    )
+                                    .await;
+                                }
+                                Err(e) => {
+                                    download_failed(
+                                        manifest_to_download.full_image.clone(),
+                                        e.to_string(),
+                                    )
+                                    .await;
+                                }
                             }
                         }
-                    }
-                    Downloadable::Config(config_to_download) => {
-                        match downloader
-                            .download_config(
-                                config_to_download.full_image.image.clone(),
-                                &config_to_download.digest,
-                            )
-                            .await
-                        {
-                            Ok((config, config_bytes)) => {
-                                // UPLOADING A CONFIG //
-                                containerd_utils::upload_content_to_containerd(
-                                    container_client.clone(),
+                        Downloadable::Config(config_to_download) => {
+                            match downloader
+                                .download_config(
+                                    config_to_download.full_image.image.clone(),
                                     &config_to_download.digest,
-                                    config_bytes.clone().into(),
-                                    {
-                                        let mut labels = HashMap::new();
-                                        labels.insert(
-                                            "containerd.io/distribution.source.docker.io"
-                                                .to_string(),
-                                            config_to_download
-                                                .full_image
-                                                .image
-                                                .library_name
-                                                .clone(),
-                                        );
-                                        labels
-                                    },
                                 )
                                 .await
-                                .expect("Failed to upload config to containerd");
+                            {
+                                Ok((config, config_bytes)) => {
+                                    // Images left without their foreign layers
+                                    // are stored, but cannot be unpacked
+                                    let skips_layers = skip_foreign_layers()
+                                        && config_to_download
+                                            .layers
+                                            .iter()
+                                            .any(|layer| layer.is_foreign());
 
-                                for (idx, layer) in config_to_download.layers.iter().enumerate() {
-                                    let layer_digest = layer.digest.clone();
-                                    let uncompressed_digest = config
-                                        .rootfs
-                                        .diff_ids
-                                        .get(idx)
-                                        .cloned()
-                                        .expect("Missing uncompressed digest");
+                                    if skips_layers {
+                                        eprintln!(
+                                            "Warning: {} is not unpacked, as its foreign layers are skipped",
+                                            config_to_download.full_image.canonical_name()
+                                        );
+                                    }
 
-                                    queue_if_not_download(
-                                        &layer_digest.clone(),
-                                        Downloadable::Layer(DownloadableLayer {
-                                            full_image: config_to_download.full_image.clone(),
-                                            digest: layer_digest,
-                                            uncompressed_digest,
-                                        }),
+                                    // UPLOADING A CONFIG //
+                                    if !dry_run {
+                                        containerd_utils::upload_content_to_containerd(
+                                            lease_for(&config_to_download.full_image).await,
+                                            &config_to_download.digest,
+                                            config_bytes.clone().into(),
+                                            source_labels(&config_to_download.full_image),
+                                        )
+                                        .await
+                                        .expect("Failed to upload config to containerd");
+
+                                        if !skips_layers {
+                                            unpack_targets.lock().await.push(UnpackTarget {
+                                                full_image: config_to_download.full_image.clone(),
+                                                config_digest: config_to_download.digest.clone(),
+                                                layers: config_to_download.layers.clone(),
+                                                diff_ids: config.rootfs.diff_ids.clone(),
+                                            });
+                                        }
+                                    }
+
+                                    for (idx, layer) in config_to_download.layers.iter().enumerate() {
+                                        if layer.is_foreign() && skip_foreign_layers() {
+                                            eprintln!(
+                                                "Warning: skipping foreign layer {}",
+                                                layer.digest
+                                            );
+                                            continue;
+                                        }
+
+                                        let layer_digest = layer.digest.clone();
+                                        let uncompressed_digest = config
+                                            .rootfs
+                                            .diff_ids
+                                            .get(idx)
+                                            .cloned()
+                                            .expect("Missing uncompressed digest");
+
+                                        queue_if_not_download(
+                                            &layer_digest.clone(),
+                                            Downloadable::Layer(DownloadableLayer {
+                                                full_image: config_to_download.full_image.clone(),
+                                                digest: layer_digest,
+                                                uncompressed_digest,
+                                                size: layer.size,
+                                            }),
+                                            config_to_download.full_image.clone(),
+                                            layer.size,
+                                        )
+                                        .await;
+                                    }
+
+                                    download_complete(
                                         config_to_download.full_image.clone(),
-                                        layer.size,
+                                        config_to_download.digest.clone(),
+                                        config_bytes.len() as u64,
+                                    )
+                                    .await;
+                                }
+                                Err(e) => {
+                                    download_failed(
+                                        config_to_download.full_image.clone(),
+                                        e.to_string(),
                                     )
                                     .await;
                                 }
-
-                                download_complete(
-                                    config_to_download.full_image.clone(),
-                                    config_to_download.digest.clone(),
-                                    config_bytes.len() as u64,
-                                )
-                                .await;
-                            }
-                            Err(e) => {
-                                download_failed(
-                                    config_to_download.full_image.clone(),
-                                    e.to_string(),
-                                )
-                                .await;
                             }
                         }
-                    }
-                    Downloadable::Layer(layer_to_download) => {
-                        match downloader
-                            .download_layer_to_containerd(
-                                container_client.clone(),
-                                layer_to_download.full_image.image.clone(),
-                                &layer_to_download.digest,
-                                &layer_to_download.uncompressed_digest,
-                                progress_bar.clone(),
-                                spinners.get(&layer_to_download.full_image),
-                                downloaded_bytes.clone(),
-                            )
-                            .await
-                        {
-                            Ok(()) => {
-                                download_complete(
-                                    layer_to_download.full_image.clone(),
-                                    layer_to_download.digest.clone(),
-                                    0,
-                                )
-                                .await;
-                            }
-                            Err(e) => {
-                                download_failed(
-                                    layer_to_download.full_image.clone(),
-                                    e.to_string(),
+                        Downloadable::Layer(layer_to_download) if dry_run => {
+                            let full_image = layer_to_download.full_image.clone();
+                            let digest = layer_to_download.digest.clone();
+                            planned_layers.lock().await.push(layer_to_download);
+                            download_complete(full_image, digest, 0).await;
+                        }
+                        Downloadable::Layer(layer_to_download) => {
+                            match downloader
+                                .download_layer_to_containerd(
+                                    lease_for(&layer_to_download.full_image).await,
+                                    layer_to_download.full_image.image.clone(),
+                                    &layer_to_download.digest,
+                                    &layer_to_download.uncompressed_digest,
+                                    progress_bar.clone(),
+                                    spinners.get(&layer_to_download.full_image),
+                                    downloaded_bytes.clone(),
                                 )
-                                .await;
+                                .await
+                            {
+                                Ok(()) => {
+                                    download_complete(
+                                        layer_to_download.full_image.clone(),
+                                        layer_to_download.digest.clone(),
+                                        0,
+                                    )
+                                    .await;
+                                }
+                                Err(e) => {
+                                    download_failed(
+                                        layer_to_download.full_image.clone(),
+                                        e.to_string(),
+                                    )
+                                    .await;
+                                }
                             }
                         }
                     }
-                }
+                })
+                .await;
+
+                set_worker(&workers, worker, "idle".to_string());
             }
         });
         tasks.push(task);
@@ -544,10 +765,127 @@ pub async fn run_pull(pull_instance: &PullInstance) -> Result<(), Box<dyn std::e
 
     futures::future::join_all(tasks).await;
     progress_bar.finish_with_message("Pull complete!");
+
+    if let Some(dashboard) = dashboard {
+        dashboard.finish().await;
+
+        let rows: Vec<_> = images.iter().map(|image| tracked[image].row()).collect();
+        print_summary(Transfer::Pull, &rows, progress_bar.position());
+    }
+
     Ok(())
 }
 
-pub async fn pull_command(compose_settings: &Compose) -> Result<(), Box<dyn std::error::Error>> {
+impl Downloadable {
+    fn full_image(&self) -> &FullImageWithTag {
+        match self {
+            Downloadable::Index(index) => &index.full_image,
+            Downloadable::Manifest(manifest) => &manifest.full_image,
+            Downloadable::Config(config) => &config.full_image,
+            Downloadable::Layer(layer) => &layer.full_image,
+        }
+    }
+}
+
+/// What a worker is busy with, for the dashboard
+fn describe(downloadable: &Downloadable) -> String {
+    let (kind, full_image, digest) = match downloadable {
+        Downloadable::Index(index) => ("index", &index.full_image, None),
+        Downloadable::Manifest(manifest) => {
+            ("manifest", &manifest.full_image, Some(&manifest.digest))
+        }
+        Downloadable::Config(config) => ("config", &config.full_image, Some(&config.digest)),
+        Downloadable::Layer(layer) => ("layer", &layer.full_image, Some(&layer.digest)),
+    };
+    let image = full_image.name();
+
+    match digest {
+        Some(digest) => format!("{} {} {}", kind, &digest[..digest.len().min(19)], image),
+        None => format!("{} {}", kind, image),
+    }
+}
+
+/// Adds the labels that content already in containerd was missing, such as
+/// the repository it is now also used by, in one concurrent batch
+async fn update_existing_labels(pull_instance: &PullInstance) {
+    let label_updates: Vec<_> = pull_instance.label_updates.lock().await.drain().collect();
+
+    if label_updates.is_empty() {
+        return;
+    }
+
+    let results = futures::future::join_all(label_updates.iter().map(|(digest, labels)| {
+        containerd_utils::update_content_labels(
+            pull_instance.container_client.clone(),
+            digest,
+            labels.clone(),
+        )
+    }))
+    .await;
+
+    for ((digest, _), result) in label_updates.iter().zip(&results) {
+        if let Err(e) = result {
+            eprintln!("Failed to update the labels of {}: {}", digest, e);
+        }
+    }
+
+    let updated = results.iter().filter(|result| result.is_ok()).count();
+    println!("Updated the labels of {} existing blobs", updated);
+}
+
+/// Reports the layers a dry run would have downloaded, by image, and
+/// optionally every single layer
+async fn print_planned_layers(pull_instance: &PullInstance, list_layers: bool) {
+    let resolved_digests = pull_instance.resolved_digests.lock().await;
+    let planned_layers = pull_instance.planned_layers.lock().await;
+    let mut images = BTreeMap::<(&str, &str), Vec<&DownloadableLayer>>::new();
+
+    for layer in planned_layers.iter() {
+        images
+            .entry((&layer.full_image.image.library_name, &layer.full_image.tag))
+            .or_default()
+            .push(layer);
+    }
+
+    for ((name, tag), layers) in &images {
+        let digest = resolved_digests
+            .get(&layers[0].full_image)
+            .map(String::as_str)
+            .unwrap_or("unknown digest");
+        let size: u64 = layers.iter().map(|layer| layer.size).sum();
+
+        println!(
+            "{}:{} ({}): {} layers, {}",
+            name,
+            tag,
+            digest,
+            layers.len(),
+            format_size(size)
+        );
+
+        if list_layers {
+            for layer in layers {
+                println!("  {} {}", layer.digest, format_size(layer.size));
+            }
+        }
+    }
+
+    let total: u64 = planned_layers.iter().map(|layer| layer.size).sum();
+    println!(
+        "Would download {} layers, {}",
+        planned_layers.len(),
+        format_size(total)
+    );
+}
+
+pub async fn pull_command(
+    compose_settings: &Compose,
+    dry_run: bool,
+    hostname_to_login: HashMap<String, LoginCredentials>,
+    default_login: Option<LoginCredentials>,
+    protocols: HashMap<String, HttpProtocol>,
+    settings: Arc<RegistrySettings>,
+) -> Result<(), Box<dyn std::error::Error>> {
     let start_dir = compose_settings
         .dir
         .clone()
@@ -577,23 +915,66 @@ pub async fn pull_command(compose_settings: &Compose) -> Result<(), Box<dyn std:
         }
     }
 
-    let mut images: Vec<_> = images_to_pull.into_iter().collect();
-    images.sort();
-
-    let full_images: Vec<FullImageWithTag> = images
+    // Compose files may spell the same image differently, such as nginx
+    // and docker.io/library/nginx, which must only be pulled once
+    let mut full_images: Vec<FullImageWithTag> = images_to_pull
+        .iter()
+        .map(|image| FullImageWithTag::parse(image, &settings))
+        .collect::<Result<HashSet<_>, _>>()?
         .into_iter()
-        .map(|image| FullImageWithTag::from_image_name(&image))
         .collect();
+    full_images.sort_by_key(|image| image.canonical_name());
 
     let leased_client = Arc::new(
         LeasedClient::with_path("default".to_string(), socket_path.to_str().unwrap()).await?,
     );
 
-    let existing_digests =
-        containerd_utils::get_existing_digests_from_containerd(leased_client.clone()).await?;
+    let existing_labels =
+        containerd_utils::get_existing_content_from_containerd(leased_client.clone()).await?;
+    let existing_digests: HashSet<String> = existing_labels.keys().cloned().collect();
     let mut download_queue = Vec::<Downloadable>::new();
 
+    // Images pulled recently by any compose project on this host, and still
+    // present in containerd, do not need to be resolved again if asked for
+    let state_path = State::path();
+    let host_state = State::load(&state_path);
+    let socket_key = socket_path.display().to_string();
+    let max_age = match &compose_settings.subcommand {
+        ComposeCmd::Pull(pull) => pull.max_age,
+        _ => None,
+    };
+    let now = state::now();
+    let image_keys: Vec<String> = full_images.iter().map(image_key).collect();
+
     for image in full_images {
+        // A digest cannot move, so the pull of an image pinned to one never
+        // goes stale
+        let max_age = match image.digest() {
+            Some(_) => Some(u64::MAX),
+            None => max_age,
+        };
+        let fresh_digest = max_age
+            .and_then(|max_age| {
+                host_state.fresh_pull(&socket_key, &image_key(&image), max_age, now)
+            })
+            .filter(|digest| existing_digests.contains(*digest));
+
+        if let Some(digest) = fresh_digest {
+            let age = host_state
+                .pulls
+                .get(&socket_key)
+                .and_then(|pulls| pulls.get(&image_key(&image)))
+                .map(|pull| now.saturating_sub(pull.pulled_at))
+                .unwrap_or(0);
+            println!(
+                "{}: \x1b[33mUnchanged\x1b[0m (pulled {} ago as {})",
+                image.name(),
+                format_duration(age),
+                digest
+            );
+            continue;
+        }
+
         download_queue.push(Downloadable::Index(DownloadableIndex {
             full_image: image.clone(),
         }));
@@ -613,34 +994,239 @@ pub async fn pull_command(compose_settings: &Compose) -> Result<(), Box<dyn std:
     */
     let pull_instance = PullInstance {
         container_client: leased_client,
+        image_leases: Arc::new(Mutex::new(HashMap::new())),
         existing_digests: Arc::new(Mutex::new(existing_digests)),
+        existing_labels: Arc::new(existing_labels),
+        label_updates: Arc::new(Mutex::new(HashMap::new())),
         download_queue: Arc::new(Mutex::new(download_queue)),
         total_bytes_to_download: Arc::new(Mutex::new(0)),
         downloaded_bytes: Arc::new(Mutex::new(0)),
 
         digest_to_image: Arc::new(Mutex::new(HashMap::new())),
+
+        resolved_digests: Arc::new(Mutex::new(HashMap::new())),
+        failed_images: Arc::new(Mutex::new(HashSet::new())),
+
+        unpack_targets: Arc::new(Mutex::new(vec![])),
+
+        dry_run,
+        planned_layers: Arc::new(Mutex::new(vec![])),
+        tui: match &compose_settings.subcommand {
+            ComposeCmd::Pull(pull) => pull.tui,
+            _ => false,
+        },
+
+        hostname_to_login,
+        default_login,
+        protocols,
+        settings,
     };
 
-    match run_pull(&pull_instance).await {
-        Ok(_) => {
-            pull_instance.container_client.delete_lease().await;
-            Ok(())
+    let result = run_pull(&pull_instance).await;
+
+    if !dry_run {
+        update_existing_labels(&pull_instance).await;
+    }
+
+    if result.is_ok() && !dry_run {
+        let snapshotter = match &compose_settings.subcommand {
+            ComposeCmd::Pull(pull) => pull.snapshotter.as_deref(),
+            _ => None,
+        }
+        .unwrap_or(DEFAULT_SNAPSHOTTER);
+
+        for target in pull_instance.unpack_targets.lock().await.iter() {
+            if pull_instance
+                .failed_images
+                .lock()
+                .await
+                .contains(&target.full_image)
+            {
+                continue;
+            }
+
+            if let Err(e) =
+                unpack_image(pull_instance.container_client.clone(), snapshotter, target).await
+            {
+                eprintln!(
+                    "{}: \x1b[31mFailed - {}\x1b[0m",
+                    target.full_image.name(),
+                    e
+                );
+                pull_instance
+                    .failed_images
+                    .lock()
+                    .await
+                    .insert(target.full_image.clone());
+            }
+        }
+    }
+
+    for (_, lease) in pull_instance.image_leases.lock().await.drain() {
+        lease.delete_lease().await;
+    }
+    pull_instance.container_client.delete_lease().await;
+
+    if dry_run {
+        let list_layers = !matches!(compose_settings.subcommand, ComposeCmd::Estimate(_));
+        print_planned_layers(&pull_instance, list_layers).await;
+        return result;
+    }
+
+    let failed_images = pull_instance.failed_images.lock().await;
+    let resolved_digests = pull_instance.resolved_digests.lock().await;
+
+    if result.is_ok() {
+        let now = state::now();
+        let result = State::update(&state_path, |state| {
+            for (image, digest) in resolved_digests.iter() {
+                if !failed_images.contains(image) {
+                    state.record_image(&image_key(image), digest, now);
+                    state.record_pull(&socket_key, &image_key(image), digest, now);
+                }
+            }
+        });
+
+        if let Err(e) = result {
+            eprintln!("Failed to save pull history: {}", e);
+        }
+    }
+
+    if let ComposeCmd::Pull(Pull {
+        metrics_file: Some(metrics_file),
+        ..
+    }) = &compose_settings.subcommand
+    {
+        let host_state = State::load(&state_path);
+        let pulled: HashSet<String> = resolved_digests
+            .keys()
+            .filter(|image| !failed_images.contains(*image))
+            .map(image_key)
+            .collect();
+        let failed: HashSet<String> = failed_images.iter().map(image_key).collect();
+        let mut metrics = PullMetrics {
+            bytes: *pull_instance.downloaded_bytes.lock().await,
+            ..Default::default()
+        };
+
+        for key in image_keys {
+            let last_success = host_state
+                .pulls
+                .get(&socket_key)
+                .and_then(|pulls| pulls.get(&key))
+                .map(|pull| pull.pulled_at);
+
+            // Images skipped because they were pulled recently count as successful
+            let success = result.is_ok()
+                && !failed.contains(&key)
+                && (pulled.contains(&key) || last_success.is_some());
+            metrics.images.insert(
+                key,
+                ImagePullMetrics {
+                    success,
+                    last_success,
+                },
+            );
         }
-        Err(e) => {
-            eprintln!("Error during pull: {}", e);
-            pull_instance.container_client.delete_lease().await;
-            Err(e)
+
+        if let Err(e) = metrics.write(metrics_file) {
+            eprintln!("Failed to write metrics: {}", e);
         }
     }
+
+    if let Err(e) = &result {
+        eprintln!("Error during pull: {}", e);
+    }
+
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::registry_config::{store_login, ConfigFile};
     use crate::test::tests::ContainerdTestEnv;
     use crate::{Compose, ComposeCmd, Pull};
     use std::fs;
 
+    fn registry_url(host: &str) -> String {
+        RegistrySettings::default().registry_url(host)
+    }
+
+    #[test]
+    fn test_pull_client_uses_stored_logins() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let login = LoginCredentials {
+            username: "ci".to_string(),
+            password: "secret".to_string(),
+        };
+        store_login(&path, "registry.example.com", Some(&login)).unwrap();
+
+        let config = ConfigFile::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        let kernel_logins = HashMap::from([(
+            registry_url("registry.example.com"),
+            LoginCredentials {
+                username: "kernel".to_string(),
+                password: "old".to_string(),
+            },
+        )]);
+        let client = pull_client(
+            Arc::default(),
+            kernel_logins,
+            &config.logins(&RegistrySettings::default()),
+            None,
+            HashMap::new(),
+            |_| None,
+        );
+
+        assert_eq!(
+            client
+                .get_credentials(&registry_url("registry.example.com"))
+                .unwrap(),
+            login
+        );
+    }
+
+    #[test]
+    fn test_pull_client_login_precedence() {
+        let login = |username: &str| LoginCredentials {
+            username: username.to_string(),
+            password: "pass".to_string(),
+        };
+        let kernel_logins = HashMap::from([
+            (registry_url("ghcr.io"), login("kernel")),
+            (registry_url("quay.io"), login("kernel")),
+            (registry_url("registry.example.com"), login("kernel")),
+        ]);
+        // As parsed from --registry-auth ghcr.io=flag:pass
+        let flag_logins = HashMap::from([(registry_url("ghcr.io"), login("flag"))]);
+        let client = pull_client(
+            Arc::default(),
+            kernel_logins,
+            &flag_logins,
+            None,
+            HashMap::new(),
+            |name| match name {
+                "DOCKER_AUTH_GHCR_IO" | "DOCKER_AUTH_QUAY_IO" => Some("env:pass".to_string()),
+                _ => None,
+            },
+        );
+
+        let username = |host: &str| {
+            client
+                .get_credentials(&registry_url(host))
+                .map(|login| login.username)
+                .ok()
+        };
+        assert_eq!(username("ghcr.io").as_deref(), Some("flag"));
+        assert_eq!(username("registry.example.com").as_deref(), Some("kernel"));
+        // Left to the client, which reads the variable itself
+        assert!(!client
+            .hostname_to_login
+            .contains_key(&registry_url("quay.io")));
+    }
+
     #[tokio::test]
     async fn test_pull_command() -> Result<(), Box<dyn std::error::Error>> {
         let env = ContainerdTestEnv::new().await?;
@@ -661,10 +1247,23 @@ services:
             dir: Some(temp_dir.path().to_path_buf()),
             socket: Some(env.socket_path.clone()),
             max_depth: Some(1),
-            subcommand: ComposeCmd::Pull(Pull {}),
+            subcommand: ComposeCmd::Pull(Pull {
+                max_age: None,
+                metrics_file: None,
+                snapshotter: None,
+                tui: false,
+            }),
         };
 
-        let result = pull_command(&compose_settings).await;
+        let result = pull_command(
+            &compose_settings,
+            false,
+            HashMap::new(),
+            None,
+            HashMap::new(),
+            Arc::default(),
+        )
+        .await;
         assert!(result.is_ok());
         Ok(())
     }
@@ -0,0 +1,361 @@
+use crate::compose::containerd::client::services::v1::{
+    self as services,
+    snapshots::{
+        CommitSnapshotRequest, PrepareSnapshotRequest, RemoveSnapshotRequest, StatSnapshotRequest,
+        UsageRequest,
+    },
+    ApplyRequest,
+};
+use crate::compose::containerd::client::types::Descriptor;
+use crate::compose::lease::LeasedClient;
+use crate::format::format_size;
+use crate::parser::FullImageWithTag;
+use crate::spec::manifest::Descriptor as LayerDescriptor;
+use crate::with_client;
+use prost_types::FieldMask;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::{Code, Request};
+
+/// The snapshotter images are unpacked into, unless another one is requested
+pub const DEFAULT_SNAPSHOTTER: &str = "overlayfs";
+
+/// An image whose layers were written to containerd, and still need to be
+/// applied into snapshots
+#[derive(Debug, Clone)]
+pub struct UnpackTarget {
+    pub full_image: FullImageWithTag,
+    pub config_digest: String,
+    pub layers: Vec<LayerDescriptor>,
+    pub diff_ids: Vec<String>,
+}
+
+/// Computes the chain IDs of a layer stack, which snapshots are named after
+pub fn chain_ids(diff_ids: &[String]) -> Vec<String> {
+    let mut chain_ids: Vec<String> = Vec::with_capacity(diff_ids.len());
+
+    for diff_id in diff_ids {
+        let chain_id = match chain_ids.last() {
+            None => diff_id.clone(),
+            Some(parent) => format!(
+                "sha256:{}",
+                sha256::digest(format!("{} {}", parent, diff_id))
+            ),
+        };
+
+        chain_ids.push(chain_id);
+    }
+
+    chain_ids
+}
+
+/// Applies a single layer on top of its parent snapshot through the diff
+/// service, returning the size of the committed snapshot
+async fn apply_layer(
+    client: &Arc<LeasedClient>,
+    snapshotter: &str,
+    layer: &LayerDescriptor,
+    parent: &str,
+    chain_id: &str,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let key = format!("extract-{}-{}", std::process::id(), chain_id);
+    let mounts = client
+        .client()
+        .snapshots()
+        .prepare(with_client!(
+            PrepareSnapshotRequest {
+                snapshotter: snapshotter.to_string(),
+                key: key.clone(),
+                parent: parent.to_string(),
+                labels: HashMap::new(),
+            },
+            client
+        ))
+        .await?
+        .into_inner()
+        .mounts;
+
+    let applied = client
+        .client()
+        .diff()
+        .apply(with_client!(
+            ApplyRequest {
+                diff: Some(Descriptor {
+                    media_type: layer.media_type.to_string().into(),
+                    digest: layer.digest.clone(),
+                    size: layer.size as i64,
+                    annotations: HashMap::new(),
+                }),
+                mounts,
+                payloads: HashMap::new(),
+                sync_fs: false,
+            },
+            client
+        ))
+        .await;
+
+    let committed = match applied {
+        Ok(_) => {
+            client
+                .client()
+                .snapshots()
+                .commit(with_client!(
+                    CommitSnapshotRequest {
+                        snapshotter: snapshotter.to_string(),
+                        name: chain_id.to_string(),
+                        key: key.clone(),
+                        labels: HashMap::new(),
+                    },
+                    client
+                ))
+                .await
+        }
+        Err(status) => Err(status),
+    };
+
+    if let Err(status) = committed {
+        // Do not leave the half-applied snapshot behind
+        let _ = client
+            .client()
+            .snapshots()
+            .remove(with_client!(
+                RemoveSnapshotRequest {
+                    snapshotter: snapshotter.to_string(),
+                    key,
+                },
+                client
+            ))
+            .await;
+
+        // Another pull committed the same layer in the meantime
+        if status.code() == Code::AlreadyExists {
+            return Ok(0);
+        }
+
+        return Err(status.message().into());
+    }
+
+    let usage = client
+        .client()
+        .snapshots()
+        .usage(with_client!(
+            UsageRequest {
+                snapshotter: snapshotter.to_string(),
+                key: chain_id.to_string(),
+            },
+            client
+        ))
+        .await
+        .map(|usage| usage.into_inner().size)
+        .unwrap_or(0);
+
+    Ok(usage)
+}
+
+/// Applies the layers of an image into snapshots, reporting how long each
+/// layer took and how large it is once extracted. Layers that are already
+/// unpacked, for example because another image shares them, are skipped
+pub async fn unpack_image(
+    client: Arc<LeasedClient>,
+    snapshotter: &str,
+    target: &UnpackTarget,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if target.layers.len() != target.diff_ids.len() {
+        return Err("The number of layers does not match the config".into());
+    }
+
+    let chain_ids = chain_ids(&target.diff_ids);
+    let mut parent = String::new();
+
+    println!(
+        "Unpacking {} into {}...",
+        target.full_image.name(),
+        snapshotter
+    );
+
+    for (layer, chain_id) in target.layers.iter().zip(&chain_ids) {
+        let exists = client
+            .client()
+            .snapshots()
+            .stat(with_client!(
+                StatSnapshotRequest {
+                    snapshotter: snapshotter.to_string(),
+                    key: chain_id.clone(),
+                },
+                client
+            ))
+            .await
+            .is_ok();
+
+        if !exists {
+            let start = Instant::now();
+            let size = apply_layer(&client, snapshotter, layer, &parent, chain_id)
+                .await
+                .map_err(|e| format!("Failed to unpack layer {}: {}", layer.digest, e))?;
+
+            println!(
+                "  {} applied in {:.1?} ({})",
+                layer.digest,
+                start.elapsed(),
+                format_size(size.max(0) as u64)
+            );
+        }
+
+        parent = chain_id.clone();
+    }
+
+    // Reference the top snapshot from the config, so that garbage collection
+    // keeps the snapshots as long as the image exists
+    let label = format!("containerd.io/gc.ref.snapshot.{}", snapshotter);
+    client
+        .client()
+        .content()
+        .update(with_client!(
+            services::UpdateRequest {
+                info: Some(services::Info {
+                    digest: target.config_digest.clone(),
+                    labels: HashMap::from([(label.clone(), parent)]),
+                    ..Default::default()
+                }),
+                update_mask: Some(FieldMask {
+                    paths: vec![format!("labels.{}", label)],
+                }),
+            },
+            client
+        ))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::pull::containerd_utils::{
+        get_existing_content_from_containerd, upload_content_to_containerd,
+    };
+    use crate::downloader::{write_layer_to_containerd, LayerProgress};
+    use crate::parser::FullImageWithTag;
+    use crate::spec::enums::MediaType;
+    use crate::test::tests::{create_test_client, ContainerdTestEnv};
+    use bytes::Bytes;
+    use indicatif::ProgressBar;
+    use std::error::Error;
+
+    /// Writes a layer into containerd the way a pull streams it from a registry
+    async fn write_layer(
+        client: &Arc<LeasedClient>,
+        full_image: &FullImageWithTag,
+        data: &[u8],
+        diff_id: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let digest = format!("sha256:{}", sha256::digest(data));
+        let chunks: Vec<Result<Bytes, std::io::Error>> = data
+            .chunks(1000)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+
+        write_layer_to_containerd(
+            client.clone(),
+            futures::stream::iter(chunks),
+            &full_image.image,
+            &digest,
+            diff_id,
+            data.len() as u64,
+            &LayerProgress {
+                progress_bar: ProgressBar::hidden(),
+                spinner: None,
+                downloaded_bytes: Arc::new(tokio::sync::Mutex::new(0)),
+            },
+        )
+        .await?;
+
+        Ok(digest)
+    }
+
+    #[tokio::test]
+    #[ignore = "starts containerd with sudo"]
+    async fn test_unpack_zstd_layer() -> Result<(), Box<dyn Error>> {
+        let env = ContainerdTestEnv::new().await?;
+        let client = create_test_client(&env.socket_path).await?;
+        let full_image = FullImageWithTag::from_image_name("example/zstd:latest").unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "etc/hostname", &b"zstd\n"[..])?;
+        let tar = builder.into_inner()?;
+        let diff_id = format!("sha256:{}", sha256::digest(&tar[..]));
+        let layer = zstd::stream::encode_all(&tar[..], 3)?;
+
+        // A layer that does not decompress to the config's diff ID is refused
+        let wrong_diff_id = format!("sha256:{}", sha256::digest("other"));
+        assert!(write_layer(&client, &full_image, &layer, &wrong_diff_id)
+            .await
+            .is_err());
+
+        let digest = write_layer(&client, &full_image, &layer, &diff_id).await?;
+        let content = get_existing_content_from_containerd(client.clone()).await?;
+        assert_eq!(
+            content[&digest].get("containerd.io/uncompressed"),
+            Some(&diff_id)
+        );
+
+        let config = format!(
+            r#"{{"rootfs":{{"type":"layers","diff_ids":["{}"]}}}}"#,
+            diff_id
+        );
+        let config_digest = format!("sha256:{}", sha256::digest(&config));
+        upload_content_to_containerd(
+            client.clone(),
+            &config_digest,
+            config.into_bytes(),
+            HashMap::new(),
+        )
+        .await?;
+
+        let target = UnpackTarget {
+            full_image,
+            config_digest,
+            layers: vec![LayerDescriptor {
+                media_type: MediaType::OciImageLayerV1TarZstd,
+                digest,
+                size: layer.len() as u64,
+                data: None,
+                urls: None,
+                annotations: None,
+            }],
+            diff_ids: vec![diff_id.clone()],
+        };
+        unpack_image(client.clone(), DEFAULT_SNAPSHOTTER, &target).await?;
+
+        client
+            .client()
+            .snapshots()
+            .stat(with_client!(
+                StatSnapshotRequest {
+                    snapshotter: DEFAULT_SNAPSHOTTER.to_string(),
+                    key: diff_id,
+                },
+                client
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_ids() {
+        let diff_ids = vec!["sha256:a".to_string(), "sha256:b".to_string()];
+        let chain_ids = chain_ids(&diff_ids);
+
+        assert_eq!(chain_ids[0], "sha256:a");
+        assert_eq!(
+            chain_ids[1],
+            format!("sha256:{}", sha256::digest("sha256:a sha256:b"))
+        );
+    }
+}
@@ -5,7 +5,10 @@ use crate::compose::up::nerdctl_utils::NetworkName;
 use crate::{compose::docker_compose_finder::find_and_parse_docker_composes, Compose};
 use std::collections::{HashMap, HashSet};
 
-pub async fn up_command(compose_settings: &Compose) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn up_command(
+    compose_settings: &Compose,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let start_dir = compose_settings
         .dir
         .clone()
@@ -53,6 +56,11 @@ pub async fn up_command(compose_settings: &Compose) -> Result<(), Box<dyn std::e
         if let Some(network_settings) = networks_to_create.get(network_name) {
             let full_name = network_name.full_name();
 
+            if dry_run {
+                println!("Would create network '{}'.", full_name);
+                continue;
+            }
+
             match nerdctl_utils::create_network(network_name, network_settings) {
                 Ok(_) => println!("Network '{}' created successfully.", full_name),
                 Err(e) => eprintln!("Failed to create network '{}': {}", full_name, e),
@@ -60,6 +68,9 @@ pub async fn up_command(compose_settings: &Compose) -> Result<(), Box<dyn std::e
         }
     }
 
-    println!("All networks have been created successfully.");
+    if !dry_run {
+        println!("All networks have been created successfully.");
+    }
+
     Ok(())
 }
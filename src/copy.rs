@@ -0,0 +1,428 @@
+use std::{error::Error, sync::Arc};
+
+use serde::Deserialize;
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    docker_archive::write_docker_archive,
+    downloader::{is_index_content_type, skip_foreign_layers, OciDownloader},
+    execution::Blob,
+    layout::{parse_output, OciLayout, Output},
+    local::LocalImage,
+    parser::{FullImage, FullImageWithTag, Reference},
+    platform::platform_requested,
+    report::Context,
+    spec::{enums::MediaType, index::ImageIndex},
+    state::{self, image_key, State},
+    uploader::{OciUploader, UploadStart},
+    Copy,
+};
+
+/// The blobs a manifest refers to. Only the digests are read, so that
+/// artifacts with layer types ocitool does not know are copied as well
+#[derive(Deserialize)]
+struct ManifestBlobs {
+    config: Option<BlobReference>,
+    #[serde(default)]
+    layers: Vec<BlobReference>,
+}
+
+#[derive(Deserialize)]
+struct BlobReference {
+    #[serde(rename = "mediaType")]
+    media_type: Option<MediaType>,
+    digest: String,
+    /// Where a foreign layer can be downloaded from, besides the registry
+    urls: Option<Vec<String>>,
+}
+
+impl BlobReference {
+    fn is_foreign(&self) -> bool {
+        self.urls.is_some()
+            || self
+                .media_type
+                .as_ref()
+                .is_some_and(MediaType::is_non_distributable)
+    }
+}
+
+/// Copies a blob unless the destination already has it. Blobs on the same
+/// registry are mounted instead of transferred
+async fn copy_blob(
+    downloader: &OciDownloader,
+    uploader: &OciUploader,
+    source: &FullImage,
+    destination: &FullImage,
+    digest: &str,
+) -> Result<(), Box<dyn Error>> {
+    if uploader
+        .blob_exists(destination.clone(), digest)
+        .await
+        .with_context(|| format!("checking blob {}", digest))?
+    {
+        return Ok(());
+    }
+
+    if uploader.is_dry_run() {
+        println!("Would copy blob {}.", digest);
+        return Ok(());
+    }
+
+    // The blob is only downloaded if the registry cannot mount it. Blobs
+    // from disk can never be mounted
+    let session = match !downloader.is_local() && uploader.can_mount(source, destination) {
+        true => {
+            match uploader
+                .start_upload(destination.clone(), digest, Some(source))
+                .await
+                .with_context(|| format!("mounting blob {}", digest))?
+            {
+                UploadStart::Mounted => return Ok(()),
+                UploadStart::Session(location) => Some(location),
+            }
+        }
+        false => None,
+    };
+
+    let data = downloader
+        .download_layer(source.clone(), digest)
+        .await
+        .with_context(|| format!("downloading blob {}", digest))?;
+
+    if sha256_digest(&data) != digest {
+        return Err(format!("Blob {} does not match its digest", digest).into());
+    }
+
+    let blob = Blob {
+        digest: digest.to_string(),
+        data,
+        mount_from: None,
+    };
+
+    match session {
+        Some(location) => {
+            uploader
+                .finish_upload(destination.clone(), &location, &blob)
+                .await
+        }
+        None => uploader.upload_blob(destination.clone(), &blob).await,
+    }
+    .with_context(|| format!("uploading blob {}", digest))?;
+
+    Ok(())
+}
+
+/// Copies a single manifest with its config and layers, keeping its digest
+async fn copy_manifest(
+    downloader: &OciDownloader,
+    uploader: &OciUploader,
+    source: &FullImage,
+    destination: &FullImageWithTag,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<(), Box<dyn Error>> {
+    let blobs: ManifestBlobs = serde_json::from_slice(&data)?;
+
+    for blob in blobs.config.iter().chain(blobs.layers.iter()) {
+        // The manifest still refers to skipped layers, which are pulled from
+        // their URLs instead
+        if blob.is_foreign() && skip_foreign_layers() {
+            println!("Skipping foreign layer {}.", blob.digest);
+            continue;
+        }
+
+        if let Some(urls) = &blob.urls {
+            downloader.add_foreign_urls(&blob.digest, urls);
+        }
+
+        copy_blob(
+            downloader,
+            uploader,
+            source,
+            &destination.image,
+            &blob.digest,
+        )
+        .await?;
+    }
+
+    uploader
+        .upload_manifest(destination.clone(), data, content_type)
+        .await
+        .with_context(|| format!("uploading manifest {}", destination.tag))?;
+
+    Ok(())
+}
+
+/// Copies an image, or an index with the manifests of the requested
+/// platforms, returning the digest it was copied under
+pub async fn copy_image(
+    downloader: &OciDownloader,
+    uploader: &OciUploader,
+    source: &FullImageWithTag,
+    destination: &FullImageWithTag,
+    platforms: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let (content_type, data) = downloader.download_manifest_raw(source.clone()).await?;
+    let mut data = data.to_vec();
+
+    if is_index_content_type(&content_type) {
+        let mut index: ImageIndex = serde_json::from_slice(&data)?;
+
+        if !platforms.is_empty() {
+            let total = index.manifests.len();
+            index.manifests.retain(|manifest| {
+                manifest
+                    .platform
+                    .as_ref()
+                    .is_some_and(|platform| platform_requested(platform, platforms))
+            });
+
+            if index.manifests.is_empty() {
+                return Err(format!("No manifest matches {}", platforms.join(", ")).into());
+            }
+
+            // A partial copy is a different index, so it gets a new digest
+            if index.manifests.len() != total {
+                data = index.to_json();
+            }
+        }
+
+        for manifest in &index.manifests {
+            println!("Copying manifest {}...", manifest.digest);
+
+            let (manifest_type, manifest_data) = downloader
+                .download_manifest_raw(FullImageWithTag {
+                    image: source.image.clone(),
+                    tag: manifest.digest.clone(),
+                })
+                .await?;
+
+            copy_manifest(
+                downloader,
+                uploader,
+                &source.image,
+                &FullImageWithTag {
+                    image: destination.image.clone(),
+                    tag: manifest.digest.clone(),
+                },
+                &manifest_type,
+                manifest_data.to_vec(),
+            )
+            .await
+            .with_context(|| format!("copying manifest {}", manifest.digest))?;
+        }
+
+        uploader
+            .upload_manifest(destination.clone(), data.clone(), &content_type)
+            .await?;
+    } else {
+        if !platforms.is_empty() {
+            println!("Source is a single-platform image, copying it whole.");
+        }
+
+        copy_manifest(
+            downloader,
+            uploader,
+            &source.image,
+            destination,
+            &content_type,
+            data.clone(),
+        )
+        .await?;
+    }
+
+    Ok(sha256_digest(&data))
+}
+
+pub async fn copy_command(
+    args: &Copy,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let local =
+        LocalImage::open(&args.source).with_context(|| format!("opening {}", args.source))?;
+    let source = match &local {
+        Some(local) => local.reference(),
+        None => FullImageWithTag::parse(&args.source, client.settings())?,
+    };
+    let output = args.output.as_deref().map(parse_output).transpose()?;
+
+    let mut archive = None;
+
+    // An image layout is named after the source tag, unless a name is given
+    let (destination, target, layout) = match (&args.destination, output) {
+        (Some(destination), None) => (
+            FullImageWithTag::parse(destination, client.settings())?,
+            destination.clone(),
+            None,
+        ),
+        (None, Some(Output::Layout(dir, name))) => {
+            let name = name.unwrap_or_else(|| source.tag.clone());
+
+            if matches!(Reference::parse(&name), Reference::Digest(_)) {
+                return Err(
+                    "Name the image with --output oci:<dir>:<name> when copying by digest".into(),
+                );
+            }
+
+            (
+                FullImageWithTag {
+                    image: source.image.clone(),
+                    tag: name.clone(),
+                },
+                format!("oci:{}:{}", dir.display(), name),
+                Some(OciLayout::new(&dir)),
+            )
+        }
+        (None, Some(Output::DockerArchive(path, reference))) => {
+            // Images from disk are already named by their tag
+            let reference = reference.unwrap_or_else(|| match &local {
+                Some(_) => source.tag.clone(),
+                None => format!("{}:{}", source.image.repository(), source.tag),
+            });
+
+            if reference.contains("sha256:") {
+                return Err("Name the image with --output docker-archive:<tar>:<reference> when copying by digest".into());
+            }
+
+            // The image is staged in a layout, and converted once complete
+            let staging = tempfile::tempdir()?;
+            let layout = OciLayout::new(staging.path());
+            let target = format!("docker-archive:{}:{}", path.display(), reference);
+            archive = Some((staging, path, reference));
+
+            (
+                FullImageWithTag {
+                    image: source.image.clone(),
+                    tag: "image".to_string(),
+                },
+                target,
+                Some(layout),
+            )
+        }
+        _ => return Err("Pass either a destination image or --output".into()),
+    };
+
+    let mut permissions = vec![];
+
+    if local.is_none() {
+        permissions.push(ImagePermission {
+            full_image: source.image.clone(),
+            permissions: ImagePermissions::Pull,
+        });
+    }
+
+    if layout.is_none() {
+        permissions.push(ImagePermission {
+            full_image: destination.image.clone(),
+            permissions: ImagePermissions::Push,
+        });
+    }
+
+    client.login(&permissions).await?;
+
+    let is_layout = layout.is_some();
+    let downloader = OciDownloader::new(client.clone(), no_cache).with_local(local);
+    let uploader = OciUploader::new(client)
+        .with_dry_run(dry_run)
+        .with_layout(layout);
+
+    let digest = copy_image(
+        &downloader,
+        &uploader,
+        &source,
+        &destination,
+        &args.platform,
+    )
+    .await
+    .with_context(|| format!("copying {}", args.source))?;
+
+    if dry_run {
+        println!("Would copy {} to {}@{}", args.source, target, digest);
+        return Ok(());
+    }
+
+    if let Some((staging, path, reference)) = &archive {
+        write_docker_archive(
+            &OciLayout::new(staging.path()),
+            &destination.tag,
+            std::slice::from_ref(reference),
+            &args.platform,
+            path,
+        )
+        .with_context(|| format!("writing {}", path.display()))?;
+    }
+
+    // Only registries are tracked in the state
+    if is_layout {
+        println!("Copied {} to {}@{}", args.source, target, digest);
+        return Ok(());
+    }
+
+    if let Err(e) = State::update(&State::path(), |state| {
+        state.record_image(&image_key(&destination), &digest, state::now())
+    }) {
+        eprintln!("Failed to save state: {}", e);
+    }
+
+    println!("Copied {} to {}@{}", args.source, target, digest);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::recording::{Interaction, Recording};
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    #[tokio::test]
+    async fn test_copy_blob_mounts_first() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let interactions = [
+            ("GET", "https://registry.example.com/auth?service=registry.example.com&scope=repository%3Ateam%2Fapp%3Apull%2Cpush".to_string(), 200, r#"{"token":"REDACTED"}"#),
+            ("HEAD", format!("https://registry.example.com/v2/team/app/blobs/{}", digest), 404, ""),
+            ("POST", format!("https://registry.example.com/v2/team/app/blobs/uploads/?mount={}&from=team/base", digest), 201, ""),
+        ];
+
+        for (index, (method, url, status, body)) in interactions.into_iter().enumerate() {
+            let interaction = Interaction {
+                method: method.to_string(),
+                url,
+                status,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(body),
+            };
+            std::fs::write(
+                dir.path()
+                    .join(format!("{:05}-{}.json", index, method.to_lowercase())),
+                serde_json::to_vec(&interaction)?,
+            )?;
+        }
+
+        let source = FullImage::from_image_name("registry.example.com/team/base")?;
+        let destination = FullImage::from_image_name("registry.example.com/team/app")?;
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: destination.clone(),
+                permissions: ImagePermissions::Push,
+            }])
+            .await?;
+
+        // The blob is not recorded, so it must not be downloaded
+        let downloader = OciDownloader::new(client.clone(), true);
+        let uploader = OciUploader::new(client);
+        copy_blob(&downloader, &uploader, &source, &destination, &digest).await?;
+
+        Ok(())
+    }
+}
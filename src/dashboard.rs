@@ -0,0 +1,538 @@
+use std::{
+    collections::VecDeque,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use console::Term;
+use indicatif::ProgressBar;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use tokio::task::JoinHandle;
+
+use crate::format::{format_duration, format_size};
+
+/// How often the dashboard is redrawn and throughput is sampled
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// How many throughput samples the graph shows
+const HISTORY: usize = 60;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Switches to the alternate screen and hides the cursor, and back
+const ENTER_SCREEN: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE_SCREEN: &str = "\x1b[?25h\x1b[?1049l";
+
+/// The signals that end ocitool while the dashboard is drawn
+const SIGNALS: [Signal; 3] = [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP];
+
+/// What the dashboard shows the progress of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// A compose pull, with a row per image
+    Pull,
+    /// An upload, with a row per platform of the plan
+    Upload,
+}
+
+impl Transfer {
+    fn title(self) -> &'static str {
+        match self {
+            Transfer::Pull => "ocitool pull",
+            Transfer::Upload => "ocitool upload",
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Transfer::Pull => "images",
+            Transfer::Upload => "platforms",
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            Transfer::Pull => "IMAGE",
+            Transfer::Upload => "PLATFORM",
+        }
+    }
+
+    fn done(self) -> &'static str {
+        match self {
+            Transfer::Pull => "Pulled",
+            Transfer::Upload => "Uploaded",
+        }
+    }
+}
+
+/// What a worker is doing, shown on its own line of the dashboard
+pub type Workers = Arc<Vec<Mutex<String>>>;
+
+pub fn new_workers(count: usize) -> Workers {
+    Arc::new((0..count).map(|_| Mutex::new("idle".to_string())).collect())
+}
+
+pub fn set_worker(workers: &Workers, worker: usize, status: String) {
+    if let Ok(mut current) = workers[worker].lock() {
+        *current = status;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RowState {
+    Failed,
+    Active,
+    Done,
+}
+
+/// One image of the transfer table
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub name: String,
+    pub status: String,
+    pub state: RowState,
+    pub bytes: u64,
+    /// Requests that had to be sent again
+    pub retries: u64,
+}
+
+/// An image or platform with a row on the dashboard: the spinner its
+/// progress is kept on, and the count of its requests that were retried
+#[derive(Clone)]
+pub struct Tracked {
+    pub name: String,
+    pub spinner: ProgressBar,
+    pub retries: Arc<AtomicU64>,
+}
+
+impl Tracked {
+    pub fn new(name: String, spinner: ProgressBar) -> Tracked {
+        Tracked {
+            name,
+            spinner,
+            retries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sets what is being done, as "name: status"
+    pub fn set_status(&self, status: &str) {
+        self.spinner
+            .set_message(format!("{}: {}", self.name, status));
+    }
+
+    pub fn row(&self) -> Row {
+        Row {
+            retries: self.retries.load(Ordering::Relaxed),
+            ..Row::from_spinner(&self.name, &self.spinner)
+        }
+    }
+}
+
+/// Removes the colors the spinner messages are written with
+fn strip_colors(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c == 'm' {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+impl Row {
+    /// Reads a row from the spinner of an image. Spinners are finished with
+    /// "name: status" and count the bytes of their image as their position.
+    /// Spinners still going may say what they are doing the same way
+    pub fn from_spinner(name: &str, spinner: &ProgressBar) -> Row {
+        let bytes = spinner.position();
+        let message = strip_colors(&spinner.message());
+        let status = message
+            .strip_prefix(name)
+            .and_then(|status| status.strip_prefix(": "));
+
+        if !spinner.is_finished() {
+            return Row {
+                name: name.to_string(),
+                status: match (status, bytes) {
+                    (Some(status), _) => status.to_string(),
+                    (None, 0) => "resolving".to_string(),
+                    (None, _) => "downloading".to_string(),
+                },
+                state: RowState::Active,
+                bytes,
+                retries: 0,
+            };
+        }
+
+        let status = status.unwrap_or(&message).to_string();
+        let state = match status.starts_with("Failed") {
+            true => RowState::Failed,
+            false => RowState::Done,
+        };
+
+        Row {
+            name: name.to_string(),
+            status,
+            state,
+            bytes,
+            retries: 0,
+        }
+    }
+}
+
+/// Everything a frame of the dashboard shows
+pub struct Snapshot {
+    pub transfer: Transfer,
+    pub rows: Vec<Row>,
+    pub workers: Vec<String>,
+    /// Bytes per second, oldest first
+    pub history: Vec<u64>,
+    pub downloaded: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+fn fit(text: &str, width: usize) -> String {
+    match text.chars().count() > width {
+        true => text
+            .chars()
+            .take(width.saturating_sub(1))
+            .chain(['…'])
+            .collect(),
+        false => format!("{:<width$}", text, width = width),
+    }
+}
+
+fn sparkline(history: &[u64], width: usize) -> String {
+    let samples = &history[history.len().saturating_sub(width)..];
+    let max = samples.iter().copied().max().unwrap_or(0).max(1);
+
+    samples
+        .iter()
+        .map(|sample| SPARKS[(*sample * (SPARKS.len() as u64 - 1) / max) as usize])
+        .collect()
+}
+
+impl Snapshot {
+    /// Renders the frame into at most `height` lines of `width` columns.
+    /// Failed and active images come first, as finished ones are only
+    /// counted once the table no longer fits
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let width = width.max(40);
+        let done = self
+            .rows
+            .iter()
+            .filter(|row| row.state != RowState::Active)
+            .count();
+        let failed = self
+            .rows
+            .iter()
+            .filter(|row| row.state == RowState::Failed)
+            .count();
+        let throughput = self.history.last().copied().unwrap_or(0);
+
+        let mut lines = vec![
+            fit(
+                &format!(
+                    "{}  {}/{} {} done, {} failed  {} / {}  {}/s  {}",
+                    self.transfer.title(),
+                    done,
+                    self.rows.len(),
+                    self.transfer.unit(),
+                    failed,
+                    format_size(self.downloaded),
+                    format_size(self.total),
+                    format_size(throughput),
+                    format_duration(self.elapsed.as_secs())
+                ),
+                width,
+            ),
+            fit(
+                &format!("throughput {}", sparkline(&self.history, width - 11)),
+                width,
+            ),
+            String::new(),
+        ];
+
+        if !self.workers.is_empty() {
+            for (worker, status) in self.workers.iter().enumerate() {
+                lines.push(fit(&format!("worker {:<2} {}", worker + 1, status), width));
+            }
+
+            lines.push(String::new());
+        }
+
+        let name_width = (width - 20) / 2;
+        let status_width = width - name_width - 20;
+        lines.push(format!(
+            "{} {} {:>10} {:>7}",
+            fit(self.transfer.column(), name_width),
+            fit("STATUS", status_width),
+            "BYTES",
+            "RETRIES"
+        ));
+
+        let mut rows: Vec<&Row> = self.rows.iter().collect();
+        rows.sort_by(|a, b| a.state.cmp(&b.state).then(a.name.cmp(&b.name)));
+
+        let room = height.saturating_sub(lines.len());
+        let shown = match rows.len() > room {
+            true => room.saturating_sub(1),
+            false => rows.len(),
+        };
+
+        for row in &rows[..shown] {
+            lines.push(format!(
+                "{} {} {:>10} {:>7}",
+                fit(&row.name, name_width),
+                fit(&row.status, status_width),
+                format_size(row.bytes),
+                row.retries
+            ));
+        }
+
+        if shown < rows.len() {
+            lines.push(format!("… and {} more", rows.len() - shown));
+        }
+
+        lines.truncate(height.max(1));
+        lines
+    }
+}
+
+/// Leaves the alternate screen before ocitool is ended by a signal, as it
+/// would otherwise leave the terminal without its contents and cursor
+extern "C" fn leave_screen_on_signal(signal: libc::c_int) {
+    // Only async-signal-safe calls here, and the default action once done
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            LEAVE_SCREEN.as_ptr().cast(),
+            LEAVE_SCREEN.len(),
+        );
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// The alternate screen the dashboard is drawn on, left again when dropped
+/// or when ocitool is interrupted
+struct Screen {
+    term: Term,
+    previous: Vec<(Signal, SigAction)>,
+}
+
+impl Screen {
+    fn enter(term: Term) -> Screen {
+        let _ = write!(&term, "{}", ENTER_SCREEN);
+
+        let action = SigAction::new(
+            SigHandler::Handler(leave_screen_on_signal),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        let previous = SIGNALS
+            .into_iter()
+            .filter_map(|signal| Some((signal, unsafe { sigaction(signal, &action) }.ok()?)))
+            .collect();
+
+        Screen { term, previous }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        for (signal, action) in &self.previous {
+            let _ = unsafe { sigaction(*signal, action) };
+        }
+
+        let _ = write!(&self.term, "{}", LEAVE_SCREEN);
+    }
+}
+
+/// A full screen view of a pull or upload, drawn on the alternate screen
+/// of the terminal in place of a spinner per image
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl Dashboard {
+    pub fn spawn(
+        transfer: Transfer,
+        rows: Vec<Tracked>,
+        total: ProgressBar,
+        workers: Workers,
+    ) -> Dashboard {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        let task = tokio::spawn(async move {
+            let screen = Screen::enter(Term::stderr());
+            let started = Instant::now();
+            let mut history = VecDeque::with_capacity(HISTORY);
+            let mut last = 0;
+            let mut interval = tokio::time::interval(REFRESH);
+
+            while !stopped.load(Ordering::Relaxed) {
+                interval.tick().await;
+
+                let downloaded = total.position();
+
+                if history.len() == HISTORY {
+                    history.pop_front();
+                }
+
+                history.push_back(
+                    (downloaded.saturating_sub(last) as f64 / REFRESH.as_secs_f64()) as u64,
+                );
+                last = downloaded;
+
+                let snapshot = Snapshot {
+                    transfer,
+                    rows: rows.iter().map(Tracked::row).collect(),
+                    workers: workers
+                        .iter()
+                        .map(|status| status.lock().map(|s| s.clone()).unwrap_or_default())
+                        .collect(),
+                    history: history.iter().copied().collect(),
+                    downloaded,
+                    total: total.length().unwrap_or(0),
+                    elapsed: started.elapsed(),
+                };
+
+                let (height, width) = screen.term.size();
+                let frame = snapshot
+                    .render(width as usize, height as usize)
+                    .join("\x1b[K\n");
+                let _ = write!(&screen.term, "\x1b[H{}\x1b[K\x1b[J", frame);
+            }
+        });
+
+        Dashboard { stop, task }
+    }
+
+    /// Stops drawing and gives the terminal back
+    pub async fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.task.await;
+    }
+}
+
+/// Prints the failures and a summary of a transfer once its dashboard is
+/// gone with the alternate screen
+pub fn print_summary(transfer: Transfer, rows: &[Row], bytes: u64) {
+    let failed: Vec<_> = rows
+        .iter()
+        .filter(|row| row.state == RowState::Failed)
+        .collect();
+
+    for row in &failed {
+        println!("{}: \x1b[31m{}\x1b[0m", row.name, row.status);
+    }
+
+    println!(
+        "{} {} {}, {} failed, {}, {} retries",
+        transfer.done(),
+        rows.len(),
+        transfer.unit(),
+        failed.len(),
+        format_size(bytes),
+        rows.iter().map(|row| row.retries).sum::<u64>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(name: &str, state: RowState, bytes: u64) -> Row {
+        Row {
+            name: name.to_string(),
+            status: format!("{:?}", state),
+            state,
+            bytes,
+            retries: 0,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let spinner = ProgressBar::hidden();
+        spinner.inc(2048);
+        spinner.finish_with_message("redis:7: \x1b[31mFailed - 404 Not Found\x1b[0m");
+        assert_eq!(
+            Row::from_spinner("redis:7", &spinner),
+            Row {
+                name: "redis:7".to_string(),
+                status: "Failed - 404 Not Found".to_string(),
+                state: RowState::Failed,
+                bytes: 2048,
+                retries: 0,
+            }
+        );
+
+        let platform = Tracked::new("linux/arm64".to_string(), ProgressBar::hidden());
+        platform.set_status("uploading");
+        platform.retries.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(platform.row().status, "uploading");
+        assert_eq!(platform.row().retries, 2);
+
+        let snapshot = Snapshot {
+            transfer: Transfer::Pull,
+            rows: vec![
+                row("alpine:3", RowState::Done, 0),
+                row("nginx:alpine", RowState::Active, 1024),
+                row("postgres:16", RowState::Done, 0),
+                row("redis:7", RowState::Failed, 2048),
+            ],
+            workers: vec![
+                "idle".to_string(),
+                "layer sha256:abc nginx:alpine".to_string(),
+            ],
+            history: vec![0, 512, 1024],
+            downloaded: 3072,
+            total: 8192,
+            elapsed: Duration::from_secs(5),
+        };
+
+        let lines = snapshot.render(60, 10);
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].starts_with("ocitool pull  3/4 images done, 1 failed"));
+        assert!(lines[1].starts_with("throughput ▁▄█"));
+        assert!(lines[4].starts_with("worker 2  layer sha256:abc"));
+        assert!(lines[7].starts_with("redis:7"));
+        assert!(lines[8].starts_with("nginx:alpine"));
+        assert_eq!(lines[9], "… and 2 more");
+
+        // Uploads have no workers, so the table follows the graph
+        let snapshot = Snapshot {
+            transfer: Transfer::Upload,
+            rows: vec![platform.row()],
+            workers: vec![],
+            history: vec![],
+            downloaded: 0,
+            total: 0,
+            elapsed: Duration::from_secs(1),
+        };
+
+        let lines = snapshot.render(60, 10);
+        assert!(lines[0].starts_with("ocitool upload  0/1 platforms done, 0 failed"));
+        assert!(lines[3].starts_with("PLATFORM"));
+        assert!(lines[4].starts_with("linux/arm64"));
+        assert!(lines[4].ends_with("      2"));
+    }
+}
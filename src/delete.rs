@@ -0,0 +1,48 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    parser::FullImageWithTag,
+    report::Context,
+    uploader::OciUploader,
+    Delete,
+};
+
+/// Deletes a manifest from a registry over HTTP. Registries only delete by
+/// digest, so a tag is resolved first, and every tag of that digest goes too
+pub async fn delete_command(
+    args: &Delete,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Delete,
+        }])
+        .await?;
+
+    let digest = match image.digest() {
+        Some(digest) => digest.to_string(),
+        None => OciDownloader::new(client.clone(), no_cache)
+            .resolve_digest(image.clone())
+            .await
+            .with_context(|| format!("resolving {}", args.image))?,
+    };
+
+    OciUploader::new(client)
+        .with_dry_run(dry_run)
+        .delete_manifest(image.image.clone(), &digest)
+        .await
+        .with_context(|| format!("deleting {}@{}", image.image.image_name, digest))?;
+
+    if !dry_run {
+        println!("Deleted {}@{}", image.image.image_name, digest);
+    }
+
+    Ok(())
+}
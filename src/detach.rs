@@ -0,0 +1,216 @@
+use std::{
+    env,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::ForkResult,
+};
+
+use crate::{
+    workspace::{detached_dir, Workspace},
+    Attach, Stop,
+};
+
+/// Set for the ocitool that runs a detached container, so that it runs it
+/// in the foreground instead of detaching again
+const DETACHED_VAR: &str = "OCITOOL_DETACHED";
+
+/// How long run --detach waits for the detached ocitool to write its pid
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often stop and attach look at the container again
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Whether this ocitool runs a container started with `run --detach`
+pub fn is_detached() -> bool {
+    env::var_os(DETACHED_VAR).is_some()
+}
+
+/// Reads the passwords the ocitool that detached this one hands on through
+/// stdin, as they may have come from a prompt or its own stdin
+pub fn inherited_passwords() -> io::Result<Vec<String>> {
+    let mut passwords = String::new();
+    io::stdin().read_to_string(&mut passwords)?;
+
+    serde_json::from_str(&passwords).map_err(io::Error::other)
+}
+
+/// Starts ocitool again with the same arguments in a session of its own,
+/// with its output going to the log of the container, and returns once it
+/// is running. The new ocitool is the one that extracts the image, runs
+/// the container and passes stop signals on to it. It gets the passwords
+/// of the logins through stdin, as it cannot ask for them
+pub fn spawn_detached(workspace: &Workspace, passwords: &[String]) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(detached_dir())?;
+
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(workspace.log_path())?;
+
+    for path in [
+        workspace.exit_code_path(),
+        workspace.error_path(),
+        workspace.pid_path(),
+    ] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(DETACHED_VAR, "1")
+        .stdin(Stdio::piped())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    // Out of the session of this terminal, so that closing it does not hang
+    // the container up, then forked again so that the ocitool left running
+    // is no session leader and can never take a terminal back
+    unsafe {
+        command.pre_exec(|| {
+            nix::unistd::setsid()?;
+
+            match nix::unistd::fork()? {
+                ForkResult::Parent { .. } => libc::_exit(0),
+                ForkResult::Child => Ok(()),
+            }
+        });
+    }
+
+    let mut child = command.spawn()?;
+
+    // Should the detached ocitool be gone already, that is reported below
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(&serde_json::to_vec(passwords)?);
+    }
+
+    child.wait()?;
+
+    // The detached ocitool writes its own pid, as only it knows it
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        if let Some(pid) = workspace.running_pid() {
+            eprintln!(
+                "Started {} (pid {}), logging to {}",
+                workspace.name,
+                pid,
+                workspace.log_path().display()
+            );
+            return Ok(());
+        }
+
+        // Exited before it was looked for
+        if workspace.exit_code_path().exists() {
+            if let Ok(error) = fs::read_to_string(workspace.error_path()) {
+                return Err(format!(
+                    "{} failed: {}, see {}",
+                    workspace.name,
+                    error,
+                    workspace.log_path().display()
+                )
+                .into());
+            }
+
+            eprintln!(
+                "{} already exited, its output is in {}",
+                workspace.name,
+                workspace.log_path().display()
+            );
+            return Ok(());
+        }
+
+        if Instant::now() > deadline {
+            return Err(format!(
+                "{} did not start, see {}",
+                workspace.name,
+                workspace.log_path().display()
+            )
+            .into());
+        }
+
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Records how the detached container exited, for attach to exit with
+pub fn finish_detached(workspace: &Workspace, code: i32) -> io::Result<()> {
+    fs::write(workspace.exit_code_path(), code.to_string())?;
+
+    match fs::remove_file(workspace.pid_path()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Records why the detached ocitool failed, before it exits, for run
+/// --detach to report and attach to exit with. Left alone if the container
+/// is run by another ocitool
+pub fn fail_detached(workspace: &Workspace, error: &dyn Error) -> io::Result<()> {
+    if workspace
+        .running_pid()
+        .is_some_and(|pid| pid != nix::unistd::getpid())
+    {
+        return Ok(());
+    }
+
+    fs::write(workspace.error_path(), error.to_string())?;
+    finish_detached(workspace, 1)
+}
+
+pub async fn stop_command(args: &Stop) -> Result<(), Box<dyn Error>> {
+    let workspace = Workspace::new(&args.name)?;
+
+    let Some(pid) = workspace.running_pid() else {
+        return Err(format!("{} is not running", args.name).into());
+    };
+
+    // The detached ocitool passes this on as the stop signal of the image,
+    // and kills the container if it does not exit within the stop timeout
+    kill(pid, Signal::SIGTERM)?;
+
+    while workspace.running_pid().is_some() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    println!("Stopped {}", args.name);
+    Ok(())
+}
+
+pub async fn attach_command(args: &Attach) -> Result<i32, Box<dyn Error>> {
+    let workspace = Workspace::new(&args.name)?;
+
+    let mut log = File::open(workspace.log_path())
+        .map_err(|_| format!("No detached container named {}", args.name))?;
+    let mut stdout = io::stdout();
+
+    loop {
+        // Looked at before reading, so that the output written right before
+        // the container exited is not missed
+        let running = workspace.running_pid().is_some();
+
+        io::copy(&mut log, &mut stdout)?;
+        stdout.flush()?;
+
+        if !running {
+            break;
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let code = fs::read_to_string(workspace.exit_code_path())
+        .map_err(|_| format!("{} exited without recording its code", args.name))?;
+
+    Ok(code.trim().parse()?)
+}
@@ -0,0 +1,373 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use walkdir::WalkDir;
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    format::format_size,
+    parser::FullImageWithTag,
+    report::Context,
+    spec::{config::ImageConfig, manifest::ImageManifest},
+    Diff,
+};
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added(String, String),
+    Removed(String, String),
+    Changed(String, String, String),
+}
+
+impl Change {
+    /// Prints the change, with the key prefixed by what kind of value it is
+    fn print(&self, kind: &str) {
+        let name = |key: &str| format!("{}{}", kind, key);
+
+        match self {
+            Change::Added(key, value) => println!("  + {}: {}", name(key), value),
+            Change::Removed(key, value) => println!("  - {}: {}", name(key), value),
+            Change::Changed(key, old, new) => {
+                println!("  ~ {}: {} -> {}", name(key), old, new)
+            }
+        }
+    }
+}
+
+/// Compares two sets of keyed values, such as environment variables or labels
+fn diff_maps(a: &BTreeMap<String, String>, b: &BTreeMap<String, String>) -> Vec<Change> {
+    let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+
+    keys.into_iter()
+        .filter_map(|key| match (a.get(key), b.get(key)) {
+            (Some(old), Some(new)) if old != new => {
+                Some(Change::Changed(key.clone(), old.clone(), new.clone()))
+            }
+            (Some(old), None) => Some(Change::Removed(key.clone(), old.clone())),
+            (None, Some(new)) => Some(Change::Added(key.clone(), new.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn env_map(config: &ImageConfig) -> BTreeMap<String, String> {
+    config
+        .config
+        .as_ref()
+        .and_then(|config| config.env.as_ref())
+        .into_iter()
+        .flatten()
+        .map(|var| match var.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (var.clone(), String::new()),
+        })
+        .collect()
+}
+
+fn label_map(config: &ImageConfig) -> BTreeMap<String, String> {
+    config
+        .config
+        .as_ref()
+        .and_then(|config| config.labels.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .collect()
+}
+
+/// The settings compared one by one, rendered as JSON so that lists read naturally
+fn settings_map(config: &ImageConfig) -> BTreeMap<String, String> {
+    let mut settings = BTreeMap::new();
+    let mut insert = |key: &str, value: serde_json::Value| {
+        if !value.is_null() {
+            settings.insert(key.to_string(), value.to_string());
+        }
+    };
+
+    insert(
+        "Architecture",
+        serde_json::to_value(&config.architecture).unwrap(),
+    );
+
+    if let Some(config) = &config.config {
+        insert(
+            "Entrypoint",
+            serde_json::to_value(&config.entrypoint).unwrap(),
+        );
+        insert("Cmd", serde_json::to_value(&config.cmd).unwrap());
+        insert("User", serde_json::to_value(&config.user).unwrap());
+        insert(
+            "WorkingDir",
+            serde_json::to_value(&config.working_dir).unwrap(),
+        );
+        insert(
+            "StopSignal",
+            serde_json::to_value(&config.stop_signal).unwrap(),
+        );
+
+        if let Some(ports) = &config.exposed_ports {
+            let ports: BTreeSet<&String> = ports.keys().collect();
+            insert("ExposedPorts", serde_json::to_value(ports).unwrap());
+        }
+    }
+
+    settings
+}
+
+fn print_config_diff(a: &ImageConfig, b: &ImageConfig) {
+    let changes = [
+        ("", diff_maps(&settings_map(a), &settings_map(b))),
+        ("Env ", diff_maps(&env_map(a), &env_map(b))),
+        ("Label ", diff_maps(&label_map(a), &label_map(b))),
+    ];
+
+    if changes.iter().all(|(_, changes)| changes.is_empty()) {
+        println!("Config: identical");
+        return;
+    }
+
+    println!("Config:");
+
+    for (kind, changes) in &changes {
+        for change in changes {
+            change.print(kind);
+        }
+    }
+}
+
+fn print_layer_diff(a: &ImageManifest, b: &ImageManifest) {
+    let a_digests: BTreeSet<&String> = a.layers.iter().map(|layer| &layer.digest).collect();
+    let b_digests: BTreeSet<&String> = b.layers.iter().map(|layer| &layer.digest).collect();
+    let removed: Vec<_> = a
+        .layers
+        .iter()
+        .filter(|layer| !b_digests.contains(&layer.digest))
+        .collect();
+    let added: Vec<_> = b
+        .layers
+        .iter()
+        .filter(|layer| !a_digests.contains(&layer.digest))
+        .collect();
+
+    println!(
+        "Layers: {} shared, {} removed ({}), {} added ({})",
+        a_digests.intersection(&b_digests).count(),
+        removed.len(),
+        format_size(removed.iter().map(|layer| layer.size).sum()),
+        added.len(),
+        format_size(added.iter().map(|layer| layer.size).sum()),
+    );
+
+    for layer in removed {
+        println!("  - {} {}", layer.digest, format_size(layer.size));
+    }
+
+    for layer in added {
+        println!("  + {} {}", layer.digest, format_size(layer.size));
+    }
+}
+
+/// What is compared about a path in an extracted root filesystem.
+/// Regular files of the same mode and size are compared by content afterwards
+#[derive(Debug, PartialEq)]
+enum Entry {
+    Directory { mode: u32 },
+    File { mode: u32, size: u64 },
+    Symlink { target: PathBuf },
+    Other { mode: u32 },
+}
+
+fn snapshot(root: &Path) -> io::Result<BTreeMap<PathBuf, Entry>> {
+    let mut entries = BTreeMap::new();
+
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        let file_type = entry.file_type();
+
+        let value = if file_type.is_symlink() {
+            Entry::Symlink {
+                target: fs::read_link(entry.path())?,
+            }
+        } else if file_type.is_dir() {
+            Entry::Directory { mode }
+        } else if file_type.is_file() {
+            Entry::File {
+                mode,
+                size: metadata.len(),
+            }
+        } else {
+            Entry::Other { mode }
+        };
+
+        let path = Path::new("/").join(entry.path().strip_prefix(root).unwrap());
+        entries.insert(path, value);
+    }
+
+    Ok(entries)
+}
+
+/// Compares two extracted root filesystems, returning each differing path
+/// marked with +, - or ~
+fn diff_files(a_root: &Path, b_root: &Path) -> io::Result<Vec<(char, PathBuf)>> {
+    let a = snapshot(a_root)?;
+    let b = snapshot(b_root)?;
+    let paths: BTreeSet<&PathBuf> = a.keys().chain(b.keys()).collect();
+    let mut changes = Vec::new();
+
+    for path in paths {
+        let change = match (a.get(path), b.get(path)) {
+            (Some(_), None) => Some('-'),
+            (None, Some(_)) => Some('+'),
+            (Some(old), Some(new)) if old != new => Some('~'),
+            (Some(Entry::File { .. }), Some(Entry::File { .. })) => {
+                let relative = path.strip_prefix("/").unwrap();
+
+                if fs::read(a_root.join(relative))? != fs::read(b_root.join(relative))? {
+                    Some('~')
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(change) = change {
+            changes.push((change, path.clone()));
+        }
+    }
+
+    Ok(changes)
+}
+
+pub async fn diff_command(
+    args: &Diff,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image_a = FullImageWithTag::parse(&args.image_a, client.settings())?;
+    let image_b = FullImageWithTag::parse(&args.image_b, client.settings())?;
+
+    client
+        .login(&[
+            ImagePermission {
+                full_image: image_a.image.clone(),
+                permissions: ImagePermissions::Pull,
+            },
+            ImagePermission {
+                full_image: image_b.image.clone(),
+                permissions: ImagePermissions::Pull,
+            },
+        ])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let platform = args.platform.as_deref();
+
+    let (manifest_a, config_a) = downloader
+        .download_image(image_a.clone(), platform)
+        .await
+        .with_context(|| format!("fetching {}", args.image_a))?;
+    let (manifest_b, config_b) = downloader
+        .download_image(image_b.clone(), platform)
+        .await
+        .with_context(|| format!("fetching {}", args.image_b))?;
+
+    println!("--- {}", args.image_a);
+    println!("+++ {}", args.image_b);
+
+    print_config_diff(&config_a, &config_b);
+    print_layer_diff(&manifest_a, &manifest_b);
+
+    if !args.files {
+        return Ok(());
+    }
+
+    let root_a = tempfile::tempdir()?;
+    let root_b = tempfile::tempdir()?;
+
+    downloader
+        .extract_image(image_a.image, &manifest_a, &root_a.path().to_path_buf())
+        .await
+        .with_context(|| format!("extracting {}", args.image_a))?;
+    downloader
+        .extract_image(image_b.image, &manifest_b, &root_b.path().to_path_buf())
+        .await
+        .with_context(|| format!("extracting {}", args.image_b))?;
+
+    let changes = diff_files(root_a.path(), root_b.path())?;
+
+    if changes.is_empty() {
+        println!("Files: identical");
+    } else {
+        println!("Files:");
+
+        for (change, path) in changes {
+            println!("  {} {}", change, path.display());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_maps() {
+        let a = BTreeMap::from([
+            ("PATH".to_string(), "/bin".to_string()),
+            ("VERSION".to_string(), "1.0".to_string()),
+            ("DEBUG".to_string(), "1".to_string()),
+        ]);
+        let b = BTreeMap::from([
+            ("PATH".to_string(), "/bin".to_string()),
+            ("VERSION".to_string(), "1.1".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+
+        assert_eq!(
+            diff_maps(&a, &b),
+            vec![
+                Change::Removed("DEBUG".to_string(), "1".to_string()),
+                Change::Added("HOME".to_string(), "/root".to_string()),
+                Change::Changed("VERSION".to_string(), "1.0".to_string(), "1.1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_files() -> io::Result<()> {
+        let a = tempfile::tempdir()?;
+        let b = tempfile::tempdir()?;
+
+        for root in [a.path(), b.path()] {
+            fs::create_dir(root.join("etc"))?;
+            fs::write(root.join("etc/hostname"), "same")?;
+        }
+
+        fs::write(a.path().join("etc/os-release"), "1.0")?;
+        fs::write(b.path().join("etc/os-release"), "1.1")?;
+        fs::write(a.path().join("removed"), "")?;
+        fs::write(b.path().join("added"), "")?;
+
+        assert_eq!(
+            diff_files(a.path(), b.path())?,
+            vec![
+                ('+', PathBuf::from("/added")),
+                ('~', PathBuf::from("/etc/os-release")),
+                ('-', PathBuf::from("/removed")),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -1,5 +1,63 @@
 use sha256::digest;
+use std::{error::Error, sync::Arc};
 
-pub fn sha256_digest(data: &Vec<u8>) -> String {
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::{is_index_content_type, OciDownloader},
+    parser::FullImageWithTag,
+    platform::platform_name,
+    report::Context,
+    spec::index::ImageIndex,
+    Digest,
+};
+
+pub fn sha256_digest(data: &[u8]) -> String {
     format!("sha256:{}", digest(data))
 }
+
+pub async fn digest_command(
+    args: &Digest,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+
+    if !args.full {
+        let digest = downloader
+            .resolve_digest(image)
+            .await
+            .with_context(|| format!("resolving {}", args.image))?;
+        println!("{}", digest);
+        return Ok(());
+    }
+
+    let (content_type, data) = downloader
+        .download_manifest_raw(image)
+        .await
+        .with_context(|| format!("resolving {}", args.image))?;
+    println!("{}", sha256_digest(&data));
+
+    if is_index_content_type(&content_type) {
+        let index: ImageIndex = serde_json::from_slice(&data)?;
+
+        for manifest in &index.manifests {
+            let platform = manifest
+                .platform
+                .as_ref()
+                .map(platform_name)
+                .unwrap_or_else(|| "unknown".to_string());
+            println!("  {} {}", manifest.digest, platform);
+        }
+    }
+
+    Ok(())
+}
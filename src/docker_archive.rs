@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use tar::{Builder, Header};
+
+use crate::{
+    archive::detect_media_type,
+    digest::sha256_digest,
+    downloader::is_index_content_type,
+    layout::OciLayout,
+    local::ArchiveImage,
+    platform::{platform_requested, PlatformMatcher},
+    spec::{enums::MediaType, index::ImageIndex, manifest::ImageManifest},
+};
+
+/// Decompresses a layer, as old docker releases only load plain tarballs
+fn uncompressed_layer(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut reader: Box<dyn Read> = match detect_media_type(data) {
+        Ok(MediaType::OciImageLayerV1TarGzip) => Box::new(GzDecoder::new(data)),
+        Ok(MediaType::OciImageLayerV1TarZstd) => Box::new(zstd::stream::Decoder::new(data)?),
+        // Empty layers have no ustar magic to be detected by
+        _ => return Ok(data.to_vec()),
+    };
+
+    let mut tar = Vec::new();
+    reader.read_to_end(&mut tar)?;
+    Ok(tar)
+}
+
+fn append(builder: &mut Builder<File>, path: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+/// Writes an image of a layout as a `docker save` tarball, with manifest.json,
+/// repositories and a plain layer.tar per layer, so that it can be loaded
+/// with docker load on hosts without registry access. A tarball holds a
+/// single platform, the first one requested or else the host's
+pub fn write_docker_archive(
+    layout: &OciLayout,
+    name: &str,
+    repo_tags: &[String],
+    platforms: &[String],
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let read = |digest: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+        layout
+            .read_blob(digest)?
+            .ok_or_else(|| format!("Blob {} is missing from the layout", digest).into())
+    };
+
+    let descriptor = layout
+        .find(name)?
+        .ok_or_else(|| format!("No image named {} was written", name))?;
+    let mut manifest_data = read(&descriptor.digest)?;
+
+    if is_index_content_type(descriptor.media_type.to_string()) {
+        let index: ImageIndex = serde_json::from_slice(&manifest_data)?;
+        let platform_matcher = PlatformMatcher::new();
+        let manifest = match platforms {
+            [] => platform_matcher.find_manifest(&index.manifests),
+            platforms => index.manifests.iter().find(|manifest| {
+                manifest
+                    .platform
+                    .as_ref()
+                    .is_some_and(|platform| platform_requested(platform, &platforms[..1]))
+            }),
+        }
+        .ok_or("No platform of the image matches, pick one with --platform")?;
+
+        manifest_data = read(&manifest.digest)?;
+    }
+
+    let manifest: ImageManifest = serde_json::from_slice(&manifest_data)?;
+    let config_file = format!(
+        "{}.json",
+        manifest.config.digest.trim_start_matches("sha256:")
+    );
+
+    let partial = path.with_extension(format!("partial-{}", std::process::id()));
+    let mut builder = Builder::new(File::create(&partial)?);
+
+    append(&mut builder, &config_file, &read(&manifest.config.digest)?)?;
+
+    let mut layers = vec![];
+
+    for layer in &manifest.layers {
+        let tar = uncompressed_layer(&read(&layer.digest)?)?;
+
+        // Layers are named after their diff ID, which the config lists
+        let layer_file = format!(
+            "{}/layer.tar",
+            sha256_digest(&tar).trim_start_matches("sha256:")
+        );
+
+        if !layers.contains(&layer_file) {
+            append(&mut builder, &layer_file, &tar)?;
+        }
+
+        layers.push(layer_file);
+    }
+
+    // The legacy repositories file points every tag at the top layer
+    let mut repositories: HashMap<String, HashMap<String, String>> = HashMap::new();
+
+    if let Some(top_layer) = layers.last() {
+        let top_layer = top_layer.trim_end_matches("/layer.tar");
+
+        for reference in repo_tags {
+            if let Some((repository, tag)) = reference.rsplit_once(':') {
+                repositories
+                    .entry(repository.to_string())
+                    .or_default()
+                    .insert(tag.to_string(), top_layer.to_string());
+            }
+        }
+    }
+
+    let images = vec![ArchiveImage {
+        config: config_file,
+        repo_tags: Some(repo_tags.to_vec()),
+        layers,
+    }];
+
+    append(&mut builder, "manifest.json", &serde_json::to_vec(&images)?)?;
+    append(
+        &mut builder,
+        "repositories",
+        &serde_json::to_vec(&repositories)?,
+    )?;
+
+    builder.into_inner()?.sync_all()?;
+    fs::rename(&partial, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{local::LocalImage, spec::index::Manifest};
+    use std::io::Write;
+
+    #[test]
+    fn test_write_docker_archive() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let layout = OciLayout::new(&dir.path().join("layout"));
+
+        let mut tar = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "etc/hostname", &b"box"[..])?;
+        let tar = tar.into_inner()?;
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&tar)?;
+        let gzip = gzip.finish()?;
+
+        let config = br#"{"architecture":"amd64","os":"linux"}"#;
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":{}}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"{}","size":{}}}]}}"#,
+            sha256_digest(config),
+            config.len(),
+            sha256_digest(&gzip),
+            gzip.len()
+        );
+
+        for blob in [&config[..], &gzip, manifest.as_bytes()] {
+            layout.write_blob(&sha256_digest(blob), blob)?;
+        }
+
+        layout.tag(
+            "image",
+            Manifest {
+                media_type: MediaType::OciImageManifestV1Json,
+                size: manifest.len() as u64,
+                digest: sha256_digest(manifest.as_bytes()),
+                platform: None,
+                artifact_type: None,
+                annotations: None,
+            },
+        )?;
+
+        let path = dir.path().join("image.tar");
+        write_docker_archive(
+            &layout,
+            "image",
+            &["ghcr.io/example/app:1.0".to_string()],
+            &[],
+            &path,
+        )?;
+
+        // The tarball reads back with the layer decompressed
+        let local = LocalImage::open(&format!(
+            "docker-archive:{}:ghcr.io/example/app:1.0",
+            path.display()
+        ))?
+        .unwrap();
+        let (_, data) = local.manifest("ghcr.io/example/app:1.0")?;
+        let read: ImageManifest = serde_json::from_slice(&data)?;
+
+        assert_eq!(read.config.digest, sha256_digest(config));
+        assert_eq!(read.layers[0].digest, sha256_digest(&tar));
+        assert_eq!(local.blob(&read.layers[0].digest)?, tar);
+
+        Ok(())
+    }
+}
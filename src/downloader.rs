@@ -3,79 +3,547 @@ use indicatif::ProgressBar;
 use tokio::fs;
 
 use crate::{
-    archive::detect_media_type,
+    archive::{detect_media_type, UncompressedDigester},
+    cache::{self, FileLock},
     client::{ImagePermission, ImagePermissions, OciClient, OciClientError},
     compose::{
-        containerd::client::services::v1::{WriteAction, WriteContentRequest},
+        containerd::client::services::v1::{AbortRequest, WriteAction, WriteContentRequest},
         lease::LeasedClient,
+        pull::containerd_utils::distribution_source_label,
     },
+    digest::sha256_digest,
+    local::LocalImage,
     macros::{impl_error, impl_from_error},
-    parser::{FullImage, FullImageWithTag},
-    spec::{config::ImageConfig, enums::MediaType, index::ImageIndex, manifest::ImageManifest},
+    mmap::map_file,
+    parser::{registry_host, FullImage, FullImageWithTag},
+    platform::{platform_requested, PlatformMatcher},
+    profile::{self, Phase},
+    registry_config::RegistrySettings,
+    spec::{
+        config::ImageConfig,
+        enums::MediaType,
+        index::ImageIndex,
+        manifest::{Descriptor, ImageManifest},
+        referrers::{referrers_fallback_tag, Referrer, ReferrersIndex},
+        schema1::{self, Schema1Manifest},
+    },
     whiteout::extract_tar,
     with_client,
 };
 use bytes::Bytes;
-use futures::StreamExt;
-use std::{collections::HashMap, io::Read, path::PathBuf, sync::Arc};
+use futures::{Stream, StreamExt};
+use reqwest::{
+    header::{HeaderMap, CONTENT_LENGTH},
+    Client, RequestBuilder, Response, StatusCode, Url,
+};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    path::PathBuf,
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tonic::Request;
 
 impl_error!(OciDownloaderError);
 impl_from_error!(OciClientError, OciDownloaderError);
-impl_from_error!(reqwest::Error, OciDownloaderError);
 impl_from_error!(serde_json::Error, OciDownloaderError);
 impl_from_error!(std::io::Error, OciDownloaderError);
 impl_from_error!(tonic::Status, OciDownloaderError);
 impl_from_error!(crate::archive::DetectError, OciDownloaderError);
 
+/// Body errors are reported with their cause, such as a blob failing its
+/// digest check while it was read
+impl From<reqwest::Error> for OciDownloaderError {
+    fn from(err: reqwest::Error) -> Self {
+        let mut message = err.to_string();
+        let mut source = std::error::Error::source(&err);
+
+        while let Some(err) = source {
+            message = format!("{}: {}", message, err);
+            source = err.source();
+        }
+
+        OciDownloaderError(message)
+    }
+}
+
+/// Set by --skip-foreign-layers, which leaves out foreign layers instead of
+/// downloading them from their URLs
+static SKIP_FOREIGN_LAYERS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_skip_foreign_layers(skip: bool) {
+    SKIP_FOREIGN_LAYERS.store(skip, Ordering::Relaxed);
+}
+
+pub fn skip_foreign_layers() -> bool {
+    SKIP_FOREIGN_LAYERS.load(Ordering::Relaxed)
+}
+
+/// An image as a mirror serves it. Mirrors are given as a host, such as
+/// mirror.gcr.io, optionally with the namespace the images are kept in, such
+/// as harbor.example.com/dockerhub, and a scheme
+fn mirror_image(image: &FullImage, mirror: &str, settings: &RegistrySettings) -> FullImage {
+    let mirror = mirror.trim_end_matches('/');
+    let (scheme, location) = match mirror.split_once("://") {
+        Some((scheme, location)) => (Some(scheme), location),
+        None => (None, mirror),
+    };
+    let (host, namespace) = match location.split_once('/') {
+        Some((host, namespace)) => (host, Some(namespace)),
+        None => (location, None),
+    };
+
+    FullImage {
+        registry: match scheme {
+            Some(scheme) => format!("{}://{}", scheme, host),
+            None => settings.registry_url(host),
+        },
+        image_name: image.image_name.clone(),
+        library_name: match namespace {
+            Some(namespace) => format!("{}/{}", namespace, image.library_name),
+            None => image.library_name.clone(),
+        },
+        service: host.to_string(),
+    }
+}
+
+/// Whether a manifest content type is a multi-platform index
+pub fn is_index_content_type(content_type: &str) -> bool {
+    content_type == "application/vnd.oci.image.index.v1+json"
+        || content_type == "application/vnd.docker.distribution.manifest.list.v2+json"
+}
+
+/// The digest of data with the algorithm of another digest, if it is one
+/// that is supported
+fn digest_like(digest: &str, data: &[u8]) -> Option<String> {
+    match digest.split_once(':')?.0 {
+        "sha256" => Some(sha256_digest(data)),
+        "sha512" => Some(format!("sha512:{:x}", Sha512::digest(data))),
+        _ => None,
+    }
+}
+
+/// Passes a blob through while digesting it, and fails at its end if it is
+/// not the expected blob, so that large blobs are not buffered to check them
+fn verify_stream<D: Digest + Send + 'static>(
+    stream: impl Stream<Item = reqwest::Result<Bytes>> + Send + Unpin + 'static,
+    expected: String,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+    futures::stream::unfold(Some((stream, D::new(), expected)), |state| async move {
+        let (mut stream, mut hasher, expected) = state?;
+
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                hasher.update(&chunk);
+                Some((Ok(chunk), Some((stream, hasher, expected))))
+            }
+            Some(Err(e)) => Some((Err(std::io::Error::other(e)), None)),
+            None => {
+                let (algorithm, _) = expected.split_once(':')?;
+                let hex: String = hasher
+                    .finalize()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                let actual = format!("{}:{}", algorithm, hex);
+
+                (actual != expected).then(|| {
+                    let error = format!("Digest mismatch: expected {}, got {}", expected, actual);
+                    (Err(std::io::Error::other(error)), None)
+                })
+            }
+        }
+    })
+}
+
+/// Checks a manifest against the digest the registry reports for it in
+/// Docker-Content-Digest and the digest it was requested by, if any.
+/// Signed schema 1 manifests are digested without their signatures
+fn verify_manifest_digest(
+    headers: &HeaderMap,
+    requested: Option<&str>,
+    data: &[u8],
+) -> Result<(), OciDownloaderError> {
+    let payload = schema1::signed_payload(data);
+    let data = payload.as_deref().unwrap_or(data);
+    let reported = headers
+        .get("docker-content-digest")
+        .and_then(|digest| digest.to_str().ok());
+
+    for expected in [reported, requested].into_iter().flatten() {
+        if let Some(digest) = digest_like(expected, data) {
+            if digest != expected {
+                return Err(OciDownloaderError(format!(
+                    "Manifest digest mismatch: expected {}, got {}",
+                    expected, digest
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Extracts the URL of the next page from a Link header,
+/// such as `</v2/library/nginx/tags/list?last=1.27&n=100>; rel="next"`
+fn next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|param| param.trim().replace('"', "") == "rel=next");
+
+        is_next.then(|| {
+            url.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        })
+    })
+}
+
 pub struct OciDownloader {
     pub client: Arc<OciClient>,
     blob_dir: PathBuf,
     no_cache: bool,
+    local: Option<LocalImage>,
+    /// Registry hosts serving the same images as a registry, by its host
+    mirrors: HashMap<String, Vec<String>>,
+    /// Mirrors that failed, by registry URL, which are not asked again
+    failed_mirrors: Mutex<HashSet<String>>,
+    /// Configs made for schema 1 manifests, which no registry has, by digest
+    converted_configs: Mutex<HashMap<String, Bytes>>,
+    /// The URLs of the foreign layers of the manifests seen, by digest
+    foreign_urls: Mutex<HashMap<String, Vec<String>>>,
 }
 
 pub enum IndexResponse {
     ImageIndex(ImageIndex),
-    ImageManifest(ImageManifest),
+    ImageManifest(Box<ImageManifest>),
 }
 
 impl OciDownloader {
     pub fn new(client: Arc<OciClient>, no_cache: bool) -> Self {
-        let cache_dir = match dirs::cache_dir() {
-            Some(dir) => dir.join("ocitool"),
-            None => PathBuf::from("/tmp/ocitool"),
-        };
-        let blob_dir = cache_dir.join("blobs");
-
         OciDownloader {
+            mirrors: client.settings().mirrors.clone(),
             client,
-            blob_dir,
+            blob_dir: cache::blob_dir(),
             no_cache,
+            local: None,
+            failed_mirrors: Mutex::new(HashSet::new()),
+            converted_configs: Mutex::new(HashMap::new()),
+            foreign_urls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads manifests and blobs from an image layout or docker-archive
+    /// instead of a registry
+    pub fn with_local(mut self, local: Option<LocalImage>) -> Self {
+        self.local = local;
+        self
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.local.is_some()
+    }
+
+    /// The image on each mirror of its registry, in the order they are
+    /// configured, leaving out mirrors that failed
+    fn mirror_images(&self, image: &FullImage) -> Vec<FullImage> {
+        let host = image
+            .registry
+            .trim_start_matches("https://")
+            .trim_start_matches("http://");
+        let Some(mirrors) = self.mirrors.get(registry_host(host)) else {
+            return vec![];
+        };
+        let failed = self.failed_mirrors.lock().unwrap();
+
+        mirrors
+            .iter()
+            .map(|mirror| mirror_image(image, mirror, self.client.settings()))
+            .filter(|mirror| !failed.contains(&mirror.registry))
+            .collect()
+    }
+
+    /// Sends a request for a manifest or blob of an image, such as
+    /// manifests/latest, to the mirrors of its registry first. The registry
+    /// itself is asked if no mirror has it, or the mirrors fail
+    async fn send_pull<F>(
+        &self,
+        image: &FullImage,
+        path: &str,
+        request: F,
+    ) -> Result<Response, OciDownloaderError>
+    where
+        F: Fn(&Client, &str) -> RequestBuilder,
+    {
+        for mirror in self.mirror_images(image) {
+            let url = format!("{}/{}", mirror.get_image_url(), path);
+            let permission = ImagePermission {
+                full_image: mirror.clone(),
+                permissions: ImagePermissions::Pull,
+            };
+
+            // Mirrors are only logged in to once they are needed
+            let result = match self.client.has_token(&permission).await {
+                true => Ok(()),
+                false => self.client.login(slice::from_ref(&permission)).await,
+            };
+            let result = match result {
+                Ok(()) => {
+                    self.client
+                        .send_authorized(&url, &permission, |client| request(client, &url))
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            let reason = match result {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) if response.status() == StatusCode::NOT_FOUND => continue,
+                Ok(response) => response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+
+            eprintln!(
+                "Mirror {} failed ({}), falling back to {}",
+                mirror.service, reason, image.service
+            );
+            self.failed_mirrors
+                .lock()
+                .unwrap()
+                .insert(mirror.registry.clone());
+        }
+
+        let url = format!("{}/{}", image.get_image_url(), path);
+        let permission = ImagePermission {
+            full_image: image.clone(),
+            permissions: ImagePermissions::Pull,
+        };
+
+        Ok(self
+            .client
+            .send_authorized(&url, &permission, |client| request(client, &url))
+            .await?)
+    }
+
+    /// Remembers the URLs a foreign layer can be downloaded from
+    pub fn add_foreign_urls(&self, digest: &str, urls: &[String]) {
+        if !urls.is_empty() {
+            self.foreign_urls
+                .lock()
+                .unwrap()
+                .insert(digest.to_string(), urls.to_vec());
+        }
+    }
+
+    /// The layers of a manifest to extract, leaving out foreign layers with a
+    /// warning if --skip-foreign-layers is set
+    fn extractable_layers(&self, layers: &[Descriptor]) -> Vec<Descriptor> {
+        let mut extractable = vec![];
+
+        for layer in layers {
+            if layer.is_foreign() && skip_foreign_layers() {
+                eprintln!("Warning: skipping foreign layer {}", layer.digest);
+                continue;
+            }
+
+            if let Some(urls) = &layer.urls {
+                self.add_foreign_urls(&layer.digest, urls);
+            }
+
+            extractable.push(layer.clone());
+        }
+
+        extractable
+    }
+
+    /// Downloads a foreign layer from one of its URLs, which may point
+    /// anywhere, so the layer is checked against its digest
+    async fn download_foreign(
+        &self,
+        url: &str,
+        digest: &str,
+    ) -> Result<Response, OciDownloaderError> {
+        let response = self.client.send(url, |client| client.get(url)).await?;
+
+        if !response.status().is_success() {
+            return Err(OciDownloaderError(response.status().to_string()));
+        }
+
+        let mut builder = http::Response::builder();
+        if let Some(length) = response.headers().get(CONTENT_LENGTH) {
+            builder = builder.header(CONTENT_LENGTH, length);
+        }
+
+        let expected = digest.to_string();
+        let stream = response.bytes_stream();
+        let body = match digest.split_once(':').map(|(algorithm, _)| algorithm) {
+            Some("sha256") => reqwest::Body::wrap_stream(verify_stream::<Sha256>(stream, expected)),
+            Some("sha512") => reqwest::Body::wrap_stream(verify_stream::<Sha512>(stream, expected)),
+            _ => reqwest::Body::wrap_stream(stream),
+        };
+
+        let response = builder
+            .body(body)
+            .map_err(|e| OciDownloaderError(e.to_string()))?;
+        Ok(Response::from(response))
+    }
+
+    /// Sends a request for a blob. Foreign layers are downloaded from their
+    /// URLs first, as registries do not have to serve them
+    async fn send_blob(
+        &self,
+        image: &FullImage,
+        digest: &str,
+    ) -> Result<Response, OciDownloaderError> {
+        let urls = self
+            .foreign_urls
+            .lock()
+            .unwrap()
+            .get(digest)
+            .cloned()
+            .unwrap_or_default();
+
+        for url in &urls {
+            match self.download_foreign(url, digest).await {
+                Ok(response) => return Ok(response),
+                Err(e) => eprintln!(
+                    "Downloading foreign layer {} from {} failed ({})",
+                    digest, url, e
+                ),
+            }
+        }
+
+        let response = self
+            .send_pull(image, &format!("blobs/{}", digest), |client, url| {
+                client.get(url)
+            })
+            .await?;
+
+        if !urls.is_empty() && !response.status().is_success() {
+            return Err(OciDownloaderError(format!(
+                "Foreign layer {} is neither at its URLs nor on the registry ({}), pass --skip-foreign-layers to leave it out",
+                digest,
+                response.status()
+            )));
+        }
+
+        Ok(response)
+    }
+
+    /// Converts a schema 1 manifest to a schema 2 manifest and config.
+    /// Schema 1 manifests do not list the diff IDs of their layers, so every
+    /// layer is downloaded to compute them
+    async fn convert_schema1(
+        &self,
+        image: &FullImage,
+        data: &[u8],
+    ) -> Result<(ImageManifest, Bytes), OciDownloaderError> {
+        let schema1: Schema1Manifest = serde_json::from_slice(data)?;
+        let mut layers = vec![];
+        let mut diff_ids = vec![];
+
+        for digest in schema1.layers().map_err(OciDownloaderError)? {
+            let blob = self.download_layer(image.clone(), &digest).await?;
+            let mut digester = UncompressedDigester::default();
+            digester.update(&blob)?;
+            diff_ids.push(digester.finish()?);
+
+            layers.push(Descriptor {
+                media_type: MediaType::DockerImageRootfsDiffTarGzip,
+                digest,
+                size: blob.len() as u64,
+                data: None,
+                urls: None,
+                annotations: None,
+            });
+        }
+
+        let config = Bytes::from(schema1.config(&diff_ids).map_err(OciDownloaderError)?);
+        let config_digest = sha256_digest(&config);
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: MediaType::DockerManifestV2Json,
+            artifact_type: None,
+            config: Descriptor {
+                media_type: MediaType::DockerConfigV1Json,
+                digest: config_digest.clone(),
+                size: config.len() as u64,
+                data: None,
+                urls: None,
+                annotations: None,
+            },
+            layers,
+            subject: None,
+            annotations: None,
+        };
+
+        self.converted_configs
+            .lock()
+            .unwrap()
+            .insert(config_digest, config);
+
+        let json = manifest.to_json().into();
+        Ok((manifest, json))
+    }
+
+    /// Parses a manifest, converting it first if it is a schema 1 manifest
+    async fn parse_manifest(
+        &self,
+        image: &FullImage,
+        content_type: Option<&str>,
+        data: Bytes,
+    ) -> Result<(ImageManifest, Bytes), OciDownloaderError> {
+        let (manifest, data): (ImageManifest, Bytes) =
+            match schema1::is_schema1(content_type, &data) {
+                true => self.convert_schema1(image, &data).await?,
+                false => (serde_json::from_slice(&data)?, data),
+            };
+
+        for layer in &manifest.layers {
+            if let Some(urls) = &layer.urls {
+                self.add_foreign_urls(&layer.digest, urls);
+            }
         }
+
+        Ok((manifest, data))
     }
 
     pub async fn download_index(
         &self,
         image: FullImageWithTag,
     ) -> Result<(IndexResponse, String), OciDownloaderError> {
-        let url = format!("{}/manifests/{}", image.image.get_image_url(), image.tag);
+        if let Some(local) = &self.local {
+            let (content_type, data) = local.manifest(&image.tag)?;
+            let json = String::from_utf8_lossy(&data).to_string();
+
+            let image_index = if is_index_content_type(&content_type) {
+                IndexResponse::ImageIndex(serde_json::from_str(&json)?)
+            } else {
+                IndexResponse::ImageManifest(serde_json::from_str(&json)?)
+            };
+            return Ok((image_index, json));
+        }
+
         // println!("Downloading {}:{}...", image.image.image_name, image.tag);
 
+        let started = Instant::now();
         let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image.image,
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .header("Accept", "application/vnd.oci.image.index.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json")
-            .send()
+            .send_pull(&image.image, &format!("manifests/{}", image.tag), |client, url| {
+                client
+                    .get(url)
+                    .header("Accept", "application/vnd.oci.image.index.v1+json,application/vnd.oci.image.manifest.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.docker.distribution.manifest.v1+prettyjws")
+            })
             .await?;
+        profile::record(Phase::Resolve, started.elapsed(), 0);
 
         let status = response.status();
 
@@ -91,15 +559,24 @@ impl OciDownloader {
             .get(reqwest::header::CONTENT_TYPE)
             .and_then(|val| val.to_str().ok());
 
-        let json = response.text().await?;
+        let data = response.bytes().await?;
+        verify_manifest_digest(&headers, image.digest(), &data)?;
 
-        let image_index = match content_type {
-            Some("application/vnd.docker.distribution.manifest.v2+json") => {
-                IndexResponse::ImageManifest(serde_json::from_str::<ImageManifest>(&json)?)
+        // Artifacts, such as those pushed by ORAS, are plain OCI manifests
+        match content_type {
+            Some(content_type) if !is_index_content_type(content_type) => {
+                let (manifest, data) = self
+                    .parse_manifest(&image.image, Some(content_type), data)
+                    .await?;
+                let json = String::from_utf8_lossy(&data).to_string();
+                Ok((IndexResponse::ImageManifest(Box::new(manifest)), json))
             }
-            _ => IndexResponse::ImageIndex(serde_json::from_str::<ImageIndex>(&json)?),
-        };
-        Ok((image_index, json))
+            _ => {
+                let json = String::from_utf8_lossy(&data).to_string();
+                let index = serde_json::from_str::<ImageIndex>(&json)?;
+                Ok((IndexResponse::ImageIndex(index), json))
+            }
+        }
     }
 
     pub async fn load_blob_cache(&self, digest: &str) -> Option<Vec<u8>> {
@@ -112,7 +589,20 @@ impl OciDownloader {
         }
 
         let blob_path = self.blob_dir.join(digest.replace(":", "-"));
-        fs::read(blob_path).await.ok()
+        let blob = fs::read(&blob_path).await.ok()?;
+        cache::touch(&blob_path);
+        Some(blob)
+    }
+
+    pub fn map_blob_cache(&self, digest: &str) -> Option<Bytes> {
+        if self.no_cache {
+            return None;
+        }
+
+        let blob_path = self.blob_dir.join(digest.replace(":", "-"));
+        let blob = map_file(&blob_path).ok()?;
+        cache::touch(&blob_path);
+        Some(blob)
     }
 
     pub fn write_blob_cache(&self, digest: &str, blob: &[u8]) -> Result<(), OciDownloaderError> {
@@ -121,40 +611,245 @@ impl OciDownloader {
         }
 
         let blob_path = self.blob_dir.join(digest.replace(":", "-"));
-        std::fs::write(blob_path, blob)?;
+        cache::write_atomic(&blob_path, blob)?;
+        cache::enforce_max_size(&self.blob_dir, &blob_path)?;
         Ok(())
     }
 
+    /// Locks a digest in the cache until the lock is dropped, so that jobs
+    /// sharing the cache wait for each other to download a blob instead of
+    /// writing it at the same time
+    async fn lock_blob(&self, digest: &str) -> Result<Option<FileLock>, OciDownloaderError> {
+        if self.no_cache {
+            return Ok(None);
+        }
+
+        let lock_path = self
+            .blob_dir
+            .join("locks")
+            .join(format!("{}.lock", digest.replace(":", "-")));
+        let lock = tokio::task::spawn_blocking(move || FileLock::acquire(&lock_path))
+            .await
+            .map_err(|e| OciDownloaderError(format!("Failed to lock {}: {}", digest, e)))??;
+
+        Ok(Some(lock))
+    }
+
+    /// Lists the tags of a repository, following the Link headers of
+    /// registries that paginate the list
+    pub async fn list_tags(&self, image: FullImage) -> Result<Vec<String>, OciDownloaderError> {
+        #[derive(serde::Deserialize)]
+        struct TagList {
+            #[serde(default)]
+            tags: Option<Vec<String>>,
+        }
+
+        let registry = image.registry.clone();
+        let mut url = Some(format!("{}/tags/list", image.get_image_url()));
+        let mut tags = vec![];
+
+        let permission = ImagePermission {
+            full_image: image,
+            permissions: ImagePermissions::Pull,
+        };
+
+        while let Some(page_url) = url.take() {
+            let response = self
+                .client
+                .send_authorized(&page_url, &permission, |client| client.get(&page_url))
+                .await?;
+
+            let status = response.status();
+
+            if !status.is_success() {
+                return Err(OciDownloaderError(format!(
+                    "Failed to list tags: {}",
+                    status
+                )));
+            }
+
+            url = response
+                .headers()
+                .get(reqwest::header::LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_link)
+                .map(|next| {
+                    if next.starts_with('/') {
+                        format!("{}{}", registry, next)
+                    } else {
+                        next
+                    }
+                });
+
+            let tag_list: TagList = serde_json::from_slice(&response.bytes().await?)?;
+            tags.extend(tag_list.tags.unwrap_or_default());
+        }
+
+        Ok(tags)
+    }
+
+    /// Lists the repositories of a registry through its catalog, following
+    /// the Link headers of registries that paginate it. The image only names
+    /// the registry, and has to be logged in with the catalog permission
+    pub async fn list_repositories(
+        &self,
+        registry: FullImage,
+    ) -> Result<Vec<String>, OciDownloaderError> {
+        #[derive(serde::Deserialize)]
+        struct Catalog {
+            #[serde(default)]
+            repositories: Option<Vec<String>>,
+        }
+
+        let base = registry.registry.clone();
+        let mut url = Some(format!("{}/v2/_catalog?n=1000", base));
+        let mut repositories = vec![];
+
+        let permission = ImagePermission {
+            full_image: registry,
+            permissions: ImagePermissions::Catalog,
+        };
+
+        while let Some(page_url) = url.take() {
+            let response = self
+                .client
+                .send_authorized(&page_url, &permission, |client| client.get(&page_url))
+                .await?;
+
+            let status = response.status();
+
+            if !status.is_success() {
+                return Err(OciDownloaderError(format!(
+                    "Failed to list repositories: {}",
+                    status
+                )));
+            }
+
+            url = response
+                .headers()
+                .get(reqwest::header::LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_link)
+                .map(|next| {
+                    if next.starts_with('/') {
+                        format!("{}{}", base, next)
+                    } else {
+                        next
+                    }
+                });
+
+            let catalog: Catalog = serde_json::from_slice(&response.bytes().await?)?;
+            repositories.extend(catalog.repositories.unwrap_or_default());
+        }
+
+        Ok(repositories)
+    }
+
+    /// Lists the manifests whose subject is the given digest, optionally only
+    /// those of one artifact type. Registries without the referrers API are
+    /// asked for the index stored under the `sha256-<hex>` tag instead
+    pub async fn list_referrers(
+        &self,
+        image: FullImage,
+        digest: &str,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<Referrer>, OciDownloaderError> {
+        let mut url = Url::parse(&format!("{}/referrers/{}", image.get_image_url(), digest))
+            .map_err(|e| OciDownloaderError(e.to_string()))?;
+
+        // Artifact types such as application/vnd.cncf+json hold characters
+        // that have to be encoded
+        if let Some(artifact_type) = artifact_type {
+            url.query_pairs_mut()
+                .append_pair("artifactType", artifact_type);
+        }
+
+        let url = url.to_string();
+
+        let permission = ImagePermission {
+            full_image: image.clone(),
+            permissions: ImagePermissions::Pull,
+        };
+        let accept = "application/vnd.oci.image.index.v1+json";
+
+        let mut response = self
+            .client
+            .send_authorized(&url, &permission, |client| {
+                client.get(&url).header("Accept", accept)
+            })
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            let fallback_url = format!(
+                "{}/manifests/{}",
+                image.get_image_url(),
+                referrers_fallback_tag(digest)
+            );
+
+            response = self
+                .client
+                .send_authorized(&fallback_url, &permission, |client| {
+                    client.get(&fallback_url).header("Accept", accept)
+                })
+                .await?;
+
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(vec![]);
+            }
+        }
+
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(OciDownloaderError(format!(
+                "Failed to list referrers: {}",
+                status
+            )));
+        }
+
+        let index: ReferrersIndex = serde_json::from_slice(&response.bytes().await?)?;
+
+        // Registries are free to ignore the filter, and the fallback index is never filtered
+        Ok(index
+            .manifests
+            .into_iter()
+            .filter(|referrer| {
+                artifact_type.is_none_or(|artifact_type| {
+                    referrer.artifact_type.as_deref() == Some(artifact_type)
+                })
+            })
+            .collect())
+    }
+
     pub async fn download_manifest(
         &self,
         image: FullImage,
         digest: &str,
     ) -> Result<(ImageManifest, Bytes), OciDownloaderError> {
+        if let Some(local) = &self.local {
+            let blob = local.blob(digest)?;
+            return Ok((serde_json::from_slice(&blob)?, blob.into()));
+        }
+
+        let _lock = self.lock_blob(digest).await?;
+
         if let Some(blob) = self.load_blob_cache(digest).await {
-            if let Ok(manifest) = serde_json::from_slice(&blob) {
-                return Ok((manifest, blob.into()));
+            if let Ok(manifest) = self.parse_manifest(&image, None, blob.into()).await {
+                return Ok(manifest);
             }
         }
 
-        let url = format!("{}/manifests/{}", image.get_image_url(), digest);
-
         // println!("Downloading manifest {}:{}...", image.image_name, digest);
 
+        let started = Instant::now();
         let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image,
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .header("Accept", "application/vnd.oci.image.manifest.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json")
-            .send()
+            .send_pull(&image, &format!("manifests/{}", digest), |client, url| {
+                client
+                    .get(url)
+                    .header("Accept", "application/vnd.oci.image.manifest.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.docker.distribution.manifest.v1+prettyjws")
+            })
             .await?;
+        profile::record(Phase::Resolve, started.elapsed(), 0);
 
         let status = response.status();
 
@@ -165,10 +860,105 @@ impl OciDownloader {
             )));
         }
 
+        let headers = response.headers().clone();
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok());
+
         let json = response.bytes().await?;
+        verify_manifest_digest(&headers, Some(digest), &json)?;
         self.write_blob_cache(digest, &json)?;
-        let result = serde_json::from_slice(&json)?;
-        Ok((result, json))
+        self.parse_manifest(&image, content_type, json).await
+    }
+
+    /// Resolves a tag to the digest of its index or manifest. The registry is
+    /// only asked for the headers, unless it does not report the digest
+    pub async fn resolve_digest(
+        &self,
+        image: FullImageWithTag,
+    ) -> Result<String, OciDownloaderError> {
+        if let Some(local) = &self.local {
+            return Ok(sha256_digest(&local.manifest(&image.tag)?.1));
+        }
+
+        if let Some(digest) = image.digest() {
+            return Ok(digest.to_string());
+        }
+
+        let started = Instant::now();
+        let response = self
+            .send_pull(&image.image, &format!("manifests/{}", image.tag), |client, url| {
+                client
+                    .head(url)
+                    .header("Accept", "application/vnd.oci.image.index.v1+json,application/vnd.oci.image.manifest.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.docker.distribution.manifest.v1+prettyjws")
+            })
+            .await?;
+        profile::record(Phase::Resolve, started.elapsed(), 0);
+
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(OciDownloaderError(format!(
+                "Failed to resolve digest: {}",
+                status
+            )));
+        }
+
+        let digest = response
+            .headers()
+            .get("docker-content-digest")
+            .and_then(|digest| digest.to_str().ok())
+            .map(|digest| digest.to_string());
+
+        match digest {
+            Some(digest) => Ok(digest),
+            None => {
+                let (_, data) = self.download_manifest_raw(image).await?;
+                Ok(sha256_digest(&data))
+            }
+        }
+    }
+
+    /// Downloads an index or manifest by tag or digest without parsing it,
+    /// returning its content type alongside the exact bytes served
+    pub async fn download_manifest_raw(
+        &self,
+        image: FullImageWithTag,
+    ) -> Result<(String, Bytes), OciDownloaderError> {
+        if let Some(local) = &self.local {
+            let (content_type, data) = local.manifest(&image.tag)?;
+            return Ok((content_type, data.into()));
+        }
+
+        let started = Instant::now();
+        let response = self
+            .send_pull(&image.image, &format!("manifests/{}", image.tag), |client, url| {
+                client
+                    .get(url)
+                    .header("Accept", "application/vnd.oci.image.index.v1+json,application/vnd.oci.image.manifest.v1+json,application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.docker.distribution.manifest.v1+prettyjws")
+            })
+            .await?;
+        profile::record(Phase::Resolve, started.elapsed(), 0);
+
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(OciDownloaderError(format!(
+                "Failed to download manifest: {}",
+                status
+            )));
+        }
+
+        let headers = response.headers().clone();
+        let content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("application/vnd.oci.image.manifest.v1+json")
+            .to_string();
+
+        let data = response.bytes().await?;
+        verify_manifest_digest(&headers, image.digest(), &data)?;
+        Ok((content_type, data))
     }
 
     pub async fn download_config(
@@ -176,29 +966,30 @@ impl OciDownloader {
         image: FullImage,
         digest: &str,
     ) -> Result<(ImageConfig, Bytes), OciDownloaderError> {
+        if let Some(local) = &self.local {
+            let blob = local.blob(digest)?;
+            return Ok((serde_json::from_slice(&blob)?, blob.into()));
+        }
+
+        if let Some(blob) = self.converted_configs.lock().unwrap().get(digest) {
+            return Ok((serde_json::from_slice(blob)?, blob.clone()));
+        }
+
+        let _lock = self.lock_blob(digest).await?;
+
         if let Some(blob) = self.load_blob_cache(digest).await {
             if let Ok(config) = serde_json::from_slice(&blob) {
                 return Ok((config, blob.into()));
             }
         }
 
-        let url = format!("{}/blobs/{}", image.get_image_url(), digest);
-
         // println!("Downloading config {}:{}...", image.image_name, digest);
 
+        let started = Instant::now();
         let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image,
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .send()
+            .send_pull(&image, &format!("blobs/{}", digest), |client, url| {
+                client.get(url)
+            })
             .await?;
 
         let status = response.status();
@@ -211,11 +1002,137 @@ impl OciDownloader {
         }
 
         let json = response.bytes().await?;
+        profile::record(Phase::Download, started.elapsed(), json.len() as u64);
         self.write_blob_cache(digest, &json)?;
         let result = serde_json::from_slice(&json)?;
         Ok((result, json))
     }
 
+    /// Downloads the manifest and config of an image. From an index, the
+    /// manifest of the requested platform, or else of this host, is chosen
+    pub async fn download_image(
+        &self,
+        image: FullImageWithTag,
+        platform: Option<&str>,
+    ) -> Result<(ImageManifest, ImageConfig), OciDownloaderError> {
+        let manifest = match self.download_index(image.clone()).await?.0 {
+            IndexResponse::ImageIndex(index) => {
+                let platform_matcher = PlatformMatcher::new();
+                let manifest = match platform {
+                    Some(platform) => index.manifests.iter().find(|manifest| {
+                        manifest.platform.as_ref().is_some_and(|manifest_platform| {
+                            platform_requested(manifest_platform, &[platform.to_string()])
+                        })
+                    }),
+                    None => platform_matcher.find_manifest(&index.manifests),
+                }
+                .ok_or(OciDownloaderError("No matching platform found".to_string()))?;
+
+                self.download_manifest(image.image.clone(), &manifest.digest)
+                    .await?
+                    .0
+            }
+            IndexResponse::ImageManifest(manifest) => *manifest,
+        };
+
+        let config = self
+            .download_config(image.image, &manifest.config.digest)
+            .await?
+            .0;
+
+        Ok((manifest, config))
+    }
+
+    /// Extracts every layer of an image on top of each other into a directory
+    pub async fn extract_image(
+        &self,
+        image: FullImage,
+        manifest: &ImageManifest,
+        dest_dir: &PathBuf,
+    ) -> Result<(), OciDownloaderError> {
+        for layer in &self.extractable_layers(&manifest.layers) {
+            // Layers are extracted by their content, so a vendor media type
+            // only matters if the content turns out not to be a tarball
+            if let MediaType::Other(media_type) = &layer.media_type {
+                eprintln!(
+                    "Warning: layer {} has the unknown media type {}",
+                    layer.digest, media_type
+                );
+            }
+
+            self.extract_layer(image.clone(), &layer.digest, &layer.media_type, dest_dir)
+                .await
+                .map_err(|e| {
+                    OciDownloaderError(format!("Failed to extract layer {}: {}", layer.digest, e))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Extracts an image like extract_image, downloading up to `jobs` layers
+    /// ahead of the one being extracted. Layers are still extracted in
+    /// order, as upper layers may remove the files of lower ones
+    pub async fn extract_image_concurrently(
+        self: &Arc<Self>,
+        image: FullImage,
+        manifest: &ImageManifest,
+        dest_dir: &PathBuf,
+        jobs: usize,
+        progress: &ProgressBar,
+    ) -> Result<(), OciDownloaderError> {
+        let layers = self.extractable_layers(&manifest.layers);
+        let count = layers.len();
+        progress.set_length(layers.iter().map(|layer| layer.size).sum());
+        progress.set_message(format!("Downloading {} layers", count));
+
+        // Downloads run as tasks of their own, so that they go on while a
+        // layer is being extracted
+        let mut downloads = futures::stream::iter(layers)
+            .map(|layer| {
+                let downloader = self.clone();
+                let image = image.clone();
+                let progress = progress.clone();
+
+                tokio::spawn(async move {
+                    let bytes = downloader.download_layer(image, &layer.digest).await;
+                    progress.inc(layer.size);
+                    (layer, bytes)
+                })
+            })
+            .buffered(jobs.max(1));
+
+        let mut extracted = 0;
+
+        while let Some(download) = downloads.next().await {
+            let (layer, bytes) = download.map_err(|e| OciDownloaderError(e.to_string()))?;
+            let bytes = bytes.map_err(|e| {
+                OciDownloaderError(format!("Failed to download layer {}: {}", layer.digest, e))
+            })?;
+
+            if let MediaType::Other(media_type) = &layer.media_type {
+                progress.suspend(|| {
+                    eprintln!(
+                        "Warning: layer {} has the unknown media type {}",
+                        layer.digest, media_type
+                    )
+                });
+            }
+
+            extracted += 1;
+            progress.set_message(format!("Extracting layer {}/{}", extracted, count));
+
+            self.extract_layer_bytes_to(&bytes[..], detect_media_type(&bytes[..])?, dest_dir)
+                .await
+                .map_err(|e| {
+                    OciDownloaderError(format!("Failed to extract layer {}: {}", layer.digest, e))
+                })?;
+        }
+
+        progress.finish_and_clear();
+        Ok(())
+    }
+
     pub async fn extract_layer_bytes_to<T: Read>(
         &self,
         bytes: T,
@@ -223,16 +1140,16 @@ impl OciDownloader {
         dest_dir: &PathBuf,
     ) -> Result<(), OciDownloaderError> {
         match media_type {
-            MediaType::OciImageLayerV1Tar => {
+            MediaType::OciImageLayerV1Tar | MediaType::DockerImageRootfsDiffTar => {
                 extract_tar(bytes, dest_dir).await?;
                 Ok(())
             }
-            MediaType::OciImageLayerV1TarGzip => {
+            MediaType::OciImageLayerV1TarGzip | MediaType::DockerImageRootfsDiffTarGzip => {
                 let decoder = GzDecoder::new(bytes);
                 extract_tar(decoder, dest_dir).await?;
                 Ok(())
             }
-            MediaType::OciImageLayerV1TarZstd => {
+            MediaType::OciImageLayerV1TarZstd | MediaType::DockerImageRootfsDiffTarZstd => {
                 let decoder = zstd::stream::Decoder::new(bytes)?;
                 extract_tar(decoder, dest_dir).await?;
                 Ok(())
@@ -253,30 +1170,28 @@ impl OciDownloader {
         _media_type: &MediaType,
         dest_dir: &PathBuf,
     ) -> Result<(), OciDownloaderError> {
+        if let Some(local) = &self.local {
+            let blob = local.blob(digest)?;
+            self.extract_layer_bytes_to(&blob[..], detect_media_type(&blob[..])?, dest_dir)
+                .await?;
+
+            return Ok(());
+        }
+
+        let lock = self.lock_blob(digest).await?;
+
         if let Some(blob) = self.load_blob_cache(digest).await {
+            drop(lock);
             self.extract_layer_bytes_to(&blob[..], detect_media_type(&blob[..])?, &dest_dir)
                 .await?;
 
             return Ok(());
         }
 
-        let url = format!("{}/blobs/{}", image.get_image_url(), digest);
         // println!("Downloading layer {}:{}...", image.image_name, digest);
 
-        let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image,
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .send()
-            .await?;
+        let started = Instant::now();
+        let response = self.send_blob(&image, digest).await?;
 
         let status = response.status();
 
@@ -288,7 +1203,9 @@ impl OciDownloader {
         }
 
         let bytes = response.bytes().await?;
+        profile::record(Phase::Download, started.elapsed(), bytes.len() as u64);
         self.write_blob_cache(digest, &bytes)?;
+        drop(lock);
         self.extract_layer_bytes_to(bytes.as_ref(), detect_media_type(&bytes[..])?, &dest_dir)
             .await?;
 
@@ -299,28 +1216,22 @@ impl OciDownloader {
         &self,
         image: FullImage,
         digest: &str,
-    ) -> Result<Vec<u8>, OciDownloaderError> {
-        if let Some(blob) = self.load_blob_cache(digest).await {
+    ) -> Result<Bytes, OciDownloaderError> {
+        if let Some(local) = &self.local {
+            return Ok(local.blob(digest)?.into());
+        }
+
+        let _lock = self.lock_blob(digest).await?;
+
+        // Map cached layers instead of reading them, they can be several GB large
+        if let Some(blob) = self.map_blob_cache(digest) {
             return Ok(blob);
         }
 
-        let url = format!("{}/blobs/{}", image.get_image_url(), digest);
         // println!("Downloading layer {}:{}...", image.image_name, digest);
 
-        let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image,
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .send()
-            .await?;
+        let started = Instant::now();
+        let response = self.send_blob(&image, digest).await?;
 
         let status = response.status();
 
@@ -332,8 +1243,9 @@ impl OciDownloader {
         }
 
         let bytes = response.bytes().await?;
+        profile::record(Phase::Download, started.elapsed(), bytes.len() as u64);
         self.write_blob_cache(digest, &bytes)?;
-        Ok(bytes.to_vec())
+        Ok(bytes)
     }
 
     pub async fn download_layer_to_containerd(
@@ -346,27 +1258,7 @@ impl OciDownloader {
         spinner: Option<&ProgressBar>,
         downloaded_bytes: Arc<tokio::sync::Mutex<u64>>,
     ) -> Result<(), OciDownloaderError> {
-        let tick = || {
-            if let Some(spinner) = spinner {
-                spinner.tick();
-            }
-        };
-        let url = format!("{}/blobs/{}", image.get_image_url(), digest);
-
-        let response = self
-            .client
-            .client
-            .get(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image.clone(),
-                        permissions: ImagePermissions::Pull,
-                    })
-                    .await?,
-            )
-            .send()
-            .await?;
+        let response = self.send_blob(&image, digest).await?;
 
         let status = response.status();
 
@@ -384,111 +1276,170 @@ impl OciDownloader {
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(0);
 
-        let mut labels = HashMap::new();
-        labels.insert(
-            "containerd.io/distribution.source.docker.io".to_string(),
-            image.library_name.clone(),
-        );
-        labels.insert(
-            "containerd.io/uncompressed".to_string(),
-            uncompressed_digest.to_string(),
-        );
+        write_layer_to_containerd(
+            container_client,
+            response.bytes_stream(),
+            &image,
+            digest,
+            uncompressed_digest,
+            content_length,
+            &LayerProgress {
+                progress_bar,
+                spinner,
+                downloaded_bytes,
+            },
+        )
+        .await
+    }
+}
 
-        // Stream the response in 16MB chunks
-        let mut stream = response.bytes_stream();
-        const CHUNK_SIZE: usize = 16 * 1000 * 1000;
-        let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+/// Where the progress of a layer written to containerd is reported
+pub struct LayerProgress<'a> {
+    pub progress_bar: ProgressBar,
+    pub spinner: Option<&'a ProgressBar>,
+    pub downloaded_bytes: Arc<tokio::sync::Mutex<u64>>,
+}
 
-        let mut offset = 0;
+impl LayerProgress<'_> {
+    async fn advance(&self, length: usize) {
+        let mut downloaded_bytes = self.downloaded_bytes.lock().await;
+        *downloaded_bytes += length as u64;
+        self.progress_bar.set_position(*downloaded_bytes);
 
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk?;
-            buffer.extend_from_slice(&chunk);
+        // Spinners only show a message, so their position counts the bytes
+        // of their image for the dashboard
+        if let Some(spinner) = self.spinner {
+            spinner.inc(length as u64);
+        }
+    }
+}
+
+/// Writes a single chunk of a layer into an open containerd ingest
+async fn write_chunk(
+    container_client: &Arc<LeasedClient>,
+    upload_request: WriteContentRequest,
+) -> Result<(), OciDownloaderError> {
+    let request_stream = with_client!(
+        futures_util::stream::iter(vec![upload_request]),
+        container_client
+    );
+
+    let content = container_client
+        .client()
+        .content()
+        .write(request_stream)
+        .await?;
+    let mut stream = content.into_inner();
+
+    // Wait for the write to complete
+    while stream.message().await?.is_some() {}
+
+    Ok(())
+}
 
-            while buffer.len() >= CHUNK_SIZE {
-                let chunk_to_write = buffer.drain(..CHUNK_SIZE).collect::<Vec<u8>>();
-                let chunk_length = chunk_to_write.len();
+/// Streams a layer into the containerd content store in 16MB chunks. The
+/// layer is decompressed along the way, whether it is gzip or zstd, and only
+/// committed if it matches the uncompressed digest from the image config
+pub async fn write_layer_to_containerd<S, E>(
+    container_client: Arc<LeasedClient>,
+    mut stream: S,
+    image: &FullImage,
+    digest: &str,
+    uncompressed_digest: &str,
+    content_length: u64,
+    progress: &LayerProgress<'_>,
+) -> Result<(), OciDownloaderError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    OciDownloaderError: From<E>,
+{
+    let mut labels = HashMap::new();
+    labels.insert(distribution_source_label(image), image.library_name.clone());
+    labels.insert(
+        "containerd.io/uncompressed".to_string(),
+        uncompressed_digest.to_string(),
+    );
+
+    const CHUNK_SIZE: usize = 16 * 1000 * 1000;
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+    let mut digester = UncompressedDigester::default();
+    let mut offset = 0;
+
+    // The download and the writes interleave, so each is timed on its own
+    let mut download_time = Duration::ZERO;
+    let mut containerd_time = Duration::ZERO;
+
+    loop {
+        let started = Instant::now();
+        let chunk = stream.next().await.transpose()?;
+        download_time += started.elapsed();
+        let finished = chunk.is_none();
+
+        if let Some(chunk) = chunk {
+            digester.update(&chunk)?;
+            buffer.extend_from_slice(&chunk);
+        }
 
-                let upload_request = WriteContentRequest {
+        // Write full chunks as they arrive, and whatever is left at the end
+        while buffer.len() >= CHUNK_SIZE || (finished && !buffer.is_empty()) {
+            let chunk_to_write = buffer
+                .drain(..CHUNK_SIZE.min(buffer.len()))
+                .collect::<Vec<u8>>();
+            let chunk_length = chunk_to_write.len();
+            let started = Instant::now();
+
+            write_chunk(
+                &container_client,
+                WriteContentRequest {
                     action: WriteAction::Write as i32,
                     r#ref: digest.to_string(),
                     total: content_length as i64,
                     expected: "".to_string(),
                     offset,
-                    data: chunk_to_write.into(),
+                    data: chunk_to_write,
                     labels: HashMap::new(),
-                };
-
-                let request_stream = with_client!(
-                    futures_util::stream::iter(vec![upload_request]),
-                    container_client
-                );
+                },
+            )
+            .await?;
 
-                let content = container_client
-                    .client()
-                    .content()
-                    .write(request_stream)
-                    .await?;
-                offset += chunk_length as i64;
-                *downloaded_bytes.lock().await += chunk_length as u64;
-                progress_bar.set_position(*downloaded_bytes.lock().await);
-                tick();
-
-                let mut stream = content.into_inner();
-                loop {
-                    match stream.message().await {
-                        Ok(None) => break,
-                        Ok(_) => {}
-                        Err(e) => return Err(e.into()),
-                    }
-                    // Wait for the upload to complete
-                }
-            }
+            containerd_time += started.elapsed();
+            offset += chunk_length as i64;
+            progress.advance(chunk_length).await;
         }
 
-        // Handle any remaining bytes in buffer
-        if !buffer.is_empty() {
-            let length = buffer.len();
-            let upload_request = WriteContentRequest {
-                action: WriteAction::Write as i32,
-                r#ref: digest.to_string(),
-                total: content_length as i64,
-                expected: "".to_string(),
-                offset,
-                data: buffer.into(),
-                labels: HashMap::new(),
-            };
+        if finished {
+            break;
+        }
+    }
 
-            let request_stream = with_client!(
-                futures_util::stream::iter(vec![upload_request]),
-                container_client
-            );
+    profile::record(Phase::Download, download_time, offset as u64);
+    profile::record(Phase::Containerd, containerd_time, offset as u64);
 
-            let content = container_client
-                .client()
-                .content()
-                .write(request_stream)
-                .await?;
+    let actual_digest = digester.finish()?;
 
-            let mut stream = content.into_inner();
-            loop {
-                match stream.message().await {
-                    Ok(None) => break,
-                    Ok(_) => {}
-                    Err(e) => return Err(e.into()),
-                }
-                // Wait for the upload to complete
-            }
+    if actual_digest != uncompressed_digest {
+        // Drop the ingest, so that the corrupt layer is not resumed later
+        let _ = container_client
+            .client()
+            .content()
+            .abort(with_client!(
+                AbortRequest {
+                    r#ref: digest.to_string(),
+                },
+                container_client
+            ))
+            .await;
 
-            // Update the offset after the final write
-            offset += length as i64;
-            *downloaded_bytes.lock().await += length as u64;
-            progress_bar.set_position(*downloaded_bytes.lock().await);
-            tick();
-        }
+        return Err(OciDownloaderError(format!(
+            "Layer {} decompresses to {}, but the image config expects {}",
+            digest, actual_digest, uncompressed_digest
+        )));
+    }
 
-        // Finalize with a commit
-        let upload_request = WriteContentRequest {
+    // Finalize with a commit
+    write_chunk(
+        &container_client,
+        WriteContentRequest {
             action: WriteAction::Commit as i32,
             r#ref: digest.to_string(),
             total: content_length as i64,
@@ -496,29 +1447,376 @@ impl OciDownloader {
             offset,
             data: vec![],
             labels,
-        };
+        },
+    )
+    .await
+}
 
-        let request_stream = with_client!(
-            futures_util::stream::iter(vec![upload_request]),
-            container_client
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::OciLayout,
+        recording::{Interaction, Recording},
+        spec::index::Manifest,
+    };
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use tar::{Builder, Header};
+
+    fn layer(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_extract_image_concurrently() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let layout = OciLayout::new(dir.path());
+        let layers = [
+            layer(&[("etc/motd", b"base"), ("etc/old", b"old")]),
+            layer(&[("etc/.wh.old", b""), ("etc/motd", b"middle")]),
+            layer(&[("etc/motd", b"top")]),
+        ];
+
+        let mut descriptors = vec![];
+
+        for data in &layers {
+            let digest = sha256_digest(data);
+            layout.write_blob(&digest, data)?;
+            descriptors.push(format!(
+                r#"{{"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"{}","size":{}}}"#,
+                digest,
+                data.len()
+            ));
+        }
+
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:00","size":0}},"layers":[{}]}}"#,
+            descriptors.join(",")
         );
+        let digest = sha256_digest(manifest.as_bytes());
+        layout.write_blob(&digest, manifest.as_bytes())?;
+        layout.tag(
+            "edge",
+            Manifest {
+                media_type: MediaType::OciImageManifestV1Json,
+                size: manifest.len() as u64,
+                digest,
+                platform: None,
+                artifact_type: None,
+                annotations: None,
+            },
+        )?;
+
+        let local = LocalImage::open(&format!("oci:{}", dir.path().display()))?.unwrap();
+        let image = local.reference().image;
+        let client = Arc::new(OciClient::new(HashMap::new(), None));
+        let downloader = Arc::new(OciDownloader::new(client, true).with_local(Some(local)));
+        let manifest: ImageManifest = serde_json::from_str(&manifest)?;
+
+        let root = tempfile::tempdir()?;
+        let progress = ProgressBar::hidden();
+        downloader
+            .extract_image_concurrently(image, &manifest, &root.path().to_path_buf(), 2, &progress)
+            .await?;
 
-        let content = container_client
-            .client()
-            .content()
-            .write(request_stream)
+        // Upper layers are applied last, however the downloads finished
+        assert_eq!(std::fs::read(root.path().join("etc/motd"))?, b"top");
+        assert!(!root.path().join("etc/old").exists());
+        assert_eq!(
+            progress.position(),
+            layers.iter().map(|data| data.len() as u64).sum::<u64>()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mirrors() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let interactions = [
+            ("https://registry.example.com/auth?service=registry.example.com&scope=repository%3Aapp%3Apull", 200, r#"{"token":"REDACTED"}"#),
+            ("https://mirror.example.com/auth?service=mirror.example.com&scope=repository%3Ahub%2Fapp%3Apull", 200, r#"{"token":"REDACTED"}"#),
+            ("https://mirror.example.com/v2/hub/app/blobs/sha256:aa", 200, "mirror"),
+            ("https://registry.example.com/v2/app/blobs/sha256:bb", 200, "upstream"),
+            ("https://mirror.example.com/v2/hub/app/blobs/sha256:cc", 500, ""),
+            ("https://registry.example.com/v2/app/blobs/sha256:cc", 200, "upstream"),
+            ("https://mirror.example.com/v2/hub/app/blobs/sha256:dd", 200, "mirror"),
+            ("https://registry.example.com/v2/app/blobs/sha256:dd", 200, "upstream"),
+        ];
+
+        for (index, (url, status, body)) in interactions.iter().enumerate() {
+            let interaction = Interaction {
+                method: "GET".to_string(),
+                url: url.to_string(),
+                status: *status,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(body),
+            };
+            std::fs::write(
+                dir.path().join(format!("{:05}-get.json", index)),
+                serde_json::to_vec(&interaction)?,
+            )?;
+        }
+
+        let image = FullImage::from_image_name("registry.example.com/app")?;
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
             .await?;
-        let mut stream = content.into_inner();
 
-        loop {
-            match stream.message().await {
-                Ok(None) => break,
-                Ok(_) => {}
-                Err(e) => return Err(e.into()),
-            }
-            // Wait for the upload to complete
+        let mut downloader = OciDownloader::new(client, true);
+        downloader.mirrors = HashMap::from([(
+            "registry.example.com".to_string(),
+            vec!["mirror.example.com/hub".to_string()],
+        )]);
+
+        let download = async |digest| downloader.download_layer(image.clone(), digest).await;
+
+        // The registry is asked for what the mirror does not have, and for
+        // everything once the mirror failed
+        assert_eq!(download("sha256:aa").await?, "mirror");
+        assert_eq!(download("sha256:bb").await?, "upstream");
+        assert_eq!(download("sha256:cc").await?, "upstream");
+        assert_eq!(download("sha256:dd").await?, "upstream");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_schema1() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let tar = layer(&[("etc/motd", b"legacy")]);
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &tar)?;
+        let gzip = encoder.finish()?;
+        let layer_digest = sha256_digest(&gzip);
+
+        let history = r#"{\"id\":\"base\",\"created\":\"2017-01-01T00:00:00Z\",\"config\":{\"Cmd\":[\"sh\"]}}"#;
+        let manifest = format!(
+            r#"{{"schemaVersion":1,"name":"app","tag":"1.0","architecture":"amd64","fsLayers":[{{"blobSum":"{}"}}],"history":[{{"v1Compatibility":"{}"}}]}}"#,
+            layer_digest, history
+        );
+        let digest = sha256_digest(manifest.as_bytes());
+        let content_type = schema1::SCHEMA1_MEDIA_TYPE.to_string();
+
+        let registry = "https://registry.example.com";
+        let interactions = [
+            (
+                format!(
+                    "{}/auth?service=registry.example.com&scope=repository%3Aapp%3Apull",
+                    registry
+                ),
+                vec![],
+                &br#"{"token":"REDACTED"}"#[..],
+            ),
+            (
+                format!("{}/v2/app/manifests/1.0", registry),
+                vec![
+                    ("content-type".to_string(), content_type.clone()),
+                    ("docker-content-digest".to_string(), digest),
+                ],
+                manifest.as_bytes(),
+            ),
+            (
+                format!("{}/v2/app/blobs/{}", registry, layer_digest),
+                vec![],
+                &gzip[..],
+            ),
+            (
+                format!("{}/v2/app/manifests/tampered", registry),
+                vec![
+                    ("content-type".to_string(), content_type),
+                    (
+                        "docker-content-digest".to_string(),
+                        format!("sha256:{}", "0".repeat(64)),
+                    ),
+                ],
+                manifest.as_bytes(),
+            ),
+        ];
+
+        for (index, (url, headers, body)) in interactions.into_iter().enumerate() {
+            let interaction = Interaction {
+                method: "GET".to_string(),
+                url,
+                status: 200,
+                headers,
+                body: BASE64_STANDARD.encode(body),
+            };
+            std::fs::write(
+                dir.path().join(format!("{:05}-get.json", index)),
+                serde_json::to_vec(&interaction)?,
+            )?;
+        }
+
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        let image = FullImageWithTag::from_image_name("registry.example.com/app:1.0")?;
+        client
+            .login(&[ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+        let downloader = OciDownloader::new(client, true);
+
+        let (manifest, config) = downloader.download_image(image, None).await?;
+        assert_eq!(manifest.layers[0].digest, layer_digest);
+        assert_eq!(config.rootfs.diff_ids, vec![sha256_digest(&tar)]);
+        assert_eq!(config.config.unwrap().cmd, Some(vec!["sh".to_string()]));
+
+        // Manifests that do not match the digest the registry reports are refused
+        let image = FullImageWithTag::from_image_name("registry.example.com/app:tampered")?;
+        let error = downloader.download_manifest_raw(image).await.unwrap_err();
+        assert!(error.0.contains("digest mismatch"), "{}", error.0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_foreign_layers() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let data = layer(&[("Files/License.txt", b"foreign")]);
+        let digest = sha256_digest(&data);
+        let missing = format!("sha256:{}", "0".repeat(64));
+        let tampered = format!("sha256:{}", "1".repeat(64));
+        let interactions = [
+            ("https://registry.example.com/auth?service=registry.example.com&scope=repository%3Aapp%3Apull".to_string(), 200, &br#"{"token":"REDACTED"}"#[..]),
+            ("https://cdn.example.com/tampered".to_string(), 200, &b"tampered"[..]),
+            ("https://cdn.example.com/layer".to_string(), 200, &data[..]),
+            ("https://cdn.example.com/missing".to_string(), 404, &b""[..]),
+            (format!("https://registry.example.com/v2/app/blobs/{}", missing), 404, &b""[..]),
+        ];
+
+        for (index, (url, status, body)) in interactions.into_iter().enumerate() {
+            let interaction = Interaction {
+                method: "GET".to_string(),
+                url,
+                status,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(body),
+            };
+            std::fs::write(
+                dir.path().join(format!("{:05}-get.json", index)),
+                serde_json::to_vec(&interaction)?,
+            )?;
         }
 
+        let image = FullImage::from_image_name("registry.example.com/app")?;
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+
+        let downloader = OciDownloader::new(client, true);
+        downloader.add_foreign_urls(&digest, &["https://cdn.example.com/layer".to_string()]);
+        downloader.add_foreign_urls(&missing, &["https://cdn.example.com/missing".to_string()]);
+        downloader.add_foreign_urls(&tampered, &["https://cdn.example.com/tampered".to_string()]);
+
+        assert_eq!(
+            downloader.download_layer(image.clone(), &digest).await?,
+            data
+        );
+
+        // Layers are checked as they are streamed, so one that is not what
+        // it should be fails once it has been read
+        let error = downloader
+            .download_layer(image.clone(), &tampered)
+            .await
+            .unwrap_err();
+        assert!(error.0.contains("Digest mismatch"), "{}", error.0);
+
+        let error = downloader
+            .download_layer(image, &missing)
+            .await
+            .unwrap_err();
+        assert!(error.0.contains("--skip-foreign-layers"), "{}", error.0);
+
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_list_referrers() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let index = r#"{"schemaVersion":2,"manifests":[{"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/spdx+json","digest":"sha256:bb","size":1}]}"#;
+        let interactions = [
+            ("https://registry.example.com/auth?service=registry.example.com&scope=repository%3Aapp%3Apull".to_string(), r#"{"token":"REDACTED"}"#),
+            (format!("https://registry.example.com/v2/app/referrers/{}?artifactType=application%2Fspdx%2Bjson", digest), index),
+        ];
+
+        for (index, (url, body)) in interactions.into_iter().enumerate() {
+            let interaction = Interaction {
+                method: "GET".to_string(),
+                url,
+                status: 200,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(body),
+            };
+            std::fs::write(
+                dir.path().join(format!("{:05}-get.json", index)),
+                serde_json::to_vec(&interaction)?,
+            )?;
+        }
+
+        let image = FullImage::from_image_name("registry.example.com/app")?;
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+
+        // The + of the artifact type is sent encoded, not as a space
+        let referrers = OciDownloader::new(client, true)
+            .list_referrers(image, &digest, Some("application/spdx+json"))
+            .await?;
+        assert_eq!(referrers.len(), 1);
+        assert_eq!(referrers[0].digest, "sha256:bb");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_link() {
+        assert_eq!(
+            next_link(r#"</v2/library/nginx/tags/list?last=1.27&n=100>; rel="next""#),
+            Some("/v2/library/nginx/tags/list?last=1.27&n=100".to_string())
+        );
+        assert_eq!(
+            next_link(
+                r#"<https://example.com/first>; rel="prev", <https://example.com/next>; rel=next"#
+            ),
+            Some("https://example.com/next".to_string())
+        );
+        assert_eq!(next_link(r#"</v2/_catalog>; rel="prev""#), None);
+    }
 }
@@ -0,0 +1,496 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::client::OciClient;
+
+const ECR_TARGET: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+const ECR_CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+/// How long before it expires an ECR token is replaced by a new one, so that
+/// a request never goes out with a token that runs out on the way
+pub const ECR_REFRESH_MARGIN: Duration = Duration::from_secs(30 * 60);
+
+/// The instance metadata service of EC2, and the credentials endpoint of ECS
+const IMDS_URL: &str = "http://169.254.169.254";
+const ECS_CREDENTIALS_URL: &str = "http://169.254.170.2";
+
+/// How long the metadata endpoints are given to answer, as they are not
+/// there at all outside of AWS
+const METADATA_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// AWS credentials, taken from the environment, the shared credentials file,
+/// or the role of the ECS task or EC2 instance
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+/// An ECR registry token, sent as basic authentication
+#[derive(Clone, Debug)]
+pub struct EcrToken {
+    pub authorization: String,
+    pub expires_at: OffsetDateTime,
+}
+
+impl EcrToken {
+    pub fn needs_refresh(&self, now: OffsetDateTime) -> bool {
+        now + ECR_REFRESH_MARGIN >= self.expires_at
+    }
+}
+
+/// The region of an ECR registry, such as eu-west-1 for
+/// https://123456789012.dkr.ecr.eu-west-1.amazonaws.com
+pub fn ecr_region(registry: &str) -> Option<&str> {
+    let host = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let host = host.split_once(':').map_or(host, |(host, _)| host);
+    let rest = host
+        .strip_suffix(".amazonaws.com")
+        .or_else(|| host.strip_suffix(".amazonaws.com.cn"))?;
+
+    match rest.split('.').collect::<Vec<_>>()[..] {
+        [account, "dkr", "ecr", region]
+            if !account.is_empty() && account.bytes().all(|c| c.is_ascii_digit()) =>
+        {
+            Some(region)
+        }
+        _ => None,
+    }
+}
+
+/// The ECR API endpoint of a region
+fn api_host(region: &str) -> String {
+    match region.starts_with("cn-") {
+        true => format!("api.ecr.{}.amazonaws.com.cn", region),
+        false => format!("api.ecr.{}.amazonaws.com", region),
+    }
+}
+
+/// Reads a profile of an AWS shared credentials file
+fn parse_credentials_file(content: &str, profile: &str) -> Option<AwsCredentials> {
+    let mut in_profile = false;
+    let mut access_key_id = None;
+    let mut secret_access_key = None;
+    let mut session_token = None;
+
+    for line in content.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            in_profile = section.trim() == profile;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+
+        if !in_profile {
+            continue;
+        }
+
+        let value = Some(value.trim().to_string());
+
+        match key.trim() {
+            "aws_access_key_id" => access_key_id = value,
+            "aws_secret_access_key" => secret_access_key = value,
+            "aws_session_token" => session_token = value,
+            _ => {}
+        }
+    }
+
+    Some(AwsCredentials {
+        access_key_id: access_key_id?,
+        secret_access_key: secret_access_key?,
+        session_token,
+    })
+}
+
+/// Reads the credentials of a role, as the ECS credentials endpoint and the
+/// instance metadata service both answer with
+fn parse_role_credentials(text: &str) -> Option<AwsCredentials> {
+    let json: serde_json::Value = serde_json::from_str(text).ok()?;
+    let field = |name: &str| json.get(name)?.as_str().map(String::from);
+
+    Some(AwsCredentials {
+        access_key_id: field("AccessKeyId")?,
+        secret_access_key: field("SecretAccessKey")?,
+        session_token: field("Token"),
+    })
+}
+
+/// The credentials of AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, or of the
+/// AWS_PROFILE profile of ~/.aws/credentials
+fn configured_credentials() -> Option<AwsCredentials> {
+    if let (Ok(access_key_id), Ok(secret_access_key)) = (
+        std::env::var("AWS_ACCESS_KEY_ID"),
+        std::env::var("AWS_SECRET_ACCESS_KEY"),
+    ) {
+        return Some(AwsCredentials {
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        });
+    }
+
+    let path = std::env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".aws/credentials")))?;
+    let profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+
+    parse_credentials_file(&fs::read_to_string(path).ok()?, &profile)
+}
+
+/// The credentials of the task role of an ECS task
+async fn ecs_credentials(client: &OciClient) -> Option<AwsCredentials> {
+    let uri = std::env::var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI").ok()?;
+    let url = format!("{}{}", ECS_CREDENTIALS_URL, uri);
+
+    let response = client
+        .send(&url, |client| client.get(&url).timeout(METADATA_TIMEOUT))
+        .await
+        .ok()?;
+
+    parse_role_credentials(&response.error_for_status().ok()?.text().await.ok()?)
+}
+
+/// The credentials of the instance profile of an EC2 instance, through the
+/// session tokens of IMDSv2
+async fn imds_credentials(client: &OciClient) -> Option<AwsCredentials> {
+    let get = async |path: &str, token: &str| {
+        let url = format!("{}{}", IMDS_URL, path);
+        let response = client
+            .send(&url, |client| {
+                client
+                    .get(&url)
+                    .header("x-aws-ec2-metadata-token", token)
+                    .timeout(METADATA_TIMEOUT)
+            })
+            .await
+            .ok()?;
+
+        response.error_for_status().ok()?.text().await.ok()
+    };
+
+    let url = format!("{}/latest/api/token", IMDS_URL);
+    let token = client
+        .send(&url, |client| {
+            client
+                .put(&url)
+                .header("x-aws-ec2-metadata-token-ttl-seconds", "60")
+                .timeout(METADATA_TIMEOUT)
+        })
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?
+        .text()
+        .await
+        .ok()?;
+
+    let roles = get("/latest/meta-data/iam/security-credentials/", &token).await?;
+    let role = roles.lines().next()?.trim();
+    let path = format!("/latest/meta-data/iam/security-credentials/{}", role);
+
+    parse_role_credentials(&get(&path, &token).await?)
+}
+
+/// Finds the ambient AWS credentials, in the order of the AWS SDKs: the
+/// environment, the shared credentials file, the ECS task role, then the
+/// EC2 instance profile unless AWS_EC2_METADATA_DISABLED is set
+pub async fn aws_credentials(client: &OciClient) -> Option<AwsCredentials> {
+    if let Some(credentials) = configured_credentials() {
+        return Some(credentials);
+    }
+
+    if let Some(credentials) = ecs_credentials(client).await {
+        return Some(credentials);
+    }
+
+    match std::env::var("AWS_EC2_METADATA_DISABLED") {
+        Ok(disabled) if disabled.eq_ignore_ascii_case("true") => None,
+        _ => imds_credentials(client).await,
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The key that signs requests to a service for a day, as of Signature Version 4
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date);
+    let key = hmac_sha256(&key, region);
+    let key = hmac_sha256(&key, service);
+    hmac_sha256(&key, "aws4_request")
+}
+
+/// Signs a GetAuthorizationToken call, returning its headers
+fn sign_request(
+    credentials: &AwsCredentials,
+    region: &str,
+    body: &str,
+    now: OffsetDateTime,
+) -> Vec<(&'static str, String)> {
+    let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+    let amz_date = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        now.hour(),
+        now.minute(),
+        now.second()
+    );
+
+    // Headers in the order they are signed in
+    let mut headers = vec![
+        ("content-type", ECR_CONTENT_TYPE.to_string()),
+        ("host", api_host(region)),
+        ("x-amz-date", amz_date.clone()),
+    ];
+
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+
+    headers.push(("x-amz-target", ECR_TARGET.to_string()));
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(body.as_bytes()))
+    );
+    let scope = format!("{}/{}/ecr/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let signature = hex(&hmac_sha256(
+        &signing_key(&credentials.secret_access_key, &date, region, "ecr"),
+        &string_to_sign,
+    ));
+
+    headers.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ),
+    ));
+
+    // The client adds the host itself
+    headers.retain(|(name, _)| *name != "host");
+    headers
+}
+
+/// Reads the token out of a GetAuthorizationToken response
+fn parse_token_response(text: &str) -> Result<EcrToken, String> {
+    let json: serde_json::Value =
+        serde_json::from_str(text).map_err(|e| format!("Invalid ECR token response: {}", e))?;
+    let data = json
+        .get("authorizationData")
+        .and_then(|data| data.get(0))
+        .ok_or_else(|| format!("No ECR token in response: {}", text))?;
+
+    let authorization = data
+        .get("authorizationToken")
+        .and_then(|token| token.as_str())
+        .ok_or_else(|| format!("No ECR token in response: {}", text))?;
+
+    // ECR tokens last for 12 hours, which is assumed if the response does not say
+    let expires_at = data
+        .get("expiresAt")
+        .and_then(|expires_at| expires_at.as_f64())
+        .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds as i64).ok())
+        .unwrap_or_else(|| OffsetDateTime::now_utc() + Duration::from_secs(12 * 60 * 60));
+
+    Ok(EcrToken {
+        authorization: format!("Basic {}", authorization),
+        expires_at,
+    })
+}
+
+/// Fetches a registry token of an ECR region with GetAuthorizationToken
+pub async fn get_authorization_token(
+    client: &OciClient,
+    credentials: &AwsCredentials,
+    region: &str,
+) -> Result<EcrToken, String> {
+    let body = "{}";
+    let headers = sign_request(credentials, region, body, OffsetDateTime::now_utc());
+    let url = format!("https://{}/", api_host(region));
+
+    let response = client
+        .send(&url, |client| {
+            headers
+                .iter()
+                .fold(client.post(&url), |request, (name, value)| {
+                    request.header(*name, value)
+                })
+                .body(body)
+        })
+        .await
+        .map_err(|e| format!("Failed to request ECR token: {}", e))?;
+
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to get text response: {}", e))?;
+
+    if !status.is_success() {
+        return Err(format!(
+            "ECR token request failed with {}: {}",
+            status, text
+        ));
+    }
+
+    parse_token_response(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{Interaction, Recording};
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_ecr_region() {
+        assert_eq!(
+            ecr_region("https://123456789012.dkr.ecr.eu-west-1.amazonaws.com"),
+            Some("eu-west-1")
+        );
+        assert_eq!(
+            ecr_region("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn"),
+            Some("cn-north-1")
+        );
+        assert_eq!(ecr_region("https://public.ecr.aws"), None);
+        assert_eq!(ecr_region("https://registry-1.docker.io"), None);
+        assert_eq!(
+            ecr_region("https://evil.dkr.ecr.us-east-1.amazonaws.com"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // The example of the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_parse_credentials_file() {
+        let content = "[default]\naws_access_key_id = AKIA1\naws_secret_access_key = secret1\n\n[ci]\naws_access_key_id=AKIA2\naws_secret_access_key=secret2\naws_session_token=token2\n";
+
+        assert_eq!(
+            parse_credentials_file(content, "ci"),
+            Some(AwsCredentials {
+                access_key_id: "AKIA2".to_string(),
+                secret_access_key: "secret2".to_string(),
+                session_token: Some("token2".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_credentials_file(content, "default").map(|c| c.session_token),
+            Some(None)
+        );
+        assert_eq!(parse_credentials_file(content, "missing"), None);
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        let token = parse_token_response(
+            r#"{"authorizationData":[{"authorizationToken":"QVdTOnBhc3M=","expiresAt":1.7e9,"proxyEndpoint":"https://123456789012.dkr.ecr.eu-west-1.amazonaws.com"}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(token.authorization, "Basic QVdTOnBhc3M=");
+        assert_eq!(token.expires_at.unix_timestamp(), 1_700_000_000);
+        assert!(token.needs_refresh(token.expires_at - Duration::from_secs(60)));
+        assert!(!token.needs_refresh(token.expires_at - Duration::from_secs(3600)));
+    }
+
+    #[tokio::test]
+    async fn test_imds_credentials() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let role = r#"{"Code":"Success","AccessKeyId":"ASIA1","SecretAccessKey":"secret1","Token":"token1","Expiration":"2026-10-16T12:00:00Z"}"#;
+        let interactions = [
+            ("PUT", "/latest/api/token", "session"),
+            (
+                "GET",
+                "/latest/meta-data/iam/security-credentials/",
+                "builder\n",
+            ),
+            (
+                "GET",
+                "/latest/meta-data/iam/security-credentials/builder",
+                role,
+            ),
+        ];
+
+        for (index, (method, path, body)) in interactions.into_iter().enumerate() {
+            let interaction = Interaction {
+                method: method.to_string(),
+                url: format!("{}{}", IMDS_URL, path),
+                status: 200,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(body),
+            };
+            fs::write(
+                dir.path()
+                    .join(format!("{:05}-{}.json", index, method.to_lowercase())),
+                serde_json::to_vec(&interaction)?,
+            )?;
+        }
+
+        let client = OciClient::new(HashMap::new(), None)
+            .with_recording(Arc::new(Recording::replay(dir.path())?));
+
+        assert_eq!(
+            imds_credentials(&client).await,
+            Some(AwsCredentials {
+                access_key_id: "ASIA1".to_string(),
+                secret_access_key: "secret1".to_string(),
+                session_token: Some("token1".to_string()),
+            })
+        );
+
+        // Outside of EC2, there are no credentials to be had
+        assert_eq!(imds_credentials(&client).await, None);
+
+        Ok(())
+    }
+}
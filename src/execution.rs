@@ -1,25 +1,55 @@
 use crate::{
-    client::{ImagePermission, ImagePermissions, OciClient},
+    archive::detect_media_type,
+    client::{count_retries, ImagePermission, ImagePermissions, OciClient},
+    dashboard::{new_workers, print_summary, Dashboard, Tracked, Transfer},
     digest::sha256_digest,
     downloader::{IndexResponse, OciDownloader},
-    parser::{FullImage, FullImageWithTag},
-    platform::PlatformMatcher,
+    layout::OciLayout,
+    mmap::map_file,
+    parser::{FullImage, FullImageWithTag, ReferenceError},
+    plan::build_bom,
+    platform::{platform_name, PlatformMatcher},
+    priority::{set_thread_priority, IoPriority},
+    profile::{self, Phase},
+    registry_config::RegistrySettings,
+    runner::OciRunner,
+    sbom::{attach_sbom, SbomSource},
     spec::{
         config::{History, ImageConfig, RootFs},
         enums::{MediaType, PlatformOS},
         index::{ImageIndex, Manifest, Platform},
         manifest::{Descriptor, ImageManifest},
-        plan::merge_image_plan_configs,
+        plan::{merge_base_config, merge_image_plan_configs},
     },
+    state::{self, image_key, State},
+    trust::TrustVerifier,
     uploader::OciUploaderError,
     walk::walk_with_filters,
 };
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use futures::{stream, StreamExt, TryStreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
 use regex_lite::Regex;
 use time::OffsetDateTime;
 
-use crate::spec::plan::{ImagePlan, ImagePlanLayerType};
-use std::{collections::HashSet, io::Write, sync::Arc};
-use tar::Builder;
+use crate::spec::plan::{
+    ImagePlan, ImagePlanLayer, ImagePlanLayerType, ImagePlanLock, ImagePlanPlatform, ImagePlanTest,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::Output,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Instant,
+};
+use tar::{Builder, Header};
 use zstd::stream::write::Encoder;
 
 use crate::uploader::OciUploader;
@@ -27,14 +57,34 @@ use std::fs;
 
 pub struct PlanExecution {
     pub plan: ImagePlan,
+    pub lock: ImagePlanLock,
     pub downloader: OciDownloader,
     pub uploader: OciUploader,
-    pub compression_level: i32,
+    pub compression: CompressionOptions,
+    pub jobs: usize,
+    pub created: OffsetDateTime,
+    /// Whether the creation time comes from SOURCE_DATE_EPOCH rather than the clock
+    pub created_pinned: bool,
+    pub trust: TrustVerifier,
+    pub sbom: Option<SbomSource>,
+    pub digest_file: Option<PathBuf>,
+    pub tui: bool,
+}
+
+/// Controls how hard, and how politely, layers are compressed
+#[derive(Clone, Copy)]
+pub struct CompressionOptions {
+    pub level: i32,
+    pub threads: u32,
+    pub nice: Option<i32>,
+    pub io_priority: Option<IoPriority>,
 }
 
 pub struct Blob {
     pub digest: String,
-    pub data: Vec<u8>,
+    pub data: Bytes,
+    /// Repository the blob was copied from, to mount it from on upload
+    pub mount_from: Option<FullImage>,
 }
 
 pub struct Layer {
@@ -49,6 +99,13 @@ pub struct Digest {
     pub uncompressed_digest: String,
 }
 
+pub struct PlatformBuild {
+    pub blobs: Vec<Blob>,
+    pub config_blob: Blob,
+    pub manifest_blob: Blob,
+    pub manifest: Manifest,
+}
+
 impl Layer {
     pub fn to_descriptor(&self) -> Descriptor {
         Descriptor {
@@ -56,12 +113,14 @@ impl Layer {
             digest: self.digest.clone(),
             size: self.size,
             data: None,
+            urls: None,
+            annotations: None,
         }
     }
 
-    pub fn to_history(&self) -> History {
+    pub fn to_history(&self, created: OffsetDateTime) -> History {
         History {
-            created: Some(OffsetDateTime::now_utc()),
+            created: Some(created),
             created_by: Some(self.comment.clone()),
             author: None,
             comment: None,
@@ -70,53 +129,229 @@ impl Layer {
     }
 }
 
+impl PlatformBuild {
+    pub fn new(
+        blobs: Vec<Blob>,
+        image_config: &ImageConfig,
+        layers: Vec<Descriptor>,
+        platform: Platform,
+    ) -> PlatformBuild {
+        let config_data = image_config.to_json();
+        let config_blob = Blob {
+            digest: sha256_digest(&config_data),
+            data: config_data.into(),
+            mount_from: None,
+        };
+
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: MediaType::OciImageManifestV1Json,
+            artifact_type: None,
+            config: Descriptor {
+                media_type: MediaType::OciImageConfigV1ConfigJson,
+                digest: config_blob.digest.clone(),
+                size: config_blob.data.len() as u64,
+                data: None,
+                urls: None,
+                annotations: None,
+            },
+            layers,
+            subject: None,
+            annotations: None,
+        };
+
+        let manifest_data = manifest.to_json();
+        let manifest_blob = Blob {
+            digest: sha256_digest(&manifest_data),
+            data: manifest_data.into(),
+            mount_from: None,
+        };
+
+        let manifest = Manifest {
+            media_type: MediaType::OciImageManifestV1Json,
+            size: manifest_blob.data.len() as u64,
+            digest: manifest_blob.digest.clone(),
+            platform: Some(platform),
+            artifact_type: None,
+            annotations: None,
+        };
+
+        PlatformBuild {
+            blobs,
+            config_blob,
+            manifest_blob,
+            manifest,
+        }
+    }
+
+    /// The index entry of the same build created at another time, which only
+    /// changes its config and the manifest referring to it
+    fn created_at(&self, created: OffsetDateTime) -> Result<Manifest, OciUploaderError> {
+        let mut config: ImageConfig = serde_json::from_slice(&self.config_blob.data)
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+        config.created = Some(created);
+
+        for history in config.history.iter_mut().flatten() {
+            history.created = Some(created);
+        }
+
+        let config_data = config.to_json();
+        let mut manifest: ImageManifest = serde_json::from_slice(&self.manifest_blob.data)
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+        manifest.config.digest = sha256_digest(&config_data);
+        manifest.config.size = config_data.len() as u64;
+
+        let manifest_data = manifest.to_json();
+
+        Ok(Manifest {
+            size: manifest_data.len() as u64,
+            digest: sha256_digest(&manifest_data),
+            ..self.manifest.clone()
+        })
+    }
+}
+
+/// The index of the platforms of an image, as pushed under its tags
+fn build_index(manifests: Vec<Manifest>) -> Vec<u8> {
+    ImageIndex {
+        schema_version: 2,
+        media_type: MediaType::OciImageIndexV1Json,
+        artifact_type: None,
+        manifests,
+        annotations: None,
+    }
+    .to_json()
+}
+
 impl PlanExecution {
     pub fn new(
         plan: ImagePlan,
+        lock: ImagePlanLock,
         client: Arc<OciClient>,
         no_cache: bool,
-        compression_level: i32,
-    ) -> Self {
-        PlanExecution {
+        compression: CompressionOptions,
+        jobs: usize,
+    ) -> Result<Self, OciUploaderError> {
+        // Honor SOURCE_DATE_EPOCH, so that rebuilding the same inputs
+        // produces the same digests and can be detected as unchanged
+        let source_date_epoch = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.parse::<i64>().ok())
+            .and_then(|epoch| OffsetDateTime::from_unix_timestamp(epoch).ok());
+        let created = source_date_epoch.unwrap_or_else(OffsetDateTime::now_utc);
+
+        let trust = plan
+            .trust
+            .as_ref()
+            .map(TrustVerifier::new)
+            .transpose()
+            .map_err(|e| OciUploaderError(format!("Failed to load trust policy: {}", e)))?
+            .unwrap_or_default();
+
+        Ok(PlanExecution {
             plan,
+            lock,
             downloader: OciDownloader::new(client.clone(), no_cache),
             uploader: OciUploader::new(client),
-            compression_level,
-        }
+            compression,
+            jobs: jobs.max(1),
+            created,
+            created_pinned: source_date_epoch.is_some(),
+            trust,
+            sbom: None,
+            digest_file: None,
+            tui: false,
+        })
     }
 
-    async fn compress_tar(&self, tar_buffer: &Vec<u8>) -> (Vec<u8>, Digest) {
-        let uncompressed_digest = sha256_digest(&tar_buffer);
-        let mut encoder = Encoder::new(Vec::new(), self.compression_level).unwrap();
+    /// How the registries of the plan are named and reached
+    fn settings(&self) -> &RegistrySettings {
+        self.downloader.client.settings()
+    }
 
-        // Enable multithreading
-        encoder.multithread(num_cpus::get() as u32).unwrap();
+    /// Attaches an SBOM to the image as a referrer artifact after uploading it
+    pub fn with_sbom(mut self, sbom: Option<SbomSource>) -> Self {
+        self.sbom = sbom;
+        self
+    }
 
-        encoder.write_all(&tar_buffer).unwrap();
-        let compressed_data = encoder.finish().unwrap();
-        let compressed_digest = sha256_digest(&compressed_data);
+    /// Builds the image, but only reports what would be uploaded
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.uploader = self.uploader.with_dry_run(dry_run);
+        self
+    }
 
-        println!(
-            "Compressing layer: {}, original size: {}, compressed size: {} ({:.2}% of original size)",
-            compressed_digest,
-            tar_buffer.len(),
-            compressed_data.len(),
-            (compressed_data.len() as f64 / tar_buffer.len() as f64) * 100.0
-        );
+    /// Writes the image into an image layout instead of pushing it
+    pub fn with_layout(mut self, layout: Option<OciLayout>) -> Self {
+        self.uploader = self.uploader.with_layout(layout);
+        self
+    }
 
-        return (
-            compressed_data,
-            Digest {
-                compressed_digest,
-                uncompressed_digest,
-            },
-        );
+    /// Writes the pushed image reference, pinned by digest, to a file
+    pub fn with_digest_file(mut self, digest_file: Option<PathBuf>) -> Self {
+        self.digest_file = digest_file;
+        self
+    }
+
+    /// Shows a full screen dashboard of the platforms while uploading
+    pub fn with_tui(mut self, tui: bool) -> Self {
+        self.tui = tui;
+        self
+    }
+
+    /// Prints the pushed image pinned by its index digest, and writes it to
+    /// the digest file if one was requested
+    fn report_digest(&self, index_digest: &str) -> Result<(), OciUploaderError> {
+        let reference = format!("{}@{}", self.plan.name, index_digest);
+
+        if self.uploader.is_dry_run() {
+            println!("Would push {}", reference);
+            return Ok(());
+        }
+
+        match self.uploader.layout() {
+            Some(layout) => println!("Wrote {} to {}", reference, layout.root().display()),
+            None => println!("Pushed {}", reference),
+        }
+
+        if let Some(digest_file) = &self.digest_file {
+            fs::write(digest_file, &reference).map_err(|e| {
+                OciUploaderError(format!(
+                    "Failed to write digest file {}: {}",
+                    digest_file.display(),
+                    e
+                ))
+            })?;
+        }
+
+        Ok(())
     }
 
-    fn build_layer(&self, data: Vec<u8>, digest: Digest, comment: &str) -> (Blob, Layer) {
+    async fn compress_tar(&self, tar_buffer: Vec<u8>) -> (Bytes, Digest) {
+        let compression = self.compression;
+
+        // Compression is CPU bound, keep it off the async workers so that
+        // platforms can be built concurrently. It gets a thread of its own
+        // rather than one of the blocking pool, as a lowered priority cannot
+        // be raised again without privileges and would stick to the pool
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            if let Err(e) = set_thread_priority(compression.nice, compression.io_priority) {
+                eprintln!("Failed to lower compression priority: {}", e);
+            }
+
+            let _ = sender.send(compress_tar(&tar_buffer, &compression));
+        });
+        let (compressed, digest) = receiver.await.expect("Compression thread panicked");
+
+        (compressed.into(), digest)
+    }
+
+    fn build_layer(&self, data: Bytes, digest: Digest, comment: &str) -> (Blob, Layer) {
         let blob = Blob {
             digest: digest.compressed_digest.clone(),
             data,
+            mount_from: None,
         };
 
         let layer = Layer {
@@ -129,23 +364,452 @@ impl PlanExecution {
         (blob, layer)
     }
 
-    pub async fn execute(&mut self) -> Result<(), OciUploaderError> {
-        let mut manifests: Vec<Manifest> = vec![];
-        let full_image = FullImage::from_image_name(&self.plan.name);
+    /// Resolves an image reference, preferring the digest pinned by `plan update` over the moving tag
+    fn locked_image(&self, source: &str) -> Result<FullImageWithTag, ReferenceError> {
+        let mut image = FullImageWithTag::parse(source, self.settings())?;
+
+        if let Some(digest) = self.lock.images.get(source) {
+            image.tag = digest.clone();
+        }
+
+        Ok(image)
+    }
+
+    /// Downloads the manifest and config of an image matching the platform
+    async fn download_platform_image(
+        &self,
+        platform: &ImagePlanPlatform,
+        image: &FullImageWithTag,
+    ) -> Result<(ImageManifest, ImageConfig), OciUploaderError> {
+        let (index, index_json) = self
+            .downloader
+            .download_index(image.clone())
+            .await
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+
+        self.trust
+            .verify(
+                &self.downloader,
+                image,
+                &sha256_digest(index_json.as_bytes()),
+            )
+            .await
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+
+        let platform_matcher = PlatformMatcher::match_platform(platform);
+
+        let downloaded_manifest = match index {
+            IndexResponse::ImageIndex(index) => {
+                let manifest = platform_matcher
+                    .find_manifest(&index.manifests)
+                    .ok_or(OciUploaderError("No matching platform found".to_string()))?;
+
+                self.downloader
+                    .download_manifest(image.image.clone(), &manifest.digest)
+                    .await
+                    .map_err(|e| OciUploaderError(e.to_string()))?
+                    .0
+            }
+            IndexResponse::ImageManifest(index) => *index,
+        };
+
+        let downloaded_config: ImageConfig = self
+            .downloader
+            .download_config(image.image.clone(), &downloaded_manifest.config.digest)
+            .await
+            .map_err(|e| OciUploaderError(e.to_string()))?
+            .0;
+
+        Ok((downloaded_manifest, downloaded_config))
+    }
+
+    async fn build_plan_layer(
+        &self,
+        platform: &ImagePlanPlatform,
+        layer: &ImagePlanLayer,
+    ) -> Result<Vec<(Bytes, Digest)>, OciUploaderError> {
+        let tar_buffers = match layer.layer_type {
+            ImagePlanLayerType::Directory => {
+                let whitelist = layer.whitelist.clone().unwrap_or_default();
+                let blacklist = layer.blacklist.clone().unwrap_or_default();
+                let source = layer.source.clone();
+                let overrides = EntryOverrides {
+                    target: layer.target.clone().unwrap_or_default(),
+                    mode: parse_layer_mode(layer)?,
+                    uid: layer.uid,
+                    gid: layer.gid,
+                };
+                let tar_buffer = tokio::task::spawn_blocking(move || {
+                    build_directory_tar(&source, &whitelist, &blacklist, &overrides)
+                })
+                .await
+                .expect("Tar building task panicked");
+
+                let (compressed_tar_buffer, digest) = self.compress_tar(tar_buffer).await;
+
+                vec![(compressed_tar_buffer, digest)]
+            }
+            ImagePlanLayerType::File => {
+                let source = layer.source.clone();
+                let target = layer.target.clone().unwrap_or_else(|| {
+                    Path::new(&layer.source)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_default()
+                });
+                let mode = parse_layer_mode(layer)?;
+                let uid = layer.uid.unwrap_or(0);
+                let gid = layer.gid.unwrap_or(0);
+                let tar_buffer = tokio::task::spawn_blocking(move || {
+                    build_file_tar(&source, &target, mode, uid, gid)
+                })
+                .await
+                .expect("Tar building task panicked")
+                .map_err(|e| OciUploaderError(format!("Failed to read {}: {}", layer.source, e)))?;
+
+                let (compressed_tar_buffer, digest) = self.compress_tar(tar_buffer).await;
+
+                vec![(compressed_tar_buffer, digest)]
+            }
+            ImagePlanLayerType::Url => {
+                let expected_digest = layer.sha256.as_deref().ok_or_else(|| {
+                    OciUploaderError(format!("Missing sha256 for {}", layer.source))
+                })?;
+                let archive =
+                    download_url_archive(&layer.source, expected_digest, self.settings()).await?;
+                let tar_buffer = tokio::task::spawn_blocking(move || decompress_archive(&archive))
+                    .await
+                    .expect("Decompression task panicked")?;
+
+                let (compressed_tar_buffer, digest) = self.compress_tar(tar_buffer).await;
+
+                vec![(compressed_tar_buffer, digest)]
+            }
+            ImagePlanLayerType::Layer => {
+                // Prebuilt layers can be huge, map them instead of reading them into memory
+                let layer_data = map_file(&layer.source).map_err(|e| {
+                    OciUploaderError(format!("Failed to read {}: {}", layer.source, e))
+                })?;
+                let digest = sha256_digest(&layer_data);
+                vec![(
+                    layer_data,
+                    Digest {
+                        compressed_digest: digest.clone(),
+                        uncompressed_digest: digest,
+                    },
+                )]
+            }
+            ImagePlanLayerType::Image => {
+                let image = self.locked_image(&layer.source)?;
+                let (downloaded_manifest, downloaded_config) =
+                    self.download_platform_image(platform, &image).await?;
+
+                let mut tar_layers: Vec<(Bytes, Digest)> = vec![];
+
+                for (index, layer) in downloaded_manifest.layers.iter().enumerate() {
+                    let layer_data = self
+                        .downloader
+                        .download_layer(image.image.clone(), &layer.digest)
+                        .await
+                        .unwrap();
+
+                    tar_layers.push((
+                        layer_data,
+                        Digest {
+                            compressed_digest: layer.digest.clone(),
+                            uncompressed_digest: downloaded_config.rootfs.diff_ids[index].clone(),
+                        },
+                    ));
+                }
+
+                tar_layers
+            }
+        };
+
+        Ok(tar_buffers)
+    }
+
+    async fn build_platform(
+        &self,
+        platform: &ImagePlanPlatform,
+    ) -> Result<PlatformBuild, OciUploaderError> {
+        let mut blobs: Vec<Blob> = vec![];
+        let mut layers: Vec<Layer> = vec![];
+
+        for layer in &platform.layers {
+            for (tar_buffer, digest) in self.build_plan_layer(platform, layer).await? {
+                let (mut blob, new_layer) = self.build_layer(tar_buffer, digest, &layer.comment);
+
+                if let ImagePlanLayerType::Image = layer.layer_type {
+                    blob.mount_from = Some(self.locked_image(&layer.source)?.image);
+                }
+
+                blobs.push(blob);
+                layers.push(new_layer);
+            }
+        }
+
+        let mut platform_config = merge_image_plan_configs(&self.plan.config, &platform.config);
+
+        // Inherit the runtime config of the base image, like FROM in a Dockerfile
+        if let Some(base) = &platform.base {
+            let image = self.locked_image(base)?;
+            let (_, base_config) = self.download_platform_image(platform, &image).await?;
+            platform_config = merge_base_config(base_config.config, platform_config);
+        }
+        let image_config = ImageConfig {
+            created: Some(self.created),
+            author: None,
+            architecture: platform.architecture.clone(),
+            os: platform.os(),
+            os_version: platform.os_version.clone(),
+            os_features: platform.os_features.clone(),
+            variant: platform.variant.clone(),
+            config: platform_config,
+            rootfs: RootFs {
+                fs_type: "layers".to_string(),
+                diff_ids: layers
+                    .iter()
+                    .map(|d| d.uncompressed_digest.clone())
+                    .collect(),
+            },
+            history: Some(layers.iter().map(|l| l.to_history(self.created)).collect()),
+        };
+
+        Ok(PlatformBuild::new(
+            blobs,
+            &image_config,
+            layers.iter().map(|l| l.to_descriptor()).collect(),
+            index_platform(platform),
+        ))
+    }
+
+    /// Uploads the blobs and manifest of a platform, counting the bytes of
+    /// the blobs on its row and on the total
+    async fn upload_platform(
+        &self,
+        full_image: &FullImage,
+        build: &PlatformBuild,
+        row: &Tracked,
+        total: &ProgressBar,
+    ) -> Result<(), OciUploaderError> {
+        for blob in build.blobs.iter().chain([&build.config_blob]) {
+            self.uploader.upload_blob(full_image.clone(), blob).await?;
+            row.spinner.inc(blob.data.len() as u64);
+            total.inc(blob.data.len() as u64);
+        }
+
+        for tag in &self.plan.tags {
+            self.uploader
+                .upload_manifest(
+                    FullImageWithTag {
+                        image: full_image.clone(),
+                        tag: tag.to_string(),
+                    },
+                    build.manifest_blob.data.to_vec(),
+                    "application/vnd.oci.image.manifest.v1+json",
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether every tag of the plan already points at the given index
+    async fn is_unchanged(
+        &self,
+        full_image: &FullImage,
+        index_digest: &str,
+    ) -> Result<bool, OciUploaderError> {
+        for tag in &self.plan.tags {
+            let remote_digest = self
+                .uploader
+                .manifest_digest(
+                    FullImageWithTag {
+                        image: full_image.clone(),
+                        tag: tag.to_string(),
+                    },
+                    MediaType::OciImageIndexV1Json.to_string(),
+                )
+                .await?;
+
+            if remote_digest.as_deref() != Some(index_digest) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The creation time of the image the first tag already points at, if any
+    async fn pushed_created(&self, full_image: &FullImage) -> Option<OffsetDateTime> {
+        let tag = self.plan.tags.first()?;
+
+        let config: ImageConfig = match self.uploader.layout() {
+            Some(layout) => {
+                let read = |digest: &str| layout.read_blob(digest).ok().flatten();
+                let index: ImageIndex =
+                    serde_json::from_slice(&read(&layout.find(tag).ok()??.digest)?).ok()?;
+                let manifest: ImageManifest =
+                    serde_json::from_slice(&read(&index.manifests.first()?.digest)?).ok()?;
+                serde_json::from_slice(&read(&manifest.config.digest)?).ok()?
+            }
+            None => {
+                let image = FullImageWithTag {
+                    image: full_image.clone(),
+                    tag: tag.to_string(),
+                };
+
+                // Looked up first, so that a new tag is not reported as a failed download
+                self.uploader
+                    .manifest_digest(image.clone(), MediaType::OciImageIndexV1Json.to_string())
+                    .await
+                    .ok()??;
+
+                let IndexResponse::ImageIndex(index) =
+                    self.downloader.download_index(image).await.ok()?.0
+                else {
+                    return None;
+                };
+                let (manifest, _) = self
+                    .downloader
+                    .download_manifest(full_image.clone(), &index.manifests.first()?.digest)
+                    .await
+                    .ok()?;
+                self.downloader
+                    .download_config(full_image.clone(), &manifest.config.digest)
+                    .await
+                    .ok()?
+                    .0
+            }
+        };
+
+        config.created
+    }
+
+    /// Without SOURCE_DATE_EPOCH every run creates the image at another time,
+    /// so it is also compared with the pushed image as if created at the same
+    /// time, returning the digest of the pushed index if they are the same
+    async fn unchanged_apart_from_created(
+        &self,
+        full_image: &FullImage,
+        builds: &[PlatformBuild],
+    ) -> Result<Option<String>, OciUploaderError> {
+        if self.created_pinned {
+            return Ok(None);
+        }
+
+        let Some(created) = self.pushed_created(full_image).await else {
+            return Ok(None);
+        };
+        let manifests = builds
+            .iter()
+            .map(|build| build.created_at(created))
+            .collect::<Result<Vec<_>, _>>()?;
+        let index_digest = sha256_digest(&build_index(manifests));
+
+        match self.is_unchanged(full_image, &index_digest).await? {
+            true => Ok(Some(index_digest)),
+            false => Ok(None),
+        }
+    }
+
+    /// Runs the plan's smoke test against a built platform, from a scratch root filesystem
+    async fn smoke_test(
+        &self,
+        build: &PlatformBuild,
+        test: &ImagePlanTest,
+    ) -> Result<(), OciUploaderError> {
+        let config: ImageConfig = serde_json::from_slice(&build.config_blob.data)
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+        let root = tempfile::tempdir().map_err(|e| OciUploaderError(e.to_string()))?;
+        let root_path = root.path().to_path_buf();
+
+        println!("Running smoke test: {}", test.command.join(" "));
+
+        for blob in &build.blobs {
+            let media_type =
+                detect_media_type(&blob.data[..]).map_err(|e| OciUploaderError(e.to_string()))?;
+
+            self.downloader
+                .extract_layer_bytes_to(&blob.data[..], media_type, &root_path)
+                .await
+                .map_err(|e| OciUploaderError(e.to_string()))?;
+        }
+
+        let runner = OciRunner::new(
+            &root_path,
+            &config.config,
+            vec![],
+            None,
+            Some(test.command.clone()),
+            None,
+            true,
+            true,
+        );
+        let output = runner
+            .output()
+            .await
+            .map_err(|e| OciUploaderError(e.to_string()))?;
+
+        check_smoke_test(test, &output)?;
+        println!("Smoke test passed.");
+        Ok(())
+    }
+
+    pub async fn execute(&self) -> Result<(), OciUploaderError> {
+        // Every platform has a row, which is only drawn with --tui
+        let rows: Vec<Tracked> = self
+            .plan
+            .platforms
+            .iter()
+            .map(|platform| {
+                let name = platform_name(&index_platform(platform));
+                let spinner = ProgressBar::hidden();
+                spinner.set_message(name.clone());
+                Tracked::new(name, spinner)
+            })
+            .collect();
+        let total = ProgressBar::hidden();
+        let dashboard = self.tui.then(|| {
+            Dashboard::spawn(
+                Transfer::Upload,
+                rows.clone(),
+                total.clone(),
+                new_workers(0),
+            )
+        });
+
+        let result = self.upload(&rows, &total).await;
+
+        if let Some(dashboard) = dashboard {
+            dashboard.finish().await;
+
+            let rows: Vec<_> = rows.iter().map(Tracked::row).collect();
+            print_summary(Transfer::Upload, &rows, total.position());
+        }
+
+        result
+    }
+
+    async fn upload(&self, rows: &[Tracked], total: &ProgressBar) -> Result<(), OciUploaderError> {
+        let full_image = FullImage::parse(&self.plan.name, self.settings())?;
 
         // First things first, log into every registry necessary
         let mut image_permissions = HashSet::<ImagePermission>::new();
 
-        image_permissions.insert(ImagePermission {
-            full_image: full_image.clone(),
-            permissions: ImagePermissions::Push,
-        });
+        if self.uploader.layout().is_none() {
+            image_permissions.insert(ImagePermission {
+                full_image: full_image.clone(),
+                permissions: ImagePermissions::Push,
+            });
+        }
 
         for platform in &self.plan.platforms {
             for layer in &platform.layers {
                 if let ImagePlanLayerType::Image = layer.layer_type {
                     let image_name = layer.source.clone();
-                    let image = FullImageWithTag::from_image_name(&image_name);
+                    let image = FullImageWithTag::parse(&image_name, self.settings())?;
 
                     image_permissions.insert(ImagePermission {
                         full_image: image.image.clone(),
@@ -153,232 +817,104 @@ impl PlanExecution {
                     });
                 }
             }
+
+            if let Some(base) = &platform.base {
+                image_permissions.insert(ImagePermission {
+                    full_image: FullImageWithTag::parse(base, self.settings())?.image,
+                    permissions: ImagePermissions::Pull,
+                });
+            }
         }
 
         let image_permissions_vec: Vec<ImagePermission> = image_permissions.into_iter().collect();
         self.downloader.client.login(&image_permissions_vec).await?;
 
-        for platform in &self.plan.platforms {
-            let mut layers: Vec<Layer> = vec![];
-
-            for layer in &platform.layers {
-                let tar_buffers = match layer.layer_type {
-                    ImagePlanLayerType::Directory => {
-                        let whitelist_regexes: Vec<Regex> =
-                            layer.whitelist.clone().map_or_else(Vec::new, |b| {
-                                b.iter().map(|s| Regex::new(s).unwrap()).collect::<Vec<_>>()
-                            });
-                        let blacklist_regexes: Vec<Regex> =
-                            layer.blacklist.clone().map_or_else(Vec::new, |b| {
-                                b.iter().map(|s| Regex::new(s).unwrap()).collect::<Vec<_>>()
-                            });
-                        let files = walk_with_filters(
-                            &layer.source,
-                            &whitelist_regexes,
-                            &blacklist_regexes,
-                        );
-
-                        println!(
-                            "Creating layer from directory: {} (collected {} files)",
-                            layer.source,
-                            files.len()
-                        );
-
-                        let mut tar_buffer = Vec::new();
-
-                        {
-                            let mut tar_builder = Builder::new(&mut tar_buffer);
-                            tar_builder.follow_symlinks(false);
-
-                            for file_path in files {
-                                tar_builder
-                                    .append_path_with_name(
-                                        &file_path,
-                                        file_path.strip_prefix(&layer.source).unwrap(),
-                                    )
-                                    .unwrap();
-                            }
-
-                            tar_builder.finish().unwrap();
-                        }
-
-                        let (compressed_tar_buffer, digest) = self.compress_tar(&tar_buffer).await;
-
-                        vec![(compressed_tar_buffer, digest)]
-                    }
-                    ImagePlanLayerType::Layer => {
-                        let layer_data = fs::read(&layer.source).unwrap();
-                        let digest = sha256_digest(&layer_data);
-                        vec![(
-                            layer_data,
-                            Digest {
-                                compressed_digest: digest.clone(),
-                                uncompressed_digest: digest,
-                            },
-                        )]
-                    }
-                    ImagePlanLayerType::Image => {
-                        let image_name = layer.source.clone();
-                        let image = FullImageWithTag::from_image_name(&image_name);
-
-                        let index = self
-                            .downloader
-                            .download_index(image.clone())
-                            .await
-                            .map_err(|e| OciUploaderError(e.to_string()))?
-                            .0;
-
-                        let platform_matcher =
-                            PlatformMatcher::match_architecture(platform.architecture.clone());
-
-                        let downloaded_manifest = match index {
-                            IndexResponse::ImageIndex(index) => {
-                                let manifest =
-                                    platform_matcher.find_manifest(&index.manifests).ok_or(
-                                        OciUploaderError("No matching platform found".to_string()),
-                                    )?;
-
-                                let downloaded_manifest = self
-                                    .downloader
-                                    .download_manifest(image.image.clone(), &manifest.digest)
-                                    .await
-                                    .map_err(|e| OciUploaderError(e.to_string()))?
-                                    .0;
-
-                                Ok::<ImageManifest, OciUploaderError>(downloaded_manifest)
-                            }
-                            IndexResponse::ImageManifest(index) => Ok(index),
-                        }?;
-
-                        let downloaded_config: ImageConfig = self
-                            .downloader
-                            .download_config(
-                                image.image.clone(),
-                                &downloaded_manifest.config.digest,
-                            )
-                            .await
-                            .unwrap()
-                            .0;
-
-                        let mut tar_layers: Vec<(Vec<u8>, Digest)> = vec![];
-
-                        for (index, layer) in downloaded_manifest.layers.iter().enumerate() {
-                            let layer_data = self
-                                .downloader
-                                .download_layer(image.image.clone(), &layer.digest)
-                                .await
-                                .unwrap();
-
-                            tar_layers.push((
-                                layer_data,
-                                Digest {
-                                    compressed_digest: layer.digest.clone(),
-                                    uncompressed_digest: downloaded_config.rootfs.diff_ids[index]
-                                        .clone(),
-                                },
-                            ));
-                        }
-
-                        tar_layers
-                    }
-                };
-
-                for (tar_buffer, digest) in tar_buffers {
-                    let layer_comment = layer.comment.clone();
-                    let (blob, new_layer) = self.build_layer(tar_buffer, digest, &layer_comment);
-                    self.uploader.upload_blob(full_image.clone(), &blob).await?;
-                    layers.push(new_layer);
-                }
-            }
+        // Retried requests are counted towards the platform they are for
+        let builds: Vec<PlatformBuild> = stream::iter(self.plan.platforms.iter().zip(rows))
+            .map(|(platform, row)| async move {
+                row.set_status("building");
+                count_retries(row.retries.clone(), self.build_platform(platform))
+                    .await
+                    .inspect_err(|e| row_failed(row, e))
+            })
+            .buffered(self.jobs)
+            .try_collect()
+            .await?;
 
-            let platform_config = merge_image_plan_configs(&self.plan.config, &platform.config);
-            let image_config = ImageConfig {
-                created: Some(OffsetDateTime::now_utc()),
-                author: None,
-                architecture: platform.architecture.clone(),
-                os: PlatformOS::Linux,
-                os_version: None,
-                os_features: None,
-                variant: platform.variant.clone(),
-                config: platform_config,
-                rootfs: RootFs {
-                    fs_type: "layers".to_string(),
-                    diff_ids: layers
-                        .iter()
-                        .map(|d| d.uncompressed_digest.clone())
-                        .collect(),
-                },
-                history: Some(layers.iter().map(|l| l.to_history()).collect()),
-            };
+        total.set_length(
+            builds
+                .iter()
+                .flat_map(|build| build.blobs.iter().chain([&build.config_blob]))
+                .map(|blob| blob.data.len() as u64)
+                .sum(),
+        );
 
-            let config_data = image_config.to_json();
-            let config_blob = Blob {
-                digest: sha256_digest(&config_data),
-                data: config_data,
-            };
+        let index_data = build_index(builds.iter().map(|b| b.manifest.clone()).collect());
+        let index_digest = sha256_digest(&index_data);
 
-            self.uploader
-                .upload_blob(full_image.clone(), &config_blob)
-                .await?;
+        let unchanged = match self.is_unchanged(&full_image, &index_digest).await? {
+            true => Some(index_digest.clone()),
+            false => {
+                self.unchanged_apart_from_created(&full_image, &builds)
+                    .await?
+            }
+        };
 
-            let manifest = ImageManifest {
-                schema_version: 2,
-                media_type: MediaType::OciImageManifestV1Json,
-                artifact_type: None,
-                config: Descriptor {
-                    media_type: MediaType::OciImageConfigV1ConfigJson,
-                    digest: config_blob.digest.clone(),
-                    size: config_blob.data.len() as u64,
-                    data: None,
-                },
-                layers: layers.iter().map(|l| l.to_descriptor()).collect(),
-                subject: None,
-                annotations: None,
-            };
+        if let Some(index_digest) = unchanged {
+            println!(
+                "Image {} is unchanged ({}), skipping upload.",
+                full_image.image_name, index_digest
+            );
 
-            let manifest_data = manifest.to_json();
+            for row in rows {
+                row_finished(row, "\x1b[33mUnchanged\x1b[0m");
+            }
 
-            let manifest_blob = Blob {
-                digest: sha256_digest(&manifest_data),
-                data: manifest_data.clone(),
-            };
+            return self.report_digest(&index_digest);
+        }
 
-            manifests.push(Manifest {
-                media_type: MediaType::OciImageManifestV1Json,
-                size: manifest_blob.data.len() as u64,
-                digest: manifest_blob.digest.clone(),
-                platform: Some(Platform {
-                    architecture: platform.architecture.clone(),
-                    os: PlatformOS::Linux,
-                    os_version: None,
-                    os_features: None,
-                    variant: platform.variant.clone(),
-                    features: None,
-                }),
-            });
+        if let Some(test) = &self.plan.test {
+            let platform_matcher = PlatformMatcher::new();
+            let host_build = self
+                .plan
+                .platforms
+                .iter()
+                .zip(&builds)
+                .find(|(platform, _)| {
+                    // The smoke test runs through proot, which can only run Linux images
+                    platform.os() == PlatformOS::Linux
+                        && platform_matcher.matches(&platform.architecture)
+                });
 
-            for tag in &self.plan.tags {
-                self.uploader
-                    .upload_manifest(
-                        FullImageWithTag {
-                            image: full_image.clone(),
-                            tag: tag.to_string(),
-                        },
-                        manifest_data.clone(),
-                        "application/vnd.oci.image.manifest.v1+json",
-                    )
-                    .await?;
+            match host_build {
+                Some((_, build)) => self.smoke_test(build, test).await?,
+                None => println!("No platform matches the host, skipping smoke test."),
             }
         }
 
-        let index = ImageIndex {
-            schema_version: 2,
-            media_type: MediaType::OciImageIndexV1Json,
-            artifact_type: None,
-            manifests,
-            annotations: None,
+        let uploaded = match self.uploader.is_dry_run() {
+            true => "\x1b[36mWould push\x1b[0m",
+            false => "\x1b[32mComplete\x1b[0m",
         };
-        let index_data = index.to_json();
+
+        stream::iter(builds.iter().zip(rows))
+            .map(|(build, row)| {
+                let full_image = &full_image;
+
+                async move {
+                    row.set_status("uploading");
+                    count_retries(
+                        row.retries.clone(),
+                        self.upload_platform(full_image, build, row, total),
+                    )
+                    .await
+                    .inspect(|_| row_finished(row, uploaded))
+                    .inspect_err(|e| row_failed(row, e))
+                }
+            })
+            .buffer_unordered(self.jobs)
+            .try_collect::<Vec<()>>()
+            .await?;
 
         for tag in &self.plan.tags {
             self.uploader
@@ -393,6 +929,695 @@ impl PlanExecution {
                 .await?;
         }
 
-        Ok(())
+        // Only registries are tracked in the state
+        if !self.uploader.is_dry_run() && self.uploader.layout().is_none() {
+            let now = state::now();
+            let result = State::update(&State::path(), |state| {
+                for tag in &self.plan.tags {
+                    let image = FullImageWithTag {
+                        image: full_image.clone(),
+                        tag: tag.to_string(),
+                    };
+                    state.record_image(&image_key(&image), &index_digest, now);
+                }
+            });
+
+            if let Err(e) = result {
+                eprintln!("Failed to save state: {}", e);
+            }
+        }
+
+        if let Some(sbom) = &self.sbom {
+            let data = match sbom {
+                SbomSource::File(path) => fs::read(path).map_err(|e| {
+                    OciUploaderError(format!("Failed to read SBOM {}: {}", path.display(), e))
+                })?,
+                SbomSource::Generate => {
+                    let bom = build_bom(&self.plan, &self.lock, &self.downloader)
+                        .await
+                        .map_err(|e| OciUploaderError(format!("Failed to generate SBOM: {}", e)))?;
+                    serde_json::to_vec(&bom).map_err(|e| OciUploaderError(e.to_string()))?
+                }
+            };
+
+            let subject = Descriptor {
+                media_type: MediaType::OciImageIndexV1Json,
+                digest: index_digest.clone(),
+                size: index_data.len() as u64,
+                data: None,
+                urls: None,
+                annotations: None,
+            };
+            attach_sbom(&self.uploader, &full_image, subject, data).await?;
+        }
+
+        self.report_digest(&index_digest)
+    }
+}
+
+/// The platform of an image plan, as listed in the image index
+fn index_platform(platform: &ImagePlanPlatform) -> Platform {
+    Platform {
+        architecture: platform.architecture.clone(),
+        os: platform.os(),
+        os_version: platform.os_version.clone(),
+        os_features: platform.os_features.clone(),
+        variant: platform.variant.clone(),
+        features: None,
+    }
+}
+
+/// Finishes the row of a platform with "name: status", as the dashboard
+/// reads it
+fn row_finished(row: &Tracked, status: &str) {
+    row.spinner
+        .finish_with_message(format!("{}: {}", row.name, status));
+}
+
+fn row_failed(row: &Tracked, error: &OciUploaderError) {
+    row_finished(row, &format!("\x1b[31mFailed - {}\x1b[0m", error));
+}
+
+/// A file read by one of the producer threads, ready to be appended to the tar
+struct TarEntry {
+    header: Header,
+    name: PathBuf,
+    link_target: Option<PathBuf>,
+    data: Vec<u8>,
+}
+
+/// Overrides applied to every entry of a directory layer
+#[derive(Clone, Default)]
+pub struct EntryOverrides {
+    /// Prefix inside the image that the directory is placed under
+    pub target: String,
+    /// Permission bits of regular files
+    pub mode: Option<u32>,
+    pub uid: Option<u64>,
+    pub gid: Option<u64>,
+}
+
+fn parse_layer_mode(layer: &ImagePlanLayer) -> Result<Option<u32>, OciUploaderError> {
+    layer
+        .mode
+        .as_deref()
+        .map(|mode| u32::from_str_radix(mode, 8))
+        .transpose()
+        .map_err(|e| OciUploaderError(format!("Invalid mode for {}: {}", layer.source, e)))
+}
+
+fn read_tar_entry(source: &str, file_path: &Path, overrides: &EntryOverrides) -> TarEntry {
+    let metadata = fs::symlink_metadata(file_path).unwrap();
+    let mut header = Header::new_gnu();
+    header.set_metadata(&metadata);
+
+    if let Some(uid) = overrides.uid {
+        header.set_uid(uid);
+    }
+
+    if let Some(gid) = overrides.gid {
+        header.set_gid(gid);
+    }
+
+    let file_type = metadata.file_type();
+
+    if let (true, Some(mode)) = (file_type.is_file(), overrides.mode) {
+        header.set_mode(mode);
+    }
+
+    let link_target = if file_type.is_symlink() {
+        Some(fs::read_link(file_path).unwrap())
+    } else {
+        None
+    };
+    let data = if file_type.is_file() {
+        fs::read(file_path).unwrap()
+    } else {
+        Vec::new()
+    };
+
+    TarEntry {
+        header,
+        name: Path::new(overrides.target.trim_start_matches('/'))
+            .join(file_path.strip_prefix(source).unwrap()),
+        link_target,
+        data,
+    }
+}
+
+pub fn build_directory_tar(
+    source: &str,
+    whitelist: &[String],
+    blacklist: &[String],
+    overrides: &EntryOverrides,
+) -> Vec<u8> {
+    let whitelist_regexes: Vec<Regex> = whitelist.iter().map(|s| Regex::new(s).unwrap()).collect();
+    let blacklist_regexes: Vec<Regex> = blacklist.iter().map(|s| Regex::new(s).unwrap()).collect();
+    let files = walk_with_filters(source, &whitelist_regexes, &blacklist_regexes);
+
+    println!(
+        "Creating layer from directory: {} (collected {} files)",
+        source,
+        files.len()
+    );
+
+    let progress_bar = ProgressBar::new(files.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} files ({per_sec})")
+            .expect("Failed to set progress bar style")
+            .progress_chars("#>-"),
+    );
+
+    let mut tar_buffer = Vec::new();
+    let next_file = AtomicUsize::new(0);
+    let producers = num_cpus::get().min(files.len()).max(1);
+
+    thread::scope(|scope| {
+        // Bound the channel so that the producers cannot run too far ahead of the writer
+        let (sender, receiver) = mpsc::sync_channel::<(usize, TarEntry)>(producers * 64);
+
+        for _ in 0..producers {
+            let sender = sender.clone();
+            let files = &files;
+            let next_file = &next_file;
+
+            scope.spawn(move || loop {
+                let index = next_file.fetch_add(1, Ordering::Relaxed);
+
+                let Some(file_path) = files.get(index) else {
+                    break;
+                };
+
+                if sender
+                    .send((index, read_tar_entry(source, file_path, overrides)))
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+
+        drop(sender);
+
+        let mut tar_builder = Builder::new(&mut tar_buffer);
+        tar_builder.follow_symlinks(false);
+
+        // Entries arrive out of order, write them in walk order to keep the tar deterministic
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+
+        for (index, entry) in receiver {
+            pending.insert(index, entry);
+
+            while let Some(mut entry) = pending.remove(&next_index) {
+                match entry.link_target {
+                    Some(target) => tar_builder
+                        .append_link(&mut entry.header, &entry.name, target)
+                        .unwrap(),
+                    None => tar_builder
+                        .append_data(&mut entry.header, &entry.name, &entry.data[..])
+                        .unwrap(),
+                }
+
+                next_index += 1;
+                progress_bar.inc(1);
+            }
+        }
+
+        tar_builder.finish().unwrap();
+    });
+
+    progress_bar.finish_and_clear();
+    tar_buffer
+}
+
+/// Builds a tar containing a single file, placed at `target` with the given ownership
+pub fn build_file_tar(
+    source: &str,
+    target: &str,
+    mode: Option<u32>,
+    uid: u64,
+    gid: u64,
+) -> std::io::Result<Vec<u8>> {
+    let metadata = fs::metadata(source)?;
+    let data = fs::read(source)?;
+
+    println!("Creating layer from file: {} -> {}", source, target);
+
+    let mut header = Header::new_gnu();
+    header.set_metadata(&metadata);
+    header.set_uid(uid);
+    header.set_gid(gid);
+
+    if let Some(mode) = mode {
+        header.set_mode(mode);
+    }
+
+    let mut tar_buffer = Vec::new();
+
+    {
+        let mut tar_builder = Builder::new(&mut tar_buffer);
+        tar_builder.append_data(&mut header, target.trim_start_matches('/'), &data[..])?;
+        tar_builder.finish()?;
+    }
+
+    Ok(tar_buffer)
+}
+
+/// Checks the result of a smoke test run against its expected exit code and output
+pub fn check_smoke_test(test: &ImagePlanTest, output: &Output) -> Result<(), OciUploaderError> {
+    let combined_output = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    if output.status.code() != Some(test.exit_code) {
+        return Err(OciUploaderError(format!(
+            "Smoke test exited with {}, expected exit code {}:\n{}",
+            output.status, test.exit_code, combined_output
+        )));
+    }
+
+    if let Some(pattern) = &test.output {
+        let regex = Regex::new(pattern)
+            .map_err(|e| OciUploaderError(format!("Invalid smoke test output regex: {}", e)))?;
+
+        if !regex.is_match(&combined_output) {
+            return Err(OciUploaderError(format!(
+                "Smoke test output does not match {}:\n{}",
+                pattern, combined_output
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads an archive over HTTPS, verifying it against the expected sha256
+pub async fn download_url_archive(
+    url: &str,
+    expected_digest: &str,
+    settings: &RegistrySettings,
+) -> Result<Bytes, OciUploaderError> {
+    if !url.starts_with("https://") {
+        return Err(OciUploaderError(format!(
+            "Layer URL must use HTTPS: {}",
+            url
+        )));
+    }
+
+    println!("Downloading layer from URL: {}", url);
+
+    // Arbitrary servers are not registries, and get a client of their own
+    let response = settings
+        .with_proxy(reqwest::Client::builder())
+        .build()?
+        .get(url)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(OciUploaderError(format!(
+            "Failed to download {}: {}",
+            url,
+            response.status()
+        )));
+    }
+
+    let data = response.bytes().await?;
+    verify_sha256(&data, expected_digest)?;
+    Ok(data)
+}
+
+pub fn verify_sha256(data: &[u8], expected_digest: &str) -> Result<(), OciUploaderError> {
+    let expected_digest = format!(
+        "sha256:{}",
+        expected_digest
+            .trim_start_matches("sha256:")
+            .to_ascii_lowercase()
+    );
+    let digest = sha256_digest(data);
+
+    if digest != expected_digest {
+        return Err(OciUploaderError(format!(
+            "Digest mismatch: expected {}, got {}",
+            expected_digest, digest
+        )));
+    }
+
+    Ok(())
+}
+
+/// Unpacks a tar, tar.gz or tar.zst archive into a plain tar
+pub fn decompress_archive(data: &[u8]) -> Result<Vec<u8>, OciUploaderError> {
+    let media_type = detect_media_type(data).map_err(|e| OciUploaderError(e.to_string()))?;
+    let mut tar_buffer = Vec::new();
+
+    match media_type {
+        MediaType::OciImageLayerV1TarGzip => {
+            GzDecoder::new(data)
+                .read_to_end(&mut tar_buffer)
+                .map_err(|e| OciUploaderError(e.to_string()))?;
+        }
+        MediaType::OciImageLayerV1TarZstd => {
+            zstd::stream::Decoder::new(data)
+                .and_then(|mut decoder| decoder.read_to_end(&mut tar_buffer))
+                .map_err(|e| OciUploaderError(e.to_string()))?;
+        }
+        _ => tar_buffer.extend_from_slice(data),
+    }
+
+    Ok(tar_buffer)
+}
+
+pub fn compress_tar(tar_buffer: &[u8], compression: &CompressionOptions) -> (Vec<u8>, Digest) {
+    let started = Instant::now();
+    let uncompressed_digest = sha256_digest(tar_buffer);
+    let mut encoder = Encoder::new(Vec::new(), compression.level).unwrap();
+
+    // Enable multithreading
+    encoder.multithread(compression.threads.max(1)).unwrap();
+
+    encoder.write_all(tar_buffer).unwrap();
+    let compressed_data = encoder.finish().unwrap();
+    let compressed_digest = sha256_digest(&compressed_data);
+    profile::record(
+        Phase::Compression,
+        started.elapsed(),
+        tar_buffer.len() as u64,
+    );
+
+    println!(
+        "Compressing layer: {}, original size: {}, compressed size: {} ({:.2}% of original size)",
+        compressed_digest,
+        tar_buffer.len(),
+        compressed_data.len(),
+        (compressed_data.len() as f64 / tar_buffer.len() as f64) * 100.0
+    );
+
+    (
+        compressed_data,
+        Digest {
+            compressed_digest,
+            uncompressed_digest,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::enums::PlatformArchitecture;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_build_and_compress_directory_tar() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("hello.txt"), b"hello").unwrap();
+        fs::write(dir.path().join("skip.log"), b"skip").unwrap();
+
+        let source = dir.path().to_str().unwrap();
+        let tar_buffer = build_directory_tar(
+            source,
+            &[],
+            &[r"\.log$".to_string()],
+            &EntryOverrides::default(),
+        );
+        let compression = CompressionOptions {
+            level: 3,
+            threads: 2,
+            nice: None,
+            io_priority: None,
+        };
+        let (compressed, digest) = compress_tar(&tar_buffer, &compression);
+
+        assert_eq!(digest.uncompressed_digest, sha256_digest(&tar_buffer));
+        assert_eq!(digest.compressed_digest, sha256_digest(&compressed));
+
+        let mut decompressed = Vec::new();
+        zstd::stream::Decoder::new(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, tar_buffer);
+
+        let mut archive = tar::Archive::new(&tar_buffer[..]);
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, vec!["hello.txt".to_string()]);
+    }
+
+    #[test]
+    fn test_build_directory_tar_is_ordered() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().to_str().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+
+        for i in 0..200 {
+            fs::write(dir.path().join(format!("file{:03}", i)), i.to_string()).unwrap();
+        }
+
+        fs::write(dir.path().join("nested/inner"), b"inner").unwrap();
+        std::os::unix::fs::symlink("file000", dir.path().join("link")).unwrap();
+
+        let tar_buffer = build_directory_tar(source, &[], &[], &EntryOverrides::default());
+        assert_eq!(
+            tar_buffer,
+            build_directory_tar(source, &[], &[], &EntryOverrides::default())
+        );
+
+        let expected: Vec<PathBuf> = walk_with_filters(source, &vec![], &vec![])
+            .into_iter()
+            .map(|path| path.strip_prefix(source).unwrap().to_path_buf())
+            .collect();
+
+        let mut archive = tar::Archive::new(&tar_buffer[..]);
+        let mut names = vec![];
+
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_path_buf();
+
+            if name == Path::new("link") {
+                assert_eq!(
+                    entry.link_name().unwrap().unwrap().to_path_buf(),
+                    PathBuf::from("file000")
+                );
+            } else if name == Path::new("file042") {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, "42");
+            }
+
+            names.push(name);
+        }
+
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_build_file_tar() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("app");
+        fs::write(&source, b"binary").unwrap();
+
+        let tar_buffer = build_file_tar(
+            source.to_str().unwrap(),
+            "/usr/local/bin/app",
+            Some(0o755),
+            1000,
+            1001,
+        )
+        .unwrap();
+
+        let mut archive = tar::Archive::new(&tar_buffer[..]);
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+
+        assert_eq!(
+            entry.path().unwrap().to_path_buf(),
+            PathBuf::from("usr/local/bin/app")
+        );
+        assert_eq!(entry.header().mode().unwrap(), 0o755);
+        assert_eq!(entry.header().uid().unwrap(), 1000);
+        assert_eq!(entry.header().gid().unwrap(), 1001);
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"binary");
+        assert!(entries.next().is_none());
+    }
+
+    #[test]
+    fn test_decompress_archive() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("hello.txt"), b"hello").unwrap();
+        let tar_buffer = build_directory_tar(
+            dir.path().to_str().unwrap(),
+            &[],
+            &[],
+            &EntryOverrides::default(),
+        );
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&tar_buffer).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::stream::encode_all(&tar_buffer[..], 3).unwrap();
+
+        assert_eq!(decompress_archive(&tar_buffer).unwrap(), tar_buffer);
+        assert_eq!(decompress_archive(&gzip).unwrap(), tar_buffer);
+        assert_eq!(decompress_archive(&zstd).unwrap(), tar_buffer);
+        assert!(decompress_archive(b"not an archive").is_err());
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let digest = sha256_digest(b"release");
+
+        assert!(verify_sha256(b"release", &digest).is_ok());
+        assert!(verify_sha256(b"release", digest.trim_start_matches("sha256:")).is_ok());
+        assert!(verify_sha256(b"release", &digest.to_ascii_uppercase()[7..]).is_ok());
+        assert!(verify_sha256(b"tampered", &digest).is_err());
+    }
+
+    #[test]
+    fn test_build_directory_tar_overrides() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("bin")).unwrap();
+        fs::write(dir.path().join("bin/app"), b"app").unwrap();
+        std::os::unix::fs::symlink("bin/app", dir.path().join("app")).unwrap();
+
+        let overrides = EntryOverrides {
+            target: "/opt/app".to_string(),
+            mode: Some(0o750),
+            uid: Some(1000),
+            gid: Some(1000),
+        };
+        let tar_buffer = build_directory_tar(dir.path().to_str().unwrap(), &[], &[], &overrides);
+
+        let mut archive = tar::Archive::new(&tar_buffer[..]);
+        let entries: Vec<(PathBuf, u32, u64, u64)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                (
+                    entry.path().unwrap().to_path_buf(),
+                    header.mode().unwrap() & 0o7777,
+                    header.uid().unwrap(),
+                    header.gid().unwrap(),
+                )
+            })
+            .collect();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, PathBuf::from("opt/app/app"));
+        assert_ne!(entries[0].1, 0o750, "symlinks keep their own mode");
+        assert_eq!(
+            entries[1],
+            (PathBuf::from("opt/app/bin/app"), 0o750, 1000, 1000)
+        );
+        assert_eq!((entries[0].2, entries[0].3), (1000, 1000));
+    }
+
+    #[test]
+    fn test_invalid_trust_policy() {
+        let plan: ImagePlan = serde_json::from_str(
+            r#"{"name":"app","tags":["1.0"],"platforms":[],"trust":{"ghcr.io":{"level":"strict"}}}"#,
+        )
+        .unwrap();
+        let compression = CompressionOptions {
+            level: 3,
+            threads: 1,
+            nice: None,
+            io_priority: None,
+        };
+        let client = Arc::new(OciClient::new(Default::default(), None));
+
+        // A policy that cannot be enforced is an error, not a panic
+        let Err(error) =
+            PlanExecution::new(plan, ImagePlanLock::default(), client, true, compression, 1)
+        else {
+            panic!("A strict policy without certificates was accepted");
+        };
+        assert!(
+            error.to_string().contains("has no certificates"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_platform_build_created_at() {
+        let build_at = |created: OffsetDateTime| {
+            let config = ImageConfig {
+                created: Some(created),
+                author: None,
+                architecture: PlatformArchitecture::Amd64,
+                os: PlatformOS::Linux,
+                os_version: None,
+                os_features: None,
+                variant: None,
+                config: None,
+                rootfs: RootFs {
+                    fs_type: "layers".to_string(),
+                    diff_ids: vec!["sha256:aa".to_string()],
+                },
+                history: Some(vec![History {
+                    created: Some(created),
+                    created_by: Some("app".to_string()),
+                    author: None,
+                    comment: None,
+                    empty_layer: None,
+                }]),
+            };
+            let platform = Platform {
+                architecture: PlatformArchitecture::Amd64,
+                os: PlatformOS::Linux,
+                os_version: None,
+                os_features: None,
+                variant: None,
+                features: None,
+            };
+
+            PlatformBuild::new(vec![], &config, vec![], platform)
+        };
+        let pushed = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let now = OffsetDateTime::from_unix_timestamp(1_800_000_000).unwrap();
+
+        // Built again now, the image only matches the pushed one created at the same time
+        let build = build_at(now);
+        assert_ne!(build.manifest.digest, build_at(pushed).manifest.digest);
+        assert_eq!(
+            build.created_at(pushed).unwrap().digest,
+            build_at(pushed).manifest.digest
+        );
+        assert_eq!(build.created_at(now).unwrap().digest, build.manifest.digest);
+    }
+
+    #[test]
+    fn test_check_smoke_test() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let test = ImagePlanTest {
+            command: vec!["app".to_string(), "--version".to_string()],
+            exit_code: 0,
+            output: Some(r"^app \d+\.\d+".to_string()),
+        };
+        let output = |code: i32, stdout: &str| Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: vec![],
+        };
+
+        assert!(check_smoke_test(&test, &output(0, "app 1.2.3\n")).is_ok());
+        assert!(check_smoke_test(&test, &output(1, "app 1.2.3\n")).is_err());
+        assert!(check_smoke_test(&test, &output(0, "command not found\n")).is_err());
     }
 }
@@ -0,0 +1,81 @@
+use std::{error::Error, fs, path::Path, sync::Arc};
+
+use tar::Builder;
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    parser::FullImageWithTag,
+    report::Context,
+    Extract,
+};
+
+/// Whether the output should be written as a tarball rather than a directory
+fn is_tarball(output: &Path) -> bool {
+    output
+        .extension()
+        .is_some_and(|extension| extension == "tar")
+}
+
+/// Writes an extracted root filesystem into a tarball, keeping symlinks as they are
+fn write_tarball(root: &Path, output: &Path) -> Result<(), Box<dyn Error>> {
+    let mut builder = Builder::new(fs::File::create(output)?);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(".", root)?;
+    builder.finish()?;
+
+    Ok(())
+}
+
+pub async fn extract_command(
+    args: &Extract,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let tarball = is_tarball(&args.output);
+
+    if !tarball && args.output.exists() && fs::read_dir(&args.output)?.next().is_some() {
+        return Err(format!("{} is not empty", args.output.display()).into());
+    }
+
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let (manifest, _) = downloader
+        .download_image(image.clone(), args.platform.as_deref())
+        .await
+        .with_context(|| format!("fetching {}", args.image))?;
+
+    if tarball {
+        let root = tempfile::tempdir()?;
+
+        downloader
+            .extract_image(image.image, &manifest, &root.path().to_path_buf())
+            .await
+            .with_context(|| format!("extracting {}", args.image))?;
+        write_tarball(root.path(), &args.output)
+            .with_context(|| format!("writing {}", args.output.display()))?;
+    } else {
+        fs::create_dir_all(&args.output)?;
+        downloader
+            .extract_image(image.image, &manifest, &args.output)
+            .await
+            .with_context(|| format!("extracting {}", args.image))?;
+    }
+
+    println!(
+        "Extracted {} layers of {} to {}",
+        manifest.layers.len(),
+        args.image,
+        args.output.display()
+    );
+
+    Ok(())
+}
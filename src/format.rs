@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether sizes are printed as exact byte counts, for scripts parsing the output
+static RAW_SIZES: AtomicBool = AtomicBool::new(false);
+
+pub fn set_raw_sizes(raw: bool) {
+    RAW_SIZES.store(raw, Ordering::Relaxed);
+}
+
+pub fn raw_sizes() -> bool {
+    RAW_SIZES.load(Ordering::Relaxed)
+}
+
+/// Whether progress is reported as lines of JSON on stderr, for scripts and
+/// CI systems following a command, instead of text
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Sets how progress is reported, as text or json
+pub fn set_progress_format(format: &str) -> Result<(), String> {
+    let json = match format {
+        "text" => false,
+        "json" => true,
+        format => return Err(format!("Unknown progress format: {}", format)),
+    };
+
+    JSON_PROGRESS.store(json, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn json_progress() -> bool {
+    JSON_PROGRESS.load(Ordering::Relaxed)
+}
+
+/// Reports an event, such as {"event":"rateLimit",...}, as a line of JSON
+/// on stderr
+pub fn progress_event(event: serde_json::Value) {
+    eprintln!("{}", event);
+}
+
+fn format_size_with(bytes: u64, raw: bool) -> String {
+    if raw {
+        bytes.to_string()
+    } else {
+        humansize::format_size(bytes, humansize::BINARY)
+    }
+}
+
+/// Formats a byte count with binary units (KiB, MiB, ...), or as the
+/// exact number of bytes when `--raw-sizes` is set
+pub fn format_size(bytes: u64) -> String {
+    format_size_with(bytes, raw_sizes())
+}
+
+/// Parses a size in bytes, or with a k, m or g suffix
+pub fn parse_size(size: &str) -> Option<u64> {
+    let lower = size.to_lowercase();
+    let (number, unit) = match lower.strip_suffix(['k', 'm', 'g']) {
+        Some(number) => (number, lower.chars().last()?),
+        None => (lower.as_str(), 'b'),
+    };
+    let shift = match unit {
+        'k' => 10,
+        'm' => 20,
+        'g' => 30,
+        _ => 0,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parses a duration in seconds, or with an s, m, h or d suffix, such as 7d
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let (number, unit) = match duration.strip_suffix(['s', 'm', 'h', 'd']) {
+        Some(number) => (number, duration.chars().last()?),
+        None => (duration, 's'),
+    };
+    let seconds = match unit {
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => 1,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(seconds)
+}
+
+/// Formats a duration as its two most significant units, such as 3m 20s or 2d 4h
+pub fn format_duration(seconds: u64) -> String {
+    const UNITS: [(u64, &str); 4] = [(86400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+
+    let parts: Vec<String> = UNITS
+        .iter()
+        .scan(seconds, |remaining, &(unit, suffix)| {
+            let value = *remaining / unit;
+            *remaining %= unit;
+            Some((value, suffix))
+        })
+        .skip_while(|(value, _)| *value == 0)
+        .take(2)
+        .filter(|(value, _)| *value != 0)
+        .map(|(value, suffix)| format!("{}{}", value, suffix))
+        .collect();
+
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size_with(512, false), "512 B");
+        assert_eq!(format_size_with(1536, false), "1.50 KiB");
+        assert_eq!(format_size_with(1536, true), "1536");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(200), "3m 20s");
+        assert_eq!(format_duration(3600), "1h");
+        assert_eq!(format_duration(3 * 86400 + 4 * 3600 + 59), "3d 4h");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("12h"), Some(12 * 3600));
+        assert_eq!(parse_duration("7d"), Some(7 * 86400));
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("1w"), None);
+    }
+}
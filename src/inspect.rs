@@ -0,0 +1,124 @@
+use std::{
+    error::Error,
+    io::{self, Write},
+    slice,
+    sync::Arc,
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::{is_index_content_type, OciDownloader},
+    parser::FullImageWithTag,
+    platform::{platform_requested, PlatformMatcher},
+    report::Context,
+    spec::index::ImageIndex,
+    Inspect,
+};
+
+pub async fn inspect_command(
+    args: &Inspect,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    if args.manifest && args.config {
+        return Err("--manifest and --config cannot be used together".into());
+    }
+
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+
+    let (content_type, data) = downloader
+        .download_manifest_raw(image.clone())
+        .await
+        .with_context(|| format!("fetching {}", args.image))?;
+    let digest = sha256_digest(&data);
+
+    if args.raw && !args.manifest && !args.config {
+        io::stdout().write_all(&data)?;
+        return Ok(());
+    }
+
+    // Resolve the index down to the manifest of a single platform
+    let (index, manifest_data) = if is_index_content_type(&content_type) {
+        let index: ImageIndex = serde_json::from_slice(&data)?;
+        let platform_matcher = PlatformMatcher::new();
+        let manifest = match &args.platform {
+            Some(platform) => index.manifests.iter().find(|manifest| {
+                manifest.platform.as_ref().is_some_and(|manifest_platform| {
+                    platform_requested(manifest_platform, slice::from_ref(platform))
+                })
+            }),
+            None => platform_matcher.find_manifest(&index.manifests),
+        }
+        .ok_or("No matching platform found")?;
+
+        let (_, manifest_data) = downloader
+            .download_manifest_raw(FullImageWithTag {
+                image: image.image.clone(),
+                tag: manifest.digest.clone(),
+            })
+            .await
+            .with_context(|| format!("fetching manifest {}", manifest.digest))?;
+
+        (Some(data), manifest_data)
+    } else {
+        (None, data)
+    };
+
+    if args.manifest {
+        return print_document(&manifest_data, args.raw);
+    }
+
+    let manifest: Value = serde_json::from_slice(&manifest_data)?;
+    let config_digest = manifest["config"]["digest"]
+        .as_str()
+        .ok_or("Manifest has no config")?;
+    let config_data = downloader
+        .download_layer(image.image.clone(), config_digest)
+        .await
+        .with_context(|| format!("fetching config {}", config_digest))?;
+
+    if args.config {
+        return print_document(&config_data, args.raw);
+    }
+
+    let index = index
+        .map(|index| serde_json::from_slice::<Value>(&index))
+        .transpose()?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&json!({
+            "name": args.image,
+            "digest": digest,
+            "index": index,
+            "manifest": manifest,
+            "config": serde_json::from_slice::<Value>(&config_data)?,
+        }))?
+    );
+
+    Ok(())
+}
+
+/// Prints a document either as the exact bytes served, or as pretty JSON
+fn print_document(data: &[u8], raw: bool) -> Result<(), Box<dyn Error>> {
+    if raw {
+        io::stdout().write_all(data)?;
+    } else {
+        let document: Value = serde_json::from_slice(data)?;
+        println!("{}", serde_json::to_string_pretty(&document)?);
+    }
+
+    Ok(())
+}
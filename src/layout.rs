@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::spec::{
+    enums::MediaType,
+    index::{ImageIndex, Manifest},
+};
+
+/// The annotation an image layout names its images with
+pub const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Where an image is written instead of a registry
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    /// An image layout directory, with the name to give the image in it
+    Layout(PathBuf, Option<String>),
+    /// A docker save tarball, with the reference to tag the image with
+    DockerArchive(PathBuf, Option<String>),
+}
+
+/// Parses an `oci:<dir>[:<name>]` or `docker-archive:<tar>[:<reference>]`
+/// output, like skopeo takes them
+pub fn parse_output(output: &str) -> Result<Output, String> {
+    let unsupported = || {
+        format!(
+            "Unsupported output {}, expected oci:<dir>[:<name>] or docker-archive:<tar>[:<reference>]",
+            output
+        )
+    };
+    let (scheme, path) = output.split_once(':').ok_or_else(unsupported)?;
+    let (path, name) = match path.split_once(':') {
+        Some((path, name)) => (path, Some(name.to_string())),
+        None => (path, None),
+    };
+
+    if path.is_empty() || name.as_deref() == Some("") {
+        return Err(format!("Invalid output {}", output));
+    }
+
+    match scheme {
+        "oci" => Ok(Output::Layout(PathBuf::from(path), name)),
+        "docker-archive" => Ok(Output::DockerArchive(PathBuf::from(path), name)),
+        _ => Err(unsupported()),
+    }
+}
+
+/// An OCI image layout directory, written instead of a registry so that
+/// images can be archived or moved to hosts without registry access
+pub struct OciLayout {
+    root: PathBuf,
+    /// Serializes the read-modify-write cycles of index.json
+    index_lock: Mutex<()>,
+}
+
+impl OciLayout {
+    /// Points at an image layout, which is only created once something is
+    /// written into it
+    pub fn new(root: &Path) -> Self {
+        OciLayout {
+            root: root.to_path_buf(),
+            index_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, digest: &str) -> io::Result<PathBuf> {
+        match digest.split_once(':') {
+            Some((algorithm, hex))
+                if !algorithm.is_empty()
+                    && !hex.is_empty()
+                    && hex.chars().all(|c| c.is_ascii_alphanumeric())
+                    && algorithm.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                Ok(self.root.join("blobs").join(algorithm).join(hex))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid digest {}", digest),
+            )),
+        }
+    }
+
+    pub fn has_blob(&self, digest: &str) -> io::Result<bool> {
+        Ok(self.blob_path(digest)?.exists())
+    }
+
+    /// Writes a blob through a temporary file, so that an interrupted export
+    /// never leaves a truncated blob behind under its digest
+    pub fn write_blob(&self, digest: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.blob_path(digest)?;
+
+        if path.exists() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let partial = path.with_extension(format!("partial-{}", std::process::id()));
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)
+    }
+
+    pub fn read_blob(&self, digest: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.blob_path(digest)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_index(&self) -> io::Result<ImageIndex> {
+        let data = match fs::read(self.root.join("index.json")) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(ImageIndex {
+                    schema_version: 2,
+                    media_type: MediaType::OciImageIndexV1Json,
+                    artifact_type: None,
+                    manifests: vec![],
+                    annotations: None,
+                })
+            }
+            Err(e) => return Err(e),
+        };
+
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_index(&self, index: &ImageIndex) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+
+        let layout_file = self.root.join("oci-layout");
+
+        if !layout_file.exists() {
+            fs::write(&layout_file, r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        }
+
+        let partial = self.root.join("index.json.partial");
+        fs::write(&partial, index.to_json())?;
+        fs::rename(&partial, self.root.join("index.json"))
+    }
+
+    /// Lists the manifests of index.json, named or not
+    pub fn manifests(&self) -> io::Result<Vec<Manifest>> {
+        let _guard = self.index_lock.lock().unwrap();
+        Ok(self.read_index()?.manifests)
+    }
+
+    /// Finds the manifest a name points at in index.json
+    pub fn find(&self, name: &str) -> io::Result<Option<Manifest>> {
+        let _guard = self.index_lock.lock().unwrap();
+
+        Ok(self
+            .read_index()?
+            .manifests
+            .into_iter()
+            .find(|manifest| ref_name(manifest) == Some(name)))
+    }
+
+    /// Points a name at a manifest in index.json, replacing the manifest the
+    /// name pointed at before
+    pub fn tag(&self, name: &str, mut manifest: Manifest) -> io::Result<()> {
+        let _guard = self.index_lock.lock().unwrap();
+        let mut index = self.read_index()?;
+
+        manifest
+            .annotations
+            .get_or_insert_with(HashMap::new)
+            .insert(REF_NAME_ANNOTATION.to_string(), name.to_string());
+        index
+            .manifests
+            .retain(|existing| ref_name(existing) != Some(name));
+        index.manifests.push(manifest);
+
+        self.write_index(&index)
+    }
+}
+
+/// The name an image layout gives a manifest, if any
+pub fn ref_name(manifest: &Manifest) -> Option<&str> {
+    manifest
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(REF_NAME_ANNOTATION))
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_digest;
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(
+            parse_output("oci:./out"),
+            Ok(Output::Layout(PathBuf::from("./out"), None))
+        );
+        assert_eq!(
+            parse_output("oci:/srv/images:alpine-3"),
+            Ok(Output::Layout(
+                PathBuf::from("/srv/images"),
+                Some("alpine-3".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_output("docker-archive:out.tar:alpine:3"),
+            Ok(Output::DockerArchive(
+                PathBuf::from("out.tar"),
+                Some("alpine:3".to_string())
+            ))
+        );
+        assert!(parse_output("oci:").is_err());
+        assert!(parse_output("oci:out:").is_err());
+        assert!(parse_output("dir:out").is_err());
+        assert!(parse_output("./out").is_err());
+    }
+
+    #[test]
+    fn test_layout() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let layout = OciLayout::new(&dir.path().join("layout"));
+        assert!(layout.find("latest")?.is_none());
+        let data = br#"{"schemaVersion":2}"#;
+        let digest = sha256_digest(data);
+        let manifest = |size| Manifest {
+            media_type: MediaType::OciImageManifestV1Json,
+            size,
+            digest: digest.clone(),
+            platform: None,
+            artifact_type: None,
+            annotations: None,
+        };
+
+        layout.write_blob(&digest, data)?;
+        layout.tag("latest", manifest(1))?;
+        layout.tag("latest", manifest(data.len() as u64))?;
+
+        assert!(dir.path().join("layout/oci-layout").exists());
+        assert_eq!(layout.read_blob(&digest)?, Some(data.to_vec()));
+        assert!(layout.write_blob("sha256:../../etc", b"").is_err());
+
+        let index = layout.read_index()?;
+        assert_eq!(index.manifests.len(), 1);
+        assert_eq!(index.manifests[0].size, data.len() as u64);
+        assert_eq!(
+            layout.find("latest")?.map(|manifest| manifest.digest),
+            Some(digest.clone())
+        );
+        assert!(layout.find("edge")?.is_none());
+
+        Ok(())
+    }
+}
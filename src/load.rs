@@ -0,0 +1,275 @@
+use std::{collections::HashSet, error::Error, io, slice, sync::Arc};
+
+use bytes::Bytes;
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::sync::Mutex;
+
+use crate::{
+    compose::{
+        lease::LeasedClient,
+        pull::{
+            containerd_utils::{
+                create_image_in_containerd, get_existing_content_from_containerd,
+                upload_content_to_containerd,
+            },
+            index_labels, manifest_labels, source_labels,
+            unpack::{unpack_image, UnpackTarget, DEFAULT_SNAPSHOTTER},
+        },
+    },
+    digest::sha256_digest,
+    downloader::{is_index_content_type, write_layer_to_containerd, IndexResponse, LayerProgress},
+    format::raw_sizes,
+    local::LocalImage,
+    parser::FullImageWithTag,
+    platform::{platform_requested, PlatformMatcher},
+    registry_config::RegistrySettings,
+    report::Context,
+    spec::{config::ImageConfig, index::ImageIndex, manifest::ImageManifest},
+    Load,
+};
+
+/// The reference an image of a tarball is loaded as. docker save names
+/// images in full, while OCI layouts often name them by tag alone
+fn image_reference(
+    name: &str,
+    full_name: Option<String>,
+    repository: Option<&str>,
+) -> Option<String> {
+    if full_name.is_some() {
+        return full_name;
+    }
+
+    if name.contains(':') || name.contains('/') {
+        return Some(name.to_string());
+    }
+
+    repository.map(|repository| format!("{}:{}", repository, name))
+}
+
+fn layer_progress_bar(total: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(total);
+    let template = if raw_sizes() {
+        "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}"
+    } else {
+        "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes}"
+    };
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template(template)
+            .expect("Failed to set progress bar style")
+            .progress_chars("#>-"),
+    );
+    progress_bar
+}
+
+/// Writes an image into the content store, layers first so that nothing
+/// refers to missing content, and points the image record at it. Returns
+/// what is left to unpack
+async fn load_image(
+    client: &Arc<LeasedClient>,
+    local: &LocalImage,
+    name: &str,
+    image: &FullImageWithTag,
+    platform: Option<&String>,
+    existing: &HashSet<String>,
+) -> Result<UnpackTarget, Box<dyn Error>> {
+    let (content_type, data) = local.manifest(name)?;
+    let digest = sha256_digest(&data);
+    let mut index = None;
+    let mut manifest_data = data.clone();
+    let mut manifest_digest = digest.clone();
+
+    // A multi-platform image only brings the platform that is run here
+    if is_index_content_type(&content_type) {
+        let image_index: ImageIndex = serde_json::from_slice(&data)?;
+        let platform_matcher = PlatformMatcher::new();
+        let manifest = match platform {
+            None => platform_matcher.find_manifest(&image_index.manifests),
+            Some(platform) => image_index.manifests.iter().find(|manifest| {
+                manifest.platform.as_ref().is_some_and(|manifest_platform| {
+                    platform_requested(manifest_platform, slice::from_ref(platform))
+                })
+            }),
+        }
+        .ok_or("No platform of the image matches, pick one with --platform")?;
+
+        manifest_digest = manifest.digest.clone();
+        manifest_data = local.blob(&manifest_digest)?;
+        index = Some(image_index);
+    }
+
+    let manifest: ImageManifest = serde_json::from_slice(&manifest_data)?;
+    let config_data = local.blob(&manifest.config.digest)?;
+    let config: ImageConfig = serde_json::from_slice(&config_data)?;
+    let diff_ids = config.rootfs.diff_ids;
+
+    if diff_ids.len() != manifest.layers.len() {
+        return Err(format!(
+            "The config of {} lists {} layers, but its manifest {}",
+            name,
+            diff_ids.len(),
+            manifest.layers.len()
+        )
+        .into());
+    }
+
+    let progress = LayerProgress {
+        progress_bar: layer_progress_bar(manifest.layers.iter().map(|layer| layer.size).sum()),
+        spinner: None,
+        downloaded_bytes: Arc::new(Mutex::new(0)),
+    };
+    progress.progress_bar.set_message(name.to_string());
+
+    for (layer, diff_id) in manifest.layers.iter().zip(&diff_ids) {
+        if existing.contains(&layer.digest) {
+            progress.progress_bar.inc(layer.size);
+            continue;
+        }
+
+        let data = Bytes::from(local.blob(&layer.digest)?);
+        write_layer_to_containerd(
+            client.clone(),
+            futures::stream::iter([Ok::<_, io::Error>(data)]),
+            &image.image,
+            &layer.digest,
+            diff_id,
+            layer.size,
+            &progress,
+        )
+        .await?;
+    }
+
+    progress.progress_bar.finish_and_clear();
+
+    let mut blobs = vec![
+        (
+            manifest.config.digest.clone(),
+            config_data,
+            source_labels(image),
+        ),
+        (
+            manifest_digest,
+            manifest_data,
+            manifest_labels(image, &manifest),
+        ),
+    ];
+
+    if let Some(index) = index {
+        let labels = index_labels(image, &IndexResponse::ImageIndex(index));
+        blobs.push((digest.clone(), data.clone(), labels));
+    }
+
+    for (digest, data, labels) in blobs {
+        if !existing.contains(&digest) {
+            upload_content_to_containerd(client.clone(), &digest, data, labels).await?;
+        }
+    }
+
+    create_image_in_containerd(
+        client.clone(),
+        image,
+        digest,
+        data.len() as i64,
+        content_type,
+    )
+    .await?;
+
+    Ok(UnpackTarget {
+        full_image: image.clone(),
+        config_digest: manifest.config.digest,
+        layers: manifest.layers,
+        diff_ids,
+    })
+}
+
+pub async fn load_command(
+    args: &Load,
+    dry_run: bool,
+    settings: &RegistrySettings,
+) -> Result<(), Box<dyn Error>> {
+    let local = LocalImage::open_archive(&args.input)
+        .with_context(|| format!("opening {}", args.input.display()))?;
+    let names = local.names()?;
+
+    if names.is_empty() {
+        return Err(format!("{} has no named images to load", args.input.display()).into());
+    }
+
+    let mut images = vec![];
+
+    for name in names {
+        let reference = image_reference(&name, local.full_name(&name)?, args.repository.as_deref())
+            .ok_or_else(|| {
+                format!(
+                    "{} is only named by its tag, give its repository with --repository",
+                    name
+                )
+            })?;
+        images.push((name, FullImageWithTag::parse(&reference, settings)?));
+    }
+
+    if dry_run {
+        for (name, image) in &images {
+            println!("Would load {} as {}", name, image.canonical_name());
+        }
+
+        return Ok(());
+    }
+
+    let socket_path = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| "/run/containerd/containerd.sock".into());
+    let snapshotter = args.snapshotter.as_deref().unwrap_or(DEFAULT_SNAPSHOTTER);
+    let client = Arc::new(
+        LeasedClient::with_path("default".to_string(), socket_path.to_str().unwrap()).await?,
+    );
+    let existing: HashSet<String> = get_existing_content_from_containerd(client.clone())
+        .await?
+        .into_keys()
+        .collect();
+
+    for (name, image) in &images {
+        let target = load_image(
+            &client,
+            &local,
+            name,
+            image,
+            args.platform.as_ref(),
+            &existing,
+        )
+        .await
+        .with_context(|| format!("loading {}", name))?;
+
+        unpack_image(client.clone(), snapshotter, &target)
+            .await
+            .with_context(|| format!("unpacking {}", name))?;
+
+        println!("Loaded {} as {}", name, image.canonical_name());
+    }
+
+    client.delete_lease().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_reference() {
+        assert_eq!(
+            image_reference("alpine:3.20", None, None).as_deref(),
+            Some("alpine:3.20")
+        );
+        assert_eq!(
+            image_reference("3.20", Some("docker.io/library/alpine:3.20".into()), None).as_deref(),
+            Some("docker.io/library/alpine:3.20")
+        );
+        assert_eq!(
+            image_reference("edge", None, Some("ghcr.io/example/app")).as_deref(),
+            Some("ghcr.io/example/app:edge")
+        );
+        assert_eq!(image_reference("edge", None, None), None);
+    }
+}
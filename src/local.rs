@@ -0,0 +1,708 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tar::{Archive, EntryType};
+
+use crate::{
+    archive::detect_media_type,
+    cat::normalize,
+    digest::sha256_digest,
+    layout::{ref_name, OciLayout},
+    parser::{FullImage, FullImageWithTag},
+    spec::{
+        enums::MediaType,
+        index::Manifest,
+        manifest::{Descriptor, ImageManifest},
+    },
+};
+
+/// The annotation buildkit names an image of an OCI layout with in full, next
+/// to the tag in the ref name annotation
+pub const CONTAINERD_NAME_ANNOTATION: &str = "io.containerd.image.name";
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn missing_blob(input: impl std::fmt::Display, digest: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} has no blob {}", input, digest),
+    )
+}
+
+/// Reads a file of a tarball by its position, or only its first bytes
+fn read_range(path: &Path, offset: u64, size: u64, limit: Option<u64>) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::new();
+    file.take(limit.unwrap_or(size).min(size))
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// An image entry of the manifest.json of a docker save tarball, read here
+/// and written for docker-archive outputs. Untagged images have null tags
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchiveImage {
+    pub config: String,
+    pub repo_tags: Option<Vec<String>>,
+    pub layers: Vec<String>,
+}
+
+/// Just enough of a manifest or index to tell them apart
+#[derive(Deserialize)]
+struct MediaTypeOnly {
+    #[serde(rename = "mediaType")]
+    media_type: Option<String>,
+    manifests: Option<serde_json::Value>,
+}
+
+/// The index.json of a layout, whose media type older podman releases leave out
+#[derive(Deserialize)]
+struct IndexJson {
+    manifests: Vec<Manifest>,
+}
+
+/// A tarball written by docker save or podman save. It carries configs and
+/// layers, but no manifests, so a manifest is made up for every image
+struct DockerArchive {
+    path: PathBuf,
+    /// Where every file of the tarball lies, as offset and size, by digest
+    blobs: HashMap<String, (u64, u64)>,
+    /// The made up manifests, by digest
+    manifests: HashMap<String, Vec<u8>>,
+    /// The names of every image with the digest of its manifest, in order
+    images: Vec<(Vec<String>, String)>,
+}
+
+impl DockerArchive {
+    fn open(path: &Path) -> io::Result<DockerArchive> {
+        let mut files: HashMap<PathBuf, (String, u64, u64)> = HashMap::new();
+        let mut links: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut manifest_json = None;
+
+        for entry in Archive::new(File::open(path)?).entries()? {
+            let mut entry = entry?;
+            let entry_path = normalize(&entry.path()?);
+
+            match entry.header().entry_type() {
+                EntryType::Symlink => {
+                    let target = entry.link_name()?.unwrap_or_default();
+                    let parent = entry_path.parent().unwrap_or(Path::new(""));
+                    links.insert(entry_path.clone(), normalize(&parent.join(target)));
+                }
+                EntryType::Link => {
+                    let target = normalize(&entry.link_name()?.unwrap_or_default());
+                    links.insert(entry_path, target);
+                }
+                EntryType::Regular | EntryType::Continuous => {
+                    if entry_path == Path::new("manifest.json") {
+                        let mut data = Vec::new();
+                        entry.read_to_end(&mut data)?;
+                        manifest_json = Some(data);
+                        continue;
+                    }
+
+                    let position = (entry.raw_file_position(), entry.size());
+                    let mut hasher = Sha256::new();
+                    io::copy(&mut entry, &mut hasher)?;
+                    let digest = format!("sha256:{:x}", hasher.finalize());
+
+                    files.insert(entry_path, (digest, position.0, position.1));
+                }
+                _ => {}
+            }
+        }
+
+        let manifest_json = manifest_json.ok_or_else(|| {
+            invalid_input(format!(
+                "{} has no manifest.json, it is not a docker-archive",
+                path.display()
+            ))
+        })?;
+        let entries: Vec<ArchiveImage> = serde_json::from_slice(&manifest_json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut archive = DockerArchive {
+            path: path.to_path_buf(),
+            blobs: HashMap::new(),
+            manifests: HashMap::new(),
+            images: vec![],
+        };
+
+        for (digest, offset, size) in files.values() {
+            archive.blobs.insert(digest.clone(), (*offset, *size));
+        }
+
+        let resolve = |entry_path: &str| -> io::Result<(String, u64)> {
+            let mut entry_path = normalize(Path::new(entry_path));
+
+            // Layers shared between images are links, bounded in case they loop
+            for _ in 0..links.len() {
+                match links.get(&entry_path) {
+                    Some(target) => entry_path = target.clone(),
+                    None => break,
+                }
+            }
+
+            files
+                .get(&entry_path)
+                .map(|(digest, _, size)| (digest.clone(), *size))
+                .ok_or_else(|| {
+                    invalid_input(format!(
+                        "{} is missing {}",
+                        path.display(),
+                        entry_path.display()
+                    ))
+                })
+        };
+
+        for entry in entries {
+            let (config_digest, config_size) = resolve(&entry.config)?;
+            let mut layers = vec![];
+
+            for layer in &entry.layers {
+                let (digest, size) = resolve(layer)?;
+
+                // Docker and podman write uncompressed layers, but nothing forbids compressed ones
+                let head = archive.read_blob(&digest, Some(512))?;
+                let media_type = match detect_media_type(&head) {
+                    Ok(MediaType::OciImageLayerV1TarGzip) => MediaType::OciImageLayerV1TarGzip,
+                    Ok(MediaType::OciImageLayerV1TarZstd) => MediaType::OciImageLayerV1TarZstd,
+                    _ => MediaType::OciImageLayerV1Tar,
+                };
+
+                layers.push(Descriptor {
+                    media_type,
+                    digest,
+                    size,
+                    data: None,
+                    urls: None,
+                    annotations: None,
+                });
+            }
+
+            let manifest = ImageManifest {
+                schema_version: 2,
+                media_type: MediaType::OciImageManifestV1Json,
+                artifact_type: None,
+                config: Descriptor {
+                    media_type: MediaType::OciImageConfigV1ConfigJson,
+                    digest: config_digest,
+                    size: config_size,
+                    data: None,
+                    urls: None,
+                    annotations: None,
+                },
+                layers,
+                subject: None,
+                annotations: None,
+            }
+            .to_json();
+            let digest = sha256_digest(&manifest);
+
+            archive.manifests.insert(digest.clone(), manifest);
+            archive
+                .images
+                .push((entry.repo_tags.unwrap_or_default(), digest));
+        }
+
+        Ok(archive)
+    }
+
+    /// Reads a blob, or only its first bytes
+    fn read_blob(&self, digest: &str, limit: Option<u64>) -> io::Result<Vec<u8>> {
+        if let Some(manifest) = self.manifests.get(digest) {
+            return Ok(manifest.clone());
+        }
+
+        let (offset, size) = self
+            .blobs
+            .get(digest)
+            .ok_or_else(|| missing_blob(self.path.display(), digest))?;
+
+        read_range(&self.path, *offset, *size, limit)
+    }
+}
+
+/// An OCI image layout packed into a tarball, as buildkit writes with
+/// --output type=oci and podman with oci-archive
+struct OciArchive {
+    path: PathBuf,
+    /// Where every blob of the tarball lies, as offset and size, by digest
+    blobs: HashMap<String, (u64, u64)>,
+    /// The manifests of index.json
+    manifests: Vec<Manifest>,
+}
+
+impl OciArchive {
+    fn open(path: &Path) -> io::Result<OciArchive> {
+        let mut blobs = HashMap::new();
+        let mut index_json = None;
+
+        for entry in Archive::new(File::open(path)?).entries()? {
+            let mut entry = entry?;
+            let entry_path = normalize(&entry.path()?);
+
+            if !matches!(
+                entry.header().entry_type(),
+                EntryType::Regular | EntryType::Continuous
+            ) {
+                continue;
+            }
+
+            if entry_path == Path::new("index.json") {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                index_json = Some(data);
+                continue;
+            }
+
+            // Blobs are stored as blobs/<algorithm>/<hex>
+            let components: Vec<_> = entry_path.iter().filter_map(|c| c.to_str()).collect();
+
+            if let ["blobs", algorithm, hex] = components[..] {
+                blobs.insert(
+                    format!("{}:{}", algorithm, hex),
+                    (entry.raw_file_position(), entry.size()),
+                );
+            }
+        }
+
+        let index_json = index_json.ok_or_else(|| {
+            invalid_input(format!(
+                "{} has no index.json, it is not an oci-archive",
+                path.display()
+            ))
+        })?;
+        let index: IndexJson = serde_json::from_slice(&index_json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(OciArchive {
+            path: path.to_path_buf(),
+            blobs,
+            manifests: index.manifests,
+        })
+    }
+
+    fn read_blob(&self, digest: &str) -> io::Result<Vec<u8>> {
+        let (offset, size) = self
+            .blobs
+            .get(digest)
+            .ok_or_else(|| missing_blob(self.path.display(), digest))?;
+
+        read_range(&self.path, *offset, *size, None)
+    }
+}
+
+/// Tells the tarballs of docker save apart from packed OCI layouts. Docker
+/// 25 and later write both, and their manifest.json names images in full,
+/// so it wins
+fn archive_scheme(path: &Path) -> io::Result<&'static str> {
+    let mut oci_layout = false;
+
+    for entry in Archive::new(File::open(path)?).entries()? {
+        let entry_path = normalize(&entry?.path()?);
+
+        if entry_path == Path::new("manifest.json") {
+            return Ok("docker-archive");
+        }
+
+        oci_layout |= entry_path == Path::new("oci-layout");
+    }
+
+    if oci_layout {
+        Ok("oci-archive")
+    } else {
+        Err(invalid_input(format!(
+            "{} is neither a docker-archive nor an oci-archive",
+            path.display()
+        )))
+    }
+}
+
+enum Source {
+    Layout(OciLayout),
+    OciArchive(OciArchive),
+    DockerArchive(DockerArchive),
+}
+
+impl Source {
+    /// The manifests of index.json, for the sources that have one
+    fn index(&self) -> io::Result<Option<Vec<Manifest>>> {
+        match self {
+            Source::Layout(layout) => layout.manifests().map(Some),
+            Source::OciArchive(archive) => Ok(Some(archive.manifests.clone())),
+            Source::DockerArchive(_) => Ok(None),
+        }
+    }
+
+    /// Finds the manifest a name points at in index.json
+    fn find(&self, name: &str) -> io::Result<Option<Manifest>> {
+        Ok(self.index()?.and_then(|manifests| {
+            manifests
+                .into_iter()
+                .find(|manifest| ref_name(manifest) == Some(name))
+        }))
+    }
+}
+
+/// An image read from disk instead of a registry: an OCI image layout, as
+/// buildkit and podman export them, packed into a tarball or not, or a
+/// docker save tarball
+pub struct LocalImage {
+    input: String,
+    source: Source,
+    /// The name or manifest digest of the image the input points at
+    tag: String,
+    /// Whether the input named the image, or it was the only one
+    named: bool,
+}
+
+impl LocalImage {
+    /// Opens an `oci:<dir>[:<name>]`, `oci-archive:<tar>[:<name>]` or
+    /// `docker-archive:<tar>[:<name>]` input. Anything else is a registry
+    /// image, and gives None
+    pub fn open(input: &str) -> io::Result<Option<LocalImage>> {
+        let (scheme, path) = match input.split_once(':') {
+            Some((scheme @ ("oci" | "oci-archive" | "docker-archive"), path)) => (scheme, path),
+            _ => return Ok(None),
+        };
+        let (path, name) = match path.split_once(':') {
+            Some((path, name)) => (path, Some(name.to_string())),
+            None => (path, None),
+        };
+
+        if path.is_empty() || name.as_deref() == Some("") {
+            return Err(invalid_input(format!("Invalid input {}", input)));
+        }
+
+        let mut local = LocalImage::new(input, scheme, Path::new(path))?;
+        local.named = name.is_some();
+
+        local.tag = match name {
+            Some(name) => name,
+            None => {
+                let names = local.names()?;
+
+                match &local.source {
+                    Source::DockerArchive(archive) => match &archive.images[..] {
+                        [(tags, digest)] => tags.first().unwrap_or(digest).clone(),
+                        _ => return Err(local.ambiguous(scheme, names)),
+                    },
+                    source => match &source.index()?.unwrap_or_default()[..] {
+                        [manifest] => ref_name(manifest)
+                            .map(str::to_string)
+                            .unwrap_or_else(|| manifest.digest.clone()),
+                        _ => return Err(local.ambiguous(scheme, names)),
+                    },
+                }
+            }
+        };
+
+        Ok(Some(local))
+    }
+
+    /// Opens every image of a tarball, telling a docker save tarball from a
+    /// packed OCI layout by its contents
+    pub fn open_archive(path: &Path) -> io::Result<LocalImage> {
+        let scheme = archive_scheme(path)?;
+        LocalImage::new(&format!("{}:{}", scheme, path.display()), scheme, path)
+    }
+
+    fn new(input: &str, scheme: &str, path: &Path) -> io::Result<LocalImage> {
+        let source = match scheme {
+            "oci" if !path.join("index.json").exists() => {
+                return Err(invalid_input(format!(
+                    "{} is not an OCI image layout",
+                    path.display()
+                )))
+            }
+            "oci" => Source::Layout(OciLayout::new(path)),
+            "oci-archive" => Source::OciArchive(OciArchive::open(path)?),
+            _ => Source::DockerArchive(DockerArchive::open(path)?),
+        };
+
+        Ok(LocalImage {
+            input: input.to_string(),
+            source,
+            tag: String::new(),
+            named: false,
+        })
+    }
+
+    fn ambiguous(&self, scheme: &str, names: Vec<String>) -> io::Error {
+        invalid_input(format!(
+            "{} holds several images, name one with {}:<path>:<name> out of: {}",
+            self.input,
+            scheme,
+            names.join(", ")
+        ))
+    }
+
+    /// Stands in for the repository of the image. It is never contacted, and
+    /// its registry never matches one that blobs could be mounted from
+    pub fn reference(&self) -> FullImageWithTag {
+        FullImageWithTag {
+            image: FullImage {
+                registry: self.input.clone(),
+                image_name: self.input.clone(),
+                library_name: self.input.clone(),
+                service: self.input.clone(),
+            },
+            tag: self.tag.clone(),
+        }
+    }
+
+    /// The names of the images to push: the image the input named, or else
+    /// every named image it holds
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        if self.named {
+            return Ok(vec![self.tag.clone()]);
+        }
+
+        Ok(match &self.source {
+            Source::DockerArchive(archive) => archive
+                .images
+                .iter()
+                .flat_map(|(tags, _)| tags.iter().cloned())
+                .collect(),
+            source => source
+                .index()?
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|manifest| ref_name(manifest).map(str::to_string))
+                .collect(),
+        })
+    }
+
+    /// The full name buildkit recorded for an image of an OCI layout, whose
+    /// ref name is only a tag
+    pub fn full_name(&self, name: &str) -> io::Result<Option<String>> {
+        Ok(self.source.find(name)?.and_then(|manifest| {
+            manifest
+                .annotations?
+                .get(CONTAINERD_NAME_ANNOTATION)
+                .cloned()
+        }))
+    }
+
+    /// Reads an index or manifest by name or digest, returning its content
+    /// type alongside its bytes
+    pub fn manifest(&self, reference: &str) -> io::Result<(String, Vec<u8>)> {
+        let digest = if reference.starts_with("sha256:") {
+            reference.to_string()
+        } else {
+            let found = match &self.source {
+                Source::DockerArchive(archive) => archive
+                    .images
+                    .iter()
+                    .find(|(tags, _)| tags.iter().any(|tag| tag == reference))
+                    .map(|(_, digest)| digest.clone()),
+                source => source.find(reference)?.map(|manifest| manifest.digest),
+            };
+
+            found.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no image named {}", self.input, reference),
+                )
+            })?
+        };
+
+        let data = self.blob(&digest)?;
+        let media_type: MediaTypeOnly = serde_json::from_slice(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let content_type = match (media_type.media_type, media_type.manifests) {
+            (Some(media_type), _) => media_type,
+            (None, Some(_)) => MediaType::OciImageIndexV1Json.to_string().to_string(),
+            (None, None) => MediaType::OciImageManifestV1Json.to_string().to_string(),
+        };
+
+        Ok((content_type, data))
+    }
+
+    pub fn blob(&self, digest: &str) -> io::Result<Vec<u8>> {
+        match &self.source {
+            Source::Layout(layout) => layout
+                .read_blob(digest)?
+                .ok_or_else(|| missing_blob(&self.input, digest)),
+            Source::OciArchive(archive) => archive.read_blob(digest),
+            Source::DockerArchive(archive) => archive.read_blob(digest, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+
+    fn append(builder: &mut Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    /// A docker save tarball with two images sharing a layer through a symlink
+    fn docker_archive() -> Vec<u8> {
+        let mut layer = Builder::new(Vec::new());
+        append(&mut layer, "etc/hostname", b"box");
+        let layer = layer.into_inner().unwrap();
+
+        let mut builder = Builder::new(Vec::new());
+        append(
+            &mut builder,
+            "manifest.json",
+            br#"[{"Config":"config.json","RepoTags":["alpine:3.20"],"Layers":["a/layer.tar"]},
+                {"Config":"config.json","RepoTags":["busybox:1"],"Layers":["b/layer.tar"]}]"#,
+        );
+        append(&mut builder, "config.json", br#"{"architecture":"amd64"}"#);
+        append(&mut builder, "a/layer.tar", &layer);
+
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "b/layer.tar", "../a/layer.tar")
+            .unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_docker_archive() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("images.tar");
+        std::fs::write(&path, docker_archive())?;
+        let input = format!("docker-archive:{}", path.display());
+
+        assert!(LocalImage::open("docker.io/library/alpine:3.20")?.is_none());
+        assert!(LocalImage::open(&input).is_err());
+
+        let local = LocalImage::open(&format!("{}:busybox:1", input))?.unwrap();
+        assert_eq!(local.reference().tag, "busybox:1");
+        assert_eq!(local.names()?, vec!["busybox:1"]);
+
+        let (content_type, data) = local.manifest("busybox:1")?;
+        assert_eq!(content_type, "application/vnd.oci.image.manifest.v1+json");
+        assert_eq!(local.manifest(&sha256_digest(&data))?.1, data);
+
+        let manifest: ImageManifest = serde_json::from_slice(&data).unwrap();
+        let layer = local.blob(&manifest.layers[0].digest)?;
+        assert_eq!(sha256_digest(&layer), manifest.layers[0].digest);
+        assert_eq!(layer.len() as u64, manifest.layers[0].size);
+        assert!(matches!(
+            manifest.layers[0].media_type,
+            MediaType::OciImageLayerV1Tar
+        ));
+        assert_eq!(
+            local.blob(&manifest.config.digest)?,
+            br#"{"architecture":"amd64"}"#
+        );
+
+        // Both images point at the same layer blob
+        let (_, alpine) = local.manifest("alpine:3.20")?;
+        let alpine: ImageManifest = serde_json::from_slice(&alpine).unwrap();
+        assert_eq!(alpine.layers[0].digest, manifest.layers[0].digest);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_oci_archive() -> io::Result<()> {
+        let manifest = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:00","size":0},"layers":[]}"#;
+        let digest = sha256_digest(manifest);
+        let index = format!(
+            r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}","size":{},"annotations":{{"org.opencontainers.image.ref.name":"edge","io.containerd.image.name":"ghcr.io/example/app:edge"}}}}]}}"#,
+            digest,
+            manifest.len()
+        );
+
+        let mut builder = Builder::new(Vec::new());
+        append(
+            &mut builder,
+            "oci-layout",
+            br#"{"imageLayoutVersion":"1.0.0"}"#,
+        );
+        append(&mut builder, "index.json", index.as_bytes());
+        append(
+            &mut builder,
+            &format!("blobs/sha256/{}", digest.trim_start_matches("sha256:")),
+            manifest,
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("image.tar");
+        std::fs::write(&path, builder.into_inner()?)?;
+
+        // A tarball without manifest.json is told apart as a packed layout
+        let local = LocalImage::open_archive(&path)?;
+        assert_eq!(local.names()?, vec!["edge"]);
+        assert_eq!(
+            local.full_name("edge")?.as_deref(),
+            Some("ghcr.io/example/app:edge")
+        );
+        assert_eq!(local.manifest("edge")?.1, manifest);
+
+        let local = LocalImage::open(&format!("oci-archive:{}", path.display()))?.unwrap();
+        assert_eq!(local.reference().tag, "edge");
+
+        let path = dir.path().join("images.tar");
+        std::fs::write(&path, docker_archive())?;
+        assert_eq!(
+            LocalImage::open_archive(&path)?.names()?,
+            vec!["alpine:3.20", "busybox:1"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = format!("oci:{}", dir.path().display());
+        assert!(LocalImage::open(&input).is_err());
+
+        let layout = OciLayout::new(dir.path());
+        let data = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+        let digest = sha256_digest(data);
+        layout.write_blob(&digest, data)?;
+        layout.tag(
+            "edge",
+            crate::spec::index::Manifest {
+                media_type: MediaType::OciImageIndexV1Json,
+                size: data.len() as u64,
+                digest: digest.clone(),
+                platform: None,
+                artifact_type: None,
+                annotations: None,
+            },
+        )?;
+
+        // The only image of a layout needs no name
+        let local = LocalImage::open(&input)?.unwrap();
+        assert_eq!(local.reference().tag, "edge");
+        assert_eq!(
+            local.manifest("edge")?,
+            (
+                "application/vnd.oci.image.index.v1+json".to_string(),
+                data.to_vec()
+            )
+        );
+        assert!(local.manifest("latest").is_err());
+
+        Ok(())
+    }
+}
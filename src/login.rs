@@ -0,0 +1,87 @@
+use std::{collections::HashMap, error::Error, path::PathBuf, sync::Arc};
+
+use crate::{
+    client::{HttpProtocol, LoginCredentials, OciClient},
+    parser::registry_host,
+    registry_config::{store_login, ConfigFile, RegistrySettings},
+    report::Context,
+    Login, Logout,
+};
+
+/// The registry host named on the command line, Docker Hub if none is
+fn registry_arg(registry: Option<&str>) -> String {
+    let host = registry
+        .unwrap_or("docker.io")
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+
+    registry_host(host).to_string()
+}
+
+fn config_path() -> Result<PathBuf, Box<dyn Error>> {
+    ConfigFile::default_path()
+        .ok_or_else(|| "No configuration directory, set OCITOOL_CONFIG to store logins".into())
+}
+
+pub async fn login_command(
+    args: &Login,
+    login: Option<LoginCredentials>,
+    protocols: HashMap<String, HttpProtocol>,
+    settings: Arc<RegistrySettings>,
+) -> Result<(), Box<dyn Error>> {
+    let host = registry_arg(args.registry.as_deref());
+    let login = login.ok_or("Pass the user to log in as with --username")?;
+    let path = config_path()?;
+
+    let client =
+        OciClient::from_settings(settings, HashMap::new(), None).with_protocol_overrides(protocols);
+    client
+        .check_login(&client.settings().registry_url(&host), &login)
+        .await
+        .with_context(|| format!("logging in to {} as {}", host, login.username))?;
+
+    store_login(&path, &host, Some(&login))
+        .with_context(|| format!("storing the credentials of {}", host))?;
+
+    println!(
+        "Logged in to {} as {}, stored in {}",
+        host,
+        login.username,
+        path.display()
+    );
+
+    Ok(())
+}
+
+pub fn logout_command(args: &Logout) -> Result<(), Box<dyn Error>> {
+    let host = registry_arg(args.registry.as_deref());
+    let path = config_path()?;
+
+    if !path.is_file()
+        || !store_login(&path, &host, None)
+            .with_context(|| format!("removing the credentials of {}", host))?
+    {
+        return Err(format!("Not logged in to {} in {}", host, path.display()).into());
+    }
+
+    println!("Logged out of {}, removed from {}", host, path.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_arg() {
+        assert_eq!(registry_arg(None), "registry-1.docker.io");
+        assert_eq!(
+            registry_arg(Some("index.docker.io")),
+            "registry-1.docker.io"
+        );
+        assert_eq!(registry_arg(Some("https://ghcr.io/")), "ghcr.io");
+        assert_eq!(registry_arg(Some("localhost:5000")), "localhost:5000");
+    }
+}
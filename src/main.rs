@@ -1,40 +1,123 @@
+use crate::artifact::artifact_command;
+use crate::assert::assert_command;
+use crate::cache::cache_command;
+use crate::cat::cat_command;
 use crate::cleanup::cleanup_command;
-use crate::client::{ImagePermission, ImagePermissions, LoginCredentials, OciClient};
-use crate::compose::pull::pull_command;
+use crate::client::{
+    env_login, parse_protocol_override, parse_registry_auth, HttpProtocol, ImagePermission,
+    ImagePermissions, LoginCredentials, OciClient,
+};
+use crate::complete::complete_image_command;
+use crate::compose::pull::{pull_command, transfer_style};
 use crate::compose::up::up_command;
-use crate::downloader::IndexResponse;
-use crate::parser::FullImageWithTag;
-use crate::spec::manifest::ImageManifest;
-use downloader::OciDownloaderError;
-use platform::PlatformMatcher;
-use runner::OciRunner;
+use crate::copy::copy_command;
+use crate::delete::delete_command;
+use crate::detach::{attach_command, stop_command};
+use crate::diff::diff_command;
+use crate::digest::digest_command;
+use crate::docker_archive::write_docker_archive;
+use crate::execution::CompressionOptions;
+use crate::extract::extract_command;
+use crate::inspect::inspect_command;
+use crate::layout::{OciLayout, Output};
+use crate::load::load_command;
+use crate::local::LocalImage;
+use crate::login::{login_command, logout_command};
+use crate::manifest::manifest_command;
+use crate::parser::{FullImage, FullImageWithTag};
+use crate::plan::{find_plan, plan_deps_command, plan_update_command, read_plan, read_plan_lock};
+use crate::priority::IoPriority;
+use crate::prune::prune_command;
+use crate::push_layout::push_layout_command;
+use crate::quarantine::restore_command;
+use crate::referrers::referrers_command;
+use crate::registry_config::{ConfigFile, RegistrySettings};
+use crate::remote_cleanup::remote_cleanup_command;
+use crate::report::{exit, report_error, Context};
+use crate::retag::retag_command;
+use crate::save::save_command;
+use crate::sbom::SbomSource;
+use crate::settings::config_command;
+use crate::state::state_command;
+use crate::supervise::exit_code;
+use crate::tags::tags_command;
+use crate::workspace::{rm_command, Workspace};
+use indicatif::ProgressBar;
+use runner::{parse_env_file, parse_variables, OciRunner, RunBackend};
 use spec::plan::ImagePlan;
 use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::path::{Path, PathBuf};
-use std::process::exit;
+use std::error::Error;
+use std::path::PathBuf;
 use std::sync::Arc;
-use walkdir::WalkDir;
+use std::time::Duration;
 
 mod access;
 mod archive;
+mod artifact;
+mod assert;
+mod cache;
+mod cat;
 mod cleanup;
 mod client;
+mod complete;
 mod compose;
+mod copy;
+mod dashboard;
+mod delete;
+mod detach;
+mod diff;
 mod digest;
+mod docker_archive;
 mod downloader;
+mod ecr;
 mod execution;
+mod extract;
+mod format;
+mod inspect;
+mod layout;
+mod load;
+mod local;
+mod login;
 mod macros;
+mod manifest;
+mod metrics;
+mod mmap;
 mod parser;
+mod plan;
 mod platform;
+mod priority;
+mod profile;
+mod prune;
+mod push_layout;
+mod quarantine;
+mod ratelimit;
+mod recording;
+mod referrers;
+mod registry_config;
+mod remote_cleanup;
+mod report;
+mod resolver;
+mod retag;
 mod runner;
+mod save;
+mod sbom;
+mod settings;
 mod spec;
+mod state;
+mod storage_check;
+mod supervise;
 mod system_login;
+mod tags;
+mod task;
 mod test;
+mod tls;
+mod trust;
+mod tty;
 mod uploader;
 mod walk;
 mod whiteout;
+mod workspace;
 
 xflags::xflags! {
     /// Uploads an OCI image to a registry
@@ -46,11 +129,105 @@ xflags::xflags! {
         repeated -u, --username username: String
 
         /// Sets the password to authenticate to the registry with (requires --host)
+        /// If left out with --username, it is asked for on the terminal
         repeated -p, --password password: String
 
+        /// Reads the password of the single --username from stdin, keeping it out
+        /// of the shell history and the process list
+        optional --password-stdin
+
+        /// Sets the credentials of a registry host, as HOST=USERNAME:PASSWORD
+        /// Can be repeated for every registry a command pulls from or pushes to
+        repeated --registry-auth registry_auth: String
+
         /// Disables the on-disk cache
         optional --no-cache
 
+        /// Caches blobs in a directory of the current CI job instead of the one shared
+        /// by every job on the host
+        optional --isolated-cache
+
+        /// Keeps the blob cache under this size, as bytes or with a k, m or g suffix,
+        /// by removing the blobs used least recently
+        /// If not set, the cache is not limited
+        optional --cache-max-size cache_max_size: String
+
+        /// Shows what would be uploaded, pulled, created or removed without doing it
+        optional --dry-run
+
+        /// Prints sizes as exact byte counts instead of KiB, MiB, ...
+        optional --raw-sizes
+
+        /// Prints how long the command spent logging in, resolving, downloading, compressing,
+        /// uploading and writing to containerd once it finishes
+        optional --profile-io
+
+        /// Forces the HTTP version used for a registry host, as HOST=http1 or HOST=http2
+        /// Hosts default to HTTP/2 if they offer it, and fall back to HTTP/1.1 after
+        /// repeated HTTP/2 failures
+        repeated --protocol protocol: String
+
+        /// Speaks HTTP/1.1 to every registry not given a --protocol, for proxies
+        /// that break HTTP/2
+        optional --http1
+
+        /// Sends registry traffic through this proxy, such as http://proxy.example.com:3128,
+        /// instead of the one of HTTPS_PROXY or HTTP_PROXY. Hosts in NO_PROXY are
+        /// still reached directly
+        optional --proxy proxy: String
+
+        /// Gives up connecting to a registry after this many seconds, shared between
+        /// its addresses
+        /// If not set, the default is 10
+        optional --connect-timeout connect_timeout: u64
+
+        /// Fails a request to a registry that sends nothing for this many seconds,
+        /// while its response is awaited or between reads of the body. Every read
+        /// starts the time anew, so a slow download that keeps receiving data goes on
+        /// If not set, requests do not time out
+        optional --read-timeout read_timeout: u64
+
+        /// Sends at most this many concurrent requests to every registry host, counting
+        /// a request until its response starts, and keeps at most this many idle
+        /// connections open to it. Response bodies may download past the limit
+        /// If not set, the default is 16
+        optional --max-connections-per-host max_connections_per_host: usize
+
+        /// Talks to this registry host, such as localhost:5000, over plain HTTP
+        /// instead of HTTPS
+        repeated --insecure-registry insecure_registry: String
+
+        /// Trusts the certificate authorities of this PEM file for registries,
+        /// on top of the built-in ones
+        repeated --registry-ca registry_ca: PathBuf
+
+        /// Presents a client certificate to a registry host, as HOST=CERT[,KEY] with
+        /// PEM files. KEY can be left out if CERT holds the private key as well
+        repeated --registry-cert registry_cert: String
+
+        /// Waits for the rate limit of a registry, such as the pull limit of Docker Hub,
+        /// and sends requests refused with 429 again instead of failing
+        optional --respect-rate-limits
+
+        /// Leaves out foreign layers, such as those of Windows base images, instead of
+        /// downloading them from the URLs of their descriptor. Copies keep referring
+        /// to them, extracted images go without them and compose pull does not unpack
+        /// the images it skips them of
+        optional --skip-foreign-layers
+
+        /// Sets how progress is reported: text, or json for lines of JSON events on
+        /// stderr, such as the pulls left of rate limited registries
+        /// If not set, the default is text
+        optional --progress progress: String
+
+        /// Records every request to a registry, and its response, as a JSON
+        /// file in this directory. Tokens are left out
+        optional --record record: PathBuf
+
+        /// Answers requests to registries from a directory written by --record,
+        /// without contacting them
+        optional --replay replay: PathBuf
+
         cmd compose {
             /// Sets the path to the compose directory
             /// If not set, the current directory will be used
@@ -66,13 +243,34 @@ xflags::xflags! {
 
             /// Pulls all images from the respective registries
             cmd pull {
+                /// Skips images that any compose project on this host pulled into the
+                /// same containerd within this many seconds, if still present
+                /// If not set, tags are always checked against the registry
+                optional --max-age max_age: u64
+
+                /// Writes Prometheus metrics about the pull to this file,
+                /// for the node_exporter textfile collector
+                optional --metrics-file metrics_file: PathBuf
 
+                /// Sets the containerd snapshotter to unpack the images into
+                /// If not set, the default is overlayfs
+                optional --snapshotter snapshotter: String
+
+                /// Shows a full screen dashboard of the transfers, workers and
+                /// throughput instead of a spinner per image
+                optional --tui
             }
 
             /// Creates the necessary networks
             cmd up {
 
             }
+
+            /// Reports how many bytes pulling the images would download,
+            /// given what is already in containerd, without downloading any layer
+            cmd estimate {
+
+            }
         }
 
         cmd upload {
@@ -84,15 +282,73 @@ xflags::xflags! {
             /// If that is not set, the default compression level will be used
             /// The compression level must be between 1 and 22
             optional -c, --compression-level compression_level: i32
+
+            /// Sets the number of platforms to build and push concurrently
+            /// If not set, the number of available CPU cores will be used
+            optional -j, --jobs jobs: usize
+
+            /// Sets the number of zstd worker threads used per layer
+            /// If not set, the available CPU cores are split between the jobs
+            optional --compress-threads compress_threads: u32
+
+            /// Sets the niceness to compress layers at, from -20 to 19
+            optional --nice nice: i32
+
+            /// Sets the I/O priority to compress layers at, like ionice
+            /// Either idle, or a best-effort level between 0 and 7
+            optional --ionice ionice: IoPriority
+
+            /// Attaches an SPDX or CycloneDX JSON SBOM to the uploaded image
+            optional --sbom sbom: PathBuf
+
+            /// Generates a CycloneDX SBOM of the base images and attaches it to the uploaded image
+            optional --generate-sbom
+
+            /// Writes the pushed image reference, as name@sha256:..., to this file
+            optional --digest-file digest_file: PathBuf
+
+            /// Writes the image into an OCI image layout directory instead of pushing it,
+            /// as oci:<dir>[:<name>]. The name defaults to the plan's tags
+            /// docker-archive:<tar>[:<reference>] writes a docker save tarball of the host platform
+            optional -o, --output output: String
+
+            /// Shows a full screen dashboard of the platforms, their retries and
+            /// the throughput instead of the progress of each step
+            optional --tui
+        }
+
+        /// Inspects an OCI image plan
+        cmd plan {
+            /// Sets a custom plan filename to use
+            optional --plan plan: String
+
+            /// Lists the base images referenced by the plan, with their
+            /// resolved digests, as a CycloneDX document
+            cmd deps {
+
+            }
+
+            /// Resolves the current digests of the plan's base images and
+            /// pins them in the plan's lockfile
+            cmd update {
+
+            }
         }
 
         cmd run {
-            /// Sets the image name to run
+            /// Sets the image name to run, or an oci:<dir>[:<name>], oci-archive:<tar>[:<name>]
+            /// or docker-archive:<tar>[:<name>] to run an image from disk
             required -i,--image image: String
 
-            /// Volumes to mount in the container
+            /// Volumes to mount in the container, as host:container[:ro|:rw]
             repeated -v,--volume volumes: String
 
+            /// Mounts the root filesystem of the container read-only
+            optional --read-only
+
+            /// Mounts a tmpfs in the container, as /path[:size] with sizes like 64m
+            repeated --tmpfs tmpfs: String
+
             /// Optional entrypoint to use
             optional -e,--entrypoint entrypoint: String
 
@@ -102,11 +358,428 @@ xflags::xflags! {
             /// Optional working directory
             optional -w,--workdir workdir: String
 
+            /// Sets an environment variable as KEY=VALUE, over the image's Env
+            /// A bare KEY passes the variable of the host through
+            repeated --env env: String
+
+            /// Reads environment variables from a file, one KEY=VALUE per line
+            repeated --env-file env_file: PathBuf
+
+            /// Sets the user to run as, as user[:group] by name or ID
+            /// If not set, the User of the image is used
+            optional --user user: String
+
+            /// Sets how the container is isolated: proot, bwrap, chroot-overlay, runc or crun
+            /// chroot-overlay needs root. If not set, proot is used
+            optional --backend backend: String
+
+            /// Runs the image as a containerd container and task, on a snapshot
+            /// of the image pulled with compose pull, instead of extracting it
+            optional --via-containerd
+
+            /// Sets the containerd socket path to use with --via-containerd
+            /// If not set, the default is /run/containerd/containerd.sock
+            optional --socket socket: PathBuf
+
+            /// Sets the snapshotter the image was unpacked into, with --via-containerd
+            /// If not set, the default is overlayfs
+            optional --snapshotter snapshotter: String
+
             /// Disables mounting the system directories (/proc, /sys, /dev)
             optional --no-mount-system
 
             /// Disables ensuring the DNS configuration
             optional --no-ensure-dns
+
+            /// Sets a name server of the container, over those of the host
+            repeated --dns dns: String
+
+            /// Sets a search domain of the container, over those of the host
+            repeated --dns-search dns_search: String
+
+            /// Sets the number of layers to download while extracting the image
+            /// If not set, the default is 4
+            optional -j, --jobs jobs: usize
+
+            /// Runs in the container of this name, reusing its root filesystem
+            /// if it was kept by an earlier run
+            optional --name name: String
+
+            /// Sets how many seconds the container gets to exit after it was sent
+            /// its stop signal on SIGINT or SIGTERM, before it is killed
+            /// If not set, the default is 10
+            optional --stop-timeout stop_timeout: u64
+
+            /// Runs the container on a terminal of its own, forwarding keys,
+            /// output and window size changes, for interactive shells
+            optional -t, --tty
+
+            /// Keeps the root filesystem of the named container after it exits,
+            /// until it is removed with ocitool rm
+            optional --keep
+
+            /// Runs the named container in the background, logging its output,
+            /// until it exits or is stopped with ocitool stop. Implies --keep
+            optional -d, --detach
+        }
+
+        /// Removes containers kept with run --name --keep
+        cmd rm {
+            /// Sets the names of the containers to remove
+            repeated names: String
+        }
+
+        /// Stops a container started with run --detach
+        cmd stop {
+            /// Sets the name of the container to stop
+            required name: String
+        }
+
+        /// Prints the output of a container started with run --detach and
+        /// follows it until the container exits, exiting with its code
+        cmd attach {
+            /// Sets the name of the container to attach to
+            required name: String
+        }
+
+        /// Extracts the flattened root filesystem of an image without running it
+        cmd extract {
+            /// Sets the image name to extract
+            required image: String
+
+            /// Sets the directory to extract to, or a .tar file to write
+            required -o,--output output: PathBuf
+
+            /// Selects the platform manifest, as os/architecture[/variant]
+            /// If not set, the platform of this host is used
+            optional --platform platform: String
+        }
+
+        /// Prints a single file of an image without extracting the whole image
+        cmd cat {
+            /// Sets the image name to read from
+            required image: String
+
+            /// Sets the path of the file in the image
+            required path: PathBuf
+
+            /// Selects the platform manifest, as os/architecture[/variant]
+            /// If not set, the platform of this host is used
+            optional --platform platform: String
+        }
+
+        /// Verifies the config of an image without running it
+        /// Exits with a non-zero status if any expectation does not hold
+        cmd assert {
+            /// Sets the image name to check
+            required image: String
+
+            /// Expected entrypoint, repeated once per argument
+            repeated --entrypoint entrypoint: String
+
+            /// Expected command, repeated once per argument
+            repeated --cmd cmd: String
+
+            /// Environment variable that must be set, as KEY or KEY=VALUE
+            repeated --has-env has_env: String
+
+            /// Expected user
+            optional --user user: String
+
+            /// Expected working directory
+            optional --workdir workdir: String
+
+            /// Port that must be exposed, as PORT or PORT/PROTOCOL
+            repeated --port port: String
+        }
+
+        /// Lists the artifacts, such as signatures and SBOMs, referring to an image
+        cmd referrers {
+            /// Sets the image name to list the referrers of
+            required image: String
+
+            /// Only lists referrers of this artifact type
+            optional --artifact-type artifact_type: String
+        }
+
+        /// Prints the index, platform manifest and config of an image as JSON
+        cmd inspect {
+            /// Sets the image name to inspect, as IMAGE[:TAG] or IMAGE@DIGEST
+            required image: String
+
+            /// Selects the platform manifest, as os/architecture[/variant]
+            /// If not set, the platform of this host is used
+            optional --platform platform: String
+
+            /// Only prints the platform manifest
+            optional --manifest
+
+            /// Only prints the image config
+            optional --config
+
+            /// Prints the exact bytes served by the registry instead of pretty JSON
+            optional --raw
+        }
+
+        /// Prints the digest a tag currently points at, for pinning it
+        cmd digest {
+            /// Sets the image name to resolve
+            required image: String
+
+            /// Also prints the manifest digest of every platform
+            optional --full
+        }
+
+        /// Compares the config, layers and optionally the files of two images
+        cmd diff {
+            /// Sets the image name to compare from
+            required image_a: String
+
+            /// Sets the image name to compare to
+            required image_b: String
+
+            /// Selects the platform manifest, as os/architecture[/variant]
+            /// If not set, the platform of this host is used
+            optional --platform platform: String
+
+            /// Also extracts both images and lists the added, removed and changed files
+            optional --files
+        }
+
+        /// Lists the tags of a repository
+        cmd tags {
+            /// Sets the repository to list the tags of
+            required image: String
+
+            /// Sets the output format, text (one tag per line) or json
+            optional --format format: String
+        }
+
+        /// Copies an image between registries without unpacking it, keeping its digests
+        cmd copy {
+            /// Sets the image name to copy from, or an oci:<dir>[:<name>], oci-archive:<tar>[:<name>]
+            /// or docker-archive:<tar>[:<name>] to copy an image from disk
+            required source: String
+
+            /// Sets the image name to copy to
+            optional destination: String
+
+            /// Writes the image into an OCI image layout directory instead of a registry,
+            /// as oci:<dir>[:<name>]. The name defaults to the source tag
+            /// docker-archive:<tar>[:<reference>] writes a docker save tarball of a single platform
+            optional -o, --output output: String
+
+            /// Only copies these platforms, as os/architecture[/variant]
+            /// If not set, every platform is copied
+            repeated --platform platform: String
+        }
+
+        /// Pushes the images of an OCI image layout or docker save tarball to a repository,
+        /// each under its own tag, as exported by buildkit, podman or docker on another machine
+        cmd push-layout {
+            /// Sets the images to push, as oci:<dir>[:<name>], oci-archive:<tar>[:<name>]
+            /// or docker-archive:<tar>[:<name>]
+            /// If no name is given, every named image is pushed
+            required input: String
+
+            /// Sets the repository to push to
+            required repository: String
+
+            /// Only pushes these platforms of multi-platform images, as os/architecture[/variant]
+            repeated --platform platform: String
+        }
+
+        /// Imports the images of a docker save tarball or a packed OCI image layout into
+        /// containerd, and unpacks them, without going through a registry
+        cmd load {
+            /// Sets the tarball to import
+            required -i,--input input: PathBuf
+
+            /// Sets the repository of images that the tarball only names by tag,
+            /// as OCI layouts often do
+            optional --repository repository: String
+
+            /// Only loads this platform of multi-platform images, as os/architecture[/variant]
+            /// If not set, the platform of this host is loaded
+            optional --platform platform: String
+
+            /// Sets the containerd socket path to use
+            /// If not set, the default is /run/containerd/containerd.sock
+            optional -s,--socket socket: PathBuf
+
+            /// Sets the containerd snapshotter to unpack the images into
+            /// If not set, the default is overlayfs
+            optional --snapshotter snapshotter: String
+        }
+
+        /// Exports images from containerd into an OCI image layout tarball, with the platforms
+        /// that were pulled, which load and other tools can import again
+        cmd save {
+            /// Sets the images to save, as pulled into containerd
+            repeated images: String
+
+            /// Sets the tarball to write
+            required -o,--output output: PathBuf
+
+            /// Sets the containerd socket path to use
+            /// If not set, the default is /run/containerd/containerd.sock
+            optional -s,--socket socket: PathBuf
+        }
+
+        /// Deletes the content of containerd that no image refers to anymore, following
+        /// the garbage collection labels of images, leases and blobs
+        cmd prune {
+            /// Sets the containerd socket path to use
+            /// If not set, the default is /run/containerd/containerd.sock
+            optional -s,--socket socket: PathBuf
+
+            /// Only prunes this containerd namespace
+            /// If not set, every namespace is pruned
+            optional -n,--namespace namespace: String
+
+            /// Agree to deleting the content without prompting
+            optional -y,--yes
+        }
+
+        /// Points another tag of the same repository at an image, without transferring any blobs
+        cmd retag {
+            /// Sets the image name to tag, as IMAGE[:TAG] or IMAGE@DIGEST
+            required image: String
+
+            /// Sets the tag to add
+            required tag: String
+        }
+
+        /// Deletes an image from a registry by its digest, removing every tag that points at it
+        cmd delete {
+            /// Sets the image name to delete, as IMAGE[:TAG] or IMAGE@DIGEST
+            required image: String
+        }
+
+        /// Assembles a multi-platform index from platform manifests that were
+        /// pushed separately, such as images built on one machine per architecture
+        cmd manifest {
+            /// Creates an index referencing already-pushed platform manifests and pushes it
+            cmd create {
+                /// Sets the image name to push the index to
+                required image: String
+
+                /// Adds a platform manifest from the same repository, as IMAGE@DIGEST or IMAGE:TAG
+                /// Its platform is read from its config
+                repeated --add add: String
+            }
+
+            /// Overrides the platform of a manifest in an existing index and pushes it again
+            cmd annotate {
+                /// Sets the image name of the index
+                required image: String
+
+                /// Sets the digest of the manifest to annotate
+                required digest: String
+
+                /// Sets the platform, as os/architecture[/variant]
+                required --platform platform: String
+
+                /// Sets the OS version, as required by Windows images
+                optional --os-version os_version: String
+            }
+        }
+
+        /// Works with artifacts, such as those pushed by ORAS, whose layers are
+        /// plain files rather than filesystem tarballs
+        cmd artifact {
+            /// Lists the files of an artifact with their media types and sizes
+            cmd list {
+                /// Sets the artifact name to list
+                required image: String
+            }
+
+            /// Downloads every file of an artifact, named after its title annotation
+            cmd download {
+                /// Sets the artifact name to download
+                required image: String
+
+                /// Sets the directory to write the files to
+                /// If not set, the current directory is used
+                optional -o,--output output: PathBuf
+            }
+        }
+
+        /// Suggests image references starting with a prefix, one per line
+        /// Used by the shell completions
+        cmd complete-image {
+            /// Sets the prefix to complete
+            optional prefix: String
+
+            /// Sets the containerd socket to list local images from
+            /// If not set, the default is /run/containerd/containerd.sock
+            optional --socket socket: PathBuf
+        }
+
+        /// Inspects the state ocitool keeps about this host
+        cmd state {
+            /// Lists the known images, lockfiles, pulls and run workspaces
+            cmd ls {
+
+            }
+
+            /// Forgets lockfiles and workspaces that no longer exist,
+            /// and images and pulls that have not been seen recently
+            cmd gc {
+                /// Sets how many days images and pulls are remembered for
+                /// If not set, the default is 30
+                optional --max-age-days max_age_days: u64
+            }
+        }
+
+        /// Inspects and empties the blob cache
+        cmd cache {
+            /// Shows where the blob cache is, how many blobs it holds and how large they are
+            cmd info {
+
+            }
+
+            /// Removes every blob from the cache
+            cmd clear {
+
+            }
+
+            /// Removes the blobs that have not been used recently, or that do not fit in
+            /// --cache-max-size. Also available as cache prune
+            cmd evict prune {
+                /// Removes the blobs not used for this long, in seconds or with an s, m,
+                /// h or d suffix, such as 7d
+                /// If not set, blobs are removed until the cache fits in --cache-max-size
+                optional --older-than older_than: String
+            }
+        }
+
+        /// Checks credentials against a registry and stores them in the configuration
+        /// file, for the other commands to log in with
+        /// The credentials are given with --username and --password
+        cmd login {
+            /// Sets the registry host to log in to, such as ghcr.io
+            /// If not set, Docker Hub is logged in to
+            optional registry: String
+        }
+
+        /// Removes the credentials of a registry from the configuration file
+        cmd logout {
+            /// Sets the registry host to log out of, such as ghcr.io
+            /// If not set, Docker Hub is logged out of
+            optional registry: String
+        }
+
+        /// Inspects the settings ocitool runs with
+        cmd config {
+            /// Shows the effective settings, merged from the defaults, the
+            /// kernel command line, the configuration file, the environment
+            /// and the global flags, with where each value came from
+            cmd show {
+                /// Sets the output format: text or json
+                /// If not set, the default is text
+                optional --format format: String
+            }
         }
 
         /// Cleans up dangling data in a Docker registry server
@@ -114,7 +787,15 @@ xflags::xflags! {
         cmd cleanup {
             /// The directory that contains the Docker registry data
             /// that is to be cleaned up
-            required -d,--dir dir: PathBuf
+            optional -d,--dir dir: PathBuf
+
+            /// Cleans up a running registry through its HTTP API instead, such as
+            /// registry.example.com, deleting the manifests of removed tags
+            optional --remote remote: String
+
+            /// Sets the storage layout of the registry: distribution, harbor or zot
+            /// If not set, it is detected from the directory
+            optional --layout layout: String
 
             /// Remove dangling commit hashes
             optional --commits
@@ -128,21 +809,58 @@ xflags::xflags! {
             /// Remove dangling blobs
             optional --blobs
 
+            /// Remove uploads abandoned for longer than --upload-age
+            optional --uploads
+
+            /// Sets how many hours an upload has to be abandoned for to be removed
+            /// If not set, the default is 24
+            optional --upload-age upload_age: u64
+
             /// Cleanup everything
             optional -a,--all
 
             /// Agree to the cleanup without prompting
             optional -y,--yes
+
+            /// Untags all but the N most recently created tags of every repository,
+            /// so that their data is cleaned up as dangling
+            optional --keep-last keep_last: usize
+
+            /// Limits the cleanup to a repository, such as owner/name
+            repeated --repo repo: String
+
+            /// Never removes tags matching this regular expression, such as latest
+            /// or ^v\d+\.\d+\.\d+$
+            repeated --keep-tags keep_tags: String
+
+            /// Sets the format of the --dry-run or --check report: text or json
+            /// If not set, the default is text
+            optional --format format: String
+
+            /// Checks the storage for link files pointing at missing blobs, manifests
+            /// referring to missing blobs and invalid JSON instead, removing nothing
+            optional --check
+
+            /// Moves what the cleanup removes into this directory instead, under the
+            /// same paths, for cleanup restore to put back
+            optional --quarantine quarantine: PathBuf
+
+            /// Removes what the options select
+            default cmd remove {
+
+            }
+
+            /// Moves what a cleanup with --quarantine removed back into --dir,
+            /// without replacing anything the registry wrote since
+            cmd restore {
+                /// The quarantine directory the cleanup moved it into
+                required quarantine: PathBuf
+            }
         }
 }
 }
 
-async fn upload_command(
-    args: &Upload,
-    no_cache: bool,
-    hostname_to_login: HashMap<String, LoginCredentials>,
-    default_login: Option<LoginCredentials>,
-) {
+async fn upload_command(args: &Upload, no_cache: bool, dry_run: bool, client: Arc<OciClient>) {
     let compression_level = args.compression_level.unwrap_or_else(|| {
         env::var("COMPRESSION_LEVEL")
             .ok()
@@ -150,132 +868,504 @@ async fn upload_command(
             .unwrap_or(19)
     });
 
-    let plan = args.plan.clone().unwrap_or_else(|| "oci.json".to_string());
-    let plan_path = Path::new(&plan);
-    let plan = if plan_path.exists() {
-        plan
-    } else {
-        let plan_basename = plan_path.file_name().expect("Invalid plan filename");
-        WalkDir::new(env::current_dir().unwrap())
-            .into_iter()
-            .filter_map(|entry| entry.ok())
-            .find(|entry| entry.file_name() == plan_basename)
-            .expect("Plan file not found")
-            .into_path()
-            .to_str()
-            .unwrap()
-            .to_string()
-    };
+    let plan = find_plan(args.plan.clone());
 
     println!("Executing plan: {}", plan);
 
-    // Set the current directory to the plan file's directory
-    if let Some(parent) = Path::new(&plan).parent() {
-        if parent.exists() {
-            env::set_current_dir(parent).expect("Failed to set current directory");
+    let lock = read_plan_lock(&plan);
+    let mut plan: ImagePlan = read_plan(&plan);
+    let output = match args.output.as_deref().map(layout::parse_output).transpose() {
+        Ok(output) => output,
+        Err(e) => {
+            report_error("Upload", Box::<dyn Error>::from(e).as_ref());
+            exit(1);
         }
-    }
+    };
 
-    let file = File::open(plan).expect("Failed to open plan file");
-    let plan: ImagePlan = serde_json::from_reader(file).unwrap();
-    let client = Arc::new(OciClient::new(hostname_to_login, default_login));
-    let mut execution = execution::PlanExecution::new(plan, client, no_cache, compression_level);
+    // A name given with the output replaces the plan's tags. A docker-archive
+    // is staged in a layout, and tagged with the references docker load takes
+    let mut archive = None;
+    let layout = match output {
+        Some(Output::Layout(dir, name)) => {
+            if let Some(name) = name {
+                plan.tags = vec![name];
+            }
 
-    if let Err(e) = execution.execute().await {
-        eprintln!("Error: {}", e);
+            Some(OciLayout::new(&dir))
+        }
+        Some(Output::DockerArchive(path, reference)) => {
+            let repository = match FullImage::parse(&plan.name, client.settings()) {
+                Ok(image) => image.repository(),
+                Err(e) => {
+                    report_error("Upload", &e);
+                    exit(1);
+                }
+            };
+            let references = match reference {
+                Some(reference) => vec![reference],
+                None => plan
+                    .tags
+                    .iter()
+                    .map(|tag| format!("{}:{}", repository, tag))
+                    .collect(),
+            };
+            let staging = match tempfile::tempdir() {
+                Ok(staging) => staging,
+                Err(e) => {
+                    report_error("Upload", &e);
+                    exit(1);
+                }
+            };
+            let layout = OciLayout::new(staging.path());
+
+            archive = Some((staging, path, references));
+            Some(layout)
+        }
+        None => None,
+    };
+    let jobs = args.jobs.unwrap_or_else(num_cpus::get);
+
+    // Share the cores between the platforms that actually compress at the same time
+    let concurrent_platforms = jobs.min(plan.platforms.len()).max(1);
+    let compression = CompressionOptions {
+        level: compression_level,
+        threads: args
+            .compress_threads
+            .unwrap_or_else(|| (num_cpus::get() / concurrent_platforms).max(1) as u32),
+        nice: args.nice,
+        io_priority: args.ionice,
+    };
+    let sbom = match (&args.sbom, args.generate_sbom) {
+        (Some(path), _) => Some(SbomSource::File(path.clone())),
+        (None, true) => Some(SbomSource::Generate),
+        (None, false) => None,
+    };
+    let name = plan.name.clone();
+    let tags = plan.tags.clone();
+    let execution =
+        match execution::PlanExecution::new(plan, lock, client, no_cache, compression, jobs) {
+            Ok(execution) => execution,
+            Err(e) => {
+                report_error("Upload", &e);
+                exit(1);
+            }
+        };
+    let execution = execution
+        .with_sbom(sbom)
+        .with_digest_file(args.digest_file.clone())
+        .with_layout(layout)
+        .with_dry_run(dry_run)
+        .with_tui(args.tui);
+
+    if let Err(e) = execution
+        .execute()
+        .await
+        .with_context(|| format!("uploading {}", name))
+    {
+        report_error("Upload", e.as_ref());
         exit(1);
     }
+
+    if let (Some((staging, path, references)), false) = (&archive, dry_run) {
+        let Some(tag) = tags.first() else {
+            return;
+        };
+
+        let result =
+            write_docker_archive(&OciLayout::new(staging.path()), tag, references, &[], path)
+                .with_context(|| format!("writing {}", path.display()));
+
+        match result {
+            Ok(()) => println!("Wrote {} to {}", references.join(", "), path.display()),
+            Err(e) => {
+                report_error("Upload", e.as_ref());
+                exit(1);
+            }
+        }
+    }
 }
 
-async fn run_command(
+/// Sets up a runner from the arguments of run, for an image whose root
+/// filesystem is at dir
+fn build_runner<'a>(
+    args: &Run,
+    dir: &'a std::path::Path,
+    config: &'a Option<spec::config::Config>,
+    env: Vec<(String, String)>,
+) -> OciRunner<'a> {
+    let cmd = args
+        .cmd
+        .as_ref()
+        .map(|cmd| cmd.split_whitespace().map(|arg| arg.to_string()).collect());
+
+    OciRunner::new(
+        dir,
+        config,
+        args.volume.clone(),
+        args.entrypoint.clone(),
+        cmd,
+        args.workdir.clone(),
+        !args.no_mount_system,
+        !args.no_ensure_dns,
+    )
+    .with_env(env)
+    .with_user(args.user.clone())
+    .with_dns(args.dns.clone(), args.dns_search.clone())
+    .with_read_only(args.read_only, args.tmpfs.clone())
+}
+
+/// Fetches the image to run and extracts it into the root filesystem
+async fn extract_run_image(
     args: &Run,
     no_cache: bool,
-    hostname_to_login: HashMap<String, LoginCredentials>,
-    default_login: Option<LoginCredentials>,
-) -> Result<(), OciDownloaderError> {
+    client: Arc<OciClient>,
+    rootfs: &std::path::Path,
+) -> Result<spec::config::ImageConfig, Box<dyn Error>> {
     let image_name = args.image.clone();
-    let volumes = args.volume.clone();
-    let entrypoint = args.entrypoint.clone();
-    let cmd = args.cmd.clone();
-    let workdir = args.workdir.clone();
+    let local = LocalImage::open(&image_name).with_context(|| format!("opening {}", image_name))?;
+    let image = match &local {
+        Some(local) => local.reference(),
+        None => FullImageWithTag::parse(&image_name, client.settings())?,
+    };
+
+    if local.is_none() {
+        client
+            .login(&[ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+    }
 
-    let image = FullImageWithTag::from_image_name(&image_name);
+    let downloader = Arc::new(downloader::OciDownloader::new(client, no_cache).with_local(local));
 
-    let client = Arc::new(OciClient::new(hostname_to_login, default_login));
+    let (downloaded_manifest, downloaded_config) = downloader
+        .download_image(image.clone(), None)
+        .await
+        .with_context(|| format!("fetching {}", image_name))?;
 
-    client
-        .login(&[ImagePermission {
-            full_image: image.image.clone(),
-            permissions: ImagePermissions::Pull,
-        }])
+    let progress = ProgressBar::new(0);
+    progress.set_style(transfer_style());
+    downloader
+        .extract_image_concurrently(
+            image.image.clone(),
+            &downloaded_manifest,
+            &rootfs.to_path_buf(),
+            args.jobs.unwrap_or(4),
+            &progress,
+        )
         .await?;
 
-    let downloader = downloader::OciDownloader::new(client, no_cache);
+    Ok(downloaded_config)
+}
+
+async fn run_command(
+    args: &Run,
+    no_cache: bool,
+    client: Arc<OciClient>,
+    passwords: &[String],
+) -> Result<i32, Box<dyn Error>> {
+    let backend = match &args.backend {
+        Some(backend) => RunBackend::parse(backend)?,
+        None => RunBackend::Proot,
+    };
+
+    if args.via_containerd && args.backend.is_some() {
+        return Err("--backend cannot be used with --via-containerd".into());
+    }
+
+    if args.via_containerd && args.name.is_some() {
+        return Err("--name cannot be used with --via-containerd".into());
+    }
+
+    if args.via_containerd && args.tty {
+        return Err("--tty cannot be used with --via-containerd".into());
+    }
+
+    if args.keep && args.name.is_none() {
+        return Err("--keep needs a --name to find the container by".into());
+    }
+
+    if args.detach && args.name.is_none() {
+        return Err("--detach needs a --name to find the container by".into());
+    }
+
+    if args.detach && (args.tty || args.via_containerd) {
+        return Err("--detach cannot be used with --tty or --via-containerd".into());
+    }
+
+    if let Some(server) = args
+        .dns
+        .iter()
+        .find(|server| server.parse::<std::net::IpAddr>().is_err())
+    {
+        return Err(format!("Invalid DNS server {}, expected an IP address", server).into());
+    }
+
+    if args.no_ensure_dns && !(args.dns.is_empty() && args.dns_search.is_empty()) {
+        return Err("--dns and --dns-search cannot be used with --no-ensure-dns".into());
+    }
+
+    let host = |key: &str| std::env::var(key).ok();
+    let mut env = vec![];
+
+    for path in &args.env_file {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        env.extend(parse_env_file(&contents, host)?);
+    }
 
-    let index = downloader.download_index(image.clone()).await?.0;
+    env.extend(parse_variables(&args.env, host)?);
 
-    let platform_matcher = PlatformMatcher::new();
+    if args.via_containerd {
+        return task::run_task(args, env, client.settings()).await;
+    }
 
-    let downloaded_manifest = match index {
-        IndexResponse::ImageIndex(index) => {
-            let manifest = platform_matcher
-                .find_manifest(&index.manifests)
-                .ok_or(OciDownloaderError("No matching platform found".to_string()))?;
+    let workspace = args.name.as_deref().map(Workspace::new).transpose()?;
+    let detached = detach::is_detached();
 
-            let downloaded_manifest = downloader
-                .download_manifest(image.image.clone(), &manifest.digest)
-                .await?
-                .0;
+    if let Some(workspace) = workspace.as_ref().filter(|_| !detached) {
+        if workspace.running_pid().is_some() {
+            return Err(format!(
+                "{} is already running, stop it with ocitool stop {}",
+                workspace.name, workspace.name
+            )
+            .into());
+        }
 
-            Ok::<ImageManifest, OciDownloaderError>(downloaded_manifest)
+        if args.detach {
+            // The detached ocitool takes the lock, fail here if it could not
+            drop(workspace.lock()?);
+            detach::spawn_detached(workspace, passwords)?;
+            return Ok(0);
         }
-        IndexResponse::ImageManifest(index) => Ok(index),
-    }?;
+    }
+
+    // Held until the run is over, so that no other run or rm of the
+    // container touches its root filesystem meanwhile
+    let _lock = workspace.as_ref().map(Workspace::lock).transpose()?;
 
-    let downloaded_config = downloader
-        .download_config(image.image.clone(), &downloaded_manifest.config.digest)
-        .await?
-        .0;
+    if let Some(workspace) = workspace.as_ref().filter(|_| detached) {
+        workspace.record_pid()?;
+    }
 
     let tmpdir = tempfile::tempdir()?;
-    let tmpdir_path = tmpdir.path();
-
-    for layer in downloaded_manifest.layers {
-        downloader
-            .extract_layer(
-                image.image.clone(),
-                &layer.digest,
-                &layer.media_type,
-                &tmpdir_path.to_path_buf(),
+    let rootfs = match &workspace {
+        Some(workspace) => workspace.rootfs(),
+        None => tmpdir.path().to_path_buf(),
+    };
+
+    let config = match workspace.as_ref().and_then(Workspace::load) {
+        Some(kept) if kept.image != args.image => {
+            return Err(format!(
+                "Container {} runs {}, not {}, remove it with ocitool rm {}",
+                kept.name, kept.image, args.image, kept.name
             )
-            .await?;
-    }
+            .into())
+        }
+        Some(kept) => {
+            eprintln!("Reusing the root filesystem of {}", kept.name);
+            kept.config
+        }
+        None => {
+            if let Some(workspace) = &workspace {
+                // Whatever is left of a run that failed to extract the image
+                workspace.discard()?;
+                std::fs::create_dir_all(&rootfs)?;
+            }
 
-    let runner = OciRunner::new(
-        tmpdir_path,
-        &downloaded_config.config,
-        volumes,
-        entrypoint,
-        cmd,
-        workdir,
-        !args.no_mount_system,
-        !args.no_ensure_dns,
-    );
+            let extracted = extract_run_image(args, no_cache, client, &rootfs).await;
 
-    runner
-        .run()
-        .await
-        .map_err(|e| OciDownloaderError(format!("{:?}", e)))?;
-    Ok(())
+            match (&workspace, extracted) {
+                (Some(workspace), Ok(config)) => workspace.save(&args.image, config)?.config,
+                (None, Ok(config)) => config,
+                (Some(workspace), Err(e)) => {
+                    let _ = workspace.discard();
+                    return Err(e);
+                }
+                (None, Err(e)) => return Err(e),
+            }
+        }
+    };
+
+    let runner = build_runner(args, &rootfs, &config.config, env)
+        .with_backend(backend)
+        .with_tty(args.tty)
+        .with_stop_timeout(args.stop_timeout.map(Duration::from_secs));
+    let result = runner.run().await;
+
+    if let Some(workspace) = &workspace {
+        if detached {
+            let code = result.as_ref().map_or(1, |status| exit_code(*status));
+            detach::finish_detached(workspace, code)?;
+        }
+
+        match args.keep || args.detach {
+            true => eprintln!(
+                "Kept {} at {}, remove it with ocitool rm {}",
+                workspace.name,
+                workspace.dir.display(),
+                workspace.name
+            ),
+            false => workspace.remove()?,
+        }
+    }
+
+    Ok(exit_code(result?))
 }
 
 #[tokio::main]
 async fn main() {
     let args = Ocitool::from_env_or_exit();
+    format::set_raw_sizes(args.raw_sizes);
+    profile::set_enabled(args.profile_io);
+    cache::set_isolated(args.isolated_cache);
+    ratelimit::set_respect_rate_limits(args.respect_rate_limits);
+    downloader::set_skip_foreign_layers(args.skip_foreign_layers);
+
+    if let Err(e) = format::set_progress_format(args.progress.as_deref().unwrap_or("text")) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+
+    if args.connect_timeout == Some(0)
+        || args.read_timeout == Some(0)
+        || args.max_connections_per_host == Some(0)
+    {
+        eprintln!(
+            "Error: --connect-timeout, --read-timeout and --max-connections-per-host must be above 0"
+        );
+        exit(1);
+    }
+
+    if let Some(size) = &args.cache_max_size {
+        match format::parse_size(size) {
+            Some(bytes) => cache::set_max_size(bytes),
+            None => {
+                eprintln!("Error: Invalid --cache-max-size: {}", size);
+                exit(1);
+            }
+        }
+    }
+
+    let config = match ConfigFile::load() {
+        Ok(config) => config,
+        Err(e) => {
+            report_error("Config", &e);
+            exit(1);
+        }
+    };
+
+    let defaults = client::ConnectionOptions::default();
+    let mut settings = RegistrySettings {
+        default_registry: config.default_registry.clone(),
+        namespaces: config.namespaces(),
+        mirrors: config.mirrors(),
+        http1_only: args.http1,
+        connection: client::ConnectionOptions {
+            connect_timeout: args
+                .connect_timeout
+                .map_or(defaults.connect_timeout, Duration::from_secs),
+            read_timeout: args.read_timeout.map(Duration::from_secs),
+            max_connections_per_host: args
+                .max_connections_per_host
+                .unwrap_or(defaults.max_connections_per_host),
+        },
+        ..Default::default()
+    };
+    settings.add_insecure_registries(
+        config
+            .insecure_registries()
+            .into_iter()
+            .chain(args.insecure_registry.iter().cloned()),
+    );
+
+    if let Some(proxy) = &args.proxy {
+        match client::parse_proxy(proxy) {
+            Ok(proxy) => settings.proxy = Some(proxy),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    match recording::open(args.record.as_deref(), args.replay.as_deref()) {
+        Ok(recording) => settings.recording = recording.map(Arc::new),
+        Err(e) => {
+            report_error("Recording", &e);
+            exit(1);
+        }
+    }
+
+    let client_certs: Vec<String> = config
+        .client_certs()
+        .into_iter()
+        .chain(args.registry_cert.iter().cloned())
+        .collect();
+
+    match tls::load(&args.registry_ca, &config.host_cas(), &client_certs) {
+        Ok(tls) => settings.tls = tls,
+        Err(e) => {
+            report_error("TLS", &e);
+            exit(1);
+        }
+    }
+
+    let settings = Arc::new(settings);
+
+    if let OcitoolCmd::Config(config_args) = &args.subcommand {
+        if let Err(e) = config_command(config_args, &args, &config, &settings) {
+            report_error("Config", e.as_ref());
+            exit(1);
+        }
+
+        return;
+    }
 
     let hosts = args.host;
     let usernames = args.username;
-    let passwords = args.password;
+    let mut passwords = args.password;
+
+    if detach::is_detached() {
+        // The ocitool that detached already read or asked for the passwords
+        match detach::inherited_passwords() {
+            Ok(inherited) => passwords = inherited,
+            Err(e) => {
+                report_error("Reading the password", &e);
+                exit(1);
+            }
+        }
+    } else if args.password_stdin {
+        if usernames.len() != 1 || !passwords.is_empty() {
+            eprintln!("Error: --password-stdin takes the password of a single --username, without --password");
+            exit(1);
+        }
+
+        match tty::read_password_stdin() {
+            Ok(password) => passwords.push(password),
+            Err(e) => {
+                report_error("Reading the password", &e);
+                exit(1);
+            }
+        }
+    } else if passwords.is_empty() {
+        // Passwords left out are asked for, rather than taken from the command line
+        for (i, username) in usernames.iter().enumerate() {
+            let prompt = match hosts.get(i) {
+                Some(host) => format!("Password for {} at {}: ", username, host),
+                None => format!("Password for {}: ", username),
+            };
+
+            match tty::prompt_password(&prompt) {
+                Ok(password) => passwords.push(password),
+                Err(e) => {
+                    report_error("Reading the password", &e);
+                    exit(1);
+                }
+            }
+        }
+    }
 
     if !hosts.is_empty() {
         if hosts.len() != usernames.len() || hosts.len() != passwords.len() {
@@ -286,28 +1376,52 @@ async fn main() {
         }
     } else if !usernames.is_empty() || !passwords.is_empty() {
         if usernames.len() != 1 || passwords.len() != 1 {
-            eprintln!(
-                "Error: without --host, a single --username and --password pair is expected"
-            );
+            eprintln!("Error: without --host, a single --username and --password pair is expected");
             exit(1);
         }
     }
 
     let has_hosts = !hosts.is_empty();
 
-    let hostname_to_login: HashMap<String, LoginCredentials> = hosts
-        .into_iter()
-        .zip(usernames.clone().into_iter())
-        .zip(passwords.clone().into_iter())
-        .map(|((host, username), password)| {
-            let hostname = if host.starts_with("http://") || host.starts_with("https://") {
-                host
-            } else {
-                format!("https://{}", host)
-            };
-            (hostname, LoginCredentials { username, password })
-        })
-        .collect();
+    // Handed on by run --detach, as the detached ocitool has no terminal to
+    // ask for them on nor the stdin they were read from
+    let resolved_passwords = passwords.clone();
+
+    // DOCKER_AUTH_<HOST> variables override the logins of the configuration
+    // file, and logins given with --host or --registry-auth override both.
+    // Variables of other hosts are looked up as they are logged in to
+    let mut hostname_to_login = config.logins(&settings);
+
+    for (registry, login) in hostname_to_login.iter_mut() {
+        if let Some((_, env_login)) = env_login(registry, |name| env::var(name).ok()) {
+            *login = env_login;
+        }
+    }
+
+    hostname_to_login.extend(
+        hosts
+            .into_iter()
+            .zip(usernames.clone().into_iter())
+            .zip(passwords.clone().into_iter())
+            .map(|((host, username), password)| {
+                (
+                    settings.registry_url(&host),
+                    LoginCredentials { username, password },
+                )
+            }),
+    );
+
+    for value in &args.registry_auth {
+        match parse_registry_auth(value, &settings) {
+            Ok((registry, login)) => {
+                hostname_to_login.insert(registry, login);
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+    }
 
     let default_login = if !has_hosts && !usernames.is_empty() {
         Some(LoginCredentials {
@@ -324,36 +1438,284 @@ async fn main() {
         }
     };
 
+    let flag_protocols: Result<HashMap<String, HttpProtocol>, String> = args
+        .protocol
+        .iter()
+        .map(|value| parse_protocol_override(value))
+        .collect();
+
+    // Protocols given with --protocol override those of the configuration file
+    let protocols = match flag_protocols {
+        Ok(flag_protocols) => {
+            let mut protocols = config.protocols();
+            protocols.extend(flag_protocols);
+            protocols
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    };
+
+    let client = Arc::new(
+        OciClient::from_settings(
+            settings.clone(),
+            hostname_to_login.clone(),
+            default_login.clone(),
+        )
+        .with_protocol_overrides(protocols.clone()),
+    );
+
+    let mut code = 0;
+
     match args.subcommand {
         OcitoolCmd::Upload(upload) => {
-            upload_command(&upload, args.no_cache, hostname_to_login, default_login).await
+            upload_command(&upload, args.no_cache, args.dry_run, client).await
         }
         OcitoolCmd::Run(run) => {
-            if let Err(e) = run_command(&run, args.no_cache, hostname_to_login, default_login).await
+            match run_command(&run, args.no_cache, client, &resolved_passwords).await {
+                Ok(exit_code) => code = exit_code,
+                Err(e) => {
+                    report_error("Run", e.as_ref());
+
+                    // Otherwise run --detach waits for it until it times out
+                    if let Some(workspace) = run
+                        .name
+                        .as_deref()
+                        .filter(|_| detach::is_detached())
+                        .and_then(|name| Workspace::new(name).ok())
+                    {
+                        if let Err(e) = detach::fail_detached(&workspace, e.as_ref()) {
+                            eprintln!("Failed to record the exit of {}: {}", workspace.name, e);
+                        }
+                    }
+
+                    exit(1);
+                }
+            }
+        }
+        OcitoolCmd::Rm(rm) => {
+            if let Err(e) = rm_command(&rm, args.dry_run) {
+                report_error("Rm", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Stop(stop) => {
+            if let Err(e) = stop_command(&stop).await {
+                report_error("Stop", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Attach(attach) => match attach_command(&attach).await {
+            Ok(exit_code) => code = exit_code,
+            Err(e) => {
+                report_error("Attach", e.as_ref());
+                exit(1);
+            }
+        },
+        OcitoolCmd::Extract(extract) => {
+            if let Err(e) = extract_command(&extract, args.no_cache, client).await {
+                report_error("Extract", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Cat(cat) => {
+            if let Err(e) = cat_command(&cat, args.no_cache, client).await {
+                report_error("Cat", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Assert(assert) => {
+            if let Err(e) = assert_command(&assert, args.no_cache, client).await {
+                report_error("Assert", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Referrers(referrers) => {
+            if let Err(e) = referrers_command(&referrers, args.no_cache, client).await {
+                report_error("Referrers", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Inspect(inspect) => {
+            if let Err(e) = inspect_command(&inspect, args.no_cache, client).await {
+                report_error("Inspect", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Digest(digest) => {
+            if let Err(e) = digest_command(&digest, args.no_cache, client).await {
+                report_error("Digest", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Diff(diff) => {
+            if let Err(e) = diff_command(&diff, args.no_cache, client).await {
+                report_error("Diff", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Tags(tags) => {
+            if let Err(e) = tags_command(&tags, args.no_cache, client).await {
+                report_error("Tags", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Copy(copy) => {
+            if let Err(e) = copy_command(&copy, args.no_cache, args.dry_run, client).await {
+                report_error("Copy", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::PushLayout(push_layout) => {
+            if let Err(e) =
+                push_layout_command(&push_layout, args.no_cache, args.dry_run, client).await
             {
-                eprintln!("Run error: {}", e);
+                report_error("Push layout", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Save(save) => {
+            if let Err(e) = save_command(&save, &settings).await {
+                report_error("Save", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Load(load) => {
+            if let Err(e) = load_command(&load, args.dry_run, &settings).await {
+                report_error("Load", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Prune(prune) => {
+            if let Err(e) = prune_command(&prune, args.dry_run).await {
+                report_error("Prune", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Retag(retag) => {
+            if let Err(e) = retag_command(&retag, args.no_cache, args.dry_run, client).await {
+                report_error("Retag", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Delete(delete) => {
+            if let Err(e) = delete_command(&delete, args.no_cache, args.dry_run, client).await {
+                report_error("Delete", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Manifest(manifest) => {
+            if let Err(e) = manifest_command(&manifest, args.no_cache, args.dry_run, client).await {
+                report_error("Manifest", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Artifact(artifact) => {
+            if let Err(e) = artifact_command(&artifact, args.no_cache, client).await {
+                report_error("Artifact", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::CompleteImage(complete_image) => {
+            // Completions must never print errors into the user's prompt
+            let _ = complete_image_command(&complete_image, client).await;
+        }
+        OcitoolCmd::State(state) => {
+            if let Err(e) = state_command(&state, args.dry_run) {
+                report_error("State", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Cache(cache) => {
+            if let Err(e) = cache_command(&cache, args.dry_run) {
+                report_error("Cache", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Login(login) => {
+            if let Err(e) = login_command(&login, default_login, protocols, settings).await {
+                report_error("Login", e.as_ref());
+                exit(1);
+            }
+        }
+        OcitoolCmd::Logout(logout) => {
+            if let Err(e) = logout_command(&logout) {
+                report_error("Logout", e.as_ref());
                 exit(1);
             }
         }
+        OcitoolCmd::Config(_) => unreachable!("config is handled before logging in"),
         OcitoolCmd::Cleanup(cleanup) => {
-            if let Err(e) = cleanup_command(cleanup) {
-                eprintln!("Cleanup error: {}", e);
+            let result = match (&cleanup.subcommand, cleanup.remote.is_some()) {
+                (CleanupCmd::Restore(restore), _) => {
+                    restore_command(&cleanup, restore, args.dry_run)
+                }
+                (CleanupCmd::Remove(_), true) => {
+                    remote_cleanup_command(&cleanup, args.no_cache, args.dry_run, client).await
+                }
+                (CleanupCmd::Remove(_), false) => cleanup_command(cleanup, args.dry_run),
+            };
+
+            if let Err(e) = result {
+                report_error("Cleanup", e.as_ref());
                 exit(1);
             }
         }
+        OcitoolCmd::Plan(ref plan) => match plan.subcommand {
+            PlanCmd::Deps(ref _deps) => {
+                if let Err(e) = plan_deps_command(plan, args.no_cache, client).await {
+                    report_error("Plan", e.as_ref());
+                    exit(1);
+                }
+            }
+            PlanCmd::Update(ref _update) => {
+                if let Err(e) = plan_update_command(plan, args.no_cache, client).await {
+                    report_error("Plan", e.as_ref());
+                    exit(1);
+                }
+            }
+        },
         OcitoolCmd::Compose(ref compose) => match compose.subcommand {
             ComposeCmd::Pull(ref _pull) => {
-                if let Err(e) = pull_command(&compose).await {
-                    eprintln!("Pull error: {}", e);
+                if let Err(e) = pull_command(
+                    &compose,
+                    args.dry_run,
+                    hostname_to_login,
+                    default_login,
+                    protocols,
+                    settings,
+                )
+                .await
+                {
+                    report_error("Pull", e.as_ref());
+                    exit(1);
+                }
+            }
+            ComposeCmd::Estimate(ref _estimate) => {
+                if let Err(e) = pull_command(
+                    compose,
+                    true,
+                    hostname_to_login,
+                    default_login,
+                    protocols,
+                    settings,
+                )
+                .await
+                {
+                    report_error("Estimate", e.as_ref());
                     exit(1);
                 }
             }
             ComposeCmd::Up(ref _up) => {
-                if let Err(e) = up_command(&compose).await {
-                    eprintln!("Up error: {}", e);
+                if let Err(e) = up_command(&compose, args.dry_run).await {
+                    report_error("Up", e.as_ref());
                     exit(1);
                 }
             }
         },
     }
+
+    // The exit code of the container, for run
+    exit(code);
 }
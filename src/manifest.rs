@@ -0,0 +1,223 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::{is_index_content_type, OciDownloader},
+    parser::FullImageWithTag,
+    platform::{parse_platform, platform_name},
+    report::Context,
+    spec::{
+        enums::MediaType,
+        index::{ImageIndex, Manifest, Platform},
+    },
+    state::{self, image_key, State},
+    uploader::OciUploader,
+    Annotate, Create, Manifest as ManifestArgs, ManifestCmd,
+};
+
+/// Describes an already-pushed platform manifest as an index entry,
+/// taking its platform from its config
+async fn index_entry(
+    downloader: &OciDownloader,
+    image: &FullImageWithTag,
+) -> Result<Manifest, Box<dyn Error>> {
+    let (content_type, data) = downloader.download_manifest_raw(image.clone()).await?;
+
+    if is_index_content_type(&content_type) {
+        return Err("Only platform manifests can be added, not indexes".into());
+    }
+
+    let manifest: serde_json::Value = serde_json::from_slice(&data)?;
+    let config_digest = manifest["config"]["digest"]
+        .as_str()
+        .ok_or("Manifest has no config")?;
+    let config = downloader
+        .download_layer(image.image.clone(), config_digest)
+        .await
+        .with_context(|| format!("fetching config {}", config_digest))?;
+    // Image configs carry the same platform fields as index entries
+    let platform: Platform = serde_json::from_slice(&config)?;
+
+    Ok(Manifest {
+        media_type: MediaType::from(content_type.as_str()),
+        size: data.len() as u64,
+        digest: sha256_digest(&data),
+        platform: Some(platform),
+        artifact_type: None,
+        annotations: None,
+    })
+}
+
+/// Uploads an index and records the digest it was pushed with
+async fn push_index(
+    uploader: &OciUploader,
+    image: &FullImageWithTag,
+    index: &ImageIndex,
+) -> Result<(), Box<dyn Error>> {
+    let data = index.to_json();
+    let digest = sha256_digest(&data);
+
+    uploader
+        .upload_manifest(image.clone(), data, index.media_type.to_string())
+        .await
+        .with_context(|| format!("uploading index {}", image.tag))?;
+
+    if uploader.is_dry_run() {
+        println!("Would push {}@{}", image.image.image_name, digest);
+        return Ok(());
+    }
+
+    if let Err(e) = State::update(&State::path(), |state| {
+        state.record_image(&image_key(image), &digest, state::now())
+    }) {
+        eprintln!("Failed to save state: {}", e);
+    }
+
+    println!("Pushed {}@{}", image.image.image_name, digest);
+
+    Ok(())
+}
+
+async fn create_command(
+    args: &Create,
+    downloader: &OciDownloader,
+    uploader: &OciUploader,
+    image: &FullImageWithTag,
+) -> Result<(), Box<dyn Error>> {
+    if args.add.is_empty() {
+        return Err("At least one manifest must be added with --add".into());
+    }
+
+    let mut manifests: Vec<Manifest> = Vec::new();
+
+    for add in &args.add {
+        let added = FullImageWithTag::parse(add, downloader.client.settings())?;
+
+        // An index can only refer to manifests stored in its own repository
+        if added.image != image.image {
+            return Err(format!("{} is not in the same repository as {}", add, args.image).into());
+        }
+
+        let manifest = index_entry(downloader, &added)
+            .await
+            .with_context(|| format!("adding {}", add))?;
+        let platform = manifest.platform.as_ref().map(platform_name);
+
+        if manifests
+            .iter()
+            .any(|other| other.platform.as_ref().map(platform_name) == platform)
+        {
+            return Err(format!(
+                "More than one manifest for {}",
+                platform.unwrap_or_default()
+            )
+            .into());
+        }
+
+        println!(
+            "Adding {} ({})",
+            manifest.digest,
+            platform.unwrap_or_default()
+        );
+        manifests.push(manifest);
+    }
+
+    // Docker manifests go into a Docker manifest list, anything else into an OCI index
+    let media_type = if manifests
+        .iter()
+        .all(|manifest| matches!(manifest.media_type, MediaType::DockerManifestV2Json))
+    {
+        MediaType::DockerManifestListV2Json
+    } else {
+        MediaType::OciImageIndexV1Json
+    };
+
+    let index = ImageIndex {
+        schema_version: 2,
+        media_type,
+        artifact_type: None,
+        manifests,
+        annotations: None,
+    };
+
+    push_index(uploader, image, &index).await
+}
+
+async fn annotate_command(
+    args: &Annotate,
+    downloader: &OciDownloader,
+    uploader: &OciUploader,
+    image: &FullImageWithTag,
+) -> Result<(), Box<dyn Error>> {
+    let requested =
+        parse_platform(&args.platform).ok_or(format!("Invalid platform {}", args.platform))?;
+
+    let (content_type, data) = downloader
+        .download_manifest_raw(image.clone())
+        .await
+        .with_context(|| format!("fetching {}", args.image))?;
+
+    if !is_index_content_type(&content_type) {
+        return Err(format!("{} is not an index", args.image).into());
+    }
+
+    let mut index: ImageIndex = serde_json::from_slice(&data)?;
+    let manifest = index
+        .manifests
+        .iter_mut()
+        .find(|manifest| manifest.digest == args.digest)
+        .ok_or(format!("{} is not part of {}", args.digest, args.image))?;
+    let mut platform = manifest.platform.take().unwrap_or(requested.clone());
+
+    platform.os = requested.os;
+    platform.architecture = requested.architecture;
+    platform.variant = requested.variant;
+
+    if args.os_version.is_some() {
+        platform.os_version = args.os_version.clone();
+    }
+
+    println!("Annotating {} as {}", args.digest, platform_name(&platform));
+    manifest.platform = Some(platform);
+
+    push_index(uploader, image, &index).await
+}
+
+pub async fn manifest_command(
+    args: &ManifestArgs,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(
+        match &args.subcommand {
+            ManifestCmd::Create(create) => &create.image,
+            ManifestCmd::Annotate(annotate) => &annotate.image,
+        },
+        client.settings(),
+    )?;
+
+    client
+        .login(&[
+            ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Pull,
+            },
+            ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Push,
+            },
+        ])
+        .await?;
+
+    let downloader = OciDownloader::new(client.clone(), no_cache);
+    let uploader = OciUploader::new(client).with_dry_run(dry_run);
+
+    match &args.subcommand {
+        ManifestCmd::Create(create) => create_command(create, &downloader, &uploader, &image).await,
+        ManifestCmd::Annotate(annotate) => {
+            annotate_command(annotate, &downloader, &uploader, &image).await
+        }
+    }
+}
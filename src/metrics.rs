@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Outcome of pulling one image
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagePullMetrics {
+    pub success: bool,
+    pub last_success: Option<u64>,
+}
+
+/// Metrics of a pull run, in the Prometheus text exposition format
+#[derive(Default, Debug)]
+pub struct PullMetrics {
+    pub images: BTreeMap<String, ImagePullMetrics>,
+    pub bytes: u64,
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl PullMetrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP ocitool_pull_success Whether the last pull of the image succeeded\n");
+        out.push_str("# TYPE ocitool_pull_success gauge\n");
+        for (image, metrics) in &self.images {
+            let _ = writeln!(
+                out,
+                "ocitool_pull_success{{image=\"{}\"}} {}",
+                escape_label(image),
+                metrics.success as u8
+            );
+        }
+
+        out.push_str("# HELP ocitool_pull_failures Number of images that failed to pull\n");
+        out.push_str("# TYPE ocitool_pull_failures gauge\n");
+        let _ = writeln!(
+            out,
+            "ocitool_pull_failures {}",
+            self.images.values().filter(|image| !image.success).count()
+        );
+
+        out.push_str(
+            "# HELP ocitool_pull_last_success_timestamp_seconds When the image was last pulled successfully\n",
+        );
+        out.push_str("# TYPE ocitool_pull_last_success_timestamp_seconds gauge\n");
+        for (image, metrics) in &self.images {
+            if let Some(last_success) = metrics.last_success {
+                let _ = writeln!(
+                    out,
+                    "ocitool_pull_last_success_timestamp_seconds{{image=\"{}\"}} {}",
+                    escape_label(image),
+                    last_success
+                );
+            }
+        }
+
+        out.push_str("# HELP ocitool_pull_bytes Bytes transferred by the pull\n");
+        out.push_str("# TYPE ocitool_pull_bytes gauge\n");
+        let _ = writeln!(out, "ocitool_pull_bytes {}", self.bytes);
+
+        out
+    }
+
+    /// Writes the metrics for the node_exporter textfile collector, replacing
+    /// the file atomically so that it is never scraped half-written
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(self.render().as_bytes())?;
+        file.persist(path).map_err(|e| e.error)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = PullMetrics {
+            bytes: 1024,
+            ..Default::default()
+        };
+        metrics.images.insert(
+            "registry-1.docker.io/library/nginx:alpine".to_string(),
+            ImagePullMetrics {
+                success: true,
+                last_success: Some(1700000000),
+            },
+        );
+        metrics.images.insert(
+            "ghcr.io/example/\"app\":latest".to_string(),
+            ImagePullMetrics {
+                success: false,
+                last_success: None,
+            },
+        );
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains(
+            "ocitool_pull_success{image=\"registry-1.docker.io/library/nginx:alpine\"} 1\n"
+        ));
+        assert!(rendered
+            .contains("ocitool_pull_success{image=\"ghcr.io/example/\\\"app\\\":latest\"} 0\n"));
+        assert!(rendered.contains("ocitool_pull_failures 1\n"));
+        assert!(rendered.contains(
+            "ocitool_pull_last_success_timestamp_seconds{image=\"registry-1.docker.io/library/nginx:alpine\"} 1700000000\n"
+        ));
+        assert!(!rendered.contains("ocitool_pull_last_success_timestamp_seconds{image=\"ghcr.io"));
+        assert!(rendered.contains("ocitool_pull_bytes 1024\n"));
+    }
+}
@@ -0,0 +1,42 @@
+use std::{fs::File, io, path::Path};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+/// Maps a file into memory and exposes it as `Bytes`.
+///
+/// The pages are backed by the page cache rather than the heap, so multi-GB
+/// layers can be hashed and uploaded without reading them into a `Vec<u8>`.
+/// Cloning the returned `Bytes` does not copy the file.
+pub fn map_file<P: AsRef<Path>>(path: P) -> io::Result<Bytes> {
+    let file = File::open(path)?;
+
+    // Mapping an empty file fails on some platforms
+    if file.metadata()?.len() == 0 {
+        return Ok(Bytes::new());
+    }
+
+    // Safety: the file must not be truncated while it is mapped, which holds
+    // for plan sources and blob cache entries during an upload
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(Bytes::from_owner(mmap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_map_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("layer.tar");
+        std::fs::write(&path, b"layer contents").unwrap();
+
+        assert_eq!(&map_file(&path).unwrap()[..], b"layer contents");
+
+        let empty = dir.path().join("empty.tar");
+        std::fs::write(&empty, b"").unwrap();
+        assert!(map_file(&empty).unwrap().is_empty());
+    }
+}
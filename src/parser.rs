@@ -1,3 +1,7 @@
+use std::net::Ipv6Addr;
+
+use crate::{macros::impl_error, registry_config::RegistrySettings};
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct FullImage {
     // The registry URL, e.g., "registry-1.docker.io"
@@ -16,10 +20,41 @@ pub struct FullImage {
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub struct FullImageWithTag {
     pub image: FullImage,
+    // The tag, e.g., "latest", or the digest of an image pinned by one,
+    // e.g., "sha256:..."
     pub tag: String,
 }
 
+/// What a reference names within a repository: a tag, which can be moved
+/// to another image, or the digest of an index or manifest, which cannot
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum Reference {
+    Tag(String),
+    Digest(String),
+}
+
+impl Reference {
+    /// Tags cannot contain a colon, so anything with one, such as
+    /// sha256:..., is a digest
+    pub fn parse(reference: &str) -> Self {
+        match reference.contains(':') {
+            true => Reference::Digest(reference.to_string()),
+            false => Reference::Tag(reference.to_string()),
+        }
+    }
+
+    /// The separator between a repository and this reference
+    pub fn separator(&self) -> char {
+        match self {
+            Reference::Tag(_) => ':',
+            Reference::Digest(_) => '@',
+        }
+    }
+}
+
 impl FullImage {
+    /// The token endpoint of the registry, for registries that do not
+    /// advertise theirs in a WWW-Authenticate challenge
     pub fn get_auth_url(&self) -> String {
         if self.registry.contains("registry-1.docker.io")
             || self.registry.contains("registry.docker.io")
@@ -39,52 +74,577 @@ impl FullImage {
     pub fn is_github_registry(&self) -> bool {
         self.registry.contains("ghcr.io")
     }
+
+    /// The fully qualified repository, such as docker.io/library/nginx or
+    /// ghcr.io/example/app
+    pub fn repository(&self) -> String {
+        format!("{}/{}", self.host(), self.library_name)
+    }
+
+    /// The registry host as references name it, such as docker.io or ghcr.io
+    pub fn host(&self) -> &str {
+        match self.service.as_str() {
+            "registry.docker.io" => "docker.io",
+            service => service,
+        }
+    }
+}
+
+/// The hosts Docker Hub is known by, all of which name the same images
+const DOCKER_HUB_HOSTS: [&str; 4] = [
+    "docker.io",
+    "index.docker.io",
+    "registry-1.docker.io",
+    "registry.docker.io",
+];
+
+/// The host of a registry as images are pulled from it, with the hosts
+/// Docker Hub is known by folded into registry-1.docker.io
+pub fn registry_host(host: &str) -> &str {
+    match DOCKER_HUB_HOSTS.contains(&host) {
+        true => "registry-1.docker.io",
+        false => host,
+    }
+}
+
+/// Repository names, with their registry host, are limited to 255 characters
+const MAX_NAME_LENGTH: usize = 255;
+
+// Why an image reference could not be parsed
+impl_error!(ReferenceError);
+
+/// Splits the registry host off a reference. Like Docker, the first
+/// component is only a host if it looks like one, so `library/nginx` and
+/// `example/app` are Docker Hub repositories
+fn split_host(reference: &str) -> (Option<&str>, &str) {
+    match reference.split_once('/') {
+        Some((host, rest))
+            if host.contains('.')
+                || host.contains(':')
+                || host == "localhost"
+                || host.chars().any(|c| c.is_ascii_uppercase()) =>
+        {
+            (Some(host), rest)
+        }
+        _ => (None, reference),
+    }
+}
+
+/// Checks a registry host: a domain name, an IPv4 address or an IPv6
+/// address in brackets, optionally followed by a port
+pub fn validate_host(host: &str) -> Result<(), String> {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => (name, Some(port)),
+        _ => (host, None),
+    };
+
+    if let Some(port) = port {
+        if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("invalid port {:?} of registry {}", port, host));
+        }
+    }
+
+    if let Some(address) = name
+        .strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+    {
+        return match address.parse::<Ipv6Addr>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("invalid IPv6 address {:?}", address)),
+        };
+    }
+
+    let valid_component = |component: &str| {
+        !component.is_empty()
+            && !component.starts_with('-')
+            && !component.ends_with('-')
+            && component
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+
+    match name.split('.').all(valid_component) {
+        true => Ok(()),
+        false => Err(format!("invalid registry host {:?}", host)),
+    }
+}
+
+/// Checks a component of a repository path: lowercase letters and digits,
+/// separated by a period, one or two underscores, or any number of dashes
+fn valid_path_component(component: &str) -> bool {
+    let mut separator = String::new();
+
+    for c in component.chars() {
+        match c {
+            'a'..='z' | '0'..='9' => {
+                if !separator.is_empty() && !is_separator(&separator) {
+                    return false;
+                }
+
+                separator.clear();
+            }
+            '.' | '_' | '-' => separator.push(c),
+            _ => return false,
+        }
+    }
+
+    let starts_alphanumeric = component
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric());
+
+    starts_alphanumeric && separator.is_empty()
+}
+
+fn is_separator(separator: &str) -> bool {
+    matches!(separator, "." | "_" | "__") || separator.chars().all(|c| c == '-')
+}
+
+/// Checks the repository path, such as library/nginx or team/tools/builder
+fn validate_path(path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("the repository name is empty".to_string());
+    }
+
+    if path.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(format!("repository name {:?} must be lowercase", path));
+    }
+
+    match path.split('/').all(valid_path_component) {
+        true => Ok(()),
+        false => Err(format!(
+            "invalid repository name {:?}, components may only hold lowercase letters and digits separated by '.', '_', '__' or '-'",
+            path
+        )),
+    }
+}
+
+/// Checks a tag: up to 128 letters, digits, underscores, periods and
+/// dashes, not starting with a period or dash
+fn validate_tag(tag: &str) -> Result<(), String> {
+    let valid = (1..=128).contains(&tag.len())
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("invalid tag {:?}", tag)),
+    }
+}
+
+/// Checks a digest, such as sha256: followed by 64 hexadecimal digits
+fn validate_digest(digest: &str) -> Result<(), String> {
+    let invalid = || format!("invalid digest {:?}", digest);
+    let (algorithm, encoded) = digest.split_once(':').ok_or_else(invalid)?;
+
+    let valid_algorithm = !algorithm.is_empty()
+        && algorithm.split(['+', '.', '_', '-']).all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        });
+    let hex_length = match algorithm {
+        "sha256" => Some(64),
+        "sha512" => Some(128),
+        _ => None,
+    };
+    let valid_encoded = match hex_length {
+        Some(length) => {
+            encoded.len() == length
+                && encoded
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        }
+        None => {
+            !encoded.is_empty()
+                && encoded
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '=' | '_' | '-'))
+        }
+    };
+
+    match valid_algorithm && valid_encoded {
+        true => Ok(()),
+        false => Err(invalid()),
+    }
 }
 
 impl FullImageWithTag {
-    pub fn from_image_name(image_name: &str) -> Self {
-        let parts: Vec<&str> = image_name.split('/').collect();
-        let registry = if parts.len() > 2 {
-            format!("https://{}", parts[0])
-        } else {
-            "https://registry-1.docker.io".to_string()
+    /// Parses an image reference, [HOST[:PORT]/]PATH[:TAG][@DIGEST] as the
+    /// distribution project defines it, normalizing the spellings of Docker
+    /// Hub images: `nginx`, `library/nginx`, `docker.io/nginx` and
+    /// `index.docker.io/library/nginx` are all the same image.
+    ///
+    /// Images named without a host are Docker Hub images, and every registry
+    /// is spoken to over HTTPS. References given by the user are parsed with
+    /// their settings instead
+    #[cfg(test)]
+    pub fn from_image_name(image_name: &str) -> Result<Self, ReferenceError> {
+        Self::parse(image_name, &RegistrySettings::default())
+    }
+
+    /// Parses an image reference, naming images without a host after the
+    /// default registry of the settings, or Docker Hub without one
+    pub fn parse(image_name: &str, settings: &RegistrySettings) -> Result<Self, ReferenceError> {
+        let invalid = |reason: String| {
+            ReferenceError(format!(
+                "Invalid image reference {:?}: {}",
+                image_name, reason
+            ))
         };
 
-        let full_name = if parts.len() == 3 {
-            parts[1..].join("/")
-        } else {
-            image_name.to_owned()
+        // A digest pins the image, and takes the place of the tag
+        let (name_and_tag, digest) = match image_name.split_once('@') {
+            Some((name_and_tag, digest)) => {
+                validate_digest(digest).map_err(invalid)?;
+                (name_and_tag, Some(digest))
+            }
+            None => (image_name, None),
         };
 
-        let name = full_name.split(':').nth(0).unwrap().to_string();
-        let tag = full_name.split(':').nth(1).unwrap_or("latest").to_string();
+        let (host, path) = split_host(name_and_tag);
 
-        let library_name = if image_name.contains('/') {
-            name.to_string()
-        } else {
-            format!("library/{}", name)
-        };
+        if let Some(host) = host {
+            validate_host(host).map_err(invalid)?;
+        }
 
-        let service = if parts.len() > 2 {
-            parts[0].to_string()
-        } else {
-            "registry.docker.io".to_string()
+        // A colon after the last slash separates the tag
+        let (name, tag) = match path.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (path, None),
         };
 
-        FullImageWithTag {
-            image: FullImage {
-                registry,
-                image_name: name,
-                library_name,
-                service,
+        if let Some(tag) = tag {
+            validate_tag(tag).map_err(invalid)?;
+        }
+
+        validate_path(name).map_err(invalid)?;
+
+        if host.map_or(0, |host| host.len() + 1) + name.len() > MAX_NAME_LENGTH {
+            return Err(invalid(format!(
+                "the repository name is longer than {} characters",
+                MAX_NAME_LENGTH
+            )));
+        }
+
+        let host = host
+            .or(settings.default_registry.as_deref())
+            .filter(|host| !DOCKER_HUB_HOSTS.contains(host));
+
+        let image = match host {
+            Some(host) => FullImage {
+                registry: settings.registry_url(host),
+                image_name: name.to_string(),
+                library_name: settings.namespaced(host, name),
+                service: host.to_string(),
             },
-            tag,
+            None => FullImage {
+                registry: "https://registry-1.docker.io".to_string(),
+                image_name: name.strip_prefix("library/").unwrap_or(name).to_string(),
+                library_name: if name.contains('/') {
+                    name.to_string()
+                } else {
+                    format!("library/{}", name)
+                },
+                service: "registry.docker.io".to_string(),
+            },
+        };
+
+        Ok(FullImageWithTag {
+            image,
+            tag: digest.or(tag).unwrap_or("latest").to_string(),
+        })
+    }
+
+    pub fn reference(&self) -> Reference {
+        Reference::parse(&self.tag)
+    }
+
+    /// The digest the image is pinned to, if it is referenced by one
+    /// rather than by a tag
+    pub fn digest(&self) -> Option<&str> {
+        match self.reference() {
+            Reference::Digest(_) => Some(&self.tag),
+            Reference::Tag(_) => None,
         }
     }
+
+    /// The repository and reference, as shown to the user, such as
+    /// library/nginx:alpine or library/nginx@sha256:...
+    pub fn name(&self) -> String {
+        format!(
+            "{}{}{}",
+            self.image.library_name,
+            self.reference().separator(),
+            self.tag
+        )
+    }
+
+    /// The fully qualified reference, as containerd and nerdctl name images,
+    /// such as docker.io/library/nginx:alpine
+    pub fn canonical_name(&self) -> String {
+        format!(
+            "{}{}{}",
+            self.image.repository(),
+            self.reference().separator(),
+            self.tag
+        )
+    }
 }
 
 impl FullImage {
-    pub fn from_image_name(image_name: &str) -> Self {
-        FullImageWithTag::from_image_name(image_name).image
+    #[cfg(test)]
+    pub fn from_image_name(image_name: &str) -> Result<Self, ReferenceError> {
+        Ok(FullImageWithTag::from_image_name(image_name)?.image)
+    }
+
+    pub fn parse(image_name: &str, settings: &RegistrySettings) -> Result<Self, ReferenceError> {
+        Ok(FullImageWithTag::parse(image_name, settings)?.image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_from_image_name_with_digest() {
+        let image = FullImageWithTag::from_image_name(&format!("nginx@{}", DIGEST)).unwrap();
+        assert_eq!(image.image.library_name, "library/nginx");
+        assert_eq!(image.tag, DIGEST);
+
+        let image =
+            FullImageWithTag::from_image_name(&format!("ghcr.io/example/app:1.0@{}", DIGEST))
+                .unwrap();
+        assert_eq!(image.image.registry, "https://ghcr.io");
+        assert_eq!(image.image.library_name, "example/app");
+        assert_eq!(image.tag, DIGEST);
+        assert_eq!(image.digest(), Some(DIGEST));
+        assert_eq!(image.name(), format!("example/app@{}", DIGEST));
+        assert_eq!(image.reference(), Reference::Digest(DIGEST.to_string()));
+
+        let image = FullImageWithTag::from_image_name("nginx:alpine").unwrap();
+        assert_eq!(image.digest(), None);
+        assert_eq!(image.name(), "library/nginx:alpine");
+        assert_eq!(image.reference(), Reference::Tag("alpine".to_string()));
+    }
+
+    #[test]
+    fn test_registry_url() {
+        let mut settings = RegistrySettings::default();
+        settings.add_insecure_registries(["http://localhost:5000/".to_string()]);
+
+        assert_eq!(
+            settings.registry_url("registry.example.com"),
+            "https://registry.example.com"
+        );
+        assert_eq!(
+            settings.registry_url("localhost:5000"),
+            "http://localhost:5000"
+        );
+        assert_eq!(
+            settings.registry_url("localhost:5001"),
+            "https://localhost:5001"
+        );
+        assert_eq!(
+            settings.registry_url("http://registry.example.com"),
+            "http://registry.example.com"
+        );
+        assert_eq!(settings.registry_url("https://ghcr.io"), "https://ghcr.io");
+        assert_eq!(registry_host("docker.io"), "registry-1.docker.io");
+        assert_eq!(registry_host("ghcr.io"), "ghcr.io");
+    }
+
+    #[test]
+    fn test_from_image_name_normalizes() {
+        // Every spelling of an image must parse to the same identity
+        let cases: &[(&[&str], &str, &str)] = &[
+            (
+                &[
+                    "nginx",
+                    "nginx:latest",
+                    "library/nginx",
+                    "docker.io/nginx",
+                    "docker.io/library/nginx:latest",
+                    "index.docker.io/library/nginx",
+                    "registry-1.docker.io/nginx",
+                ],
+                "https://registry-1.docker.io",
+                "docker.io/library/nginx:latest",
+            ),
+            (
+                &["bitnami/redis:7", "docker.io/bitnami/redis:7"],
+                "https://registry-1.docker.io",
+                "docker.io/bitnami/redis:7",
+            ),
+            (
+                &["ghcr.io/example/app:1.0"],
+                "https://ghcr.io",
+                "ghcr.io/example/app:1.0",
+            ),
+            (
+                &["registry.example.com/team/tools/builder:2"],
+                "https://registry.example.com",
+                "registry.example.com/team/tools/builder:2",
+            ),
+            (
+                &["localhost:5000/app"],
+                "https://localhost:5000",
+                "localhost:5000/app:latest",
+            ),
+            (
+                &["[::1]:5000/a/b/c/d:v1.2_rc-3"],
+                "https://[::1]:5000",
+                "[::1]:5000/a/b/c/d:v1.2_rc-3",
+            ),
+            (
+                &["my-registry.local/my__app/web.ui--v2"],
+                "https://my-registry.local",
+                "my-registry.local/my__app/web.ui--v2:latest",
+            ),
+        ];
+
+        for (spellings, registry, canonical) in cases {
+            let first = FullImageWithTag::from_image_name(spellings[0]).unwrap();
+
+            for spelling in *spellings {
+                let image = FullImageWithTag::from_image_name(spelling).unwrap();
+                assert_eq!(image, first, "{}", spelling);
+                assert_eq!(image.image.registry, *registry, "{}", spelling);
+                assert_eq!(image.canonical_name(), *canonical, "{}", spelling);
+            }
+        }
+
+        assert_eq!(
+            FullImage::from_image_name("library/nginx")
+                .unwrap()
+                .image_name,
+            "nginx"
+        );
+        assert_eq!(
+            FullImage::from_image_name("ghcr.io/example/app:1.0")
+                .unwrap()
+                .repository(),
+            "ghcr.io/example/app"
+        );
+    }
+
+    #[test]
+    fn test_insecure_registry() {
+        let mut settings = RegistrySettings::default();
+        settings.add_insecure_registries(["localhost:5000".to_string()]);
+
+        let image = FullImageWithTag::parse("localhost:5000/app:1.0", &settings).unwrap();
+        assert_eq!(image.image.registry, "http://localhost:5000");
+        assert_eq!(image.image.get_image_url(), "http://localhost:5000/v2/app");
+
+        // Without the settings, the same reference is spoken to over HTTPS
+        let image = FullImageWithTag::from_image_name("localhost:5000/app:1.0").unwrap();
+        assert_eq!(image.image.registry, "https://localhost:5000");
+    }
+
+    #[test]
+    fn test_default_namespace() {
+        let settings = RegistrySettings {
+            namespaces: HashMap::from([("registry.example.com".to_string(), "team".to_string())]),
+            ..Default::default()
+        };
+        let parse = |name: &str| {
+            FullImageWithTag::parse(name, &settings)
+                .unwrap()
+                .canonical_name()
+        };
+
+        assert_eq!(
+            parse("registry.example.com/app"),
+            "registry.example.com/team/app:latest"
+        );
+        assert_eq!(
+            parse("registry.example.com/other/app"),
+            "registry.example.com/other/app:latest"
+        );
+        assert_eq!(parse("ghcr.io/app"), "ghcr.io/app:latest");
+    }
+
+    #[test]
+    fn test_default_registry() {
+        let settings = RegistrySettings {
+            default_registry: Some("registry.example.com".to_string()),
+            ..Default::default()
+        };
+        let parse = |name: &str| {
+            FullImageWithTag::parse(name, &settings)
+                .unwrap()
+                .canonical_name()
+        };
+
+        // Only images named without a host are taken from the default registry
+        assert_eq!(parse("nginx"), "registry.example.com/nginx:latest");
+        assert_eq!(parse("team/app:1.0"), "registry.example.com/team/app:1.0");
+        assert_eq!(parse("docker.io/nginx"), "docker.io/library/nginx:latest");
+        assert_eq!(parse("ghcr.io/example/app"), "ghcr.io/example/app:latest");
+
+        let settings = RegistrySettings {
+            default_registry: Some("docker.io".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            FullImageWithTag::parse("nginx", &settings)
+                .unwrap()
+                .canonical_name(),
+            "docker.io/library/nginx:latest"
+        );
+    }
+
+    #[test]
+    fn test_from_image_name_rejects_invalid() {
+        let cases = [
+            ("", "the repository name is empty"),
+            ("ghcr.io/", "the repository name is empty"),
+            ("nginx:", "invalid tag \"\""),
+            ("nginx:-latest", "invalid tag \"-latest\""),
+            ("nginx:a+b", "invalid tag \"a+b\""),
+            (
+                "example/App",
+                "repository name \"example/App\" must be lowercase",
+            ),
+            ("example//app", "invalid repository name \"example//app\""),
+            ("example/_app", "invalid repository name \"example/_app\""),
+            ("example/a___b", "invalid repository name \"example/a___b\""),
+            ("example/a._b", "invalid repository name \"example/a._b\""),
+            ("example/app-", "invalid repository name \"example/app-\""),
+            ("nginx@sha256:abc", "invalid digest \"sha256:abc\""),
+            ("nginx@abc", "invalid digest \"abc\""),
+            (
+                "localhost:port/app",
+                "invalid port \"port\" of registry localhost:port",
+            ),
+            (
+                "-bad.example.com/app",
+                "invalid registry host \"-bad.example.com\"",
+            ),
+            ("[::g]:5000/app", "invalid IPv6 address \"::g\""),
+        ];
+
+        for (reference, reason) in cases {
+            let error = FullImageWithTag::from_image_name(reference).unwrap_err();
+            assert!(
+                error.to_string().contains(reason),
+                "{}: {}",
+                reference,
+                error
+            );
+        }
+
+        let long = format!("ghcr.io/{}", "a".repeat(MAX_NAME_LENGTH));
+        assert!(FullImageWithTag::from_image_name(&long).is_err());
+        assert!(FullImageWithTag::from_image_name(&format!("app:{}", "v".repeat(128))).is_ok());
+        assert!(FullImageWithTag::from_image_name(&format!("app:{}", "v".repeat(129))).is_err());
     }
 }
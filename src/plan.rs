@@ -0,0 +1,432 @@
+use std::{
+    collections::HashSet,
+    env,
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use sha256::digest;
+use walkdir::WalkDir;
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    parser::{FullImage, FullImageWithTag, ReferenceError},
+    spec::plan::{ImagePlan, ImagePlanLayerType, ImagePlanLock},
+    state::{self, image_key, State},
+    Plan,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CycloneDxBom {
+    pub bom_format: String,
+    pub spec_version: String,
+    pub version: u32,
+    pub metadata: CycloneDxMetadata,
+    pub components: Vec<CycloneDxComponent>,
+}
+
+#[derive(Serialize)]
+pub struct CycloneDxMetadata {
+    pub component: CycloneDxComponent,
+}
+
+#[derive(Serialize)]
+pub struct CycloneDxComponent {
+    #[serde(rename = "type")]
+    pub component_type: String,
+    #[serde(rename = "bom-ref", skip_serializing_if = "Option::is_none")]
+    pub bom_ref: Option<String>,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purl: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hashes: Vec<CycloneDxHash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<CycloneDxProperty>,
+}
+
+#[derive(Serialize)]
+pub struct CycloneDxHash {
+    pub alg: String,
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct CycloneDxProperty {
+    pub name: String,
+    pub value: String,
+}
+
+/// Finds the plan file, searching the current directory tree if the path does not exist
+pub fn find_plan(plan: Option<String>) -> String {
+    let plan = plan.unwrap_or_else(|| "oci.json".to_string());
+    let plan_path = Path::new(&plan);
+    let plan = if plan_path.exists() {
+        plan_path.to_path_buf()
+    } else {
+        let plan_basename = plan_path.file_name().expect("Invalid plan filename");
+        WalkDir::new(env::current_dir().unwrap())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name() == plan_basename)
+            .expect("Plan file not found")
+            .into_path()
+    };
+
+    plan.canonicalize()
+        .unwrap_or(plan)
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Reads the plan file, switching to its directory so that relative sources resolve
+pub fn read_plan(plan: &str) -> ImagePlan {
+    // Set the current directory to the plan file's directory
+    if let Some(parent) = Path::new(plan).parent() {
+        if parent.exists() {
+            env::set_current_dir(parent).expect("Failed to set current directory");
+        }
+    }
+
+    let file = File::open(plan).expect("Failed to open plan file");
+    serde_json::from_reader(file).unwrap()
+}
+
+/// Returns the path of the lockfile belonging to a plan, e.g. `oci.lock.json` for `oci.json`
+pub fn lock_path(plan: &str) -> PathBuf {
+    let path = Path::new(plan);
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "oci".to_string());
+
+    path.with_file_name(format!("{}.lock.json", stem))
+}
+
+/// Reads the lockfile of a plan, returning an empty lock if there is none
+pub fn read_plan_lock(plan: &str) -> ImagePlanLock {
+    match fs::read(lock_path(plan)) {
+        Ok(data) => serde_json::from_slice(&data).expect("Failed to parse plan lockfile"),
+        Err(_) => ImagePlanLock::default(),
+    }
+}
+
+/// Returns the base images and `image` layer sources of the plan, in order of appearance
+pub fn image_sources(plan: &ImagePlan) -> Vec<String> {
+    let mut seen = HashSet::new();
+
+    plan.platforms
+        .iter()
+        .flat_map(|platform| {
+            platform.base.iter().chain(
+                platform
+                    .layers
+                    .iter()
+                    .filter(|layer| matches!(layer.layer_type, ImagePlanLayerType::Image))
+                    .map(|layer| &layer.source),
+            )
+        })
+        .filter(|source| seen.insert(source.to_string()))
+        .cloned()
+        .collect()
+}
+
+/// Resolves the digest that a tag currently points at
+pub async fn resolve_digest(
+    downloader: &OciDownloader,
+    image: &FullImageWithTag,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(digest) = image.digest() {
+        return Ok(digest.to_string());
+    }
+
+    let (_, json) = downloader.download_index(image.clone()).await?;
+    Ok(format!("sha256:{}", digest(&json)))
+}
+
+pub fn image_component(source: &str, image: &FullImageWithTag, digest: &str) -> CycloneDxComponent {
+    let name = image
+        .image
+        .library_name
+        .rsplit('/')
+        .next()
+        .unwrap_or(&image.image.library_name)
+        .to_string();
+    let registry = image
+        .image
+        .registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://");
+    let purl = format!(
+        "pkg:oci/{}@{}?repository_url={}/{}&tag={}",
+        name,
+        digest.replace(':', "%3A"),
+        registry,
+        image.image.library_name,
+        image.tag
+    );
+
+    CycloneDxComponent {
+        component_type: "container".to_string(),
+        bom_ref: Some(purl.clone()),
+        name: image.image.image_name.clone(),
+        version: Some(image.tag.clone()),
+        purl: Some(purl),
+        hashes: vec![CycloneDxHash {
+            alg: "SHA-256".to_string(),
+            content: digest.trim_start_matches("sha256:").to_string(),
+        }],
+        properties: vec![CycloneDxProperty {
+            name: "ocitool:source".to_string(),
+            value: source.to_string(),
+        }],
+    }
+}
+
+/// Builds a CycloneDX document listing the base images of a plan,
+/// using the digests pinned in the lockfile where there are any
+pub async fn build_bom(
+    plan: &ImagePlan,
+    lock: &ImagePlanLock,
+    downloader: &OciDownloader,
+) -> Result<CycloneDxBom, Box<dyn Error>> {
+    let mut components = vec![];
+
+    for source in image_sources(plan) {
+        let image = FullImageWithTag::parse(&source, downloader.client.settings())?;
+        let digest = match lock.images.get(&source) {
+            Some(digest) => digest.clone(),
+            None => resolve_digest(downloader, &image).await?,
+        };
+        components.push(image_component(&source, &image, &digest));
+    }
+
+    Ok(CycloneDxBom {
+        bom_format: "CycloneDX".to_string(),
+        spec_version: "1.5".to_string(),
+        version: 1,
+        metadata: CycloneDxMetadata {
+            component: CycloneDxComponent {
+                component_type: "container".to_string(),
+                bom_ref: None,
+                name: plan.name.clone(),
+                version: plan.tags.first().cloned(),
+                purl: None,
+                hashes: vec![],
+                properties: vec![],
+            },
+        },
+        components,
+    })
+}
+
+pub async fn plan_deps_command(
+    args: &Plan,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let plan_path = find_plan(args.plan.clone());
+    let plan = read_plan(&plan_path);
+    let lock = read_plan_lock(&plan_path);
+    let permissions: Vec<ImagePermission> = image_sources(&plan)
+        .iter()
+        .map(|source| {
+            Ok(ImagePermission {
+                full_image: FullImage::parse(source, client.settings())?,
+                permissions: ImagePermissions::Pull,
+            })
+        })
+        .collect::<Result<_, ReferenceError>>()?;
+    client.login(&permissions).await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let bom = build_bom(&plan, &lock, &downloader).await?;
+
+    println!("{}", serde_json::to_string_pretty(&bom)?);
+    Ok(())
+}
+
+/// Describes how the pinned digests moved between two locks
+pub fn lock_changelog(old: &ImagePlanLock, new: &ImagePlanLock) -> Vec<String> {
+    let mut changelog = vec![];
+
+    for (source, digest) in &new.images {
+        match old.images.get(source) {
+            None => changelog.push(format!("{}: pinned {}", source, digest)),
+            Some(old_digest) if old_digest != digest => {
+                changelog.push(format!("{}: {} -> {}", source, old_digest, digest))
+            }
+            Some(_) => changelog.push(format!("{}: unchanged ({})", source, digest)),
+        }
+    }
+
+    for (source, digest) in &old.images {
+        if !new.images.contains_key(source) {
+            changelog.push(format!("{}: removed ({})", source, digest));
+        }
+    }
+
+    changelog
+}
+
+pub async fn plan_update_command(
+    args: &Plan,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let plan_path = find_plan(args.plan.clone());
+    let plan = read_plan(&plan_path);
+    let old_lock = read_plan_lock(&plan_path);
+    let sources = image_sources(&plan);
+    let permissions: Vec<ImagePermission> = sources
+        .iter()
+        .map(|source| {
+            Ok(ImagePermission {
+                full_image: FullImage::parse(source, client.settings())?,
+                permissions: ImagePermissions::Pull,
+            })
+        })
+        .collect::<Result<_, ReferenceError>>()?;
+    client.login(&permissions).await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let mut new_lock = ImagePlanLock::default();
+    let mut image_keys = vec![];
+
+    for source in sources {
+        let image = FullImageWithTag::parse(&source, downloader.client.settings())?;
+        let digest = resolve_digest(&downloader, &image).await?;
+        image_keys.push((image_key(&image), digest.clone()));
+        new_lock.images.insert(source, digest);
+    }
+
+    for line in lock_changelog(&old_lock, &new_lock) {
+        println!("{}", line);
+    }
+
+    if new_lock != old_lock {
+        let lock_path = lock_path(&plan_path);
+        let mut data = serde_json::to_vec_pretty(&new_lock)?;
+        data.push(b'\n');
+        fs::write(&lock_path, data)?;
+        println!("Wrote {}", lock_path.display());
+    }
+
+    let now = state::now();
+    let result = State::update(&State::path(), |state| {
+        state.record_lockfile(&lock_path(&plan_path), Path::new(&plan_path), now);
+
+        for (key, digest) in &image_keys {
+            state.record_image(key, digest, now);
+        }
+    });
+
+    if let Err(e) = result {
+        eprintln!("Failed to save state: {}", e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_sources_are_unique() {
+        let plan: ImagePlan = serde_json::from_str(
+            r#"{
+                "name": "test/image",
+                "tags": ["latest"],
+                "platforms": [
+                    {
+                        "architecture": "amd64",
+                        "layers": [
+                            { "type": "image", "source": "alpine:3.20", "comment": "" },
+                            { "type": "dir", "source": "./bin", "comment": "" }
+                        ]
+                    },
+                    {
+                        "architecture": "arm64",
+                        "base": "debian:12",
+                        "layers": [
+                            { "type": "image", "source": "alpine:3.20", "comment": "" },
+                            { "type": "image", "source": "ghcr.io/owner/base:1", "comment": "" }
+                        ]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            image_sources(&plan),
+            vec![
+                "alpine:3.20".to_string(),
+                "debian:12".to_string(),
+                "ghcr.io/owner/base:1".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_image_component_purl() {
+        let image = FullImageWithTag::from_image_name("ghcr.io/owner/base:1").unwrap();
+        let component = image_component("ghcr.io/owner/base:1", &image, "sha256:abcdef");
+
+        assert_eq!(
+            component.purl.as_deref(),
+            Some("pkg:oci/base@sha256%3Aabcdef?repository_url=ghcr.io/owner/base&tag=1")
+        );
+        assert_eq!(component.hashes[0].content, "abcdef");
+    }
+
+    #[test]
+    fn test_lock_path() {
+        assert_eq!(
+            lock_path("/work/oci.json"),
+            PathBuf::from("/work/oci.lock.json")
+        );
+        assert_eq!(
+            lock_path("/work/app.plan.json"),
+            PathBuf::from("/work/app.plan.lock.json")
+        );
+    }
+
+    #[test]
+    fn test_lock_changelog() {
+        let mut old = ImagePlanLock::default();
+        old.images
+            .insert("alpine:3.20".to_string(), "sha256:aaa".to_string());
+        old.images
+            .insert("debian:12".to_string(), "sha256:bbb".to_string());
+        old.images
+            .insert("busybox:1".to_string(), "sha256:ccc".to_string());
+
+        let mut new = ImagePlanLock::default();
+        new.images
+            .insert("alpine:3.20".to_string(), "sha256:aaa".to_string());
+        new.images
+            .insert("debian:12".to_string(), "sha256:ddd".to_string());
+        new.images
+            .insert("ubuntu:24.04".to_string(), "sha256:eee".to_string());
+
+        assert_eq!(
+            lock_changelog(&old, &new),
+            vec![
+                "alpine:3.20: unchanged (sha256:aaa)".to_string(),
+                "debian:12: sha256:bbb -> sha256:ddd".to_string(),
+                "ubuntu:24.04: pinned sha256:eee".to_string(),
+                "busybox:1: removed (sha256:ccc)".to_string(),
+            ]
+        );
+    }
+}
@@ -1,8 +1,16 @@
-use crate::spec::{enums::PlatformArchitecture, index::Manifest};
+use crate::spec::{
+    enums::{PlatformArchitecture, PlatformOS},
+    index::{Manifest, Platform},
+    plan::ImagePlanPlatform,
+};
 use std::env;
 
 pub struct PlatformMatcher {
     pub platform: PlatformArchitecture,
+    /// Operating system the manifest has to be for, any if not set
+    pub os: Option<PlatformOS>,
+    /// Operating system version the manifest has to be for, any if not set
+    pub os_version: Option<String>,
 }
 
 impl PlatformMatcher {
@@ -21,24 +29,43 @@ impl PlatformMatcher {
             _ => PlatformArchitecture::Unknown,
         };
 
-        PlatformMatcher { platform }
+        PlatformMatcher {
+            platform,
+            os: None,
+            os_version: None,
+        }
     }
 
-    pub fn match_architecture(platform: PlatformArchitecture) -> Self {
-        PlatformMatcher { platform }
+    /// Matches the base image manifest of a plan platform, which has to be
+    /// for the same operating system (and version, if the plan sets one)
+    pub fn match_platform(platform: &ImagePlanPlatform) -> Self {
+        PlatformMatcher {
+            platform: platform.architecture.clone(),
+            os: Some(platform.os()),
+            os_version: platform.os_version.clone(),
+        }
     }
 
     pub fn matches(&self, image_platform: &PlatformArchitecture) -> bool {
         self.platform == *image_platform
     }
 
+    pub fn matches_platform(&self, image_platform: &Platform) -> bool {
+        self.matches(&image_platform.architecture)
+            && self.os.as_ref().is_none_or(|os| *os == image_platform.os)
+            && self
+                .os_version
+                .as_ref()
+                .is_none_or(|version| image_platform.os_version.as_ref() == Some(version))
+    }
+
     pub fn find_manifest<'a, I>(&'a self, manifests: I) -> Option<&'a Manifest>
     where
         I: IntoIterator<Item = &'a Manifest>,
     {
         for manifest in manifests {
             if let Some(platform) = &manifest.platform {
-                if self.matches(&platform.architecture) {
+                if self.matches_platform(platform) {
                     return Some(manifest);
                 }
             }
@@ -47,3 +74,165 @@ impl PlatformMatcher {
         None
     }
 }
+
+/// Formats a platform as os/architecture[/variant]
+pub fn platform_name(platform: &Platform) -> String {
+    let mut parts: Vec<String> = [
+        serde_json::to_value(&platform.os),
+        serde_json::to_value(&platform.architecture),
+    ]
+    .into_iter()
+    .filter_map(|value| value.ok()?.as_str().map(String::from))
+    .collect();
+
+    if let Some(variant) = &platform.variant {
+        parts.push(variant.clone());
+    }
+
+    parts.join("/")
+}
+
+/// Parses a platform written as os/architecture[/variant]
+pub fn parse_platform(name: &str) -> Option<Platform> {
+    let mut parts = name.splitn(3, '/');
+    let os = serde_json::from_value(parts.next()?.into()).ok()?;
+    let architecture = serde_json::from_value(parts.next()?.into()).ok()?;
+
+    Some(Platform {
+        architecture,
+        os,
+        os_version: None,
+        os_features: None,
+        variant: parts.next().map(String::from),
+        features: None,
+    })
+}
+
+/// Whether a platform was requested, as os/architecture or os/architecture/variant
+pub fn platform_requested(platform: &Platform, requested: &[String]) -> bool {
+    let name = platform_name(platform);
+
+    requested.iter().any(|requested| {
+        name == *requested
+            || name
+                .strip_prefix(requested.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::enums::PlatformOS;
+
+    #[test]
+    fn test_platform_requested() {
+        let platform = |architecture, variant: Option<&str>| Platform {
+            architecture,
+            os: PlatformOS::Linux,
+            os_version: None,
+            os_features: None,
+            variant: variant.map(String::from),
+            features: None,
+        };
+        let requested = vec!["linux/amd64".to_string(), "linux/arm/v7".to_string()];
+
+        assert!(platform_requested(
+            &platform(PlatformArchitecture::Amd64, None),
+            &requested
+        ));
+        assert!(platform_requested(
+            &platform(PlatformArchitecture::Amd64, Some("v3")),
+            &requested
+        ));
+        assert!(platform_requested(
+            &platform(PlatformArchitecture::Arm, Some("v7")),
+            &requested
+        ));
+        assert!(!platform_requested(
+            &platform(PlatformArchitecture::Arm, Some("v6")),
+            &requested
+        ));
+        assert!(!platform_requested(
+            &platform(PlatformArchitecture::Arm64, None),
+            &requested
+        ));
+    }
+
+    #[test]
+    fn test_match_platform() {
+        let manifests: Vec<Manifest> = serde_json::from_str(
+            r#"[
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+                    "size": 1,
+                    "platform": {"architecture": "amd64", "os": "windows", "os.version": "10.0.17763.6414"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+                    "size": 1,
+                    "platform": {"architecture": "amd64", "os": "windows", "os.version": "10.0.20348.2762"}
+                },
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "digest": "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+                    "size": 1,
+                    "platform": {"architecture": "amd64", "os": "linux"}
+                }
+            ]"#,
+        )
+        .unwrap();
+        let digest = |mut platform: serde_json::Value| {
+            platform["layers"] = serde_json::json!([]);
+            let platform: ImagePlanPlatform = serde_json::from_value(platform).unwrap();
+
+            PlatformMatcher::match_platform(&platform)
+                .find_manifest(&manifests)
+                .map(|manifest| manifest.digest.to_string())
+        };
+        let nth = |n: &str| Some(format!("sha256:{}", n.repeat(64)));
+
+        assert_eq!(
+            digest(serde_json::json!({"architecture": "amd64"})),
+            nth("3")
+        );
+        assert_eq!(
+            digest(serde_json::json!({"architecture": "amd64", "os": "windows"})),
+            nth("1")
+        );
+        assert_eq!(
+            digest(serde_json::json!({
+                "architecture": "amd64",
+                "os": "windows",
+                "os.version": "10.0.20348.2762"
+            })),
+            nth("2")
+        );
+        assert_eq!(
+            digest(serde_json::json!({
+                "architecture": "amd64",
+                "os": "windows",
+                "os.version": "10.0.26100.2033"
+            })),
+            None
+        );
+        assert_eq!(digest(serde_json::json!({"architecture": "arm64"})), None);
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        let platform = parse_platform("linux/arm/v7").unwrap();
+
+        assert_eq!(platform.os, PlatformOS::Linux);
+        assert_eq!(platform.architecture, PlatformArchitecture::Arm);
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+        assert_eq!(
+            platform_name(&parse_platform("linux/amd64").unwrap()),
+            "linux/amd64"
+        );
+        assert!(parse_platform("linux").is_none());
+        assert!(parse_platform("linux/sparc").is_none());
+    }
+}
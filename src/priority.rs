@@ -0,0 +1,105 @@
+use std::{fmt, io, str::FromStr};
+
+/// I/O scheduling class for the heavy phases of an upload, mirroring `ionice`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoPriority {
+    /// Only perform I/O when no other process needs the disk
+    Idle,
+    /// Best-effort scheduling at the given level, from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "idle" {
+            return Ok(IoPriority::Idle);
+        }
+
+        match s.parse::<u8>() {
+            Ok(level) if level <= 7 => Ok(IoPriority::BestEffort(level)),
+            _ => Err(format!(
+                "Invalid I/O priority {}, expected idle or a level between 0 and 7",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoPriority::Idle => write!(f, "idle"),
+            IoPriority::BestEffort(level) => write!(f, "{}", level),
+        }
+    }
+}
+
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+const IOPRIO_CLASS_BE: i32 = 2;
+const IOPRIO_CLASS_IDLE: i32 = 3;
+const IOPRIO_WHO_PROCESS: i32 = 1;
+
+impl IoPriority {
+    fn to_ioprio(self) -> i32 {
+        match self {
+            IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            IoPriority::BestEffort(level) => (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level as i32,
+        }
+    }
+}
+
+/// Sets the CPU niceness and I/O priority of the calling thread.
+///
+/// On Linux both settings are per-thread and inherited by threads spawned
+/// afterwards, so calling this before compressing also covers the zstd workers.
+pub fn set_thread_priority(nice: Option<i32>, io_priority: Option<IoPriority>) -> io::Result<()> {
+    if let Some(nice) = nice {
+        // Who = 0 targets the calling thread rather than the whole process on Linux
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(io_priority) = io_priority {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                io_priority.to_ioprio(),
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_io_priority() {
+        assert_eq!("idle".parse::<IoPriority>(), Ok(IoPriority::Idle));
+        assert_eq!("7".parse::<IoPriority>(), Ok(IoPriority::BestEffort(7)));
+        assert!("8".parse::<IoPriority>().is_err());
+        assert!("realtime".parse::<IoPriority>().is_err());
+    }
+
+    #[test]
+    fn test_set_thread_priority() {
+        // Lowering the priority of a scratch thread never needs privileges
+        std::thread::spawn(|| {
+            set_thread_priority(Some(19), Some(IoPriority::Idle)).unwrap();
+            assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }, 19);
+        })
+        .join()
+        .unwrap();
+    }
+}
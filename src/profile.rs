@@ -0,0 +1,176 @@
+use std::{
+    fmt::Write as _,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::format::format_size;
+
+/// Where a command spends its time, as summarized by `--profile-io`
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    /// Logging into registries
+    Auth,
+    /// DNS lookups, and fetching manifests and indexes to resolve references
+    Resolve,
+    /// Downloading configs and layers
+    Download,
+    /// Compressing layers
+    Compression,
+    /// Uploading blobs and manifests
+    Upload,
+    /// Writing content into containerd
+    Containerd,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::Auth,
+        Phase::Resolve,
+        Phase::Download,
+        Phase::Compression,
+        Phase::Upload,
+        Phase::Containerd,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Auth => "auth",
+            Phase::Resolve => "resolve",
+            Phase::Download => "download",
+            Phase::Compression => "compression",
+            Phase::Upload => "upload",
+            Phase::Containerd => "containerd",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    nanos: AtomicU64,
+    operations: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// The time, operations and bytes recorded for every phase
+#[derive(Default)]
+pub struct Profile {
+    totals: [Totals; 6],
+}
+
+impl Profile {
+    pub fn record(&self, phase: Phase, elapsed: Duration, bytes: u64) {
+        let totals = &self.totals[phase as usize];
+        totals
+            .nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        totals.operations.fetch_add(1, Ordering::Relaxed);
+        totals.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Renders a line per phase that anything was recorded for, with the
+    /// throughput of the phases that move bytes
+    pub fn render(&self, wall: Duration) -> String {
+        let mut out = format!(
+            "I/O profile, {:.2}s in total (concurrent operations overlap):\n",
+            wall.as_secs_f64()
+        );
+
+        for phase in Phase::ALL {
+            let totals = &self.totals[phase as usize];
+            let operations = totals.operations.load(Ordering::Relaxed);
+
+            if operations == 0 {
+                continue;
+            }
+
+            let seconds = Duration::from_nanos(totals.nanos.load(Ordering::Relaxed)).as_secs_f64();
+            let bytes = totals.bytes.load(Ordering::Relaxed);
+            let _ = write!(
+                out,
+                "  {:<12} {:>9.2}s {:>6} ops",
+                phase.name(),
+                seconds,
+                operations
+            );
+
+            if bytes > 0 {
+                let _ = write!(out, "  {:>11}", format_size(bytes));
+
+                if seconds > 0.0 {
+                    let _ = write!(out, "  {}/s", format_size((bytes as f64 / seconds) as u64));
+                }
+            }
+
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+/// Whether `--profile-io` was given, so that nothing is printed otherwise
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static PROFILE: OnceLock<Profile> = OnceLock::new();
+
+fn profile() -> &'static Profile {
+    PROFILE.get_or_init(Profile::default)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn record(phase: Phase, elapsed: Duration, bytes: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        profile().record(phase, elapsed, bytes);
+    }
+}
+
+/// Awaits a future, recording how long it took as one operation
+pub async fn timed<F: Future>(phase: Phase, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    record(phase, started.elapsed(), 0);
+    output
+}
+
+/// Prints the summary to stderr at the end of a command, if enabled
+pub fn print_summary() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let wall = STARTED.get().map(Instant::elapsed).unwrap_or_default();
+    eprint!("{}", profile().render(wall));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let profile = Profile::default();
+        profile.record(Phase::Auth, Duration::from_millis(250), 0);
+        profile.record(Phase::Download, Duration::from_secs(1), 1024 * 1024);
+        profile.record(Phase::Download, Duration::from_secs(1), 1024 * 1024);
+
+        let rendered = profile.render(Duration::from_secs(3));
+        let lines: Vec<&str> = rendered.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("3.00s in total"));
+        assert_eq!(lines[1], "  auth              0.25s      1 ops");
+        assert_eq!(
+            lines[2],
+            "  download          2.00s      2 ops        2 MiB  1 MiB/s"
+        );
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io::stdin,
+};
+
+use serde_json::Value;
+use tonic::{Code, Request};
+
+use crate::{
+    access::ensure_socket_access,
+    compose::containerd::client::{
+        services::v1::{
+            DeleteContentRequest, Info, ListContentRequest, ListImagesRequest,
+            ListNamespacesRequest, ListRequest, ListResourcesRequest,
+        },
+        Client,
+    },
+    format::format_size,
+    save::read_namespaced_content,
+    with_namespace, Prune,
+};
+
+/// The labels containerd's garbage collector follows from a blob to the
+/// blobs it refers to, such as containerd.io/gc.ref.content.l.0 for a layer
+const GC_REF_CONTENT_LABEL: &str = "containerd.io/gc.ref.content";
+
+/// Content that containerd's garbage collector never removes
+const GC_ROOT_LABEL: &str = "containerd.io/gc.root";
+
+/// Blobs without gc labels are read to find the blobs they refer to, in case
+/// they are manifests or indexes, as long as they are this small
+const MAX_MANIFEST_SIZE: i64 = 4 * 1024 * 1024;
+
+/// The digests a label set refers to
+fn label_refs(labels: &HashMap<String, String>) -> impl Iterator<Item = &String> {
+    labels
+        .iter()
+        .filter(|(key, _)| key.starts_with(GC_REF_CONTENT_LABEL))
+        .map(|(_, digest)| digest)
+}
+
+/// The digests a manifest or an index refers to, or none for anything else
+pub fn manifest_refs(data: &[u8]) -> Vec<String> {
+    let Ok(value) = serde_json::from_slice::<Value>(data) else {
+        return vec![];
+    };
+
+    let descriptors = value
+        .get("manifests")
+        .into_iter()
+        .chain(value.get("layers"))
+        .filter_map(Value::as_array)
+        .flatten()
+        .chain(value.get("config"))
+        .chain(value.get("subject"));
+
+    descriptors
+        .filter_map(|descriptor| descriptor.get("digest")?.as_str())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Every digest the roots refer to, directly or through other blobs
+pub fn reachable(roots: Vec<String>, refs: &HashMap<String, Vec<String>>) -> HashSet<String> {
+    let mut seen = HashSet::new();
+    let mut queue = roots;
+
+    while let Some(digest) = queue.pop() {
+        if !seen.insert(digest.clone()) {
+            continue;
+        }
+
+        if let Some(children) = refs.get(&digest) {
+            queue.extend(children.iter().cloned());
+        }
+    }
+
+    seen
+}
+
+/// The content of a namespace that no image, lease or gc root refers to,
+/// largest first
+pub fn unreferenced<'a>(content: &'a [Info], reachable: &HashSet<String>) -> Vec<&'a Info> {
+    let mut unreferenced: Vec<&Info> = content
+        .iter()
+        .filter(|info| !reachable.contains(&info.digest))
+        .collect();
+
+    unreferenced.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.digest.cmp(&b.digest)));
+    unreferenced
+}
+
+async fn list_namespaces(client: &Client) -> Result<Vec<String>, Box<dyn Error>> {
+    let response = client
+        .namespaces()
+        .list(Request::new(ListNamespacesRequest {
+            filter: String::new(),
+        }))
+        .await?;
+
+    Ok(response
+        .into_inner()
+        .namespaces
+        .into_iter()
+        .map(|namespace| namespace.name)
+        .collect())
+}
+
+async fn list_content(client: &Client, namespace: &str) -> Result<Vec<Info>, Box<dyn Error>> {
+    let mut stream = client
+        .content()
+        .list(with_namespace!(
+            ListContentRequest { filters: vec![] },
+            namespace
+        ))
+        .await?
+        .into_inner();
+
+    let mut content = vec![];
+    while let Some(response) = stream.message().await? {
+        content.extend(response.info);
+    }
+
+    Ok(content)
+}
+
+/// The digests that are kept no matter what refers to them: the targets of
+/// images, the content of leases, such as pulls in progress, and gc roots
+async fn roots(
+    client: &Client,
+    namespace: &str,
+    content: &[Info],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let images = client
+        .images()
+        .list(with_namespace!(
+            ListImagesRequest { filters: vec![] },
+            namespace
+        ))
+        .await?
+        .into_inner()
+        .images;
+
+    let mut roots = vec![];
+
+    for image in images {
+        roots.extend(label_refs(&image.labels).cloned());
+        roots.extend(image.target.map(|target| target.digest));
+    }
+
+    let leases = client
+        .leases()
+        .list(with_namespace!(ListRequest { filters: vec![] }, namespace))
+        .await?
+        .into_inner()
+        .leases;
+
+    for lease in leases {
+        let resources = client
+            .leases()
+            .list_resources(with_namespace!(
+                ListResourcesRequest { id: lease.id },
+                namespace
+            ))
+            .await?
+            .into_inner()
+            .resources;
+
+        roots.extend(
+            resources
+                .into_iter()
+                .filter(|resource| resource.r#type == "content")
+                .map(|resource| resource.id),
+        );
+    }
+
+    roots.extend(
+        content
+            .iter()
+            .filter(|info| info.labels.contains_key(GC_ROOT_LABEL))
+            .map(|info| info.digest.clone()),
+    );
+
+    Ok(roots)
+}
+
+/// What every blob refers to, by its gc labels. Blobs that have none, as
+/// tools that do not label what they import leave them, are read instead
+async fn content_refs(
+    client: &Client,
+    namespace: &str,
+    content: &[Info],
+) -> Result<HashMap<String, Vec<String>>, Box<dyn Error>> {
+    let mut refs = HashMap::new();
+
+    for info in content {
+        let mut children: Vec<String> = label_refs(&info.labels).cloned().collect();
+
+        if children.is_empty() && info.size <= MAX_MANIFEST_SIZE {
+            if let Some(data) = read_namespaced_content(client, namespace, &info.digest).await? {
+                children = manifest_refs(&data);
+            }
+        }
+
+        refs.insert(info.digest.clone(), children);
+    }
+
+    Ok(refs)
+}
+
+fn confirm(args: &Prune) -> bool {
+    if args.yes {
+        return true;
+    }
+
+    println!("Do you want to delete this content? (y/N)");
+
+    let mut input = String::new();
+    stdin().read_line(&mut input).expect("Failed to read line");
+
+    if !input.trim().eq_ignore_ascii_case("y") {
+        println!("Prune aborted.");
+        return false;
+    }
+
+    true
+}
+
+/// Deletes the content of the node's containerd that nothing refers to
+/// anymore, as registry cleanups leave the nodes that pulled it alone
+pub async fn prune_command(args: &Prune, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let socket_path = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| "/run/containerd/containerd.sock".into());
+    let socket = socket_path.to_str().unwrap();
+
+    ensure_socket_access(socket);
+    let client = Client::from_path(socket).await?;
+
+    let namespaces = match &args.namespace {
+        Some(namespace) => vec![namespace.clone()],
+        None => list_namespaces(&client).await?,
+    };
+
+    let mut doomed = vec![];
+
+    for namespace in &namespaces {
+        let content = list_content(&client, namespace).await?;
+        let roots = roots(&client, namespace, &content).await?;
+        let refs = content_refs(&client, namespace, &content).await?;
+        let reachable = reachable(roots, &refs);
+
+        let unreferenced: Vec<Info> = unreferenced(&content, &reachable)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        if unreferenced.is_empty() {
+            continue;
+        }
+
+        let bytes: i64 = unreferenced.iter().map(|info| info.size).sum();
+        println!(
+            "{}: {} unreferenced blobs ({})",
+            namespace,
+            unreferenced.len(),
+            format_size(bytes as u64)
+        );
+
+        for info in &unreferenced {
+            println!("  {} ({})", info.digest, format_size(info.size as u64));
+        }
+
+        doomed.push((namespace, unreferenced));
+    }
+
+    let total: i64 = doomed
+        .iter()
+        .flat_map(|(_, infos)| infos)
+        .map(|info| info.size)
+        .sum();
+
+    if doomed.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+
+    println!(
+        "Total space that would be freed: {}",
+        format_size(total as u64)
+    );
+
+    if dry_run || !confirm(args) {
+        return Ok(());
+    }
+
+    let mut freed = 0;
+
+    for (namespace, infos) in doomed {
+        for info in infos {
+            let result = client
+                .content()
+                .delete(with_namespace!(
+                    DeleteContentRequest {
+                        digest: info.digest.clone(),
+                    },
+                    namespace
+                ))
+                .await;
+
+            match result {
+                Ok(_) => freed += info.size,
+                // Removed by containerd's own garbage collection meanwhile
+                Err(status) if status.code() == Code::NotFound => {}
+                Err(status) => eprintln!(
+                    "Failed to delete {} from {}: {}",
+                    info.digest,
+                    namespace,
+                    status.message()
+                ),
+            }
+        }
+    }
+
+    println!("Pruned {}", format_size(freed as u64));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(digest: &str, size: i64) -> Info {
+        Info {
+            digest: digest.to_string(),
+            size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_manifest_refs() {
+        let index = br#"{"manifests":[{"digest":"sha256:m1"},{"digest":"sha256:m2"}]}"#;
+        assert_eq!(manifest_refs(index), vec!["sha256:m1", "sha256:m2"]);
+
+        let manifest = br#"{"config":{"digest":"sha256:c"},"layers":[{"digest":"sha256:l"}]}"#;
+        assert_eq!(manifest_refs(manifest), vec!["sha256:l", "sha256:c"]);
+
+        assert!(manifest_refs(b"\x1f\x8b layer data").is_empty());
+    }
+
+    #[test]
+    fn test_unreferenced() {
+        let refs = HashMap::from([
+            (
+                "sha256:index".to_string(),
+                vec!["sha256:manifest".to_string()],
+            ),
+            (
+                "sha256:manifest".to_string(),
+                vec!["sha256:config".to_string(), "sha256:layer".to_string()],
+            ),
+            (
+                "sha256:orphan".to_string(),
+                vec!["sha256:orphan-layer".to_string()],
+            ),
+        ]);
+        let content = vec![
+            info("sha256:index", 1),
+            info("sha256:manifest", 2),
+            info("sha256:config", 3),
+            info("sha256:layer", 400),
+            info("sha256:orphan", 5),
+            info("sha256:orphan-layer", 600),
+        ];
+
+        let reachable = reachable(vec!["sha256:index".to_string()], &refs);
+        let digests: Vec<&str> = unreferenced(&content, &reachable)
+            .into_iter()
+            .map(|info| info.digest.as_str())
+            .collect();
+
+        assert_eq!(digests, vec!["sha256:orphan-layer", "sha256:orphan"]);
+    }
+}
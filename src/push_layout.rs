@@ -0,0 +1,115 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    copy::copy_image,
+    downloader::OciDownloader,
+    local::LocalImage,
+    parser::FullImageWithTag,
+    report::Context,
+    state::{self, image_key, State},
+    uploader::OciUploader,
+    PushLayout,
+};
+
+/// The tag an image is pushed under. Layouts name images by tag, while
+/// docker save names them by their full reference
+fn tag_of(name: &str) -> &str {
+    match name.rsplit_once(':') {
+        Some((_, tag)) if !tag.contains('/') => tag,
+        _ if name.contains('/') => "latest",
+        _ => name,
+    }
+}
+
+pub async fn push_layout_command(
+    args: &PushLayout,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let local = LocalImage::open(&args.input)
+        .with_context(|| format!("opening {}", args.input))?
+        .ok_or_else(|| {
+            format!(
+                "Unsupported input {}, expected oci:<dir>, oci-archive:<tar> or docker-archive:<tar>",
+                args.input
+            )
+        })?;
+
+    let last_component = args.repository.rsplit('/').next().unwrap_or_default();
+
+    if last_component.contains(':') || last_component.contains('@') {
+        return Err("Pass a repository without a tag, images are pushed under their own".into());
+    }
+
+    let names = local.names()?;
+
+    if names.is_empty() {
+        return Err(format!("{} has no named images to push", args.input).into());
+    }
+
+    let source = local.reference();
+    let repository = FullImageWithTag::parse(&args.repository, client.settings())?.image;
+
+    client
+        .login(&[ImagePermission {
+            full_image: repository.clone(),
+            permissions: ImagePermissions::Push,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client.clone(), no_cache).with_local(Some(local));
+    let uploader = OciUploader::new(client).with_dry_run(dry_run);
+
+    for name in &names {
+        let destination = FullImageWithTag {
+            image: repository.clone(),
+            tag: tag_of(name).to_string(),
+        };
+        let target = format!("{}:{}", args.repository, destination.tag);
+
+        println!("Pushing {} to {}...", name, target);
+
+        let digest = copy_image(
+            &downloader,
+            &uploader,
+            &FullImageWithTag {
+                image: source.image.clone(),
+                tag: name.clone(),
+            },
+            &destination,
+            &args.platform,
+        )
+        .await
+        .with_context(|| format!("pushing {}", name))?;
+
+        if dry_run {
+            println!("Would push {} to {}@{}", name, target, digest);
+            continue;
+        }
+
+        if let Err(e) = State::update(&State::path(), |state| {
+            state.record_image(&image_key(&destination), &digest, state::now())
+        }) {
+            eprintln!("Failed to save state: {}", e);
+        }
+
+        println!("Pushed {} to {}@{}", name, target, digest);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_of() {
+        assert_eq!(tag_of("3.20"), "3.20");
+        assert_eq!(tag_of("alpine:3.20"), "3.20");
+        assert_eq!(tag_of("localhost:5000/app:edge"), "edge");
+        assert_eq!(tag_of("localhost:5000/app"), "latest");
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    error::Error,
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use crate::{cache::write_atomic, Cleanup, Restore};
+
+/// Where `cleanup --quarantine` moves what it would have removed, under the
+/// same paths relative to the storage directory, so that `cleanup restore`
+/// can put it back
+pub struct Quarantine<'a> {
+    storage_dir: &'a Path,
+    dir: &'a Path,
+}
+
+impl<'a> Quarantine<'a> {
+    pub fn new(storage_dir: &'a Path, dir: &'a Path) -> Result<Self, String> {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or(path.to_path_buf());
+
+        // The next cleanup would find it as a repository, or remove it
+        if absolute(dir).starts_with(absolute(storage_dir)) {
+            return Err(format!(
+                "The quarantine {} cannot be inside the storage directory {}",
+                dir.display(),
+                storage_dir.display()
+            ));
+        }
+
+        Ok(Quarantine { storage_dir, dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir
+    }
+
+    fn path_of(&self, path: &Path) -> io::Result<PathBuf> {
+        let relative = path.strip_prefix(self.storage_dir).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is outside of {}",
+                    path.display(),
+                    self.storage_dir.display()
+                ),
+            )
+        })?;
+
+        Ok(self.dir.join(relative))
+    }
+
+    /// Moves a path of the storage directory into the quarantine, over what
+    /// an earlier cleanup left there under the same path
+    pub fn keep(&self, path: &Path) -> io::Result<()> {
+        move_path(path, &self.path_of(path)?)
+    }
+
+    /// Keeps the entries removed from the index.json of an OCI layout, added
+    /// to those an earlier cleanup removed from it
+    pub fn keep_index_entries(&self, index_path: &Path, entries: Vec<Value>) -> io::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.path_of(index_path)?;
+        let mut index =
+            read_index(&path).unwrap_or_else(|_| serde_json::json!({ "manifests": [] }));
+        merge_index_entries(&mut index, entries);
+
+        write_atomic(&path, &serde_json::to_vec(&index)?)
+    }
+}
+
+fn read_index(path: &Path) -> io::Result<Value> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Adds the entries that are not in the manifests of an index yet
+fn merge_index_entries(index: &mut Value, entries: Vec<Value>) {
+    if index.get("manifests").and_then(Value::as_array).is_none() {
+        index["manifests"] = Value::Array(vec![]);
+    }
+
+    if let Some(manifests) = index["manifests"].as_array_mut() {
+        for entry in entries {
+            if !manifests.contains(&entry) {
+                manifests.push(entry);
+            }
+        }
+    }
+}
+
+fn copy_path(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+
+    if metadata.is_symlink() {
+        symlink(fs::read_link(from)?, to)
+    } else if metadata.is_dir() {
+        fs::create_dir_all(to)?;
+
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        Ok(())
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Moves a file or directory, merging directories into those already there
+/// and replacing files. Copies it when the quarantine is on another device
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok_and(|metadata| metadata.is_dir())
+        && fs::symlink_metadata(from)?.is_dir()
+    {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            move_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        return fs::remove_dir(from);
+    }
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_path(from, to)?;
+
+            match fs::symlink_metadata(from)?.is_dir() {
+                true => fs::remove_dir_all(from),
+                false => fs::remove_file(from),
+            }
+        }
+        result => result,
+    }
+}
+
+/// What a restore put back
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RestoreSummary {
+    pub restored: u64,
+    /// Paths the registry has again since the cleanup, which stay in the
+    /// quarantine
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Moves what is in the quarantine back into the storage directory, without
+/// replacing anything the registry wrote since. The tags of an OCI layout
+/// are added back to its index.json. A dry run only counts what would be
+/// moved back
+pub fn restore(quarantine: &Path, storage_dir: &Path, dry_run: bool) -> io::Result<RestoreSummary> {
+    let mut summary = RestoreSummary::default();
+    restore_path(quarantine, storage_dir, dry_run, &mut summary)?;
+    Ok(summary)
+}
+
+fn restore_path(
+    from: &Path,
+    to: &Path,
+    dry_run: bool,
+    summary: &mut RestoreSummary,
+) -> io::Result<()> {
+    let Ok(existing) = fs::symlink_metadata(to) else {
+        if !dry_run {
+            move_path(from, to)?;
+        }
+
+        summary.restored += 1;
+        return Ok(());
+    };
+
+    if fs::symlink_metadata(from)?.is_dir() && existing.is_dir() {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            restore_path(&entry.path(), &to.join(entry.file_name()), dry_run, summary)?;
+        }
+
+        // Left in place when something in it was skipped
+        if !dry_run {
+            let _ = fs::remove_dir(from);
+        }
+
+        return Ok(());
+    }
+
+    if from.file_name().is_some_and(|name| name == "index.json") {
+        if dry_run {
+            summary.restored += 1;
+            return Ok(());
+        }
+
+        let entries = read_index(from)?
+            .get("manifests")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let mut index = read_index(to)?;
+        merge_index_entries(&mut index, entries);
+
+        write_atomic(to, &serde_json::to_vec(&index)?)?;
+        fs::remove_file(from)?;
+        summary.restored += 1;
+        return Ok(());
+    }
+
+    summary.skipped.push(to.to_path_buf());
+    Ok(())
+}
+
+pub fn restore_command(
+    cleanup: &Cleanup,
+    args: &Restore,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let Some(dir) = &cleanup.dir else {
+        return Err("Give the storage directory to restore into with --dir".into());
+    };
+
+    if !args.quarantine.is_dir() {
+        return Err(format!("No quarantine at {}", args.quarantine.display()).into());
+    }
+
+    Quarantine::new(dir, &args.quarantine)?;
+
+    let summary = restore(&args.quarantine, dir, dry_run)?;
+
+    for path in &summary.skipped {
+        eprintln!(
+            "{} {} in the quarantine, the registry has it again",
+            match dry_run {
+                true => "Would keep",
+                false => "Kept",
+            },
+            path.display()
+        );
+    }
+
+    match dry_run {
+        true => println!(
+            "Would restore {} paths into {}",
+            summary.restored,
+            dir.display()
+        ),
+        false => println!("Restored {} paths into {}", summary.restored, dir.display()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_quarantine_and_restore() {
+        let root = tempdir().unwrap();
+        let storage = root.path().join("registry");
+        let quarantine_dir = root.path().join("quarantine");
+
+        let blob = storage.join("blobs/sha256/ab/abcd");
+        fs::create_dir_all(&blob).unwrap();
+        fs::write(blob.join("data"), "blob").unwrap();
+
+        let index_path = storage.join("app/index.json");
+        fs::create_dir_all(index_path.parent().unwrap()).unwrap();
+        fs::write(&index_path, r#"{"manifests":[{"digest":"sha256:aa"}]}"#).unwrap();
+
+        assert!(Quarantine::new(&storage, &storage.join("quarantine")).is_err());
+
+        let quarantine = Quarantine::new(&storage, &quarantine_dir).unwrap();
+        quarantine.keep(&blob).unwrap();
+        quarantine
+            .keep_index_entries(
+                &index_path,
+                vec![serde_json::json!({"digest": "sha256:bb"})],
+            )
+            .unwrap();
+
+        assert!(!blob.exists());
+        assert_eq!(
+            fs::read_to_string(quarantine_dir.join("blobs/sha256/ab/abcd/data")).unwrap(),
+            "blob"
+        );
+        assert!(quarantine.keep(&root.path().join("elsewhere")).is_err());
+
+        // The registry wrote an upload since, which the restore leaves alone
+        let upload = storage.join("_uploads/id/data");
+        fs::create_dir_all(upload.parent().unwrap()).unwrap();
+        fs::write(&upload, "new").unwrap();
+        fs::create_dir_all(quarantine_dir.join("_uploads/id")).unwrap();
+        fs::write(quarantine_dir.join("_uploads/id/data"), "old").unwrap();
+
+        // A dry run counts the same paths, and leaves both directories be
+        let summary = restore(&quarantine_dir, &storage, true).unwrap();
+        assert_eq!(summary.restored, 2);
+        assert_eq!(summary.skipped, vec![upload.clone()]);
+        assert!(!blob.exists());
+        assert!(quarantine_dir.join("blobs/sha256/ab/abcd/data").exists());
+        assert_eq!(
+            read_index(&index_path).unwrap()["manifests"],
+            serde_json::json!([{"digest": "sha256:aa"}])
+        );
+
+        let summary = restore(&quarantine_dir, &storage, false).unwrap();
+        assert_eq!(summary.restored, 2);
+        assert_eq!(summary.skipped, vec![upload.clone()]);
+
+        assert_eq!(fs::read_to_string(blob.join("data")).unwrap(), "blob");
+        let index = read_index(&index_path).unwrap();
+        assert_eq!(
+            index["manifests"],
+            serde_json::json!([{"digest": "sha256:aa"}, {"digest": "sha256:bb"}])
+        );
+        assert!(!quarantine_dir.join("blobs").exists());
+        assert_eq!(fs::read_to_string(&upload).unwrap(), "new");
+    }
+}
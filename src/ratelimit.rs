@@ -0,0 +1,206 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::Serialize;
+
+use crate::format::format_duration;
+
+/// How long to wait for a rate limit without a Retry-After, doubled with
+/// every wait up to MAX_WAIT
+const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+const MAX_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// How many times a rate limited request is waited for and sent again,
+/// before the rate limit is given up on
+pub const MAX_RATE_LIMIT_WAITS: u32 = 6;
+
+/// Set by --respect-rate-limits, which waits out rate limits instead of
+/// failing on them
+static RESPECT_RATE_LIMITS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_respect_rate_limits(respect: bool) {
+    RESPECT_RATE_LIMITS.store(respect, Ordering::Relaxed);
+}
+
+pub fn respect_rate_limits() -> bool {
+    RESPECT_RATE_LIMITS.load(Ordering::Relaxed)
+}
+
+/// The pulls a registry has left for this client, as Docker Hub reports
+/// them in its ratelimit-limit and ratelimit-remaining headers
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    pub limit: Option<u64>,
+    pub remaining: u64,
+    /// The window the limit counts pulls over, such as 21600 for 6 hours
+    pub window_seconds: Option<u64>,
+    /// Who the limit applies to, an IP address or an account
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl RateLimit {
+    pub fn describe(&self) -> String {
+        let window = self
+            .window_seconds
+            .map(|seconds| format!(" per {}", format_duration(seconds)))
+            .unwrap_or_default();
+
+        match self.limit {
+            Some(limit) => format!("{} of {} pulls left{}", self.remaining, limit, window),
+            None => format!("{} pulls left{}", self.remaining, window),
+        }
+    }
+}
+
+/// Parses a quota header, like 76;w=21600
+fn parse_quota(value: &str) -> Option<(u64, Option<u64>)> {
+    let mut parts = value.split(';').map(str::trim);
+    let count = parts.next()?.parse().ok()?;
+    let window = parts
+        .find_map(|part| part.strip_prefix("w="))
+        .and_then(|window| window.parse().ok());
+
+    Some((count, window))
+}
+
+/// The rate limit a response reports, if any
+pub fn parse_rate_limit(headers: &HeaderMap) -> Option<RateLimit> {
+    let header = |name: &str| headers.get(name)?.to_str().ok();
+    let (remaining, remaining_window) = parse_quota(header("ratelimit-remaining")?)?;
+    let (limit, limit_window) = match header("ratelimit-limit").and_then(parse_quota) {
+        Some((limit, window)) => (Some(limit), window),
+        None => (None, None),
+    };
+
+    Some(RateLimit {
+        limit,
+        remaining,
+        window_seconds: remaining_window.or(limit_window),
+        source: header("docker-ratelimit-source").map(str::to_string),
+    })
+}
+
+/// How long the Retry-After header of a response asks to wait, given either
+/// in seconds or as a date
+pub fn retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => Some(
+            httpdate::parse_http_date(value)
+                .ok()?
+                .duration_since(now)
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// How long to wait before sending a rate limited request again, after
+/// having waited for it a number of times already
+pub fn wait_time(headers: &HeaderMap, waits: u32, now: SystemTime) -> Duration {
+    retry_after(headers, now)
+        .unwrap_or_else(|| DEFAULT_WAIT.saturating_mul(1 << waits.min(8)))
+        .min(MAX_WAIT)
+}
+
+/// The error of a request a registry refused with 429
+pub fn rate_limited_message(
+    host: &str,
+    headers: &HeaderMap,
+    rate_limit: Option<&RateLimit>,
+) -> String {
+    let mut message = format!("Rate limited by {}", host);
+
+    if let Some(wait) = retry_after(headers, SystemTime::now()) {
+        message += &format!(", retry after {}", format_duration(wait.as_secs()));
+    }
+
+    if let Some(rate_limit) = rate_limit {
+        message += &format!(" ({})", rate_limit.describe());
+    }
+
+    match respect_rate_limits() {
+        true => message,
+        false => message + ". Pass --respect-rate-limits to wait for it",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    fn headers(values: &[(&'static str, &str)]) -> HeaderMap {
+        values
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let rate_limit = parse_rate_limit(&headers(&[
+            ("ratelimit-limit", "100;w=21600"),
+            ("ratelimit-remaining", "76;w=21600"),
+            ("docker-ratelimit-source", "192.0.2.1"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            rate_limit,
+            RateLimit {
+                limit: Some(100),
+                remaining: 76,
+                window_seconds: Some(21600),
+                source: Some("192.0.2.1".to_string()),
+            }
+        );
+        assert_eq!(rate_limit.describe(), "76 of 100 pulls left per 6h");
+        assert_eq!(
+            parse_rate_limit(&headers(&[("ratelimit-remaining", "5")]))
+                .unwrap()
+                .describe(),
+            "5 pulls left"
+        );
+        assert_eq!(
+            parse_rate_limit(&headers(&[("ratelimit-limit", "100")])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_wait_time() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+
+        assert_eq!(
+            wait_time(&headers(&[("retry-after", "120")]), 0, now),
+            Duration::from_secs(120)
+        );
+        assert_eq!(
+            wait_time(
+                &headers(&[("retry-after", "Wed, 21 Oct 2015 07:30:30 GMT")]),
+                0,
+                now
+            ),
+            Duration::from_secs(150)
+        );
+        assert_eq!(wait_time(&headers(&[]), 0, now), DEFAULT_WAIT);
+        assert_eq!(wait_time(&headers(&[]), 2, now), DEFAULT_WAIT * 4);
+        assert_eq!(wait_time(&headers(&[]), 10, now), MAX_WAIT);
+        assert_eq!(
+            wait_time(&headers(&[("retry-after", "86400")]), 0, now),
+            MAX_WAIT
+        );
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::{Response, ResponseBuilderExt, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::macros::{impl_error, impl_from_error};
+
+impl_error!(RecordingError);
+impl_from_error!(reqwest::Error, RecordingError);
+
+/// Fields of token responses that are never written to a recording
+const SECRET_FIELDS: [&str; 3] = ["token", "access_token", "refresh_token"];
+
+/// One request to a registry and the response it got
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The body of the response, in base64
+    pub body: String,
+}
+
+impl Interaction {
+    /// The response of the interaction, unless its fixture was edited into
+    /// something that is not a response
+    fn into_response(self) -> Result<Response, RecordingError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            RecordingError(format!(
+                "Invalid recorded response to {} {}: {}",
+                self.method, self.url, e
+            ))
+        };
+        let mut builder = http::Response::builder().status(self.status);
+
+        if let Ok(url) = Url::parse(&self.url) {
+            builder = builder.url(url);
+        }
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+
+        let body = BASE64_STANDARD
+            .decode(&self.body)
+            .map_err(|e| invalid(&e))?;
+        let response = builder.body(body).map_err(|e| invalid(&e))?;
+
+        Ok(Response::from(response))
+    }
+}
+
+/// Replaces the tokens in the body of a token response, so that recordings
+/// can be attached to bug reports
+fn redact(body: &[u8]) -> Vec<u8> {
+    let Ok(serde_json::Value::Object(mut json)) = serde_json::from_slice(body) else {
+        return body.to_vec();
+    };
+
+    let mut redacted = false;
+
+    for field in SECRET_FIELDS {
+        if let Some(value) = json.get_mut(field) {
+            *value = "REDACTED".into();
+            redacted = true;
+        }
+    }
+
+    match redacted {
+        true => serde_json::to_vec(&json).unwrap_or_default(),
+        false => body.to_vec(),
+    }
+}
+
+/// Registry interactions captured to, or replayed from, a directory of
+/// fixtures, one JSON file per interaction in the order they happened
+pub enum Recording {
+    Record {
+        dir: PathBuf,
+        next: AtomicUsize,
+    },
+    /// Recorded responses by method and URL, handed out in their order
+    Replay {
+        interactions: Mutex<HashMap<(String, String), VecDeque<Interaction>>>,
+    },
+}
+
+impl Recording {
+    pub fn record(dir: &Path) -> io::Result<Recording> {
+        fs::create_dir_all(dir)?;
+
+        Ok(Recording::Record {
+            dir: dir.to_path_buf(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn replay(dir: &Path) -> io::Result<Recording> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+        paths.sort();
+
+        let mut interactions: HashMap<_, VecDeque<_>> = HashMap::new();
+
+        for path in paths {
+            let interaction: Interaction = serde_json::from_slice(&fs::read(&path)?)
+                .map_err(|e| io::Error::other(format!("{}: {}", path.display(), e)))?;
+
+            interactions
+                .entry((interaction.method.clone(), interaction.url.clone()))
+                .or_default()
+                .push_back(interaction);
+        }
+
+        Ok(Recording::Replay {
+            interactions: Mutex::new(interactions),
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Recording::Replay { .. })
+    }
+
+    /// Writes a response to the recording, returning it with the body that
+    /// had to be read for that
+    pub async fn record_response(
+        &self,
+        method: &str,
+        url: &str,
+        response: Response,
+    ) -> Result<Response, RecordingError> {
+        let Recording::Record { dir, next } = self else {
+            return Ok(response);
+        };
+
+        let status = response.status().as_u16();
+        let headers: Vec<(String, String)> = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| *name != reqwest::header::SET_COOKIE)
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = response.bytes().await?;
+
+        let interaction = Interaction {
+            method: method.to_string(),
+            url: url.to_string(),
+            status,
+            headers,
+            body: BASE64_STANDARD.encode(redact(&body)),
+        };
+
+        let path = dir.join(format!(
+            "{:05}-{}.json",
+            next.fetch_add(1, Ordering::Relaxed),
+            method.to_lowercase()
+        ));
+
+        if let Err(e) = fs::write(&path, serde_json::to_vec_pretty(&interaction).unwrap()) {
+            eprintln!("Failed to record {}: {}", path.display(), e);
+        }
+
+        Interaction {
+            body: BASE64_STANDARD.encode(&body),
+            ..interaction
+        }
+        .into_response()
+    }
+
+    /// The next recorded response to a request. Requests that were not
+    /// recorded get a 404, as a registry without the content would answer
+    pub fn replay_response(&self, method: &str, url: &str) -> Result<Response, RecordingError> {
+        let Recording::Replay { interactions } = self else {
+            panic!("Not replaying a recording");
+        };
+
+        let interaction = interactions
+            .lock()
+            .unwrap()
+            .get_mut(&(method.to_string(), url.to_string()))
+            .and_then(VecDeque::pop_front);
+
+        match interaction {
+            Some(interaction) => interaction.into_response(),
+            None => {
+                eprintln!("No recorded response to {} {}", method, url);
+
+                Interaction {
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    status: StatusCode::NOT_FOUND.as_u16(),
+                    headers: vec![],
+                    body: String::new(),
+                }
+                .into_response()
+            }
+        }
+    }
+}
+
+/// The recording of `--record` or `--replay`, if either is given
+pub fn open(record: Option<&Path>, replay: Option<&Path>) -> io::Result<Option<Recording>> {
+    match (record, replay) {
+        (Some(_), Some(_)) => Err(io::Error::other(
+            "--record and --replay cannot be used together",
+        )),
+        (Some(dir), None) => Recording::record(dir).map(Some),
+        (None, Some(dir)) => Recording::replay(dir).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        client::{count_retries, ImagePermission, ImagePermissions, LoginCredentials, OciClient},
+        downloader::{IndexResponse, OciDownloader},
+        parser::FullImageWithTag,
+    };
+
+    fn write(dir: &Path, name: &str, interaction: Interaction) {
+        fs::write(dir.join(name), serde_json::to_vec(&interaction).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(br#"{"token":"secret","expires_in":300}"#),
+            br#"{"expires_in":300,"token":"REDACTED"}"#
+        );
+        assert_eq!(redact(b"not json"), b"not json");
+    }
+
+    #[tokio::test]
+    async fn test_replay_download_index() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let image = FullImageWithTag::from_image_name("registry.example.com/app:1.0").unwrap();
+        let manifest = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:aa","size":2},"layers":[]}"#;
+
+        write(
+            dir.path(),
+            "00000-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://registry.example.com/auth?service=registry.example.com&scope=repository%3Aapp%3Apull".to_string(),
+                status: 200,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(r#"{"token":"REDACTED"}"#),
+            },
+        );
+        write(
+            dir.path(),
+            "00001-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://registry.example.com/v2/app/manifests/1.0".to_string(),
+                status: 200,
+                headers: vec![(
+                    "content-type".to_string(),
+                    "application/vnd.oci.image.manifest.v1+json".to_string(),
+                )],
+                body: BASE64_STANDARD.encode(manifest),
+            },
+        );
+
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+
+        let (response, json) = OciDownloader::new(client.clone(), true)
+            .download_index(image.clone())
+            .await?;
+
+        assert!(matches!(response, IndexResponse::ImageManifest(_)));
+        assert_eq!(json, manifest);
+
+        // Each recorded response is only replayed once
+        assert!(OciDownloader::new(client, true)
+            .download_index(image)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_invalid_fixture() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let url = "https://registry.example.com/v2/app/manifests/1.0";
+
+        write(
+            dir.path(),
+            "00000-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: url.to_string(),
+                status: 200,
+                headers: vec![("content type".to_string(), "text/plain".to_string())],
+                body: String::new(),
+            },
+        );
+
+        // A fixture edited into an invalid response fails the request
+        let client = OciClient::new(HashMap::new(), None)
+            .with_recording(Arc::new(Recording::replay(dir.path())?));
+        let error = client
+            .send(url, |client| client.get(url))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid recorded response"),
+            "{}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_discovers_token_endpoint() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let image =
+            FullImageWithTag::from_image_name("harbor.example.com/project/app:1.0").unwrap();
+
+        write(
+            dir.path(),
+            "00000-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://harbor.example.com/v2/".to_string(),
+                status: 401,
+                headers: vec![(
+                    "www-authenticate".to_string(),
+                    r#"Bearer realm="https://harbor.example.com/service/token",service="harbor-registry""#.to_string(),
+                )],
+                body: String::new(),
+            },
+        );
+        write(
+            dir.path(),
+            "00001-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://harbor.example.com/service/token?service=harbor-registry&scope=repository%3Aproject%2Fapp%3Apull".to_string(),
+                status: 200,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(r#"{"token":"REDACTED"}"#),
+            },
+        );
+
+        let client = OciClient::new(HashMap::new(), None)
+            .with_recording(Arc::new(Recording::replay(dir.path())?));
+        let permission = ImagePermission {
+            full_image: image.image,
+            permissions: ImagePermissions::Pull,
+        };
+        client.login(std::slice::from_ref(&permission)).await?;
+
+        let headers = client.auth_headers(permission).await?;
+        assert_eq!(headers[reqwest::header::AUTHORIZATION], "Bearer REDACTED");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_retries_after_expired_token() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let image = FullImageWithTag::from_image_name("registry.example.com/app:1.0").unwrap();
+        let token_url =
+            "https://registry.example.com/auth?service=registry.example.com&scope=repository%3Aapp%3Apull";
+        let manifest_url = "https://registry.example.com/v2/app/manifests/1.0";
+        let manifest = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:aa","size":2},"layers":[]}"#;
+
+        for (name, url, status, body) in [
+            ("00000-get.json", token_url, 200, r#"{"token":"first"}"#),
+            ("00001-get.json", manifest_url, 401, ""),
+            ("00002-get.json", token_url, 200, r#"{"token":"second"}"#),
+            ("00003-get.json", manifest_url, 200, manifest),
+        ] {
+            write(
+                dir.path(),
+                name,
+                Interaction {
+                    method: "GET".to_string(),
+                    url: url.to_string(),
+                    status,
+                    headers: vec![(
+                        "content-type".to_string(),
+                        "application/vnd.oci.image.manifest.v1+json".to_string(),
+                    )],
+                    body: BASE64_STANDARD.encode(body),
+                },
+            );
+        }
+
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        let permission = ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        };
+        client.login(std::slice::from_ref(&permission)).await?;
+
+        let retries = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let (_, json) = count_retries(
+            retries.clone(),
+            OciDownloader::new(client.clone(), true).download_index(image),
+        )
+        .await?;
+
+        assert_eq!(json, manifest);
+        assert_eq!(retries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            client.auth_headers(permission).await?[reqwest::header::AUTHORIZATION],
+            "Bearer second"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_checks_login() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let token_url = "https://ghcr.io/token?service=ghcr.io";
+        let challenge = vec![(
+            "www-authenticate".to_string(),
+            r#"Bearer realm="https://ghcr.io/token",service="ghcr.io""#.to_string(),
+        )];
+
+        // Every check pings /v2/, then asks for a token with the credentials
+        for (name, url, status, headers) in [
+            (
+                "00000-get.json",
+                "https://ghcr.io/v2/",
+                401,
+                challenge.clone(),
+            ),
+            ("00001-get.json", token_url, 401, vec![]),
+            ("00002-get.json", "https://ghcr.io/v2/", 401, challenge),
+            ("00003-get.json", token_url, 200, vec![]),
+        ] {
+            write(
+                dir.path(),
+                name,
+                Interaction {
+                    method: "GET".to_string(),
+                    url: url.to_string(),
+                    status,
+                    headers,
+                    body: String::new(),
+                },
+            );
+        }
+
+        let client = OciClient::new(HashMap::new(), None)
+            .with_recording(Arc::new(Recording::replay(dir.path())?));
+        let login = LoginCredentials {
+            username: "bot".to_string(),
+            password: "token".to_string(),
+        };
+
+        let error = client.check_login("https://ghcr.io", &login).await;
+        assert!(error.unwrap_err().to_string().contains("Invalid username"));
+        client.check_login("https://ghcr.io", &login).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_reports_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let image = FullImageWithTag::from_image_name("nginx:alpine").unwrap();
+
+        write(
+            dir.path(),
+            "00000-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://auth.docker.io/token?service=registry.docker.io&scope=repository%3Alibrary%2Fnginx%3Apull".to_string(),
+                status: 200,
+                headers: vec![],
+                body: BASE64_STANDARD.encode(r#"{"token":"REDACTED"}"#),
+            },
+        );
+        write(
+            dir.path(),
+            "00001-get.json",
+            Interaction {
+                method: "GET".to_string(),
+                url: "https://registry-1.docker.io/v2/library/nginx/manifests/alpine".to_string(),
+                status: 429,
+                headers: vec![
+                    ("ratelimit-limit".to_string(), "100;w=21600".to_string()),
+                    ("ratelimit-remaining".to_string(), "0;w=21600".to_string()),
+                    ("retry-after".to_string(), "3600".to_string()),
+                ],
+                body: String::new(),
+            },
+        );
+
+        let client = Arc::new(
+            OciClient::new(HashMap::new(), None)
+                .with_recording(Arc::new(Recording::replay(dir.path())?)),
+        );
+        client
+            .login(&[ImagePermission {
+                full_image: image.image.clone(),
+                permissions: ImagePermissions::Pull,
+            }])
+            .await?;
+
+        let Err(error) = OciDownloader::new(client.clone(), true)
+            .download_index(image)
+            .await
+        else {
+            panic!("A rate limited pull succeeded");
+        };
+        let error = error.to_string();
+
+        assert!(error.contains(
+            "Rate limited by registry-1.docker.io, retry after 1h (0 of 100 pulls left per 6h)"
+        ));
+        assert_eq!(
+            client
+                .rate_limit("registry-1.docker.io")
+                .map(|limit| limit.remaining),
+            Some(0)
+        );
+
+        Ok(())
+    }
+}
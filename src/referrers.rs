@@ -0,0 +1,58 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::OciDownloader,
+    format::format_size,
+    parser::FullImageWithTag,
+    Referrers,
+};
+
+pub async fn referrers_command(
+    args: &Referrers,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let image = FullImageWithTag::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+
+    let digest = match image.digest() {
+        Some(digest) => digest.to_string(),
+        None => {
+            let (_, index_json) = downloader.download_index(image.clone()).await?;
+            sha256_digest(index_json.as_bytes())
+        }
+    };
+
+    let referrers = downloader
+        .list_referrers(image.image.clone(), &digest, args.artifact_type.as_deref())
+        .await?;
+
+    if referrers.is_empty() {
+        println!("No referrers found for {} ({})", args.image, digest);
+        return Ok(());
+    }
+
+    for referrer in referrers {
+        println!(
+            "{}  {}  {}",
+            referrer.digest,
+            referrer
+                .artifact_type
+                .as_deref()
+                .unwrap_or(&referrer.media_type),
+            format_size(referrer.size)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,449 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use reqwest::{ClientBuilder, Proxy};
+use serde::Deserialize;
+use toml_edit::DocumentMut;
+
+use crate::{
+    client::{ConnectionOptions, HttpProtocol, LoginCredentials},
+    parser::{registry_host, validate_host},
+    recording::Recording,
+    tls::TlsOptions,
+};
+
+/// The settings of one registry host in the configuration file
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryConfig {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Talks to the registry over plain HTTP
+    #[serde(default)]
+    pub insecure: bool,
+    /// A PEM file of certificate authorities trusted for this registry
+    pub ca: Option<PathBuf>,
+    /// A PEM client certificate presented to the registry, and its key
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Forces the HTTP version, like --protocol
+    pub protocol: Option<HttpProtocol>,
+    /// Registry hosts serving the same images, tried before this one
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+    /// The namespace of repositories named without one, like library on
+    /// Docker Hub, so that registry.example.com/app is registry.example.com/team/app
+    pub namespace: Option<String>,
+}
+
+/// The configuration file, ~/.config/ocitool/config.toml:
+///
+/// ```toml
+/// default_registry = "registry.example.com"
+///
+/// [registries."registry.example.com"]
+/// username = "ci"
+/// password = "secret"
+/// ca = "/etc/ssl/corp-ca.pem"
+/// namespace = "team"
+///
+/// [registries."localhost:5000"]
+/// insecure = true
+/// ```
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The file the configuration was read from, if any
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// The registry of images named without a host, in place of Docker Hub
+    pub default_registry: Option<String>,
+    #[serde(default)]
+    pub registries: BTreeMap<String, RegistryConfig>,
+}
+
+impl ConfigFile {
+    pub fn parse(content: &str) -> Result<ConfigFile, String> {
+        let config: ConfigFile = toml::from_str(content).map_err(|e| e.to_string())?;
+
+        if let Some(host) = &config.default_registry {
+            validate_host(host).map_err(|e| format!("Invalid default_registry: {}", e))?;
+        }
+
+        for (host, registry) in &config.registries {
+            if registry.username.is_some() != registry.password.is_some() {
+                return Err(format!(
+                    "Registry {} needs both a username and a password",
+                    host
+                ));
+            }
+
+            if registry.key.is_some() && registry.cert.is_none() {
+                return Err(format!("Registry {} has a key but no cert", host));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The file read by default: $OCITOOL_CONFIG, or else config.toml in the
+    /// ocitool directory of the user configuration directory
+    pub fn default_path() -> Option<PathBuf> {
+        match env::var_os("OCITOOL_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_dir().map(|dir| dir.join("ocitool").join("config.toml")),
+        }
+    }
+
+    /// Reads the configuration file, which is optional
+    pub fn load() -> io::Result<ConfigFile> {
+        let Some(path) = Self::default_path().filter(|path| path.is_file()) else {
+            return Ok(ConfigFile::default());
+        };
+
+        let content = fs::read_to_string(&path)?;
+        let mut config = ConfigFile::parse(&content)
+            .map_err(|e| io::Error::other(format!("{}: {}", path.display(), e)))?;
+        config.path = Some(path);
+
+        Ok(config)
+    }
+
+    /// The credentials of the registries, by registry URL
+    pub fn logins(&self, settings: &RegistrySettings) -> HashMap<String, LoginCredentials> {
+        self.registries
+            .iter()
+            .filter_map(|(host, registry)| {
+                Some((
+                    settings.registry_url(registry_host(host)),
+                    LoginCredentials {
+                        username: registry.username.clone()?,
+                        password: registry.password.clone()?,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    pub fn insecure_registries(&self) -> Vec<String> {
+        self.registries
+            .iter()
+            .filter(|(_, registry)| registry.insecure)
+            .map(|(host, _)| host.clone())
+            .collect()
+    }
+
+    pub fn protocols(&self) -> HashMap<String, HttpProtocol> {
+        self.registries
+            .iter()
+            .filter_map(|(host, registry)| Some((host_name(host), registry.protocol?)))
+            .collect()
+    }
+
+    pub fn namespaces(&self) -> HashMap<String, String> {
+        self.registries
+            .iter()
+            .filter_map(|(host, registry)| Some((host.clone(), registry.namespace.clone()?)))
+            .collect()
+    }
+
+    /// The mirrors of the registries, by registry host as images are pulled
+    /// from it
+    pub fn mirrors(&self) -> HashMap<String, Vec<String>> {
+        self.registries
+            .iter()
+            .filter(|(_, registry)| !registry.mirrors.is_empty())
+            .map(|(host, registry)| (registry_host(host).to_string(), registry.mirrors.clone()))
+            .collect()
+    }
+
+    /// The certificate authorities trusted for single registries
+    pub fn host_cas(&self) -> Vec<(String, PathBuf)> {
+        self.registries
+            .iter()
+            .filter_map(|(host, registry)| Some((host_name(host), registry.ca.clone()?)))
+            .collect()
+    }
+
+    /// The client certificates, as HOST=CERT[,KEY] like --registry-cert
+    pub fn client_certs(&self) -> Vec<String> {
+        self.registries
+            .iter()
+            .filter_map(|(host, registry)| {
+                let cert = registry.cert.as_ref()?;
+
+                Some(match &registry.key {
+                    Some(key) => {
+                        format!("{}={},{}", host_name(host), cert.display(), key.display())
+                    }
+                    None => format!("{}={}", host_name(host), cert.display()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Stores the credentials of a registry in the configuration file, or
+/// removes them with None, keeping the rest of the file as it was written.
+/// Returns whether the file held credentials for the registry before
+pub fn store_login(path: &Path, host: &str, login: Option<&LoginCredentials>) -> io::Result<bool> {
+    let invalid =
+        |e: &dyn std::fmt::Display| io::Error::other(format!("{}: {}", path.display(), e));
+
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let mut document: DocumentMut = content.parse().map_err(|e| invalid(&e))?;
+
+    let registries = document
+        .entry("registries")
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| invalid(&"registries is not a table"))?;
+    registries.set_implicit(true);
+
+    let registry = registries
+        .entry(host)
+        .or_insert(toml_edit::table())
+        .as_table_mut()
+        .ok_or_else(|| invalid(&format!("registries.{} is not a table", host)))?;
+    let had_login = registry.contains_key("username") || registry.contains_key("password");
+
+    match login {
+        Some(login) => {
+            registry["username"] = toml_edit::value(&login.username);
+            registry["password"] = toml_edit::value(&login.password);
+        }
+        None => {
+            registry.remove("username");
+            registry.remove("password");
+        }
+    }
+
+    if registry.is_empty() {
+        registries.remove(host);
+    }
+
+    if registries.is_empty() {
+        document.remove("registries");
+    }
+
+    let content = document.to_string();
+    ConfigFile::parse(&content).map_err(|e| invalid(&e))?;
+
+    // The file holds passwords, so it is only readable by its owner
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(content.as_bytes())?;
+    file.persist(path).map_err(|e| e.error)?;
+
+    Ok(had_login)
+}
+
+/// The host name of a registry, without its port, as connections are
+/// told apart by
+fn host_name(host: &str) -> String {
+    host.split_once(':')
+        .map_or(host, |(name, _)| name)
+        .to_string()
+}
+
+/// How registries are named and reached, from the global flags and the
+/// configuration file. Image references are parsed and clients are built
+/// with these
+#[derive(Clone, Default)]
+pub struct RegistrySettings {
+    /// Registry hosts spoken to over plain HTTP, such as localhost:5000
+    pub insecure_registries: HashSet<String>,
+    /// The registry of images named without a host, in place of Docker Hub
+    pub default_registry: Option<String>,
+    /// The namespaces of repositories named without one, by registry host
+    pub namespaces: HashMap<String, String>,
+    /// Registry hosts serving the same images, by registry host as images
+    /// are pulled from it
+    pub mirrors: HashMap<String, Vec<String>>,
+    /// Speaks HTTP/1.1 to every registry not given a protocol of its own,
+    /// without offering HTTP/2
+    pub http1_only: bool,
+    /// Takes the place of HTTPS_PROXY and HTTP_PROXY
+    pub proxy: Option<Proxy>,
+    /// The pool, timeouts and connection limits of every client
+    pub connection: ConnectionOptions,
+    /// The CA certificates and client certificates of the registries
+    pub tls: TlsOptions,
+    /// Records the requests to registries, or replays them
+    pub recording: Option<Arc<Recording>>,
+}
+
+impl RegistrySettings {
+    /// Talks to the given registry hosts over plain HTTP instead of HTTPS.
+    /// Hosts may be given as URLs, such as http://localhost:5000/
+    pub fn add_insecure_registries(&mut self, hosts: impl IntoIterator<Item = String>) {
+        self.insecure_registries
+            .extend(hosts.into_iter().map(|host| {
+                host.trim_start_matches("http://")
+                    .trim_start_matches("https://")
+                    .trim_end_matches('/')
+                    .to_string()
+            }));
+    }
+
+    /// The URL of a registry host, over HTTP for insecure registries and
+    /// HTTPS for all others. URLs are returned as they are
+    pub fn registry_url(&self, host: &str) -> String {
+        if host.starts_with("http://") || host.starts_with("https://") {
+            return host.to_string();
+        }
+
+        match self.insecure_registries.contains(host) {
+            true => format!("http://{}", host),
+            false => format!("https://{}", host),
+        }
+    }
+
+    /// The name of a repository of a registry host other than Docker Hub,
+    /// in the host's default namespace if it has none
+    pub fn namespaced(&self, host: &str, name: &str) -> String {
+        match self.namespaces.get(host).filter(|_| !name.contains('/')) {
+            Some(namespace) => format!("{}/{}", namespace, name),
+            None => name.to_string(),
+        }
+    }
+
+    /// Sends the requests of a client through the proxy of --proxy. Without
+    /// it, reqwest follows HTTPS_PROXY, HTTP_PROXY and NO_PROXY itself
+    pub fn with_proxy(&self, builder: ClientBuilder) -> ClientBuilder {
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+default_registry = "registry.example.com"
+
+[registries."registry.example.com"]
+username = "ci"
+password = "secret"
+ca = "/etc/ssl/corp-ca.pem"
+cert = "/etc/ocitool/client.pem"
+key = "/etc/ocitool/client.key"
+protocol = "http1"
+mirrors = ["mirror.example.com"]
+namespace = "team"
+
+[registries."localhost:5000"]
+insecure = true
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = ConfigFile::parse(CONFIG).unwrap();
+
+        assert_eq!(
+            config.default_registry.as_deref(),
+            Some("registry.example.com")
+        );
+        assert_eq!(
+            config.logins(&RegistrySettings::default()),
+            HashMap::from([(
+                "https://registry.example.com".to_string(),
+                LoginCredentials {
+                    username: "ci".to_string(),
+                    password: "secret".to_string(),
+                }
+            )])
+        );
+        assert_eq!(config.insecure_registries(), vec!["localhost:5000"]);
+        assert_eq!(
+            config.protocols(),
+            HashMap::from([("registry.example.com".to_string(), HttpProtocol::Http1)])
+        );
+        assert_eq!(
+            config.namespaces(),
+            HashMap::from([("registry.example.com".to_string(), "team".to_string())])
+        );
+        assert_eq!(
+            config.host_cas(),
+            vec![(
+                "registry.example.com".to_string(),
+                PathBuf::from("/etc/ssl/corp-ca.pem")
+            )]
+        );
+        assert_eq!(
+            config.client_certs(),
+            vec!["registry.example.com=/etc/ocitool/client.pem,/etc/ocitool/client.key"]
+        );
+        assert_eq!(
+            config.mirrors(),
+            HashMap::from([(
+                "registry.example.com".to_string(),
+                vec!["mirror.example.com".to_string()]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_store_login() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ocitool/config.toml");
+        let login = LoginCredentials {
+            username: "bot".to_string(),
+            password: "token".to_string(),
+        };
+
+        assert!(!store_login(&path, "ghcr.io", Some(&login)).unwrap());
+        fs::write(
+            &path,
+            format!(
+                "# Kept as written\n{}\n[registries.\"localhost:5000\"]\ninsecure = true\n",
+                fs::read_to_string(&path).unwrap()
+            ),
+        )
+        .unwrap();
+        assert!(!store_login(&path, "localhost:5000", Some(&login)).unwrap());
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("# Kept as written\n"));
+
+        let config = ConfigFile::parse(&content).unwrap();
+        assert_eq!(
+            config.logins(&RegistrySettings::default())["https://ghcr.io"],
+            login
+        );
+        assert!(config.registries["localhost:5000"].insecure);
+
+        assert!(store_login(&path, "ghcr.io", None).unwrap());
+        assert!(!store_login(&path, "ghcr.io", None).unwrap());
+
+        let config = ConfigFile::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(!config.registries.contains_key("ghcr.io"));
+        assert_eq!(config.insecure_registries(), vec!["localhost:5000"]);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid() {
+        assert!(ConfigFile::parse("[registries.\"ghcr.io\"]\nusername = \"bot\"\n").is_err());
+        assert!(ConfigFile::parse("[registries.\"ghcr.io\"]\nmirror = \"typo\"\n").is_err());
+        assert!(ConfigFile::parse("[registries.\"ghcr.io\"]\nprotocol = \"http3\"\n").is_err());
+        assert!(ConfigFile::parse("default_registry = \"https://ghcr.io\"\n").is_err());
+        assert_eq!(ConfigFile::parse("").unwrap(), ConfigFile::default());
+    }
+}
@@ -0,0 +1,474 @@
+use std::{collections::HashSet, error::Error, sync::Arc};
+
+use futures::{stream, StreamExt, TryStreamExt};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::{
+    cleanup::{
+        check_format, confirm, expired_tags, is_commit, print_dry_run, print_report, CleanupFilter,
+        CleanupReport, RepositoryReport,
+    },
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::{OciDownloader, OciDownloaderError},
+    parser::{FullImage, FullImageWithTag, ReferenceError},
+    registry_config::RegistrySettings,
+    report::Context,
+    uploader::OciUploader,
+    Cleanup,
+};
+
+/// How many tags of a repository are read at once
+const CONCURRENT_TAGS: usize = 8;
+
+/// A tag of a remote repository and the manifest it points at
+#[derive(Debug, Clone)]
+pub struct RemoteTag {
+    pub name: String,
+    pub digest: String,
+    pub created: Option<OffsetDateTime>,
+    /// The manifests of an index, which stay as long as the index does
+    pub children: Vec<String>,
+}
+
+/// A --remote registry, which may be given as a host or as a URL
+struct RemoteRegistry {
+    host: String,
+    /// The URL given, such as http://localhost:5000. Hosts given without one
+    /// are reached like any other, over plain HTTP if --insecure-registry
+    url: Option<String>,
+}
+
+impl RemoteRegistry {
+    fn parse(remote: &str) -> Self {
+        let remote = remote.trim_end_matches('/');
+
+        match remote.split_once("://") {
+            Some((_, host)) => RemoteRegistry {
+                host: host.to_string(),
+                url: Some(remote.to_string()),
+            },
+            None => RemoteRegistry {
+                host: remote.to_string(),
+                url: None,
+            },
+        }
+    }
+
+    fn image(&self, name: &str, settings: &RegistrySettings) -> Result<FullImage, ReferenceError> {
+        let mut image = FullImage::parse(&format!("{}/{}", self.host, name), settings)?;
+
+        if let Some(url) = &self.url {
+            image.registry = url.clone();
+        }
+
+        Ok(image)
+    }
+}
+
+/// The tags to remove from a repository, and the manifests that only those
+/// tags point at. Deleting a manifest removes every tag of it, so manifests
+/// a kept tag points at stay, with all of their tags
+pub fn plan_repository(
+    repository: &str,
+    tags: &[RemoteTag],
+    cleanup: &Cleanup,
+    filter: &CleanupFilter,
+) -> RepositoryReport {
+    let removes_commits = cleanup.all || cleanup.commits;
+    let mut removed: HashSet<&str> = tags
+        .iter()
+        .filter(|tag| removes_commits && is_commit(&tag.name) && !filter.keeps_tag(&tag.name))
+        .map(|tag| tag.name.as_str())
+        .collect();
+
+    if let Some(keep) = cleanup.keep_last {
+        let candidates = tags
+            .iter()
+            .filter(|tag| !removed.contains(tag.name.as_str()) && !filter.keeps_tag(&tag.name))
+            .map(|tag| (tag.name.clone(), tag.created))
+            .collect();
+        let expired = expired_tags(candidates, keep);
+
+        removed.extend(
+            tags.iter()
+                .filter(|tag| expired.contains(&tag.name))
+                .map(|tag| tag.name.as_str()),
+        );
+    }
+
+    // Deleting a manifest of a kept index would break the index
+    let kept_digests: HashSet<&str> = tags
+        .iter()
+        .filter(|tag| !removed.contains(tag.name.as_str()))
+        .flat_map(|tag| std::iter::once(&tag.digest).chain(&tag.children))
+        .map(|digest| digest.as_str())
+        .collect();
+
+    let mut manifests: Vec<String> = tags
+        .iter()
+        .filter(|tag| removed.contains(tag.name.as_str()))
+        .map(|tag| tag.digest.clone())
+        .filter(|digest| !kept_digests.contains(digest.as_str()))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    manifests.sort();
+
+    // Tags go with the manifests they point at, so tags of kept manifests
+    // are not removed after all
+    let mut tags: Vec<String> = tags
+        .iter()
+        .filter(|tag| removed.contains(tag.name.as_str()))
+        .filter(|tag| !kept_digests.contains(tag.digest.as_str()))
+        .map(|tag| tag.name.clone())
+        .collect();
+    tags.sort();
+
+    RepositoryReport {
+        repository: repository.to_string(),
+        paths: vec![],
+        tags,
+        manifests,
+        bytes: 0,
+    }
+}
+
+/// When the image of a manifest was created, from its config. An index is
+/// as old as the first manifest in it
+async fn image_created(
+    downloader: &OciDownloader,
+    image: &FullImage,
+    manifest: &[u8],
+) -> Option<OffsetDateTime> {
+    let mut manifest: Value = serde_json::from_slice(manifest).ok()?;
+
+    if let Some(first) = manifest
+        .get("manifests")
+        .and_then(|manifests| manifests.as_array())
+        .and_then(|manifests| manifests.first())
+    {
+        let child = FullImageWithTag {
+            image: image.clone(),
+            tag: first.get("digest")?.as_str()?.to_string(),
+        };
+        let (_, data) = downloader.download_manifest_raw(child).await.ok()?;
+        manifest = serde_json::from_slice(&data).ok()?;
+    }
+
+    let config = manifest.get("config")?.get("digest")?.as_str()?;
+    let (config, _) = downloader
+        .download_config(image.clone(), config)
+        .await
+        .ok()?;
+
+    config.created
+}
+
+async fn read_tag(
+    downloader: &OciDownloader,
+    image: &FullImage,
+    tag: String,
+    with_created: bool,
+) -> Result<RemoteTag, OciDownloaderError> {
+    let (_, data) = downloader
+        .download_manifest_raw(FullImageWithTag {
+            image: image.clone(),
+            tag: tag.clone(),
+        })
+        .await?;
+
+    let created = match with_created {
+        true => image_created(downloader, image, &data).await,
+        false => None,
+    };
+    let children = serde_json::from_slice::<Value>(&data)
+        .ok()
+        .and_then(|manifest| manifest.get("manifests")?.as_array().cloned())
+        .into_iter()
+        .flatten()
+        .filter_map(|child| Some(child.get("digest")?.as_str()?.to_string()))
+        .collect();
+
+    Ok(RemoteTag {
+        name: tag,
+        digest: sha256_digest(&data),
+        created,
+        children,
+    })
+}
+
+/// Cleans up a running registry through the distribution API: the tags
+/// --commits and --keep-last remove are found through the catalog and the
+/// tag lists, and the manifests only they point at are deleted. The blobs
+/// of those manifests are freed by the garbage collection of the registry
+pub async fn remote_cleanup_command(
+    cleanup: &Cleanup,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    check_format(cleanup, dry_run)?;
+
+    let registry = RemoteRegistry::parse(cleanup.remote.as_deref().unwrap_or_default());
+    let host = registry.host.as_str();
+
+    if cleanup.dir.is_some()
+        || cleanup.layout.is_some()
+        || cleanup.quarantine.is_some()
+        || cleanup.check
+    {
+        return Err("--remote cannot be used with --dir, --layout, --quarantine or --check".into());
+    }
+
+    if cleanup.indexes || cleanup.layers || cleanup.blobs || cleanup.uploads {
+        return Err("--remote only removes tags and their manifests, the registry's garbage collection frees layers, blobs and uploads".into());
+    }
+
+    if !cleanup.all && !cleanup.commits && cleanup.keep_last.is_none() {
+        return Err(
+            "No cleanup options specified. Use --all, --commits or --keep-last with --remote."
+                .into(),
+        );
+    }
+
+    let filter = CleanupFilter::new(cleanup)?;
+    let downloader = OciDownloader::new(client.clone(), no_cache);
+
+    let names = match filter.repositories.is_empty() {
+        true => {
+            let mut catalog = registry.image("catalog", client.settings())?;
+            catalog.image_name = String::new();
+            catalog.library_name = String::new();
+
+            client
+                .login(&[ImagePermission {
+                    full_image: catalog.clone(),
+                    permissions: ImagePermissions::Catalog,
+                }])
+                .await?;
+
+            downloader
+                .list_repositories(catalog)
+                .await
+                .with_context(|| format!("listing the repositories of {}", host))?
+        }
+        false => filter.repositories.clone(),
+    };
+
+    // Deleting needs more than reading, which is all a dry run does
+    let permissions = match dry_run {
+        true => ImagePermissions::Pull,
+        false => ImagePermissions::Delete,
+    };
+    let images: Vec<(String, FullImage)> = names
+        .into_iter()
+        .map(|name| {
+            let image = registry.image(&name, client.settings())?;
+            Ok((name, image))
+        })
+        .collect::<Result<_, ReferenceError>>()?;
+
+    client
+        .login(
+            &images
+                .iter()
+                .map(|(_, image)| ImagePermission {
+                    full_image: image.clone(),
+                    permissions: permissions.clone(),
+                })
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+
+    let mut repositories = vec![];
+
+    for (name, image) in &images {
+        let tag_names = downloader
+            .list_tags(image.clone())
+            .await
+            .with_context(|| format!("listing the tags of {}", name))?;
+
+        let tags: Vec<RemoteTag> = stream::iter(tag_names)
+            .map(|tag| read_tag(&downloader, image, tag, cleanup.keep_last.is_some()))
+            .buffered(CONCURRENT_TAGS)
+            .try_collect()
+            .await
+            .with_context(|| format!("reading the tags of {}", name))?;
+
+        let report = plan_repository(name, &tags, cleanup, &filter);
+
+        if !report.tags.is_empty() {
+            repositories.push(report);
+        }
+    }
+
+    let report = CleanupReport {
+        repositories,
+        blobs: vec![],
+        total_bytes: 0,
+    };
+
+    if dry_run {
+        return print_dry_run(cleanup, &report);
+    }
+
+    print_report(&report);
+
+    if !confirm(cleanup) {
+        return Ok(());
+    }
+
+    let uploader = OciUploader::new(client.clone());
+
+    for repository in &report.repositories {
+        let image = registry.image(&repository.repository, client.settings())?;
+
+        for digest in &repository.manifests {
+            match uploader.delete_manifest(image.clone(), digest).await {
+                Ok(()) => println!("Deleted {}@{}", repository.repository, digest),
+                Err(e) => eprintln!(
+                    "Failed to delete {}@{}: {}",
+                    repository.repository, digest, e
+                ),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CleanupCmd, Remove};
+
+    fn tag(name: &str, digest: &str, created: i64) -> RemoteTag {
+        RemoteTag {
+            name: name.to_string(),
+            digest: digest.to_string(),
+            created: OffsetDateTime::from_unix_timestamp(created).ok(),
+            children: vec![],
+        }
+    }
+
+    #[test]
+    fn test_plan_repository() {
+        let commit = "da5929574d2d37543b174334c73def59a5aa724b";
+        let tags = vec![
+            tag(commit, "sha256:aa", 100),
+            tag("v1", "sha256:bb", 200),
+            tag("v2", "sha256:cc", 300),
+            tag("latest", "sha256:cc", 300),
+            tag("old", "sha256:dd", 50),
+        ];
+
+        let cleanup = Cleanup {
+            dir: None,
+            remote: Some("registry.example.com".to_string()),
+            layout: None,
+            commits: true,
+            indexes: false,
+            layers: false,
+            blobs: false,
+            all: false,
+            yes: true,
+            format: None,
+            keep_last: Some(2),
+            uploads: false,
+            upload_age: None,
+            quarantine: None,
+            check: false,
+            subcommand: CleanupCmd::Remove(Remove {}),
+            repo: vec![],
+            keep_tags: vec!["^latest$".to_string()],
+        };
+        let filter = CleanupFilter::new(&cleanup).unwrap();
+
+        // latest is protected, v2 and v1 are the two newest of the rest
+        let report = plan_repository("team/app", &tags, &cleanup, &filter);
+        assert_eq!(report.tags, vec![commit, "old"]);
+        assert_eq!(report.manifests, vec!["sha256:aa", "sha256:dd"]);
+
+        // v2 expires, but latest keeps its manifest
+        let cleanup = Cleanup {
+            keep_last: Some(1),
+            ..cleanup
+        };
+        let report = plan_repository("team/app", &tags, &cleanup, &filter);
+        assert_eq!(report.tags, vec![commit, "old", "v1"]);
+        assert_eq!(
+            report.manifests,
+            vec!["sha256:aa", "sha256:bb", "sha256:dd"]
+        );
+    }
+
+    #[test]
+    fn test_kept_manifests() {
+        let tags = vec![
+            RemoteTag {
+                children: vec!["sha256:amd64".to_string(), "sha256:arm64".to_string()],
+                ..tag("latest", "sha256:index", 300)
+            },
+            tag("arm64", "sha256:arm64", 200),
+            tag("stale", "sha256:index", 100),
+            tag("old", "sha256:old", 50),
+        ];
+        let cleanup = Cleanup {
+            dir: None,
+            remote: Some("registry.example.com".to_string()),
+            layout: None,
+            commits: false,
+            indexes: false,
+            layers: false,
+            blobs: false,
+            all: false,
+            yes: true,
+            format: None,
+            keep_last: Some(1),
+            uploads: false,
+            upload_age: None,
+            quarantine: None,
+            check: false,
+            subcommand: CleanupCmd::Remove(Remove {}),
+            repo: vec![],
+            keep_tags: vec![],
+        };
+        let filter = CleanupFilter::new(&cleanup).unwrap();
+
+        // The platform manifest of the kept index stays, and so do the tags
+        // of manifests that stay
+        let report = plan_repository("team/app", &tags, &cleanup, &filter);
+        assert_eq!(report.tags, vec!["old"]);
+        assert_eq!(report.manifests, vec!["sha256:old"]);
+    }
+
+    #[test]
+    fn test_remote_registry() {
+        let settings = RegistrySettings::default();
+        let registry = RemoteRegistry::parse("https://registry.example.com/");
+        assert_eq!(registry.host, "registry.example.com");
+        assert_eq!(
+            registry.image("team/app", &settings).unwrap().registry,
+            "https://registry.example.com"
+        );
+
+        let registry = RemoteRegistry::parse("http://localhost:5000");
+        assert_eq!(registry.host, "localhost:5000");
+        assert_eq!(
+            registry.image("team/app", &settings).unwrap().registry,
+            "http://localhost:5000"
+        );
+
+        let registry = RemoteRegistry::parse("localhost:5000");
+        assert_eq!(registry.url, None);
+        assert_eq!(
+            registry
+                .image("team/app", &settings)
+                .unwrap()
+                .get_image_url(),
+            "https://localhost:5000/v2/team/app"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{error::Error, fmt};
+
+use console::style;
+use regex_lite::Regex;
+
+/// An error together with the operation that was being performed when it happened
+#[derive(Debug)]
+pub struct ContextError {
+    context: String,
+    source: Box<dyn Error>,
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.context.fmt(f)
+    }
+}
+
+impl Error for ContextError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// Describes the operation a fallible call belongs to, so that a failure
+/// is reported as a chain such as image → blob → request
+pub trait Context<T> {
+    fn with_context<C, F>(self, context: F) -> Result<T, Box<dyn Error>>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
+}
+
+impl<T, E> Context<T> for Result<T, E>
+where
+    E: Into<Box<dyn Error>>,
+{
+    fn with_context<C, F>(self, context: F) -> Result<T, Box<dyn Error>>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|source| {
+            Box::new(ContextError {
+                context: context().into(),
+                source: source.into(),
+            }) as Box<dyn Error>
+        })
+    }
+}
+
+/// Finds the HTTP status a registry answered with in an error message
+fn http_status(message: &str) -> Option<u16> {
+    let status = Regex::new(r"\b([45]\d\d) [A-Z]").unwrap();
+
+    status
+        .captures(message)
+        .and_then(|captures| captures[1].parse().ok())
+}
+
+/// Suggests how to fix the most common failures
+fn hint(messages: &[String]) -> Option<&'static str> {
+    let status = messages
+        .iter()
+        .rev()
+        .find_map(|message| http_status(message));
+
+    let status_hint = match status {
+        Some(401) => Some("the registry did not accept the credentials. Log in with ocitool login <registry>, or pass them with --registry-auth HOST=USERNAME:PASSWORD"),
+        Some(403) => Some("the credentials are valid, but not allowed to access this repository"),
+        Some(404) => Some("check the image name and tag, and that the repository exists on that registry"),
+        Some(405) => Some("the registry does not allow this operation. Deleting must be enabled on a distribution registry with REGISTRY_STORAGE_DELETE_ENABLED=true"),
+        Some(429) => Some("the registry is rate limiting requests. Authenticate to get a higher limit, or try again later"),
+        Some(500..=599) => Some("the registry failed to handle the request. Try again later"),
+        _ => None,
+    };
+
+    if status_hint.is_some() {
+        return status_hint;
+    }
+
+    let message = messages.join("\n").to_lowercase();
+
+    if message.contains("containerd") && message.contains("no such file") {
+        Some("is containerd running? Pass --socket if it listens somewhere else")
+    } else if message.contains("permission denied") {
+        Some("run ocitool as a user that can access the file or socket, or with sudo")
+    } else if message.contains("error sending request") || message.contains("dns error") {
+        Some("the registry could not be reached. Check the network and the registry host name")
+    } else {
+        None
+    }
+}
+
+/// Renders an error with its chain of causes and a hint, if one applies
+pub fn render(operation: &str, error: &dyn Error, colors: bool) -> String {
+    let mut messages = vec![error.to_string()];
+    let mut source = error.source();
+
+    while let Some(error) = source {
+        messages.push(error.to_string());
+        source = error.source();
+    }
+
+    let mut out = format!(
+        "{} {} failed\n",
+        style("error:").red().bold().force_styling(colors),
+        operation
+    );
+
+    for (i, message) in messages.iter().enumerate() {
+        let branch = if i + 1 == messages.len() {
+            "└─"
+        } else {
+            "├─"
+        };
+        let message = if i + 1 == messages.len() {
+            style(message.as_str()).bold().force_styling(colors)
+        } else {
+            style(message.as_str()).force_styling(colors)
+        };
+
+        out.push_str(&format!("  {} {}\n", branch, message));
+    }
+
+    if let Some(hint) = hint(&messages) {
+        out.push_str(&format!(
+            "  {} {}\n",
+            style("help:").cyan().bold().force_styling(colors),
+            hint
+        ));
+    }
+
+    out
+}
+
+/// Prints an error to stderr, colored when stderr is a terminal
+pub fn report_error(operation: &str, error: &dyn Error) {
+    eprint!(
+        "{}",
+        render(operation, error, console::colors_enabled_stderr())
+    );
+}
+
+/// Exits with the given code, removing the isolated cache and printing the
+/// profile first. Every exit goes through here, as std::process::exit runs
+/// no destructors that could do it
+pub fn exit(code: i32) -> ! {
+    crate::cache::remove_isolated();
+    crate::profile::print_summary();
+    std::process::exit(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uploader::OciUploaderError;
+
+    #[test]
+    fn test_render() {
+        let error: Result<(), _> = Err(OciUploaderError(
+            "Failed to upload blob: 401 Unauthorized".to_string(),
+        ));
+        let error = error
+            .with_context(|| "uploading blob sha256:abc")
+            .with_context(|| "copying nginx:alpine")
+            .unwrap_err();
+
+        assert_eq!(
+            render("Copy", error.as_ref(), false),
+            "error: Copy failed\n  \
+             ├─ copying nginx:alpine\n  \
+             ├─ uploading blob sha256:abc\n  \
+             └─ Failed to upload blob: 401 Unauthorized\n  \
+             help: the registry did not accept the credentials. Log in with ocitool login <registry>, or pass them with --registry-auth HOST=USERNAME:PASSWORD\n"
+        );
+    }
+
+    #[test]
+    fn test_hint() {
+        let hint_for = |message: &str| hint(&[message.to_string()]);
+
+        assert!(hint_for("Failed to download layer: 404 Not Found")
+            .unwrap()
+            .contains("image name"));
+        assert!(hint_for("Failed to download index: 429 Too Many Requests")
+            .unwrap()
+            .contains("rate limiting"));
+        assert!(hint_for("Blob sha256:401abc does not match its digest").is_none());
+    }
+}
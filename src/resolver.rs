@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{OnceLock, RwLock},
+};
+
+use hickory_resolver::{config::LookupIpStrategy, TokioResolver};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::profile::{self, Phase};
+
+/// Resolves registries to both their IPv4 and IPv6 addresses, starting with
+/// the address that last worked. The HTTP client tries the family of the
+/// first address, and races the other family shortly after, so a host with
+/// a broken IPv6 route falls back to IPv4 instead of hanging
+#[derive(Default)]
+pub struct RegistryResolver {
+    /// Created on first use, since a Tokio runtime is required
+    resolver: OnceLock<TokioResolver>,
+    /// The address each host was last reached at
+    working: RwLock<HashMap<String, IpAddr>>,
+}
+
+impl RegistryResolver {
+    fn resolver(&self) -> Result<&TokioResolver, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(resolver) = self.resolver.get() {
+            return Ok(resolver);
+        }
+
+        let mut builder = TokioResolver::builder_tokio()?;
+        builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok(self.resolver.get_or_init(|| builder.build()))
+    }
+
+    /// Remembers the address a request to a host went out on
+    pub fn remember(&self, host: &str, address: IpAddr) {
+        self.working
+            .write()
+            .unwrap()
+            .insert(host.to_string(), address);
+    }
+
+    /// Forgets the address of a host after connecting to it failed, so that
+    /// the next attempt does not start with it again
+    pub fn forget(&self, host: &str) {
+        self.working.write().unwrap().remove(host);
+    }
+}
+
+/// Orders addresses like RFC 8305 does, alternating between IPv6 and IPv4,
+/// with the preferred address and its family first
+pub fn order_addresses(addresses: Vec<IpAddr>, preferred: Option<IpAddr>) -> Vec<IpAddr> {
+    let preferred = preferred.filter(|preferred| addresses.contains(preferred));
+    let prefer_ipv4 = preferred.is_some_and(|preferred| preferred.is_ipv4());
+
+    let (mut first, mut second): (Vec<IpAddr>, Vec<IpAddr>) = addresses
+        .into_iter()
+        .filter(|address| Some(*address) != preferred)
+        .partition(|address| address.is_ipv4() == prefer_ipv4);
+    first.dedup();
+    second.dedup();
+
+    let mut ordered: Vec<IpAddr> = preferred.into_iter().collect();
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+
+    // The preferred address already stands in for its family
+    if preferred.is_some() {
+        ordered.extend(second.next());
+    }
+
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    ordered
+}
+
+impl Resolve for RegistryResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let resolver = self.resolver().cloned();
+        let preferred = self.working.read().unwrap().get(&host).copied();
+
+        Box::pin(async move {
+            let addresses: Vec<IpAddr> = profile::timed(Phase::Resolve, resolver?.lookup_ip(host))
+                .await?
+                .into_iter()
+                .collect();
+            let addrs: Addrs = Box::new(
+                order_addresses(addresses, preferred)
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_addresses() {
+        let v4 = |last: u8| IpAddr::from([192, 0, 2, last]);
+        let v6 = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, last]);
+
+        assert_eq!(
+            order_addresses(vec![v4(1), v4(2), v6(1), v6(2)], None),
+            vec![v6(1), v4(1), v6(2), v4(2)]
+        );
+        assert_eq!(
+            order_addresses(vec![v4(1), v4(2), v6(1), v6(2)], Some(v4(2))),
+            vec![v4(2), v6(1), v4(1), v6(2)]
+        );
+        assert_eq!(
+            order_addresses(vec![v4(1), v4(2)], Some(v6(1))),
+            vec![v4(1), v4(2)]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    digest::sha256_digest,
+    downloader::OciDownloader,
+    parser::FullImageWithTag,
+    report::Context,
+    state::{self, image_key, State},
+    uploader::OciUploader,
+    Retag,
+};
+
+/// Points another tag of the same repository at an image. Only the manifest
+/// is uploaded again, as the registry already has every blob it refers to
+pub async fn retag_command(
+    args: &Retag,
+    no_cache: bool,
+    dry_run: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let source = FullImageWithTag::parse(&args.image, client.settings())?;
+    let destination = FullImageWithTag {
+        image: source.image.clone(),
+        tag: args.tag.clone(),
+    };
+
+    client
+        .login(&[
+            ImagePermission {
+                full_image: source.image.clone(),
+                permissions: ImagePermissions::Pull,
+            },
+            ImagePermission {
+                full_image: source.image.clone(),
+                permissions: ImagePermissions::Push,
+            },
+        ])
+        .await?;
+
+    let downloader = OciDownloader::new(client.clone(), no_cache);
+    let uploader = OciUploader::new(client).with_dry_run(dry_run);
+
+    let (content_type, data) = downloader
+        .download_manifest_raw(source.clone())
+        .await
+        .with_context(|| format!("fetching {}", args.image))?;
+    let digest = sha256_digest(&data);
+
+    uploader
+        .upload_manifest(destination.clone(), data.to_vec(), &content_type)
+        .await
+        .with_context(|| format!("tagging {}", args.tag))?;
+
+    if dry_run {
+        println!(
+            "Would tag {}:{} as {}@{}",
+            source.image.image_name, args.tag, source.image.image_name, digest
+        );
+        return Ok(());
+    }
+
+    if let Err(e) = State::update(&State::path(), |state| {
+        state.record_image(&image_key(&destination), &digest, state::now())
+    }) {
+        eprintln!("Failed to save state: {}", e);
+    }
+
+    println!(
+        "Tagged {}:{} as {}@{}",
+        source.image.image_name, args.tag, source.image.image_name, digest
+    );
+
+    Ok(())
+}
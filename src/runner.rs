@@ -1,27 +1,546 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io::{self, IsTerminal},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    process::ExitStatus,
+    time::Duration,
+};
+
+use nix::{
+    sys::signal::{kill, killpg},
+    unistd::Pid,
+};
 
+use serde_json::json;
 use tokio::{
-    fs::{create_dir_all, File},
+    fs::{self, create_dir_all, OpenOptions},
     io::AsyncWriteExt,
 };
 
 use crate::{
+    format::parse_size,
     macros::{impl_error, impl_from_error},
     spec::config::Config,
+    supervise::{supervise, StopPolicy},
+    tty::{Pty, Session},
 };
 
 impl_error!(OciRunnerError);
 impl_from_error!(std::io::Error, OciRunnerError);
 
+/// The PATH Docker gives containers whose image does not set one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Splits a KEY=VALUE variable. A bare KEY takes its value from the host,
+/// and is left out if the host does not have it either
+fn parse_variable(
+    variable: &str,
+    host: impl Fn(&str) -> Option<String>,
+) -> Result<Option<(String, String)>, OciRunnerError> {
+    let (key, value) = match variable.split_once('=') {
+        Some((key, value)) => (key, Some(value.to_string())),
+        None => (variable, host(variable)),
+    };
+
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(OciRunnerError(format!(
+            "Invalid environment variable: {}",
+            variable
+        )));
+    }
+
+    Ok(value.map(|value| (key.to_string(), value)))
+}
+
+/// Parses the KEY=VALUE variables given with --env
+pub fn parse_variables(
+    variables: &[String],
+    host: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<(String, String)>, OciRunnerError> {
+    let mut parsed = vec![];
+
+    for variable in variables {
+        parsed.extend(parse_variable(variable, &host)?);
+    }
+
+    Ok(parsed)
+}
+
+/// Reads the variables of an env file, one KEY=VALUE per line, skipping
+/// blank lines and # comments
+pub fn parse_env_file(
+    contents: &str,
+    host: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<(String, String)>, OciRunnerError> {
+    let mut variables = vec![];
+
+    for line in contents.lines() {
+        let line = line.trim_start();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        variables.extend(parse_variable(line.trim_end_matches('\r'), &host)?);
+    }
+
+    Ok(variables)
+}
+
+/// Merges variables over the Env of the image config, later ones winning,
+/// in the order they were first set
+pub fn merge_env(image: &[String], overrides: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut merged: Vec<(String, String)> = vec![];
+    let mut positions = HashMap::new();
+
+    let image = image
+        .iter()
+        .filter_map(|variable| variable.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+
+    for (key, value) in image.chain(overrides) {
+        match positions.get(&key) {
+            Some(&position) => merged[position] = (key, value),
+            None => {
+                positions.insert(key.clone(), merged.len());
+                merged.push((key, value));
+            }
+        }
+    }
+
+    if !positions.contains_key("PATH") {
+        merged.push(("PATH".to_string(), DEFAULT_PATH.to_string()));
+    }
+
+    merged
+}
+
+/// The name servers a container is given when the host has none to copy
+const FALLBACK_NAMESERVERS: &str = "nameserver 8.8.8.8\nnameserver 8.8.4.4\n";
+
+/// Writes etc/resolv.conf into a root filesystem. The image may ship it, or
+/// etc, as a symlink to a host path such as /etc/resolv.conf, which running
+/// as root would overwrite, so a symlinked resolv.conf is replaced and never
+/// followed, and an etc outside of the root filesystem is refused
+async fn write_resolv_conf(root: &Path, contents: &str) -> Result<(), OciRunnerError> {
+    let etc = root.join("etc");
+    create_dir_all(&etc).await?;
+
+    let etc = fs::canonicalize(&etc).await?;
+    if !etc.starts_with(fs::canonicalize(root).await?) {
+        return Err(OciRunnerError(format!(
+            "The etc directory of the image points outside of it, to {}",
+            etc.display()
+        )));
+    }
+
+    let resolv_conf = etc.join("resolv.conf");
+    match fs::remove_file(&resolv_conf).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&resolv_conf)
+        .await?;
+    file.write_all(contents.as_bytes()).await?;
+    Ok(())
+}
+
+/// Writes the resolv.conf of a container from the host's, replacing its name
+/// servers and search domains with the given ones
+pub fn resolv_conf(servers: &[String], search: &[String], host: Option<&str>) -> String {
+    let host = host.unwrap_or_default();
+    let host_lines = |keywords: &[&str]| -> Vec<String> {
+        host.lines()
+            .map(str::trim)
+            .filter(|line| {
+                line.split_whitespace()
+                    .next()
+                    .is_some_and(|keyword| keywords.contains(&keyword))
+            })
+            .map(|line| format!("{}\n", line))
+            .collect()
+    };
+
+    let nameservers = match servers.is_empty() {
+        true => host_lines(&["nameserver"]),
+        false => servers
+            .iter()
+            .map(|server| format!("nameserver {}\n", server))
+            .collect(),
+    };
+    let search = match search.is_empty() {
+        true => host_lines(&["search", "domain"]),
+        false => vec![format!("search {}\n", search.join(" "))],
+    };
+
+    let mut conf = match nameservers.is_empty() {
+        true => FALLBACK_NAMESERVERS.to_string(),
+        false => nameservers.concat(),
+    };
+    conf.extend(search);
+    conf.extend(host_lines(&["options"]));
+    conf
+}
+
+/// Finds an entry of /etc/passwd or /etc/group by its name or ID, returning
+/// all of its fields, the name first and the ID third
+fn find_entry(contents: &str, name: &str) -> Option<Vec<String>> {
+    contents.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+
+        (fields.len() >= 3 && (fields[0] == name || fields[2] == name))
+            .then(|| fields.iter().map(|field| field.to_string()).collect())
+    })
+}
+
+/// Resolves a user as the image config gives it, as user[:group] by name or
+/// ID, to a uid and gid using the passwd and group files of the root
+/// filesystem. Without a group, the user's primary group is used
+pub fn resolve_user(root: &Path, user: &str) -> Result<(u32, u32), OciRunnerError> {
+    resolve_user_in(&[root], user)
+}
+
+/// Resolves a user like resolve_user, in a root filesystem made of layer
+/// directories, the upper ones first
+pub fn resolve_user_in(layers: &[&Path], user: &str) -> Result<(u32, u32), OciRunnerError> {
+    let read = |path: &str| {
+        layers
+            .iter()
+            .find_map(|layer| std::fs::read_to_string(layer.join(path)).ok())
+            .unwrap_or_default()
+    };
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None),
+    };
+
+    // A malformed ID must not fall back to 0, which would run as root
+    let parse_id = |entry: &[String], index: usize, file: &str| {
+        entry
+            .get(index)
+            .and_then(|id| id.parse::<u32>().ok())
+            .ok_or_else(|| {
+                OciRunnerError(format!(
+                    "{} has an invalid ID in the image's {}",
+                    entry[0], file
+                ))
+            })
+    };
+
+    let passwd = find_entry(&read("etc/passwd"), user);
+    let uid = match (user.parse::<u32>(), &passwd) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(entry)) => parse_id(entry, 2, "/etc/passwd")?,
+        (Err(_), None) => {
+            return Err(OciRunnerError(format!(
+                "User {} is not in the image's /etc/passwd",
+                user
+            )))
+        }
+    };
+
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => match find_entry(&read("etc/group"), group) {
+                Some(entry) => parse_id(&entry, 2, "/etc/group")?,
+                None => {
+                    return Err(OciRunnerError(format!(
+                        "Group {} is not in the image's /etc/group",
+                        group
+                    )))
+                }
+            },
+        },
+        None => match &passwd {
+            Some(entry) => parse_id(entry, 3, "/etc/passwd")?,
+            None => 0,
+        },
+    };
+
+    Ok((uid, gid))
+}
+
+/// What isolates the container from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunBackend {
+    /// Traces system calls with ptrace, needing neither root nor namespaces
+    Proot,
+    /// bubblewrap, with user namespaces instead of system call tracing
+    Bwrap,
+    /// An overlayfs mount and chroot, needing root
+    ChrootOverlay,
+    /// An OCI runtime, runc or crun, with real namespaces and cgroups
+    Runtime(&'static str),
+}
+
+impl RunBackend {
+    pub fn parse(backend: &str) -> Result<RunBackend, OciRunnerError> {
+        match backend {
+            "proot" => Ok(RunBackend::Proot),
+            "bwrap" => Ok(RunBackend::Bwrap),
+            "chroot-overlay" => Ok(RunBackend::ChrootOverlay),
+            "runc" => Ok(RunBackend::Runtime("runc")),
+            "crun" => Ok(RunBackend::Runtime("crun")),
+            _ => Err(OciRunnerError(format!(
+                "Unknown backend {}, expected proot, bwrap, chroot-overlay, runc or crun",
+                backend
+            ))),
+        }
+    }
+}
+
+/// Something mounted into the container over its root filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mount {
+    /// A path of the host
+    Bind {
+        source: String,
+        target: String,
+        read_only: bool,
+    },
+    /// An empty, writable directory that goes away with the container, of
+    /// at most the given size in bytes
+    Tmpfs { target: String, size: Option<u64> },
+}
+
+impl Mount {
+    pub fn target(&self) -> &str {
+        match self {
+            Mount::Bind { target, .. } | Mount::Tmpfs { target, .. } => target,
+        }
+    }
+
+    /// Parses a volume as host:container, with an optional :ro or :rw
+    pub fn parse_volume(volume: &str) -> Result<Mount, OciRunnerError> {
+        let (source, target, read_only) = match volume.split(':').collect::<Vec<_>>()[..] {
+            [source, target] => (source, target, false),
+            [source, target, "ro"] => (source, target, true),
+            [source, target, "rw"] => (source, target, false),
+            _ => return Err(OciRunnerError(format!("Invalid volume format: {}", volume))),
+        };
+
+        Ok(Mount::Bind {
+            source: source.to_string(),
+            target: target.to_string(),
+            read_only,
+        })
+    }
+
+    /// Parses a tmpfs as /path, with an optional :size such as :64m
+    pub fn parse_tmpfs(tmpfs: &str) -> Result<Mount, OciRunnerError> {
+        let (target, size) = match tmpfs.split_once(':') {
+            Some((target, size)) => {
+                let size = size.strip_prefix("size=").unwrap_or(size);
+                let bytes = parse_size(size)
+                    .ok_or_else(|| OciRunnerError(format!("Invalid tmpfs size: {}", size)))?;
+                (target, Some(bytes))
+            }
+            None => (tmpfs, None),
+        };
+
+        if !target.starts_with('/') {
+            return Err(OciRunnerError(format!(
+                "The tmpfs path must be absolute: {}",
+                target
+            )));
+        }
+
+        Ok(Mount::Tmpfs {
+            target: target.to_string(),
+            size,
+        })
+    }
+}
+
+/// The capabilities Docker grants containers by default
+const DEFAULT_CAPABILITIES: [&str; 14] = [
+    "CAP_AUDIT_WRITE",
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_MKNOD",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_RAW",
+    "CAP_SETFCAP",
+    "CAP_SETGID",
+    "CAP_SETPCAP",
+    "CAP_SETUID",
+    "CAP_SYS_CHROOT",
+];
+
+/// Describes a container as the config.json of an OCI runtime bundle. The
+/// network of the host is shared, as there is nothing to set up another.
+/// Without root, a user namespace maps the caller to root in the container,
+/// like `runc spec --rootless` does
+pub fn runtime_spec(
+    root: &Path,
+    args: &[String],
+    env: &[(String, String)],
+    cwd: &str,
+    user: (u32, u32),
+    extra_mounts: &[Mount],
+    rootless: bool,
+) -> serde_json::Value {
+    let mut namespaces = vec![
+        json!({"type": "pid"}),
+        json!({"type": "ipc"}),
+        json!({"type": "uts"}),
+        json!({"type": "mount"}),
+    ];
+    let mut mounts = vec![
+        json!({"destination": "/proc", "type": "proc", "source": "proc"}),
+        json!({
+            "destination": "/dev",
+            "type": "tmpfs",
+            "source": "tmpfs",
+            "options": ["nosuid", "strictatime", "mode=755", "size=65536k"]
+        }),
+        json!({
+            "destination": "/dev/pts",
+            "type": "devpts",
+            "source": "devpts",
+            "options": if rootless {
+                json!(["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620"])
+            } else {
+                json!(["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"])
+            }
+        }),
+        json!({
+            "destination": "/dev/shm",
+            "type": "tmpfs",
+            "source": "shm",
+            "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"]
+        }),
+        json!({
+            "destination": "/dev/mqueue",
+            "type": "mqueue",
+            "source": "mqueue",
+            "options": ["nosuid", "noexec", "nodev"]
+        }),
+    ];
+
+    // sysfs can only be mounted in a network namespace of its own
+    mounts.push(if rootless {
+        json!({
+            "destination": "/sys",
+            "type": "none",
+            "source": "/sys",
+            "options": ["rbind", "nosuid", "noexec", "nodev", "ro"]
+        })
+    } else {
+        json!({
+            "destination": "/sys",
+            "type": "sysfs",
+            "source": "sysfs",
+            "options": ["nosuid", "noexec", "nodev", "ro"]
+        })
+    });
+
+    for mount in extra_mounts {
+        mounts.push(match mount {
+            Mount::Bind {
+                source,
+                target,
+                read_only,
+            } => json!({
+                "destination": target,
+                "type": "bind",
+                "source": source,
+                "options": if *read_only { json!(["rbind", "ro"]) } else { json!(["rbind"]) }
+            }),
+            Mount::Tmpfs { target, size } => {
+                let mut options = vec![
+                    "nosuid".to_string(),
+                    "nodev".to_string(),
+                    "mode=1777".to_string(),
+                ];
+                options.extend(size.map(|size| format!("size={}", size)));
+
+                json!({
+                    "destination": target,
+                    "type": "tmpfs",
+                    "source": "tmpfs",
+                    "options": options
+                })
+            }
+        });
+    }
+
+    let mut linux = json!({"namespaces": []});
+
+    if rootless {
+        namespaces.push(json!({"type": "user"}));
+        linux["uidMappings"] =
+            json!([{"containerID": 0, "hostID": nix::unistd::geteuid().as_raw(), "size": 1}]);
+        linux["gidMappings"] =
+            json!([{"containerID": 0, "hostID": nix::unistd::getegid().as_raw(), "size": 1}]);
+    }
+
+    linux["namespaces"] = json!(namespaces);
+
+    json!({
+        "ociVersion": "1.0.2",
+        "root": {"path": root, "readonly": false},
+        "hostname": "ocitool",
+        "process": {
+            "terminal": false,
+            "user": {"uid": user.0, "gid": user.1},
+            "args": args,
+            "env": env
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>(),
+            "cwd": cwd,
+            "capabilities": {
+                "bounding": DEFAULT_CAPABILITIES,
+                "effective": DEFAULT_CAPABILITIES,
+                "permitted": DEFAULT_CAPABILITIES,
+            },
+            "noNewPrivileges": true
+        },
+        "mounts": mounts,
+        "linux": linux
+    })
+}
+
+/// A mount the chroot-overlay backend makes in the child, over the overlay
+struct ChildMount {
+    source: CString,
+    target: CString,
+    fstype: Option<CString>,
+    flags: libc::c_ulong,
+    data: Option<CString>,
+    read_only: bool,
+}
+
 pub struct OciRunner<'a> {
     dir: &'a Path,
     config: &'a Option<Config>,
     volumes: Vec<String>,
     entrypoint: Option<String>,
-    cmd: Option<String>,
+    cmd: Option<Vec<String>>,
     workdir: Option<String>,
     mount_system: bool,
     ensure_dns: bool,
+    env: Vec<(String, String)>,
+    user: Option<String>,
+    backend: RunBackend,
+    tty: bool,
+    stop_timeout: Option<Duration>,
+    dns: Vec<String>,
+    dns_search: Vec<String>,
+    read_only: bool,
+    tmpfs: Vec<String>,
 }
 
 impl<'a> OciRunner<'a> {
@@ -30,7 +549,7 @@ impl<'a> OciRunner<'a> {
         config: &'a Option<Config>,
         volumes: Vec<String>,
         entrypoint: Option<String>,
-        cmd: Option<String>,
+        cmd: Option<Vec<String>>,
         workdir: Option<String>,
         mount_system: bool,
         ensure_dns: bool,
@@ -44,85 +563,836 @@ impl<'a> OciRunner<'a> {
             workdir,
             mount_system,
             ensure_dns,
+            env: vec![],
+            user: None,
+            backend: RunBackend::Proot,
+            tty: false,
+            stop_timeout: None,
+            dns: vec![],
+            dns_search: vec![],
+            read_only: false,
+            tmpfs: vec![],
         }
     }
 
-    pub async fn run(&self) -> Result<(), OciRunnerError> {
+    /// Sets variables that override the Env of the image config
+    pub fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Sets the user to run as instead of the User of the image config
+    pub fn with_user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// Sets what isolates the container, proot unless set
+    pub fn with_backend(mut self, backend: RunBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Runs the container on a terminal of its own, attached to this one
+    pub fn with_tty(mut self, tty: bool) -> Self {
+        self.tty = tty;
+        self
+    }
+
+    /// Sets how long the container gets to exit after the stop signal
+    pub fn with_stop_timeout(mut self, stop_timeout: Option<Duration>) -> Self {
+        self.stop_timeout = stop_timeout;
+        self
+    }
+
+    /// Sets the name servers and search domains of the container, over
+    /// those of the host
+    pub fn with_dns(mut self, dns: Vec<String>, dns_search: Vec<String>) -> Self {
+        self.dns = dns;
+        self.dns_search = dns_search;
+        self
+    }
+
+    /// Mounts the root filesystem read-only, with tmpfs mounts at the given
+    /// paths, as /path or /path:size
+    pub fn with_read_only(mut self, read_only: bool, tmpfs: Vec<String>) -> Self {
+        self.read_only = read_only;
+        self.tmpfs = tmpfs;
+        self
+    }
+
+    /// The arguments to run, the entrypoint followed by the command
+    fn args(&self) -> Vec<String> {
+        let config = self.config.as_ref();
+        let entrypoint = match &self.entrypoint {
+            Some(entrypoint) => vec![entrypoint.clone()],
+            None => config
+                .and_then(|config| config.entrypoint.clone())
+                .unwrap_or_default(),
+        };
+        let cmd = match &self.cmd {
+            Some(cmd) => cmd.clone(),
+            None => config
+                .and_then(|config| config.cmd.clone())
+                .unwrap_or_default(),
+        };
+
+        entrypoint.into_iter().chain(cmd).collect()
+    }
+
+    /// What to mount into the container, with the system directories first
+    fn mounts(&self) -> Result<Vec<Mount>, OciRunnerError> {
+        let mut mounts = vec![];
+
+        if self.mount_system {
+            for dir in ["/dev", "/proc", "/sys"] {
+                mounts.push(Mount::Bind {
+                    source: dir.to_string(),
+                    target: dir.to_string(),
+                    read_only: false,
+                });
+            }
+        }
+
+        for volume in &self.volumes {
+            mounts.push(Mount::parse_volume(volume)?);
+        }
+
+        for tmpfs in &self.tmpfs {
+            mounts.push(Mount::parse_tmpfs(tmpfs)?);
+        }
+
+        Ok(mounts)
+    }
+
+    /// The mounts for a runtime, which mounts /dev, /proc and /sys itself
+    fn runtime_mounts(&self) -> Result<Vec<Mount>, OciRunnerError> {
+        Ok(self
+            .mounts()?
+            .into_iter()
+            .filter(|mount| !["/dev", "/proc", "/sys"].contains(&mount.target()))
+            .collect())
+    }
+
+    fn user(&self) -> Option<&str> {
+        self.user.as_deref().or_else(|| {
+            self.config
+                .as_ref()
+                .and_then(|config| config.user.as_deref())
+                .filter(|user| !user.is_empty())
+        })
+    }
+
+    fn workdir(&self) -> Option<String> {
+        self.workdir.clone().or_else(|| {
+            self.config
+                .as_ref()
+                .and_then(|config| config.working_dir.clone())
+        })
+    }
+
+    /// The environment of the container: that of its image, not the host's
+    fn env(&self) -> Vec<(String, String)> {
+        let image_env = self
+            .config
+            .as_ref()
+            .and_then(|config| config.env.as_deref())
+            .unwrap_or_default();
+
+        merge_env(image_env, self.env.clone())
+    }
+
+    /// The resolv.conf of the container, the host's unless name servers or
+    /// search domains were given
+    pub fn resolv_conf(&self) -> String {
+        let host = std::fs::read_to_string("/etc/resolv.conf").ok();
+        resolv_conf(&self.dns, &self.dns_search, host.as_deref())
+    }
+
+    /// Describes the container as a containerd task, whose root filesystem
+    /// is mounted at rootfs in its bundle. The user is resolved from the
+    /// layer directories of the snapshot, the upper ones first. Instead of
+    /// writing a resolv.conf into the image, the given file is bound
+    /// read-only over it
+    pub fn task_spec(
+        &self,
+        layers: &[&Path],
+        resolv_conf: &Path,
+    ) -> Result<serde_json::Value, OciRunnerError> {
+        let user = match self.user() {
+            Some(user) => resolve_user_in(layers, user)?,
+            None => (0, 0),
+        };
+        let workdir = self.workdir();
+
+        let mut spec = runtime_spec(
+            Path::new("rootfs"),
+            &self.args(),
+            &self.env(),
+            workdir.as_deref().unwrap_or("/"),
+            user,
+            &self.runtime_mounts()?,
+            false,
+        );
+        spec["root"]["readonly"] = json!(self.read_only);
+
+        if self.ensure_dns {
+            if let Some(mounts) = spec["mounts"].as_array_mut() {
+                mounts.push(json!({
+                    "destination": "/etc/resolv.conf",
+                    "type": "bind",
+                    "source": resolv_conf,
+                    "options": ["rbind", "ro"]
+                }));
+            }
+        }
+
+        Ok(spec)
+    }
+
+    /// Builds the command for the backend. The returned directory holds the
+    /// overlay of chroot-overlay or the bundle of a runtime, and must live as
+    /// long as the process
+    async fn command(
+        &self,
+    ) -> Result<(tokio::process::Command, Option<tempfile::TempDir>), OciRunnerError> {
         if self.ensure_dns {
-            let etc = self.dir.join("etc");
-            create_dir_all(etc.clone()).await?;
+            write_resolv_conf(self.dir, &self.resolv_conf()).await?;
+        }
+
+        let user = self
+            .user()
+            .map(|user| resolve_user(self.dir, user))
+            .transpose()?;
+        let workdir = self.workdir();
+        let mounts = self.mounts()?;
+        let env = self.env();
+
+        let (mut command, dir) = match self.backend {
+            RunBackend::Proot => {
+                let (command, scratch) = self.proot_command(user, &workdir, mounts)?;
+                (command, scratch)
+            }
+            RunBackend::Bwrap => (self.bwrap_command(user, &workdir, &mounts)?, None),
+            RunBackend::ChrootOverlay => {
+                let (command, overlay) = self.overlay_command(user, &workdir, &mounts)?;
+                (command, Some(overlay))
+            }
+            RunBackend::Runtime(runtime) => {
+                // The runtime reads the environment from the spec instead
+                let (command, bundle) = self.runtime_command(runtime, user, &workdir, &env)?;
+                return Ok((command, Some(bundle)));
+            }
+        };
 
-            let resolv_conf = etc.join("resolv.conf");
-            let mut resolv_conf_file = File::create(resolv_conf).await?;
+        // Except for the variables that configure proot itself
+        command.env_clear();
+        command.envs(std::env::vars().filter(|(key, _)| key.starts_with("PROOT_")));
+        command.envs(env);
 
-            resolv_conf_file
-                .write_all(b"nameserver 8.8.8.8\nnameserver 8.8.4.4\n")
-                .await?;
+        Ok((command, dir))
+    }
+
+    /// Builds the proot command. proot cannot make anything read-only, and
+    /// mounts no tmpfs: a directory of the returned scratch directory is
+    /// bound in place of each, which goes away with the container
+    fn proot_command(
+        &self,
+        user: Option<(u32, u32)>,
+        workdir: &Option<String>,
+        mounts: Vec<Mount>,
+    ) -> Result<(tokio::process::Command, Option<tempfile::TempDir>), OciRunnerError> {
+        let read_only_volume = mounts.iter().any(|mount| {
+            matches!(
+                mount,
+                Mount::Bind {
+                    read_only: true,
+                    ..
+                }
+            )
+        });
+
+        if self.read_only || read_only_volume {
+            return Err(OciRunnerError(
+                "proot cannot mount read-only, use another --backend".to_string(),
+            ));
         }
 
         let proot = which::which("proot")
-            .or_else(|_| Err(OciRunnerError("proot not found in PATH".to_string())))?;
+            .map_err(|_| OciRunnerError("proot not found in PATH".to_string()))?;
 
         let mut command = tokio::process::Command::new(proot);
 
         command.arg("-r").arg(self.dir);
 
-        if self.mount_system {
-            command.arg("-b").arg("/dev:/dev");
-            command.arg("-b").arg("/proc:/proc");
-            command.arg("-b").arg("/sys:/sys");
+        // proot fakes the IDs the process sees, it cannot really switch users
+        match user {
+            Some((0, 0)) => {
+                command.arg("-0");
+            }
+            Some((uid, gid)) => {
+                command.arg("-i").arg(format!("{}:{}", uid, gid));
+            }
+            None => {}
         }
 
-        for volume in &self.volumes {
-            let parts: Vec<&str> = volume.split(':').collect();
+        let mut scratch = None;
 
-            if parts.len() != 2 {
-                eprintln!("Invalid volume format: {}", volume);
-                std::process::exit(1);
-            }
+        for (index, mount) in mounts.into_iter().enumerate() {
+            let (source, target) = match mount {
+                Mount::Bind { source, target, .. } => (source, target),
+                Mount::Tmpfs { target, .. } => {
+                    let scratch = match &mut scratch {
+                        Some(scratch) => scratch,
+                        None => scratch.insert(tempfile::tempdir()?),
+                    };
+                    let dir = scratch.path().join(index.to_string());
+                    std::fs::create_dir(&dir)?;
+                    (dir.display().to_string(), target)
+                }
+            };
 
-            command.arg("-b").arg(format!("{}:{}", parts[0], parts[1]));
+            command.arg("-b").arg(format!("{}:{}", source, target));
         }
 
-        if let Some(workdir) = &self.workdir {
+        if let Some(workdir) = workdir {
             command.arg("-w").arg(workdir);
-        } else if let Some(config) = &self.config {
-            if let Some(workdir) = &config.working_dir {
-                command.arg("-w").arg(workdir);
-            }
         }
 
-        if let Some(entrypoint) = &self.entrypoint {
-            command.arg(entrypoint);
-        } else if let Some(config) = &self.config {
-            if let Some(entrypoints) = &config.entrypoint {
-                for arg in entrypoints {
-                    command.arg(arg);
+        command.args(self.args());
+        Ok((command, scratch))
+    }
+
+    fn bwrap_command(
+        &self,
+        user: Option<(u32, u32)>,
+        workdir: &Option<String>,
+        mounts: &[Mount],
+    ) -> Result<tokio::process::Command, OciRunnerError> {
+        let bwrap = which::which("bwrap")
+            .map_err(|_| OciRunnerError("bwrap not found in PATH".to_string()))?;
+
+        let mut command = tokio::process::Command::new(bwrap);
+
+        match self.read_only {
+            true => command.arg("--ro-bind").arg(self.dir).arg("/"),
+            false => command.arg("--bind").arg(self.dir).arg("/"),
+        };
+        command.arg("--die-with-parent");
+
+        for mount in mounts {
+            // bwrap cannot create the mount points in a read-only root
+            let is_dir = match mount {
+                Mount::Bind { source, .. } => Path::new(source).is_dir(),
+                Mount::Tmpfs { .. } => true,
+            };
+
+            if self.read_only && is_dir {
+                let target = mount.target().trim_start_matches('/');
+                std::fs::create_dir_all(self.dir.join(target))?;
+            }
+
+            match mount {
+                Mount::Bind { target, .. } if target == "/dev" => {
+                    command.arg("--dev").arg("/dev");
+                }
+                Mount::Bind { target, .. } if target == "/proc" => {
+                    command.arg("--proc").arg("/proc");
+                }
+                Mount::Bind {
+                    source,
+                    target,
+                    read_only,
+                } => {
+                    match read_only {
+                        true => command.arg("--ro-bind"),
+                        false => command.arg("--bind"),
+                    };
+                    command.arg(source).arg(target);
+                }
+                Mount::Tmpfs { target, size } => {
+                    if let Some(size) = size {
+                        command.arg("--size").arg(size.to_string());
+                    }
+                    command.arg("--tmpfs").arg(target);
                 }
             }
         }
 
-        if let Some(cmd) = &self.cmd {
-            for arg in cmd.split_whitespace() {
-                command.arg(arg);
-            }
-        } else if let Some(config) = &self.config {
-            if let Some(cmd) = &config.cmd {
-                for arg in cmd {
-                    command.arg(arg);
+        // A user namespace maps the IDs, without privileges on the host
+        if let Some((uid, gid)) = user {
+            command.arg("--unshare-user");
+            command.arg("--uid").arg(uid.to_string());
+            command.arg("--gid").arg(gid.to_string());
+        }
+
+        command
+            .arg("--chdir")
+            .arg(workdir.as_deref().unwrap_or("/"));
+        command.arg("--").args(self.args());
+        Ok(command)
+    }
+
+    /// Mounts an overlay over the root filesystem in a mount namespace of the
+    /// child, so that the extracted image stays untouched, then chroots into
+    /// it. The mounts go away with the namespace when the process exits
+    fn overlay_command(
+        &self,
+        user: Option<(u32, u32)>,
+        workdir: &Option<String>,
+        mounts: &[Mount],
+    ) -> Result<(tokio::process::Command, tempfile::TempDir), OciRunnerError> {
+        if !nix::unistd::Uid::effective().is_root() {
+            return Err(OciRunnerError(
+                "The chroot-overlay backend must run as root".to_string(),
+            ));
+        }
+
+        let args = self.args();
+        let Some(program) = args.first() else {
+            return Err(OciRunnerError(
+                "The image has no command to run".to_string(),
+            ));
+        };
+
+        let overlay = tempfile::tempdir()?;
+        let merged = overlay.path().join("merged");
+
+        for dir in ["upper", "work", "merged"] {
+            std::fs::create_dir_all(overlay.path().join(dir))?;
+        }
+
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| OciRunnerError(format!("Invalid path {}", path.display())))
+        };
+
+        let options = c_path(Path::new(&format!(
+            "lowerdir={},upperdir={},workdir={}",
+            self.dir.display(),
+            overlay.path().join("upper").display(),
+            overlay.path().join("work").display()
+        )))?;
+        let merged_path = c_path(&merged)?;
+        let workdir = Path::new(workdir.as_deref().unwrap_or("/"));
+        std::fs::create_dir_all(self.dir.join(workdir.strip_prefix("/").unwrap_or(workdir)))?;
+        let workdir = c_path(workdir)?;
+        let read_only = self.read_only;
+        let mut child_mounts = vec![];
+
+        for mount in mounts {
+            // Mount targets are created in the image, the overlay shows them
+            let target = Path::new(mount.target())
+                .strip_prefix("/")
+                .unwrap_or(Path::new(mount.target()));
+            std::fs::create_dir_all(self.dir.join(target))?;
+            let target = c_path(&merged.join(target))?;
+
+            child_mounts.push(match mount {
+                Mount::Bind {
+                    source, read_only, ..
+                } => ChildMount {
+                    source: c_path(Path::new(source))?,
+                    target,
+                    fstype: None,
+                    flags: libc::MS_BIND | libc::MS_REC,
+                    data: None,
+                    read_only: *read_only,
+                },
+                Mount::Tmpfs { size, .. } => ChildMount {
+                    source: c"tmpfs".to_owned(),
+                    target,
+                    fstype: Some(c"tmpfs".to_owned()),
+                    flags: libc::MS_NOSUID | libc::MS_NODEV,
+                    data: Some(c_path(Path::new(&match size {
+                        Some(size) => format!("mode=1777,size={}", size),
+                        None => "mode=1777".to_string(),
+                    }))?),
+                    read_only: false,
+                },
+            });
+        }
+
+        let mut command = tokio::process::Command::new(program);
+        command.args(&args[1..]);
+
+        // Runs in the forked child, between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                let check = |result: libc::c_int| match result {
+                    0 => Ok(()),
+                    _ => Err(std::io::Error::last_os_error()),
+                };
+                let none = std::ptr::null();
+
+                check(libc::unshare(libc::CLONE_NEWNS))?;
+                check(libc::mount(
+                    none,
+                    c"/".as_ptr(),
+                    none,
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                check(libc::mount(
+                    c"overlay".as_ptr(),
+                    merged_path.as_ptr(),
+                    c"overlay".as_ptr(),
+                    0,
+                    options.as_ptr().cast(),
+                ))?;
+
+                let read_only_remount = |target: &CString| {
+                    check(libc::mount(
+                        none,
+                        target.as_ptr(),
+                        none,
+                        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+                        std::ptr::null(),
+                    ))
+                };
+
+                for mount in &child_mounts {
+                    check(libc::mount(
+                        mount.source.as_ptr(),
+                        mount.target.as_ptr(),
+                        mount.fstype.as_ref().map_or(none, |fstype| fstype.as_ptr()),
+                        mount.flags,
+                        mount
+                            .data
+                            .as_ref()
+                            .map_or(std::ptr::null(), |data| data.as_ptr().cast()),
+                    ))?;
+
+                    if mount.read_only {
+                        read_only_remount(&mount.target)?;
+                    }
                 }
-            }
+
+                if read_only {
+                    read_only_remount(&merged_path)?;
+                }
+
+                check(libc::chroot(merged_path.as_ptr()))?;
+                check(libc::chdir(workdir.as_ptr()))?;
+
+                if let Some((uid, gid)) = user {
+                    check(libc::setgroups(0, std::ptr::null()))?;
+                    check(libc::setgid(gid))?;
+                    check(libc::setuid(uid))?;
+                }
+
+                Ok(())
+            });
         }
 
-        let status = command.status().await?;
+        Ok((command, overlay))
+    }
+
+    /// Writes a bundle with the runtime spec of the container, and runs it
+    /// with runc or crun, which share the same command line
+    fn runtime_command(
+        &self,
+        runtime: &str,
+        user: Option<(u32, u32)>,
+        workdir: &Option<String>,
+        env: &[(String, String)],
+    ) -> Result<(tokio::process::Command, tempfile::TempDir), OciRunnerError> {
+        let program = which::which(runtime)
+            .map_err(|_| OciRunnerError(format!("{} not found in PATH", runtime)))?;
+        let rootless = !nix::unistd::Uid::effective().is_root();
+        let user = user.unwrap_or((0, 0));
 
-        if !status.success() {
+        if rootless && user != (0, 0) {
             return Err(OciRunnerError(format!(
-                "Command exited with status: {}",
-                status
+                "Without root, {} can only run the container as root",
+                runtime
             )));
         }
 
+        let mut spec = runtime_spec(
+            &self.dir.canonicalize()?,
+            &self.args(),
+            env,
+            workdir.as_deref().unwrap_or("/"),
+            user,
+            &self.runtime_mounts()?,
+            rootless,
+        );
+        spec["root"]["readonly"] = json!(self.read_only);
+
+        let bundle = tempfile::tempdir()?;
+        std::fs::write(
+            bundle.path().join("config.json"),
+            serde_json::to_vec_pretty(&spec).map_err(|e| OciRunnerError(e.to_string()))?,
+        )?;
+
+        let mut command = tokio::process::Command::new(program);
+        command
+            .arg("run")
+            .arg("--bundle")
+            .arg(bundle.path())
+            .arg(format!("ocitool-{}", std::process::id()));
+
+        Ok((command, bundle))
+    }
+
+    /// Runs the container to completion, returning its exit status
+    pub async fn run(&self) -> Result<ExitStatus, OciRunnerError> {
+        let stop_signal = self
+            .config
+            .as_ref()
+            .and_then(|config| config.stop_signal.as_deref());
+        let policy = StopPolicy::new(stop_signal, self.stop_timeout).map_err(OciRunnerError)?;
+
+        let (mut command, _overlay) = self.command().await?;
+        let pty = match self.tty {
+            true => Some(Pty::attach(&mut command)?),
+            false => None,
+        };
+
+        // Unless it shares the terminal with ocitool, the container gets a
+        // process group of its own, that signals are passed on to
+        let group = self.tty || !io::stdin().is_terminal();
+
+        if group && !self.tty {
+            command.process_group(0);
+        }
+
+        let mut child = command.spawn()?;
+        drop(command);
+
+        let session = pty.map(Pty::start).transpose()?;
+        let pid = Pid::from_raw(child.id().unwrap_or_default() as i32);
+        let status = supervise(
+            child.wait(),
+            group,
+            &policy,
+            |signal| {
+                let _ = match group {
+                    true => killpg(pid, signal),
+                    false => kill(pid, signal),
+                };
+            },
+            || session.iter().for_each(Session::resize),
+        )
+        .await??;
+
+        if let Some(session) = session {
+            session.finish().await;
+        }
+
+        Ok(status)
+    }
+
+    /// Runs the container to completion, capturing its output instead of inheriting it
+    pub async fn output(&self) -> Result<std::process::Output, OciRunnerError> {
+        let (mut command, _overlay) = self.command().await?;
+        Ok(command.output().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(
+            RunBackend::parse("chroot-overlay").unwrap(),
+            RunBackend::ChrootOverlay
+        );
+        assert_eq!(RunBackend::parse("bwrap").unwrap(), RunBackend::Bwrap);
+        assert!(RunBackend::parse("docker").is_err());
+    }
+
+    #[test]
+    fn test_runtime_spec() {
+        let spec = runtime_spec(
+            Path::new("/tmp/rootfs"),
+            &["nginx".to_string(), "-g".to_string()],
+            &[("PATH".to_string(), "/bin".to_string())],
+            "/srv",
+            (101, 101),
+            &[
+                Mount::parse_volume("/data:/var/lib/data:ro").unwrap(),
+                Mount::parse_tmpfs("/tmp:64m").unwrap(),
+            ],
+            false,
+        );
+
+        assert_eq!(spec["root"]["path"], "/tmp/rootfs");
+        assert_eq!(spec["process"]["args"], json!(["nginx", "-g"]));
+        assert_eq!(spec["process"]["env"], json!(["PATH=/bin"]));
+        assert_eq!(spec["process"]["cwd"], "/srv");
+        assert_eq!(spec["process"]["user"], json!({"uid": 101, "gid": 101}));
+        let mounts = spec["mounts"].as_array().unwrap();
+        assert_eq!(mounts[mounts.len() - 2]["source"], "/data");
+        assert_eq!(mounts[mounts.len() - 2]["options"], json!(["rbind", "ro"]));
+        assert_eq!(
+            mounts[mounts.len() - 1]["options"],
+            json!(["nosuid", "nodev", "mode=1777", "size=67108864"])
+        );
+        assert!(spec["linux"].get("uidMappings").is_none());
+
+        let rootless = runtime_spec(Path::new("/"), &[], &[], "/", (0, 0), &[], true);
+        let namespaces = rootless["linux"]["namespaces"].as_array().unwrap();
+        assert!(namespaces.contains(&json!({"type": "user"})));
+        assert_eq!(rootless["linux"]["uidMappings"][0]["containerID"], 0);
+    }
+
+    #[test]
+    fn test_resolve_user() -> Result<(), OciRunnerError> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("etc"))?;
+        std::fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\nnginx:x:101:101:nginx:/var/cache/nginx:/sbin/nologin\nbroken:x:abc:0::/:/bin/sh\nnogroup:x:102:abc::/:/bin/sh\n",
+        )?;
+        std::fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\nnginx:x:101:\nwww-data:x:82:\nbad:x::\n",
+        )?;
+
+        assert_eq!(resolve_user(root.path(), "root")?, (0, 0));
+        assert_eq!(resolve_user(root.path(), "nginx")?, (101, 101));
+        assert_eq!(resolve_user(root.path(), "nginx:www-data")?, (101, 82));
+        assert_eq!(resolve_user(root.path(), "1000")?, (1000, 0));
+        assert_eq!(resolve_user(root.path(), "1000:1000")?, (1000, 1000));
+        assert!(resolve_user(root.path(), "missing").is_err());
+        assert!(resolve_user(root.path(), "nginx:missing").is_err());
+
+        // Malformed IDs are errors rather than root
+        assert!(resolve_user(root.path(), "broken").is_err());
+        assert!(resolve_user(root.path(), "nogroup").is_err());
+        assert!(resolve_user(root.path(), "nginx:bad").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mounts() {
+        assert_eq!(
+            Mount::parse_volume("/srv:/data:ro").unwrap(),
+            Mount::Bind {
+                source: "/srv".to_string(),
+                target: "/data".to_string(),
+                read_only: true,
+            }
+        );
+        assert_eq!(
+            Mount::parse_volume("/srv:/data:rw").unwrap(),
+            Mount::parse_volume("/srv:/data").unwrap()
+        );
+        assert!(Mount::parse_volume("/srv:/data:z").is_err());
+        assert!(Mount::parse_volume("/srv").is_err());
+
+        assert_eq!(
+            Mount::parse_tmpfs("/run").unwrap(),
+            Mount::Tmpfs {
+                target: "/run".to_string(),
+                size: None,
+            }
+        );
+        assert_eq!(
+            Mount::parse_tmpfs("/tmp:size=1g").unwrap(),
+            Mount::Tmpfs {
+                target: "/tmp".to_string(),
+                size: Some(1 << 30),
+            }
+        );
+        assert_eq!(parse_size("512k"), Some(512 << 10));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert!(Mount::parse_tmpfs("tmp").is_err());
+        assert!(Mount::parse_tmpfs("/tmp:lots").is_err());
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let host = "# Generated\nnameserver 127.0.0.53\nsearch lan\noptions edns0 trust-ad\n";
+        let servers = vec!["1.1.1.1".to_string(), "9.9.9.9".to_string()];
+        let search = vec!["corp.example.com".to_string(), "example.com".to_string()];
+
+        assert_eq!(
+            resolv_conf(&[], &[], Some(host)),
+            "nameserver 127.0.0.53\nsearch lan\noptions edns0 trust-ad\n"
+        );
+        assert_eq!(
+            resolv_conf(&servers, &[], Some(host)),
+            "nameserver 1.1.1.1\nnameserver 9.9.9.9\nsearch lan\noptions edns0 trust-ad\n"
+        );
+        assert_eq!(
+            resolv_conf(&[], &search, None),
+            "nameserver 8.8.8.8\nnameserver 8.8.4.4\nsearch corp.example.com example.com\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_resolv_conf() {
+        let host = tempfile::tempdir().unwrap();
+        let host_resolv_conf = host.path().join("resolv.conf");
+        std::fs::write(&host_resolv_conf, "nameserver 10.0.0.1\n").unwrap();
+
+        // A symlink to a host file is replaced, rather than written through
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        std::os::unix::fs::symlink(&host_resolv_conf, root.path().join("etc/resolv.conf")).unwrap();
+
+        write_resolv_conf(root.path(), "nameserver 1.1.1.1\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&host_resolv_conf).unwrap(),
+            "nameserver 10.0.0.1\n"
+        );
+        assert!(
+            !std::fs::symlink_metadata(root.path().join("etc/resolv.conf"))
+                .unwrap()
+                .is_symlink()
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/resolv.conf")).unwrap(),
+            "nameserver 1.1.1.1\n"
+        );
+
+        // So is an etc that points outside of the root filesystem
+        let root = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(host.path(), root.path().join("etc")).unwrap();
+        assert!(write_resolv_conf(root.path(), "nameserver 1.1.1.1\n")
+            .await
+            .is_err());
+        assert_eq!(
+            std::fs::read_to_string(&host_resolv_conf).unwrap(),
+            "nameserver 10.0.0.1\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_env() -> Result<(), OciRunnerError> {
+        let host = |key: &str| (key == "TOKEN").then(|| "secret".to_string());
+        let file = parse_env_file(
+            "# comment\n\nMODE=file\nLEVEL=debug\nTOKEN\nMISSING\n",
+            host,
+        )?;
+        let flags = parse_variables(&["MODE=flag".to_string(), "EMPTY=".to_string()], host)?;
+
+        let image = vec!["PATH=/bin".to_string(), "MODE=image".to_string()];
+        let merged = merge_env(&image, file.into_iter().chain(flags).collect());
+        let pair = |key: &str, value: &str| (key.to_string(), value.to_string());
+
+        assert_eq!(
+            merged,
+            vec![
+                pair("PATH", "/bin"),
+                pair("MODE", "flag"),
+                pair("LEVEL", "debug"),
+                pair("TOKEN", "secret"),
+                pair("EMPTY", ""),
+            ]
+        );
+
+        assert_eq!(merge_env(&[], vec![]), vec![pair("PATH", DEFAULT_PATH)]);
+        assert!(parse_variables(&["=value".to_string()], host).is_err());
+
         Ok(())
     }
 }
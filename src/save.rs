@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File},
+};
+
+use tar::{Builder, Header};
+use tonic::{Code, Request};
+
+use crate::{
+    access::ensure_socket_access,
+    compose::containerd::client::{
+        services::v1::{GetImageRequest, ReadContentRequest},
+        types::Descriptor,
+        Client,
+    },
+    downloader::is_index_content_type,
+    layout::REF_NAME_ANNOTATION,
+    local::CONTAINERD_NAME_ANNOTATION,
+    parser::FullImageWithTag,
+    registry_config::RegistrySettings,
+    report::Context,
+    spec::{
+        enums::MediaType,
+        index::{ImageIndex, Manifest},
+        manifest::ImageManifest,
+    },
+    with_namespace, Save,
+};
+
+const NAMESPACE: &str = "default";
+
+/// The names containerd may know an image by: the one ocitool pulls it
+/// under, then the name as given
+fn image_names(name: &str, settings: &RegistrySettings) -> Vec<String> {
+    let Ok(pulled) = FullImageWithTag::parse(name, settings).map(|image| image.canonical_name())
+    else {
+        return vec![name.to_string()];
+    };
+
+    if pulled == name {
+        vec![pulled]
+    } else {
+        vec![pulled, name.to_string()]
+    }
+}
+
+/// Finds the image record of a name, returning the name it was found under
+pub async fn find_image(
+    client: &Client,
+    name: &str,
+    settings: &RegistrySettings,
+) -> Result<(String, Descriptor), Box<dyn Error>> {
+    for candidate in image_names(name, settings) {
+        let response = client
+            .images()
+            .get(with_namespace!(
+                GetImageRequest {
+                    name: candidate.clone(),
+                },
+                NAMESPACE
+            ))
+            .await;
+
+        match response {
+            Ok(response) => {
+                if let Some(target) = response.into_inner().image.and_then(|image| image.target) {
+                    return Ok((candidate, target));
+                }
+            }
+            Err(status) if status.code() == Code::NotFound => {}
+            Err(status) => return Err(status.into()),
+        }
+    }
+
+    Err(format!("containerd has no image named {}", name).into())
+}
+
+/// Reads a blob through the Read RPC of the content service, or None if
+/// containerd does not have it
+pub async fn read_content(
+    client: &Client,
+    digest: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    read_namespaced_content(client, NAMESPACE, digest).await
+}
+
+/// Reads a blob like read_content, from the content of another namespace
+pub async fn read_namespaced_content(
+    client: &Client,
+    namespace: &str,
+    digest: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let response = client
+        .content()
+        .read(with_namespace!(
+            ReadContentRequest {
+                digest: digest.to_string(),
+                offset: 0,
+                size: 0,
+            },
+            namespace
+        ))
+        .await;
+
+    let mut stream = match response {
+        Ok(response) => response.into_inner(),
+        Err(status) if status.code() == Code::NotFound => return Ok(None),
+        Err(status) => return Err(status.into()),
+    };
+
+    let mut data = Vec::new();
+
+    loop {
+        match stream.message().await {
+            Ok(Some(chunk)) => data.extend_from_slice(&chunk.data),
+            Ok(None) => return Ok(Some(data)),
+            Err(status) if status.code() == Code::NotFound && data.is_empty() => return Ok(None),
+            Err(status) => return Err(status.into()),
+        }
+    }
+}
+
+fn append(builder: &mut Builder<File>, path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+/// Copies an image and everything it refers to into the tarball. Only the
+/// platforms that were pulled are in containerd, so the manifests of an
+/// index may be missing, but nothing a manifest refers to may be
+async fn save_image(
+    client: &Client,
+    builder: &mut Builder<File>,
+    written: &mut HashSet<String>,
+    target: &Descriptor,
+) -> Result<(), Box<dyn Error>> {
+    let mut queue = vec![(target.digest.clone(), target.media_type.clone(), true)];
+
+    while let Some((digest, media_type, required)) = queue.pop() {
+        if written.contains(&digest) {
+            continue;
+        }
+
+        let Some(data) = read_content(client, &digest).await? else {
+            if required {
+                return Err(format!("containerd is missing {}", digest).into());
+            }
+
+            continue;
+        };
+
+        if is_index_content_type(&media_type) {
+            let index: ImageIndex = serde_json::from_slice(&data)?;
+
+            for manifest in index.manifests {
+                queue.push((
+                    manifest.digest,
+                    manifest.media_type.to_string().to_string(),
+                    false,
+                ));
+            }
+        } else if media_type.contains("manifest") {
+            let manifest: ImageManifest = serde_json::from_slice(&data)?;
+
+            queue.push((
+                manifest.config.digest,
+                manifest.config.media_type.to_string().to_string(),
+                true,
+            ));
+
+            for layer in manifest.layers {
+                queue.push((layer.digest, layer.media_type.to_string().to_string(), true));
+            }
+        }
+
+        append(builder, &blob_path(&digest), &data)?;
+        written.insert(digest);
+    }
+
+    Ok(())
+}
+
+pub async fn save_command(args: &Save, settings: &RegistrySettings) -> Result<(), Box<dyn Error>> {
+    if args.images.is_empty() {
+        return Err("Name at least one image to save".into());
+    }
+
+    let socket_path = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| "/run/containerd/containerd.sock".into());
+    let socket = socket_path.to_str().unwrap();
+
+    ensure_socket_access(socket);
+    let client = Client::from_path(socket).await?;
+
+    let partial = args
+        .output
+        .with_extension(format!("partial-{}", std::process::id()));
+    let mut builder = Builder::new(File::create(&partial)?);
+    let mut written = HashSet::new();
+    let mut manifests = vec![];
+
+    let result = async {
+        for name in &args.images {
+            let (full_name, target) = find_image(&client, name, settings).await?;
+
+            save_image(&client, &mut builder, &mut written, &target)
+                .await
+                .with_context(|| format!("saving {}", full_name))?;
+
+            let tag = FullImageWithTag::parse(&full_name, settings)?.tag;
+            manifests.push(Manifest {
+                media_type: MediaType::from(target.media_type.as_str()),
+                size: target.size as u64,
+                digest: target.digest.clone(),
+                platform: None,
+                artifact_type: None,
+                annotations: Some(HashMap::from([
+                    (REF_NAME_ANNOTATION.to_string(), tag),
+                    (CONTAINERD_NAME_ANNOTATION.to_string(), full_name.clone()),
+                ])),
+            });
+
+            println!("Saved {}@{}", full_name, target.digest);
+        }
+
+        let index = ImageIndex {
+            schema_version: 2,
+            media_type: MediaType::OciImageIndexV1Json,
+            artifact_type: None,
+            manifests,
+            annotations: None,
+        };
+
+        append(
+            &mut builder,
+            "oci-layout",
+            br#"{"imageLayoutVersion":"1.0.0"}"#,
+        )?;
+        append(&mut builder, "index.json", &index.to_json())?;
+        builder.into_inner()?.sync_all()?;
+        fs::rename(&partial, &args.output)?;
+
+        Ok::<_, Box<dyn Error>>(())
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_names() {
+        assert_eq!(
+            image_names("nginx:alpine", &RegistrySettings::default()),
+            vec!["docker.io/library/nginx:alpine", "nginx:alpine"]
+        );
+        assert_eq!(
+            image_names(
+                "docker.io/library/nginx:alpine",
+                &RegistrySettings::default()
+            ),
+            vec!["docker.io/library/nginx:alpine"]
+        );
+        assert_eq!(blob_path("sha256:abc"), "blobs/sha256/abc");
+    }
+}
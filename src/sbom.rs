@@ -0,0 +1,249 @@
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::{
+    digest::sha256_digest,
+    execution::Blob,
+    parser::{FullImage, FullImageWithTag},
+    spec::{
+        enums::MediaType,
+        index::{ImageIndex, Manifest},
+        manifest::{Descriptor, ImageManifest},
+        referrers::referrers_fallback_tag,
+    },
+    uploader::{OciUploader, OciUploaderError},
+};
+
+/// The empty JSON object, used as the config of artifact manifests
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+/// Where the SBOM attached to an uploaded image comes from
+#[derive(Clone, Debug)]
+pub enum SbomSource {
+    /// An existing SPDX or CycloneDX JSON document
+    File(PathBuf),
+    /// A CycloneDX document generated from the plan's base images
+    Generate,
+}
+
+/// Detects whether a JSON document is a CycloneDX or an SPDX SBOM
+pub fn sbom_media_type(data: &[u8]) -> Result<MediaType, OciUploaderError> {
+    let document: Value = serde_json::from_slice(data)
+        .map_err(|e| OciUploaderError(format!("Failed to parse SBOM: {}", e)))?;
+
+    if document.get("bomFormat").and_then(Value::as_str) == Some("CycloneDX") {
+        Ok(MediaType::CycloneDxJson)
+    } else if document.get("spdxVersion").is_some() {
+        Ok(MediaType::SpdxJson)
+    } else {
+        Err(OciUploaderError(
+            "SBOM is neither a CycloneDX nor an SPDX JSON document".to_string(),
+        ))
+    }
+}
+
+/// Builds the artifact manifest carrying the SBOM, with the image as its subject
+fn referrer_manifest(subject: Descriptor, sbom: Descriptor) -> ImageManifest {
+    ImageManifest {
+        schema_version: 2,
+        media_type: MediaType::OciImageManifestV1Json,
+        artifact_type: Some(String::from(sbom.media_type.to_string())),
+        config: Descriptor {
+            media_type: MediaType::OciEmptyV1Json,
+            digest: sha256_digest(EMPTY_CONFIG),
+            size: EMPTY_CONFIG.len() as u64,
+            data: None,
+            urls: None,
+            annotations: None,
+        },
+        layers: vec![sbom],
+        subject: Some(subject),
+        annotations: None,
+    }
+}
+
+/// Adds a referrer to the index kept under the fallback tag, replacing
+/// any previous entry with the same digest
+fn add_fallback_referrer(index: Option<ImageIndex>, referrer: Manifest) -> ImageIndex {
+    let mut index = index.unwrap_or(ImageIndex {
+        schema_version: 2,
+        media_type: MediaType::OciImageIndexV1Json,
+        artifact_type: None,
+        manifests: vec![],
+        annotations: None,
+    });
+
+    index
+        .manifests
+        .retain(|manifest| manifest.digest != referrer.digest);
+    index.manifests.push(referrer);
+    index
+}
+
+/// Pushes an SBOM as an OCI artifact referring to the given image.
+/// Registries without the referrers API get the artifact listed in
+/// the index tagged after the subject's digest instead
+pub async fn attach_sbom(
+    uploader: &OciUploader,
+    image: &FullImage,
+    subject: Descriptor,
+    data: Vec<u8>,
+) -> Result<(), OciUploaderError> {
+    let media_type = sbom_media_type(&data)?;
+    let subject_digest = subject.digest.clone();
+
+    let config_blob = Blob {
+        digest: sha256_digest(EMPTY_CONFIG),
+        data: EMPTY_CONFIG.into(),
+        mount_from: None,
+    };
+    let sbom_blob = Blob {
+        digest: sha256_digest(&data),
+        data: data.into(),
+        mount_from: None,
+    };
+
+    uploader.upload_blob(image.clone(), &config_blob).await?;
+    uploader.upload_blob(image.clone(), &sbom_blob).await?;
+
+    let manifest = referrer_manifest(
+        subject,
+        Descriptor {
+            media_type,
+            digest: sbom_blob.digest.clone(),
+            size: sbom_blob.data.len() as u64,
+            data: None,
+            urls: None,
+            annotations: None,
+        },
+    );
+    let artifact_type = manifest.artifact_type.clone();
+    let manifest_data = manifest.to_json();
+    let manifest_digest = sha256_digest(&manifest_data);
+
+    println!("Attaching SBOM to {}...", subject_digest);
+
+    let headers = uploader
+        .upload_manifest(
+            FullImageWithTag {
+                image: image.clone(),
+                tag: manifest_digest.clone(),
+            },
+            manifest_data.clone(),
+            "application/vnd.oci.image.manifest.v1+json",
+        )
+        .await?;
+
+    if headers.contains_key("oci-subject") {
+        return Ok(());
+    }
+
+    // The registry does not index referrers itself, so maintain the
+    // sha256-<hex> tag that clients fall back to
+    let fallback = FullImageWithTag {
+        image: image.clone(),
+        tag: referrers_fallback_tag(&subject_digest),
+    };
+    let existing = uploader
+        .get_manifest(fallback.clone(), "application/vnd.oci.image.index.v1+json")
+        .await?
+        .map(|data| serde_json::from_slice::<ImageIndex>(&data))
+        .transpose()
+        .map_err(|e| OciUploaderError(format!("Failed to parse referrers index: {}", e)))?;
+
+    let index = add_fallback_referrer(
+        existing,
+        Manifest {
+            media_type: MediaType::OciImageManifestV1Json,
+            size: manifest_data.len() as u64,
+            digest: manifest_digest,
+            platform: None,
+            artifact_type,
+            annotations: None,
+        },
+    );
+
+    uploader
+        .upload_manifest(
+            fallback,
+            index.to_json(),
+            "application/vnd.oci.image.index.v1+json",
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sbom_media_type() {
+        let cyclonedx = br#"{"bomFormat":"CycloneDX","specVersion":"1.5"}"#;
+        let spdx = br#"{"spdxVersion":"SPDX-2.3","SPDXID":"SPDXRef-DOCUMENT"}"#;
+
+        assert!(matches!(
+            sbom_media_type(cyclonedx),
+            Ok(MediaType::CycloneDxJson)
+        ));
+        assert!(matches!(sbom_media_type(spdx), Ok(MediaType::SpdxJson)));
+        assert!(sbom_media_type(b"{}").is_err());
+        assert!(sbom_media_type(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_referrer_manifest() {
+        let subject = Descriptor {
+            media_type: MediaType::OciImageIndexV1Json,
+            digest: "sha256:abc".to_string(),
+            size: 123,
+            data: None,
+            urls: None,
+            annotations: None,
+        };
+        let sbom = Descriptor {
+            media_type: MediaType::SpdxJson,
+            digest: "sha256:def".to_string(),
+            size: 456,
+            data: None,
+            urls: None,
+            annotations: None,
+        };
+
+        let json: Value = serde_json::from_slice(&referrer_manifest(subject, sbom).to_json())
+            .expect("Failed to parse manifest");
+
+        assert_eq!(json["artifactType"], "application/spdx+json");
+        assert_eq!(
+            json["config"]["mediaType"],
+            "application/vnd.oci.empty.v1+json"
+        );
+        assert_eq!(
+            json["config"]["digest"],
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(json["subject"]["digest"], "sha256:abc");
+        assert_eq!(json["layers"][0]["digest"], "sha256:def");
+    }
+
+    #[test]
+    fn test_add_fallback_referrer() {
+        let referrer = |digest: &str| Manifest {
+            media_type: MediaType::OciImageManifestV1Json,
+            size: 1,
+            digest: digest.to_string(),
+            platform: None,
+            artifact_type: Some("application/spdx+json".to_string()),
+            annotations: None,
+        };
+
+        let index = add_fallback_referrer(None, referrer("sha256:a"));
+        let index = add_fallback_referrer(Some(index), referrer("sha256:b"));
+        let index = add_fallback_referrer(Some(index), referrer("sha256:a"));
+
+        let digests: Vec<&str> = index.manifests.iter().map(|m| m.digest.as_str()).collect();
+        assert_eq!(digests, vec!["sha256:b", "sha256:a"]);
+    }
+}
@@ -6,7 +6,7 @@ use super::enums::{PlatformArchitecture, PlatformOS};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ImageConfig {
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     #[serde(with = "time::serde::rfc3339::option")]
     pub created: Option<OffsetDateTime>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -1,36 +1,35 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "lowercase")]
+/// The media type of a descriptor. Artifacts may use any media type, such
+/// as the custom config and layer types of ORAS, which are kept as Other
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MediaType {
-    #[serde(rename = "application/vnd.oci.image.index.v1+json")]
     OciImageIndexV1Json,
-    #[serde(rename = "application/vnd.oci.image.manifest.v1+json")]
     OciImageManifestV1Json,
-    #[serde(rename = "application/vnd.oci.image.config.v1+json")]
     OciImageConfigV1ConfigJson,
-    #[serde(rename = "application/vnd.oci.image.layer.v1.tar+zstd")]
     OciImageLayerV1TarZstd,
-    #[serde(rename = "application/vnd.oci.image.layer.v1.tar+gzip")]
     OciImageLayerV1TarGzip,
-    #[serde(rename = "application/vnd.oci.image.layer.v1.tar")]
     OciImageLayerV1Tar,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.list.v2+json")]
     DockerManifestListV2Json,
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v2+json")]
     DockerManifestV2Json,
-    #[serde(rename = "application/vnd.docker.container.image.v1+json")]
     DockerConfigV1Json,
-    #[serde(rename = "application/vnd.docker.image.rootfs.diff.tar.gzip")]
     DockerImageRootfsDiffTarGzip,
-    #[serde(rename = "application/vnd.docker.image.rootfs.diff.tar.zstd")]
     DockerImageRootfsDiffTarZstd,
-    #[serde(rename = "application/vnd.docker.image.rootfs.diff.tar")]
     DockerImageRootfsDiffTar,
+    /// Layers that registries do not have to serve, such as those of Windows
+    /// base images, which are fetched from the URLs of their descriptor
+    OciImageLayerNondistributableV1TarZstd,
+    OciImageLayerNondistributableV1TarGzip,
+    OciImageLayerNondistributableV1Tar,
+    DockerImageRootfsForeignDiffTarGzip,
+    OciEmptyV1Json,
+    CycloneDxJson,
+    SpdxJson,
+    Other(String),
 }
 
 impl MediaType {
-    pub fn to_string(&self) -> &'static str {
+    pub fn to_string(&self) -> &str {
         match self {
             MediaType::OciImageIndexV1Json => "application/vnd.oci.image.index.v1+json",
             MediaType::OciImageManifestV1Json => "application/vnd.oci.image.manifest.v1+json",
@@ -52,8 +51,90 @@ impl MediaType {
                 "application/vnd.docker.image.rootfs.diff.tar.zstd"
             }
             MediaType::DockerImageRootfsDiffTar => "application/vnd.docker.image.rootfs.diff.tar",
+            MediaType::OciImageLayerNondistributableV1TarZstd => {
+                "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd"
+            }
+            MediaType::OciImageLayerNondistributableV1TarGzip => {
+                "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+            }
+            MediaType::OciImageLayerNondistributableV1Tar => {
+                "application/vnd.oci.image.layer.nondistributable.v1.tar"
+            }
+            MediaType::DockerImageRootfsForeignDiffTarGzip => {
+                "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip"
+            }
+            MediaType::OciEmptyV1Json => "application/vnd.oci.empty.v1+json",
+            MediaType::CycloneDxJson => "application/vnd.cyclonedx+json",
+            MediaType::SpdxJson => "application/spdx+json",
+            MediaType::Other(media_type) => media_type,
         }
     }
+
+    /// Whether layers of this type may be left out of registries
+    pub fn is_non_distributable(&self) -> bool {
+        matches!(
+            self,
+            MediaType::OciImageLayerNondistributableV1TarZstd
+                | MediaType::OciImageLayerNondistributableV1TarGzip
+                | MediaType::OciImageLayerNondistributableV1Tar
+                | MediaType::DockerImageRootfsForeignDiffTarGzip
+        )
+    }
+}
+
+impl From<&str> for MediaType {
+    fn from(media_type: &str) -> Self {
+        match media_type {
+            "application/vnd.oci.image.index.v1+json" => MediaType::OciImageIndexV1Json,
+            "application/vnd.oci.image.manifest.v1+json" => MediaType::OciImageManifestV1Json,
+            "application/vnd.oci.image.config.v1+json" => MediaType::OciImageConfigV1ConfigJson,
+            "application/vnd.oci.image.layer.v1.tar+zstd" => MediaType::OciImageLayerV1TarZstd,
+            "application/vnd.oci.image.layer.v1.tar+gzip" => MediaType::OciImageLayerV1TarGzip,
+            "application/vnd.oci.image.layer.v1.tar" => MediaType::OciImageLayerV1Tar,
+            "application/vnd.docker.distribution.manifest.list.v2+json" => {
+                MediaType::DockerManifestListV2Json
+            }
+            "application/vnd.docker.distribution.manifest.v2+json" => {
+                MediaType::DockerManifestV2Json
+            }
+            "application/vnd.docker.container.image.v1+json" => MediaType::DockerConfigV1Json,
+            "application/vnd.docker.image.rootfs.diff.tar.gzip" => {
+                MediaType::DockerImageRootfsDiffTarGzip
+            }
+            "application/vnd.docker.image.rootfs.diff.tar.zstd" => {
+                MediaType::DockerImageRootfsDiffTarZstd
+            }
+            "application/vnd.docker.image.rootfs.diff.tar" => MediaType::DockerImageRootfsDiffTar,
+            "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd" => {
+                MediaType::OciImageLayerNondistributableV1TarZstd
+            }
+            "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip" => {
+                MediaType::OciImageLayerNondistributableV1TarGzip
+            }
+            "application/vnd.oci.image.layer.nondistributable.v1.tar" => {
+                MediaType::OciImageLayerNondistributableV1Tar
+            }
+            "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip" => {
+                MediaType::DockerImageRootfsForeignDiffTarGzip
+            }
+            "application/vnd.oci.empty.v1+json" => MediaType::OciEmptyV1Json,
+            "application/vnd.cyclonedx+json" => MediaType::CycloneDxJson,
+            "application/spdx+json" => MediaType::SpdxJson,
+            media_type => MediaType::Other(media_type.to_string()),
+        }
+    }
+}
+
+impl Serialize for MediaType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for MediaType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(MediaType::from(String::deserialize(deserializer)?.as_str()))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -90,7 +171,7 @@ pub enum PlatformArchitecture {
     Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum PlatformOS {
     #[serde(rename = "aix")]
     Aix,
@@ -125,3 +206,29 @@ pub enum PlatformOS {
     #[serde(rename = "unknown")]
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_type_round_trip() {
+        let known: MediaType =
+            serde_json::from_str(r#""application/vnd.oci.image.layer.v1.tar+zstd""#).unwrap();
+        assert_eq!(known, MediaType::OciImageLayerV1TarZstd);
+        assert!(!known.is_non_distributable());
+
+        let foreign: MediaType =
+            serde_json::from_str(r#""application/vnd.docker.image.rootfs.foreign.diff.tar.gzip""#)
+                .unwrap();
+        assert!(foreign.is_non_distributable());
+
+        let vendor = r#""application/vnd.example.layer.v1+squashfs""#;
+        let other: MediaType = serde_json::from_str(vendor).unwrap();
+        assert_eq!(
+            other,
+            MediaType::Other("application/vnd.example.layer.v1+squashfs".to_string())
+        );
+        assert_eq!(serde_json::to_string(&other).unwrap(), vendor);
+    }
+}
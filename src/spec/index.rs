@@ -27,7 +27,7 @@ impl ImageIndex {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Manifest {
     #[serde(rename = "mediaType")]
     pub media_type: MediaType,
@@ -36,9 +36,16 @@ pub struct Manifest {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub platform: Option<Platform>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Platform {
     pub architecture: PlatformArchitecture,
     pub os: PlatformOS,
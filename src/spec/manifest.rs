@@ -13,6 +13,20 @@ pub struct Descriptor {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<String>,
+
+    /// Where a foreign layer can be downloaded from, besides the registry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub urls: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Descriptor {
+    /// Whether this is a foreign layer, one the registry may not serve
+    pub fn is_foreign(&self) -> bool {
+        self.urls.is_some() || self.media_type.is_non_distributable()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
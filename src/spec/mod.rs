@@ -1,5 +1,7 @@
 pub mod config;
 pub mod enums;
 pub mod index;
-pub mod plan;
 pub mod manifest;
+pub mod plan;
+pub mod referrers;
+pub mod schema1;
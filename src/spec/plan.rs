@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
 use crate::spec::{
     config::{self, Healthcheck},
-    enums::PlatformArchitecture,
+    enums::{PlatformArchitecture, PlatformOS},
 };
 
 use super::config::Config;
@@ -17,6 +17,47 @@ pub struct ImagePlan {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<ImagePlanConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<ImagePlanTest>,
+
+    /// Notary v2 trust policies for base images, keyed by registry host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trust: Option<HashMap<String, ImagePlanTrustPolicy>>,
+}
+
+/// How the signatures of images from a registry are enforced
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ImagePlanTrustLevel {
+    /// Refuse images without a trusted signature
+    #[serde(rename = "strict")]
+    Strict,
+    /// Warn about images without a trusted signature
+    #[serde(rename = "audit")]
+    Audit,
+    #[serde(rename = "skip")]
+    Skip,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImagePlanTrustPolicy {
+    pub level: ImagePlanTrustLevel,
+
+    /// PEM files with the root certificates that signatures must chain up to
+    #[serde(default)]
+    pub certificates: Vec<String>,
+}
+
+/// Smoke test run against the built image on the host platform before it is pushed
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImagePlanTest {
+    /// Command to run instead of the image's cmd
+    pub command: Vec<String>,
+    #[serde(rename = "exitCode", default)]
+    pub exit_code: i32,
+    /// Regex that the combined stdout and stderr must match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -72,6 +113,27 @@ impl ImagePlanConfig {
     }
 }
 
+impl From<Config> for ImagePlanConfig {
+    fn from(config: Config) -> Self {
+        ImagePlanConfig {
+            user: config.user,
+            exposed_ports: config.exposed_ports,
+            env: config.env,
+            entrypoint: config.entrypoint,
+            cmd: config.cmd,
+            volumes: config.volumes,
+            working_dir: config.working_dir,
+            labels: config.labels,
+            stop_signal: config.stop_signal,
+            args_escaped: config.args_escaped,
+            memory: config.memory,
+            memory_swap: config.memory_swap,
+            cpu_shares: config.cpu_shares,
+            healthcheck: config.healthcheck,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ImagePlanPlatform {
     pub architecture: PlatformArchitecture,
@@ -79,12 +141,32 @@ pub struct ImagePlanPlatform {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub variant: Option<String>,
 
+    /// Operating system of the image, defaults to linux
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<PlatformOS>,
+
+    #[serde(rename = "os.version", skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+
+    #[serde(rename = "os.features", skip_serializing_if = "Option::is_none")]
+    pub os_features: Option<Vec<String>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<ImagePlanConfig>,
 
+    /// Image whose runtime config is inherited, like FROM in a Dockerfile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+
     pub layers: Vec<ImagePlanLayer>,
 }
 
+impl ImagePlanPlatform {
+    pub fn os(&self) -> PlatformOS {
+        self.os.clone().unwrap_or(PlatformOS::Linux)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum ImagePlanLayerType {
     #[serde(rename = "tar")]
@@ -93,6 +175,10 @@ pub enum ImagePlanLayerType {
     Directory,
     #[serde(rename = "image")]
     Image,
+    #[serde(rename = "file")]
+    File,
+    #[serde(rename = "url")]
+    Url,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -106,6 +192,28 @@ pub struct ImagePlanLayer {
     pub whitelist: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub blacklist: Option<Vec<String>>,
+
+    /// Path of the file inside the image for `file` layers,
+    /// or the prefix the directory is placed under for `dir` layers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Octal permission bits of the files, for `file` and `dir` layers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(alias = "owner", skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u64>,
+    #[serde(alias = "group", skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u64>,
+
+    /// Expected sha256 of the downloaded archive, for `url` layers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Pins the image sources of a plan to the digests they resolved to
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct ImagePlanLock {
+    pub images: BTreeMap<String, String>,
 }
 
 pub fn merge_image_plan_configs(
@@ -207,3 +315,104 @@ pub fn merge_image_plan_configs(
         (None, None) => None,
     }
 }
+
+/// Merges the config of a base image under the plan config, following Dockerfile semantics:
+/// environment variables are overridden by name, a new entrypoint resets the inherited cmd
+/// and a healthcheck replaces the inherited one entirely
+pub fn merge_base_config(base: Option<Config>, config: Option<Config>) -> Option<Config> {
+    let base = base.map(ImagePlanConfig::from);
+    let config = config.map(ImagePlanConfig::from);
+    let mut merged = merge_image_plan_configs(&base, &config)?;
+
+    if let (Some(base), Some(config)) = (base, config) {
+        if let (Some(base_env), Some(env)) = (base.env, config.env) {
+            let mut merged_env = base_env;
+
+            for variable in env {
+                let name = variable.split('=').next().unwrap_or_default();
+                merged_env.retain(|existing| existing.split('=').next() != Some(name));
+                merged_env.push(variable);
+            }
+
+            merged.env = Some(merged_env);
+        }
+
+        if config.entrypoint.is_some() && config.cmd.is_none() {
+            merged.cmd = None;
+        }
+
+        merged.healthcheck = config.healthcheck.or(base.healthcheck);
+    }
+
+    Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Option<Config> {
+        Some(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn test_merge_base_config() {
+        let base = config(
+            r#"{
+                "Env": ["PATH=/usr/bin", "LANG=C"],
+                "Entrypoint": ["/docker-entrypoint.sh"],
+                "Cmd": ["nginx"],
+                "WorkingDir": "/srv",
+                "Labels": { "vendor": "base" }
+            }"#,
+        );
+        let plan = config(
+            r#"{
+                "Env": ["LANG=C.UTF-8", "APP=1"],
+                "Entrypoint": ["/app"],
+                "Labels": { "app": "demo" }
+            }"#,
+        );
+
+        let merged = merge_base_config(base, plan).unwrap();
+
+        assert_eq!(
+            merged.env.unwrap(),
+            vec!["PATH=/usr/bin", "LANG=C.UTF-8", "APP=1"]
+        );
+        assert_eq!(merged.entrypoint.unwrap(), vec!["/app"]);
+        assert!(merged.cmd.is_none());
+        assert_eq!(merged.working_dir.as_deref(), Some("/srv"));
+        assert_eq!(merged.labels.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_platform_os() {
+        let platforms: Vec<ImagePlanPlatform> = serde_json::from_str(
+            r#"[
+                { "architecture": "amd64", "layers": [] },
+                {
+                    "architecture": "amd64",
+                    "os": "windows",
+                    "os.version": "10.0.20348.2113",
+                    "os.features": ["win32k"],
+                    "layers": []
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(platforms[0].os(), PlatformOS::Linux);
+        assert_eq!(platforms[1].os(), PlatformOS::Windows);
+        assert_eq!(platforms[1].os_version.as_deref(), Some("10.0.20348.2113"));
+        assert_eq!(platforms[1].os_features, Some(vec!["win32k".to_string()]));
+    }
+
+    #[test]
+    fn test_merge_base_config_without_plan_config() {
+        let base = config(r#"{ "Cmd": ["sh"] }"#);
+        let merged = merge_base_config(base, None).unwrap();
+
+        assert_eq!(merged.cmd.unwrap(), vec!["sh"]);
+    }
+}
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A manifest referring to another one through its `subject`, as listed by the
+/// referrers API. The media types are kept as strings, since artifacts can use
+/// any media type they like
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Referrer {
+    #[serde(rename = "mediaType")]
+    pub media_type: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "artifactType")]
+    pub artifact_type: Option<String>,
+
+    pub digest: String,
+    pub size: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+/// The image index returned by the referrers API, or stored under the
+/// `sha256-<hex>` fallback tag
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReferrersIndex {
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+
+    #[serde(default)]
+    pub manifests: Vec<Referrer>,
+}
+
+/// The tag referrers are listed under on registries without the referrers API
+pub fn referrers_fallback_tag(digest: &str) -> String {
+    digest.replacen(':', "-", 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_referrers_index_with_unknown_media_types() {
+        let index: ReferrersIndex = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [{
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "artifactType": "application/vnd.example.custom",
+                    "digest": "sha256:abc",
+                    "size": 123,
+                    "annotations": { "org.opencontainers.image.created": "2024-01-01T00:00:00Z" }
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            index.manifests[0].artifact_type.as_deref(),
+            Some("application/vnd.example.custom")
+        );
+        assert_eq!(referrers_fallback_tag("sha256:abc"), "sha256-abc");
+    }
+}
@@ -0,0 +1,267 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+/// The media types of Docker image manifests v2, schema 1, signed or not
+pub const SCHEMA1_SIGNED_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.v1+prettyjws";
+pub const SCHEMA1_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v1+json";
+
+/// Fields of a v1 image config that only describe the layer they came with
+const LAYER_FIELDS: [&str; 6] = ["id", "parent", "parent_id", "layer_id", "Size", "throwaway"];
+
+#[derive(Deserialize)]
+struct SchemaVersion {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+}
+
+/// Whether a manifest is a schema 1 manifest, by its content type or, for
+/// registries that serve them as plain JSON, by its schema version
+pub fn is_schema1(content_type: Option<&str>, data: &[u8]) -> bool {
+    content_type.is_some_and(|content_type| {
+        content_type == SCHEMA1_SIGNED_MEDIA_TYPE || content_type == SCHEMA1_MEDIA_TYPE
+    }) || serde_json::from_slice::<SchemaVersion>(data)
+        .is_ok_and(|version| version.schema_version == 1)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FsLayer {
+    #[serde(rename = "blobSum")]
+    pub blob_sum: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct V1History {
+    /// The v1 config of the layer, as a JSON string
+    #[serde(rename = "v1Compatibility")]
+    pub v1_compatibility: String,
+}
+
+/// A Docker image manifest v2, schema 1, as registries that predate schema 2
+/// still serve them. Layers and their history are listed from the top layer
+/// down to the base layer
+#[derive(Deserialize, Debug)]
+pub struct Schema1Manifest {
+    #[serde(default)]
+    pub architecture: String,
+    #[serde(rename = "fsLayers")]
+    pub fs_layers: Vec<FsLayer>,
+    pub history: Vec<V1History>,
+}
+
+#[derive(Deserialize, Default)]
+struct ContainerConfig {
+    #[serde(rename = "Cmd")]
+    cmd: Option<Vec<String>>,
+}
+
+/// The parts of a v1 config that make up the history of an image
+#[derive(Deserialize, Default)]
+struct V1Compatibility {
+    created: Option<String>,
+    author: Option<String>,
+    comment: Option<String>,
+    container_config: Option<ContainerConfig>,
+    /// Set on layers that do not change the filesystem, such as those of ENV
+    #[serde(default)]
+    throwaway: bool,
+}
+
+impl Schema1Manifest {
+    fn v1_compatibility(&self) -> Result<Vec<V1Compatibility>, String> {
+        if self.fs_layers.len() != self.history.len() {
+            return Err(format!(
+                "Schema 1 manifest has {} layers but {} history entries",
+                self.fs_layers.len(),
+                self.history.len()
+            ));
+        }
+
+        self.history
+            .iter()
+            .map(|history| {
+                serde_json::from_str(&history.v1_compatibility)
+                    .map_err(|e| format!("Invalid v1Compatibility: {}", e))
+            })
+            .collect()
+    }
+
+    /// The digests of the layers that change the filesystem, from the base
+    /// layer up, as a schema 2 manifest lists them
+    pub fn layers(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .fs_layers
+            .iter()
+            .zip(self.v1_compatibility()?)
+            .rev()
+            .filter(|(_, v1)| !v1.throwaway)
+            .map(|(layer, _)| layer.blob_sum.clone())
+            .collect())
+    }
+
+    /// The image config of the image, made of the v1 config of its top layer
+    /// with the history of every layer and the diff IDs of the layers
+    pub fn config(&self, diff_ids: &[String]) -> Result<Vec<u8>, String> {
+        let history: Vec<Value> = self
+            .v1_compatibility()?
+            .into_iter()
+            .rev()
+            .map(|v1| {
+                let mut entry = Map::new();
+                let created_by = v1
+                    .container_config
+                    .and_then(|config| config.cmd)
+                    .map(|cmd| cmd.join(" "));
+
+                for (name, value) in [
+                    ("created", v1.created),
+                    ("author", v1.author),
+                    ("created_by", created_by),
+                    ("comment", v1.comment),
+                ] {
+                    if let Some(value) = value {
+                        entry.insert(name.to_string(), value.into());
+                    }
+                }
+
+                if v1.throwaway {
+                    entry.insert("empty_layer".to_string(), true.into());
+                }
+
+                Value::Object(entry)
+            })
+            .collect();
+
+        let top = self
+            .history
+            .first()
+            .ok_or("Schema 1 manifest has no layers")?;
+        let mut config: Map<String, Value> = serde_json::from_str(&top.v1_compatibility)
+            .map_err(|e| format!("Invalid v1Compatibility: {}", e))?;
+
+        for field in LAYER_FIELDS {
+            config.remove(field);
+        }
+
+        if !config.contains_key("architecture") {
+            config.insert("architecture".to_string(), self.architecture.clone().into());
+        }
+
+        if !config.contains_key("os") {
+            config.insert("os".to_string(), "linux".into());
+        }
+
+        config.insert(
+            "rootfs".to_string(),
+            json!({"type": "layers", "diff_ids": diff_ids}),
+        );
+        config.insert("history".to_string(), history.into());
+
+        serde_json::to_vec(&config).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Signature {
+    protected: String,
+}
+
+#[derive(Deserialize)]
+struct Signed {
+    signatures: Vec<Signature>,
+}
+
+/// Where the signatures were cut into the payload
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Protected {
+    format_length: usize,
+    format_tail: String,
+}
+
+/// The payload of a signed schema 1 manifest, without its signatures, which
+/// is what its digest is taken of
+pub fn signed_payload(data: &[u8]) -> Option<Vec<u8>> {
+    let signed: Signed = serde_json::from_slice(data).ok()?;
+    let decode = |value: &str| {
+        BASE64_URL_SAFE_NO_PAD
+            .decode(value.trim_end_matches('='))
+            .ok()
+    };
+
+    let protected = decode(&signed.signatures.first()?.protected)?;
+    let protected: Protected = serde_json::from_slice(&protected).ok()?;
+
+    let mut payload = data.get(..protected.format_length)?.to_vec();
+    payload.extend(decode(&protected.format_tail)?);
+
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::config::ImageConfig;
+
+    const MANIFEST: &str = r##"{
+   "schemaVersion": 1,
+   "name": "library/app",
+   "tag": "1.0",
+   "architecture": "amd64",
+   "fsLayers": [
+      {"blobSum": "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"},
+      {"blobSum": "sha256:bb"}
+   ],
+   "history": [
+      {"v1Compatibility": "{\"id\":\"top\",\"parent\":\"base\",\"created\":\"2017-01-02T00:00:00Z\",\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"#(nop) \",\"CMD [\\\"sh\\\"]\"]},\"config\":{\"Cmd\":[\"sh\"],\"Env\":[\"PATH=/bin\"]},\"throwaway\":true,\"os\":\"linux\"}"},
+      {"v1Compatibility": "{\"id\":\"base\",\"created\":\"2017-01-01T00:00:00Z\",\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"#(nop) ADD file:abc in / \"]}}"}
+   ]
+}"##;
+
+    #[test]
+    fn test_convert() {
+        assert!(is_schema1(None, MANIFEST.as_bytes()));
+        assert!(!is_schema1(None, br#"{"schemaVersion": 2}"#));
+
+        let manifest: Schema1Manifest = serde_json::from_str(MANIFEST).unwrap();
+        assert_eq!(manifest.layers().unwrap(), vec!["sha256:bb"]);
+
+        let config = manifest.config(&["sha256:cc".to_string()]).unwrap();
+        let config: ImageConfig = serde_json::from_slice(&config).unwrap();
+        assert_eq!(config.rootfs.diff_ids, vec!["sha256:cc"]);
+        assert_eq!(config.config.unwrap().cmd, Some(vec!["sh".to_string()]));
+
+        let history = config.history.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[0].created_by.as_deref(),
+            Some("/bin/sh -c #(nop) ADD file:abc in / ")
+        );
+        assert_eq!(history[0].empty_layer, None);
+        assert_eq!(history[1].empty_layer, Some(true));
+    }
+
+    #[test]
+    fn test_signed_payload() {
+        // The signatures are cut in before the closing brace
+        let split = MANIFEST.len() - 2;
+        let protected = BASE64_URL_SAFE_NO_PAD.encode(format!(
+            r#"{{"formatLength":{},"formatTail":"{}","time":"2017-01-02T00:00:00Z"}}"#,
+            split,
+            BASE64_URL_SAFE_NO_PAD.encode(&MANIFEST[split..])
+        ));
+        let signed = format!(
+            "{},\n   \"signatures\": [{{\"header\": {{\"alg\": \"ES256\"}}, \"signature\": \"c2ln\", \"protected\": \"{}\"}}]\n}}",
+            &MANIFEST[..split],
+            protected
+        );
+
+        assert_eq!(
+            signed_payload(signed.as_bytes()).unwrap(),
+            MANIFEST.as_bytes()
+        );
+        assert!(serde_json::from_str::<Schema1Manifest>(&signed).is_ok());
+        assert_eq!(signed_payload(MANIFEST.as_bytes()), None);
+    }
+}
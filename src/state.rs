@@ -0,0 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    cache::{self, FileLock},
+    parser::FullImageWithTag,
+    State as StateArgs, StateCmd,
+};
+
+/// An image reference, and the digest it was last seen at
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ImageRecord {
+    pub digest: String,
+    #[serde(rename = "seenAt")]
+    pub seen_at: u64,
+}
+
+/// A plan lockfile written by `plan update`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LockfileRecord {
+    pub plan: String,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: u64,
+}
+
+/// An image that was fully pulled into containerd
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PullRecord {
+    pub digest: String,
+    #[serde(rename = "pulledAt")]
+    pub pulled_at: u64,
+}
+
+/// A named directory an image was extracted into to be run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorkspaceRecord {
+    pub path: String,
+    pub image: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+}
+
+/// Host-wide ocitool state, kept in the cache directory and shared by every
+/// invocation on the machine
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    /// Known images, keyed by image reference
+    #[serde(default)]
+    pub images: BTreeMap<String, ImageRecord>,
+
+    /// Known lockfiles, keyed by path
+    #[serde(default)]
+    pub lockfiles: BTreeMap<String, LockfileRecord>,
+
+    /// Pulled images, keyed by containerd socket and then by image reference
+    #[serde(default)]
+    pub pulls: BTreeMap<String, BTreeMap<String, PullRecord>>,
+
+    /// Run workspaces, keyed by name
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, WorkspaceRecord>,
+}
+
+/// Identifies an image independently of how a plan or compose file spelled it
+pub fn image_key(image: &FullImageWithTag) -> String {
+    format!(
+        "{}/{}:{}",
+        image.image.registry.trim_start_matches("https://"),
+        image.image.library_name,
+        image.tag
+    )
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl State {
+    pub fn path() -> PathBuf {
+        cache::cache_dir().join("state.json")
+    }
+
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the state, replacing the file atomically
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir)?;
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Applies a change to the latest state on disk and writes it back right
+    /// away, holding a lock so that concurrent invocations do not lose each
+    /// other's changes
+    pub fn update<F>(path: &Path, change: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut State),
+    {
+        let _lock = FileLock::acquire(&path.with_extension("lock"))?;
+        let mut state = State::load(path);
+        change(&mut state);
+        state.save(path)
+    }
+
+    pub fn record_image(&mut self, image: &str, digest: &str, now: u64) {
+        self.images.insert(
+            image.to_string(),
+            ImageRecord {
+                digest: digest.to_string(),
+                seen_at: now,
+            },
+        );
+    }
+
+    pub fn record_lockfile(&mut self, lockfile: &Path, plan: &Path, now: u64) {
+        self.lockfiles.insert(
+            lockfile.display().to_string(),
+            LockfileRecord {
+                plan: plan.display().to_string(),
+                updated_at: now,
+            },
+        );
+    }
+
+    pub fn record_pull(&mut self, socket: &str, image: &str, digest: &str, now: u64) {
+        self.pulls.entry(socket.to_string()).or_default().insert(
+            image.to_string(),
+            PullRecord {
+                digest: digest.to_string(),
+                pulled_at: now,
+            },
+        );
+    }
+
+    pub fn record_workspace(&mut self, name: &str, path: &Path, image: &str, now: u64) {
+        self.workspaces.insert(
+            name.to_string(),
+            WorkspaceRecord {
+                path: path.display().to_string(),
+                image: image.to_string(),
+                created_at: now,
+            },
+        );
+    }
+
+    /// Returns the digest an image was pulled at, if that was recent enough
+    pub fn fresh_pull(&self, socket: &str, image: &str, max_age: u64, now: u64) -> Option<&str> {
+        self.pulls
+            .get(socket)?
+            .get(image)
+            .filter(|record| now.saturating_sub(record.pulled_at) <= max_age)
+            .map(|record| record.digest.as_str())
+    }
+
+    /// Forgets lockfiles and workspaces that no longer exist on disk, and
+    /// images and pulls not seen within the given age. Returns how many
+    /// entries were removed
+    pub fn gc(&mut self, max_age: u64, now: u64) -> usize {
+        let before = self.len();
+        let is_recent = |time: u64| now.saturating_sub(time) <= max_age;
+
+        self.lockfiles.retain(|path, _| Path::new(path).is_file());
+        self.workspaces
+            .retain(|_, workspace| Path::new(&workspace.path).is_dir());
+        self.images.retain(|_, image| is_recent(image.seen_at));
+
+        for images in self.pulls.values_mut() {
+            images.retain(|_, pull| is_recent(pull.pulled_at));
+        }
+
+        self.pulls.retain(|_, images| !images.is_empty());
+
+        before - self.len()
+    }
+
+    fn len(&self) -> usize {
+        self.images.len()
+            + self.lockfiles.len()
+            + self.workspaces.len()
+            + self
+                .pulls
+                .values()
+                .map(|images| images.len())
+                .sum::<usize>()
+    }
+}
+
+fn print_state(state: &State) {
+    println!("Images:");
+    for (image, record) in &state.images {
+        println!("  {} {}", image, record.digest);
+    }
+
+    println!("Lockfiles:");
+    for (lockfile, record) in &state.lockfiles {
+        println!("  {} (plan {})", lockfile, record.plan);
+    }
+
+    println!("Pulls:");
+    for (socket, images) in &state.pulls {
+        for (image, record) in images {
+            println!("  {} {} ({})", image, record.digest, socket);
+        }
+    }
+
+    println!("Workspaces:");
+    for (name, workspace) in &state.workspaces {
+        println!("  {} {} ({})", name, workspace.path, workspace.image);
+    }
+}
+
+/// Forgets the stale entries of the state file, or only counts them on a
+/// dry run
+fn gc_state(path: &Path, max_age: u64, now: u64, dry_run: bool) -> io::Result<usize> {
+    if dry_run {
+        return Ok(State::load(path).gc(max_age, now));
+    }
+
+    let mut removed = 0;
+    State::update(path, |state| removed = state.gc(max_age, now))?;
+    Ok(removed)
+}
+
+pub fn state_command(args: &StateArgs, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let path = State::path();
+
+    match &args.subcommand {
+        StateCmd::Ls(_) => print_state(&State::load(&path)),
+        StateCmd::Gc(gc) => {
+            let max_age = gc.max_age_days.unwrap_or(30) * 24 * 60 * 60;
+            let removed = gc_state(&path, max_age, now(), dry_run)?;
+
+            match dry_run {
+                true => println!(
+                    "Would remove {} stale entries from {}",
+                    removed,
+                    path.display()
+                ),
+                false => println!("Removed {} stale entries from {}", removed, path.display()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fresh_pull() {
+        let mut state = State::default();
+        state.record_pull(
+            "/run/containerd.sock",
+            "library/nginx:alpine",
+            "sha256:a",
+            1000,
+        );
+
+        assert_eq!(
+            state.fresh_pull("/run/containerd.sock", "library/nginx:alpine", 60, 1030),
+            Some("sha256:a")
+        );
+        assert_eq!(
+            state.fresh_pull("/run/containerd.sock", "library/nginx:alpine", 60, 1100),
+            None
+        );
+        assert_eq!(
+            state.fresh_pull("/other.sock", "library/nginx:alpine", 60, 1030),
+            None
+        );
+    }
+
+    #[test]
+    fn test_update_keeps_other_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        State::update(&path, |state| {
+            state.record_image("nginx:alpine", "sha256:a", 1000)
+        })
+        .unwrap();
+        State::update(&path, |state| {
+            state.record_pull("sock", "nginx:alpine", "sha256:a", 1000)
+        })
+        .unwrap();
+
+        let state = State::load(&path);
+        assert_eq!(state.images["nginx:alpine"].digest, "sha256:a");
+        assert_eq!(
+            state.fresh_pull("sock", "nginx:alpine", 0, 1000),
+            Some("sha256:a")
+        );
+    }
+
+    #[test]
+    fn test_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let lockfile = dir.path().join("oci.lock.json");
+        fs::write(&lockfile, "{}").unwrap();
+
+        let mut state = State::default();
+        state.record_image("old:latest", "sha256:a", 0);
+        state.record_image("new:latest", "sha256:b", 1000);
+        state.record_pull("sock", "old:latest", "sha256:a", 0);
+        state.record_lockfile(&lockfile, &dir.path().join("oci.json"), 0);
+        state.record_lockfile(
+            &dir.path().join("gone.lock.json"),
+            Path::new("gone.json"),
+            0,
+        );
+        state.workspaces.insert(
+            "gone".to_string(),
+            WorkspaceRecord {
+                path: dir.path().join("gone").display().to_string(),
+                image: "new:latest".to_string(),
+                created_at: 1000,
+            },
+        );
+
+        assert_eq!(state.gc(100, 1000), 4);
+        assert!(state.images.contains_key("new:latest"));
+        assert!(state.pulls.is_empty());
+        assert_eq!(state.lockfiles.len(), 1);
+        assert!(state.workspaces.is_empty());
+    }
+
+    #[test]
+    fn test_gc_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        State::update(&path, |state| {
+            state.record_image("old:latest", "sha256:a", 0)
+        })
+        .unwrap();
+        let before = fs::read(&path).unwrap();
+
+        assert_eq!(gc_state(&path, 100, 1000, true).unwrap(), 1);
+        assert_eq!(fs::read(&path).unwrap(), before);
+
+        assert_eq!(gc_state(&path, 100, 1000, false).unwrap(), 1);
+        assert!(State::load(&path).images.is_empty());
+    }
+}
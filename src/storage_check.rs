@@ -0,0 +1,404 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::cleanup::{CleanupFilter, DigestStore, DockerRepository, Repository, StorageLayout};
+
+/// What is wrong with a path of the storage
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// A link file that cannot be read or holds no sha256 digest
+    InvalidLink,
+    /// A link file pointing at a blob that is not stored
+    MissingBlob,
+    /// A manifest, an index or an index.json that is not valid JSON
+    InvalidJson,
+    /// A manifest or an index referring to a blob that is not stored
+    MissingReference,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub repository: String,
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    /// The digest that is missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// Everything `cleanup --check` found
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckReport {
+    pub repositories: usize,
+    pub manifests: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// The hex part of a sha256 digest, or None for other algorithms and
+/// anything that is no digest
+fn sha256_hex(digest: &str) -> Option<&str> {
+    let hex = digest.trim().strip_prefix("sha256:")?;
+
+    match hex.len() == 64 && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(hex),
+        false => None,
+    }
+}
+
+/// The digests of the blobs a manifest or an index refers to. Foreign layers
+/// that are downloaded from their URLs are not stored in the registry
+fn references(json: &Value) -> Vec<&str> {
+    json.get("manifests")
+        .into_iter()
+        .chain(json.get("layers"))
+        .filter_map(Value::as_array)
+        .flatten()
+        .chain(json.get("config"))
+        .filter(|descriptor| descriptor.get("urls").is_none())
+        .filter_map(|descriptor| descriptor.get("digest")?.as_str())
+        .collect()
+}
+
+struct Checker<'a> {
+    repository: &'a Repository,
+    store: DigestStore,
+    checked: HashSet<String>,
+    report: &'a mut CheckReport,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, kind: ProblemKind, path: &Path, digest: Option<&str>) {
+        self.report.problems.push(Problem {
+            repository: self.repository.path_name(),
+            kind,
+            path: path.to_path_buf(),
+            digest: digest.map(str::to_string),
+        });
+    }
+
+    fn has_blob(&self, hex: &str) -> bool {
+        self.store.data_path(hex).is_file()
+    }
+
+    fn read_json(&mut self, path: &Path) -> Option<Value> {
+        let data = fs::read(path).ok()?;
+
+        match serde_json::from_slice(&data) {
+            Ok(json) => Some(json),
+            Err(_) => {
+                self.problem(ProblemKind::InvalidJson, path, None);
+                None
+            }
+        }
+    }
+
+    /// Checks the blobs a stored manifest or index refers to, and the
+    /// manifests of an index in turn
+    fn check_manifest(&mut self, hex: &str) {
+        if !self.checked.insert(hex.to_string()) {
+            return;
+        }
+
+        self.report.manifests += 1;
+
+        let path = self.store.data_path(hex);
+        let Some(json) = self.read_json(&path) else {
+            return;
+        };
+
+        let is_index = json.get("manifests").is_some();
+
+        for digest in references(&json) {
+            let Some(child) = sha256_hex(digest) else {
+                continue;
+            };
+
+            if !self.has_blob(child) {
+                self.problem(ProblemKind::MissingReference, &path, Some(digest));
+            } else if is_index {
+                self.check_manifest(child);
+            }
+        }
+    }
+
+    /// Checks that a link file names a stored blob, returning its digest
+    fn check_link(&mut self, link: &Path) -> Option<String> {
+        let content = fs::read_to_string(link).unwrap_or_default();
+
+        let Some(hex) = sha256_hex(&content) else {
+            self.problem(ProblemKind::InvalidLink, link, None);
+            return None;
+        };
+
+        if !self.has_blob(hex) {
+            self.problem(ProblemKind::MissingBlob, link, Some(content.trim()));
+            return None;
+        }
+
+        Some(hex.to_string())
+    }
+
+    fn link_dirs(dir: &Path) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect();
+        dirs.sort();
+        dirs
+    }
+
+    fn check_distribution(&mut self) {
+        let repo = self.repository;
+
+        for layer in Self::link_dirs(&repo.layer_dir) {
+            self.check_link(&layer.join("link"));
+        }
+
+        for revision in Self::link_dirs(&repo.revision_dir) {
+            if let Some(hex) = self.check_link(&revision.join("link")) {
+                self.check_manifest(&hex);
+            }
+        }
+
+        for tag in Self::link_dirs(&repo.tag_dir) {
+            let link = tag.join("current/link");
+
+            if let Some(hex) = self.check_link(&link) {
+                if !repo.revision_dir.join(&hex).join("link").is_file() {
+                    self.problem(
+                        ProblemKind::MissingReference,
+                        &link,
+                        Some(&format!("sha256:{}", hex)),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_layout(&mut self) {
+        let index_path = self.repository.dir.join("index.json");
+        let Some(index) = self.read_json(&index_path) else {
+            return;
+        };
+
+        for digest in references(&index) {
+            let Some(hex) = sha256_hex(digest) else {
+                continue;
+            };
+
+            match self.has_blob(hex) {
+                true => self.check_manifest(hex),
+                false => self.problem(ProblemKind::MissingReference, &index_path, Some(digest)),
+            }
+        }
+    }
+}
+
+/// Checks the repositories of a storage directory without changing it: link
+/// files have to point at stored blobs, and manifests and indexes have to be
+/// valid JSON referring to stored blobs only
+pub fn check_storage(
+    repository: &DockerRepository,
+    layout: StorageLayout,
+    filter: &CleanupFilter,
+) -> CheckReport {
+    let mut report = CheckReport::default();
+
+    for repo in repository
+        .repositories
+        .iter()
+        .filter(|repo| filter.selects(repo))
+    {
+        report.repositories += 1;
+
+        let store = match layout {
+            StorageLayout::Zot => DigestStore::Flat(repo.layer_dir.clone()),
+            _ => repository.store(),
+        };
+        let mut checker = Checker {
+            repository: repo,
+            store,
+            checked: HashSet::new(),
+            report: &mut report,
+        };
+
+        match layout {
+            StorageLayout::Zot => checker.check_layout(),
+            _ => checker.check_distribution(),
+        }
+    }
+
+    report
+}
+
+pub fn print_check_report(report: &CheckReport) {
+    for problem in &report.problems {
+        let kind = match problem.kind {
+            ProblemKind::InvalidLink => "invalid link",
+            ProblemKind::MissingBlob => "missing blob",
+            ProblemKind::InvalidJson => "invalid JSON",
+            ProblemKind::MissingReference => "missing reference",
+        };
+
+        match &problem.digest {
+            Some(digest) => println!(
+                "{}: {} {} at {}",
+                problem.repository,
+                kind,
+                digest,
+                problem.path.display()
+            ),
+            None => println!(
+                "{}: {} at {}",
+                problem.repository,
+                kind,
+                problem.path.display()
+            ),
+        }
+    }
+
+    println!(
+        "Checked {} repositories and {} manifests, found {} problems",
+        report.repositories,
+        report.manifests,
+        report.problems.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cleanup::get_repository;
+    use tempfile::tempdir;
+
+    fn digest(c: char) -> String {
+        c.to_string().repeat(64)
+    }
+
+    fn write_blob(blobs: &Path, hex: &str, data: &str) {
+        let dir = blobs.join(&hex[..2]).join(hex);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data"), data).unwrap();
+    }
+
+    fn write_link(dir: &Path, hex: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("link"), format!("sha256:{}", hex)).unwrap();
+    }
+
+    #[test]
+    fn test_check_distribution() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("docker/registry/v2");
+        let blobs = root.join("blobs/sha256");
+        let repo = root.join("repositories/team/app");
+
+        let (config, layer, missing, manifest, broken) = (
+            digest('a'),
+            digest('b'),
+            digest('c'),
+            digest('d'),
+            digest('e'),
+        );
+
+        write_blob(&blobs, &config, "{}");
+        write_blob(&blobs, &layer, "layer");
+        write_blob(
+            &blobs,
+            &manifest,
+            &format!(
+                r#"{{"config":{{"digest":"sha256:{}"}},"layers":[{{"digest":"sha256:{}"}},{{"digest":"sha256:{}"}}]}}"#,
+                config, layer, missing
+            ),
+        );
+        write_blob(&blobs, &broken, "not json");
+
+        write_link(&repo.join("_layers/sha256").join(&layer), &layer);
+        write_link(&repo.join("_layers/sha256").join(&missing), &missing);
+        write_link(
+            &repo.join("_manifests/revisions/sha256").join(&manifest),
+            &manifest,
+        );
+        write_link(
+            &repo.join("_manifests/revisions/sha256").join(&broken),
+            &broken,
+        );
+        write_link(&repo.join("_manifests/tags/latest/current"), &manifest);
+        fs::create_dir_all(repo.join("_manifests/tags/bad/current")).unwrap();
+        fs::write(repo.join("_manifests/tags/bad/current/link"), "garbage").unwrap();
+
+        let repository =
+            get_repository(dir.path().to_path_buf(), StorageLayout::Distribution).unwrap();
+        let report = check_storage(
+            &repository,
+            StorageLayout::Distribution,
+            &CleanupFilter::default(),
+        );
+
+        assert_eq!(report.repositories, 1);
+        assert_eq!(report.manifests, 2);
+
+        let found: Vec<(ProblemKind, Option<&str>)> = report
+            .problems
+            .iter()
+            .map(|problem| (problem.kind, problem.digest.as_deref()))
+            .collect();
+        let missing = format!("sha256:{}", missing);
+
+        assert_eq!(
+            found,
+            vec![
+                (ProblemKind::MissingBlob, Some(missing.as_str())),
+                (ProblemKind::MissingReference, Some(missing.as_str())),
+                (ProblemKind::InvalidJson, None),
+                (ProblemKind::InvalidLink, None),
+            ]
+        );
+        assert!(report.problems[3].path.ends_with("tags/bad/current/link"));
+    }
+
+    #[test]
+    fn test_check_layout() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path().join("app");
+        let blobs = repo.join("blobs/sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        fs::write(repo.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#).unwrap();
+
+        let (manifest, gone) = (digest('1'), digest('2'));
+        fs::write(
+            blobs.join(&manifest),
+            format!(r#"{{"config":{{"digest":"sha256:{}"}},"layers":[]}}"#, gone),
+        )
+        .unwrap();
+        fs::write(
+            repo.join("index.json"),
+            format!(
+                r#"{{"manifests":[{{"digest":"sha256:{}"}},{{"digest":"sha256:{}"}}]}}"#,
+                manifest, gone
+            ),
+        )
+        .unwrap();
+
+        let repository = get_repository(dir.path().to_path_buf(), StorageLayout::Zot).unwrap();
+        let report = check_storage(&repository, StorageLayout::Zot, &CleanupFilter::default());
+
+        assert_eq!(report.manifests, 1);
+        assert_eq!(report.problems.len(), 2);
+        assert!(report
+            .problems
+            .iter()
+            .all(|problem| problem.kind == ProblemKind::MissingReference));
+    }
+}
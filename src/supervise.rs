@@ -0,0 +1,131 @@
+use std::{
+    future::Future, io, os::unix::process::ExitStatusExt, process::ExitStatus, str::FromStr,
+    time::Duration,
+};
+
+use nix::sys::signal::Signal;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::{sleep_until, Instant},
+};
+
+/// How long a container gets to exit after the stop signal, unless
+/// `--stop-timeout` is given
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Parses a signal as the StopSignal of images gives it: SIGTERM, TERM or 15
+pub fn parse_signal(name: &str) -> Result<Signal, String> {
+    let signal = match name.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) => {
+            let name = name.to_uppercase();
+
+            match name.starts_with("SIG") {
+                true => Signal::from_str(&name).ok(),
+                false => Signal::from_str(&format!("SIG{}", name)).ok(),
+            }
+        }
+    };
+
+    signal.ok_or_else(|| format!("Unknown signal: {}", name))
+}
+
+/// The exit code a shell gives a status: the code the process exited with,
+/// or 128 plus the signal that killed it
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// How a container is stopped when ocitool is asked to stop
+pub struct StopPolicy {
+    /// What SIGTERM is passed on as, the StopSignal of the image
+    pub signal: Signal,
+    /// How long to wait after a signal was passed on before killing it
+    pub timeout: Duration,
+}
+
+impl StopPolicy {
+    pub fn new(stop_signal: Option<&str>, timeout: Option<Duration>) -> Result<StopPolicy, String> {
+        Ok(StopPolicy {
+            signal: stop_signal
+                .map(parse_signal)
+                .transpose()?
+                .unwrap_or(Signal::SIGTERM),
+            timeout: timeout.unwrap_or(DEFAULT_STOP_TIMEOUT),
+        })
+    }
+}
+
+/// Waits for a container to exit while passing on the signals ocitool gets.
+/// SIGTERM is sent as the stop signal, SIGINT as itself unless the container
+/// shares the terminal and got it already. Once either arrived, the
+/// container is sent SIGKILL if it has not exited within the stop timeout.
+/// SIGWINCH calls `on_resize`
+pub async fn supervise<T>(
+    exited: impl Future<Output = T>,
+    forward_interrupt: bool,
+    policy: &StopPolicy,
+    mut send: impl FnMut(Signal),
+    mut on_resize: impl FnMut(),
+) -> io::Result<T> {
+    let mut interrupts = signal(SignalKind::interrupt())?;
+    let mut terminations = signal(SignalKind::terminate())?;
+    let mut window_changes = signal(SignalKind::window_change())?;
+    let mut deadline: Option<Instant> = None;
+    tokio::pin!(exited);
+
+    loop {
+        let kill_at = deadline;
+        let timed_out = async move {
+            match kill_at {
+                Some(kill_at) => sleep_until(kill_at).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = &mut exited => return Ok(result),
+            _ = interrupts.recv() => {
+                if forward_interrupt {
+                    send(Signal::SIGINT);
+                }
+
+                deadline.get_or_insert_with(|| Instant::now() + policy.timeout);
+            }
+            _ = terminations.recv() => {
+                send(policy.signal);
+                deadline.get_or_insert_with(|| Instant::now() + policy.timeout);
+            }
+            _ = window_changes.recv() => on_resize(),
+            _ = timed_out => {
+                eprintln!("The container did not stop in time, killing it");
+                send(Signal::SIGKILL);
+                deadline = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGQUIT"), Ok(Signal::SIGQUIT));
+        assert_eq!(parse_signal("winch"), Ok(Signal::SIGWINCH));
+        assert_eq!(parse_signal("9"), Ok(Signal::SIGKILL));
+        assert!(parse_signal("SIGNOPE").is_err());
+        assert!(parse_signal("99").is_err());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(libc::SIGKILL)), 137);
+    }
+}
@@ -1,10 +1,14 @@
 use std::collections::HashMap;
 
 use crate::client::LoginCredentials;
+use crate::registry_config::RegistrySettings;
 use std::fs;
 
 /// Parses the kernel command line and extracts login credentials.
-pub fn parse_kernel_cmdline(cmdline: &str) -> HashMap<String, LoginCredentials> {
+pub fn parse_kernel_cmdline(
+    cmdline: &str,
+    settings: &RegistrySettings,
+) -> HashMap<String, LoginCredentials> {
     let mut credentials = HashMap::new();
 
     // Find the dockerlogin=... part
@@ -18,11 +22,7 @@ pub fn parse_kernel_cmdline(cmdline: &str) -> HashMap<String, LoginCredentials>
 
                 let fields: Vec<&str> = entry.split(',').collect();
                 if fields.len() == 3 {
-                    let mut hostname = fields[0].trim().trim_matches('"').to_string();
-
-                    if !hostname.starts_with("https://") && !hostname.starts_with("http://") {
-                        hostname = format!("https://{}", hostname);
-                    }
+                    let hostname = settings.registry_url(fields[0].trim().trim_matches('"'));
 
                     let username = fields[1].trim().to_string();
                     let password = fields[2].trim().to_string();
@@ -41,9 +41,9 @@ pub fn parse_kernel_cmdline(cmdline: &str) -> HashMap<String, LoginCredentials>
     credentials
 }
 
-pub fn get_system_login() -> HashMap<String, LoginCredentials> {
+pub fn get_system_login(settings: &RegistrySettings) -> HashMap<String, LoginCredentials> {
     let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
-    parse_kernel_cmdline(&cmdline)
+    parse_kernel_cmdline(&cmdline, settings)
 }
 
 #[cfg(test)]
@@ -53,7 +53,7 @@ mod tests {
     #[test]
     fn test_parse_kernel_cmdline() {
         let cmdline = "quiet splash dockerlogin=registry.tohka.us,pirates,pass;registry2.example.com,user2,pass2;";
-        let creds = parse_kernel_cmdline(cmdline);
+        let creds = parse_kernel_cmdline(cmdline, &RegistrySettings::default());
         assert_eq!(creds.len(), 2);
         assert_eq!(creds["https://registry.tohka.us"].username, "pirates");
         assert_eq!(creds["https://registry2.example.com"].password, "pass2");
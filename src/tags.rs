@@ -0,0 +1,46 @@
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient},
+    downloader::OciDownloader,
+    parser::FullImage,
+    report::Context,
+    Tags,
+};
+
+pub async fn tags_command(
+    args: &Tags,
+    no_cache: bool,
+    client: Arc<OciClient>,
+) -> Result<(), Box<dyn Error>> {
+    let json = match args.format.as_deref() {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => return Err(format!("Unknown format: {}", format).into()),
+    };
+
+    let image = FullImage::parse(&args.image, client.settings())?;
+
+    client
+        .login(&[ImagePermission {
+            full_image: image.clone(),
+            permissions: ImagePermissions::Pull,
+        }])
+        .await?;
+
+    let downloader = OciDownloader::new(client, no_cache);
+    let tags = downloader
+        .list_tags(image)
+        .await
+        .with_context(|| format!("listing the tags of {}", args.image))?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&tags)?);
+    } else {
+        for tag in tags {
+            println!("{}", tag);
+        }
+    }
+
+    Ok(())
+}
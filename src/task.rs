@@ -0,0 +1,421 @@
+use std::{
+    error::Error,
+    ffi::CString,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use prost_types::Any;
+use tokio::{net::unix::pipe, sync::watch};
+use tonic::Request;
+
+use crate::{
+    compose::{
+        containerd::client::{
+            services::v1::{
+                container::Runtime,
+                snapshots::{PrepareSnapshotRequest, RemoveSnapshotRequest, StatSnapshotRequest},
+                Container, CreateContainerRequest, CreateTaskRequest, DeleteContainerRequest,
+                DeleteTaskRequest, KillRequest, StartRequest, WaitRequest,
+            },
+            types::Mount,
+        },
+        lease::LeasedClient,
+        pull::unpack::{chain_ids, DEFAULT_SNAPSHOTTER},
+    },
+    downloader::is_index_content_type,
+    platform::PlatformMatcher,
+    registry_config::RegistrySettings,
+    report::Context,
+    save::{find_image, read_content},
+    spec::{config::ImageConfig, index::ImageIndex, manifest::ImageManifest},
+    supervise::{supervise, StopPolicy},
+    with_client, Run,
+};
+
+const RUNTIME: &str = "io.containerd.runc.v2";
+const SPEC_TYPE: &str = "types.containerd.io/opencontainers/runtime-spec/1/Spec";
+
+/// The directories a snapshot is made of, the upper ones first, as given by
+/// the mounts of an overlayfs or native snapshot
+fn layer_dirs(mounts: &[Mount]) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+
+    for mount in mounts {
+        match mount.r#type.as_str() {
+            "overlay" => {
+                let option = |name: &str| {
+                    mount
+                        .options
+                        .iter()
+                        .find_map(|option| option.strip_prefix(name))
+                };
+
+                dirs.extend(option("upperdir=").map(PathBuf::from));
+                dirs.extend(
+                    option("lowerdir=")
+                        .into_iter()
+                        .flat_map(|lower| lower.split(':'))
+                        .map(PathBuf::from),
+                );
+            }
+            "bind" => dirs.push(PathBuf::from(&mount.source)),
+            _ => {}
+        }
+    }
+
+    dirs
+}
+
+/// Reads the manifest and config of an image in containerd, picking the
+/// manifest of this platform from an index
+async fn read_image(
+    client: &Arc<LeasedClient>,
+    name: &str,
+    settings: &RegistrySettings,
+) -> Result<(String, ImageConfig), Box<dyn Error>> {
+    let containerd = client.client();
+    let (full_name, target) = find_image(&containerd, name, settings).await?;
+    let read = async |digest: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+        match read_content(&containerd, digest).await? {
+            Some(data) => Ok(data),
+            None => Err(format!("containerd is missing {}", digest).into()),
+        }
+    };
+
+    let mut manifest_digest = target.digest.clone();
+
+    if is_index_content_type(&target.media_type) {
+        let index: ImageIndex = serde_json::from_slice(&read(&target.digest).await?)?;
+        manifest_digest = PlatformMatcher::new()
+            .find_manifest(&index.manifests)
+            .ok_or_else(|| format!("{} has no manifest for this platform", full_name))?
+            .digest
+            .clone();
+    }
+
+    let manifest: ImageManifest = serde_json::from_slice(&read(&manifest_digest).await?)?;
+    let config = serde_json::from_slice(&read(&manifest.config.digest).await?)?;
+
+    Ok((full_name, config))
+}
+
+/// Creates a FIFO for the shim to write the output of the task into. It is
+/// opened for writing as well, so that it reads no end of file before the
+/// shim opens it
+fn open_fifo(path: &Path) -> io::Result<pipe::Receiver> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    pipe::OpenOptions::new()
+        .read_write(true)
+        .open_receiver(path)
+}
+
+/// Copies whatever is in the FIFO without waiting for more
+fn drain(fifo: &pipe::Receiver, out: &mut impl Write) -> io::Result<()> {
+    let mut buf = [0; 8192];
+
+    loop {
+        match fifo.try_read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => out.write_all(&buf[..n])?,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copies the output of the task until it exited, then what is left of it
+async fn forward(
+    fifo: pipe::Receiver,
+    mut out: impl Write,
+    mut exited: watch::Receiver<bool>,
+) -> io::Result<()> {
+    loop {
+        tokio::select! {
+            readable = fifo.readable() => {
+                readable?;
+                drain(&fifo, &mut out)?;
+            }
+            _ = exited.changed() => {
+                drain(&fifo, &mut out)?;
+                return out.flush();
+            }
+        }
+    }
+}
+
+/// Runs the task of the container to completion, returning its exit status
+async fn run(
+    client: &Arc<LeasedClient>,
+    id: &str,
+    mounts: Vec<Mount>,
+    io_dir: &Path,
+    policy: &StopPolicy,
+) -> Result<u32, Box<dyn Error>> {
+    let containerd = client.client();
+    let stdout_path = io_dir.join("stdout");
+    let stderr_path = io_dir.join("stderr");
+    let (exited, exit_watch) = watch::channel(false);
+    let stdout = tokio::spawn(forward(
+        open_fifo(&stdout_path)?,
+        io::stdout(),
+        exit_watch.clone(),
+    ));
+    let stderr = tokio::spawn(forward(open_fifo(&stderr_path)?, io::stderr(), exit_watch));
+
+    containerd
+        .tasks()
+        .create(with_client!(
+            CreateTaskRequest {
+                container_id: id.to_string(),
+                rootfs: mounts,
+                stdout: stdout_path.display().to_string(),
+                stderr: stderr_path.display().to_string(),
+                ..Default::default()
+            },
+            client
+        ))
+        .await
+        .with_context(|| "creating the task".to_string())?;
+
+    let result = async {
+        // Waiting before starting, so that the exit cannot be missed
+        let mut waiter = containerd.tasks();
+        let wait_request = with_client!(
+            WaitRequest {
+                container_id: id.to_string(),
+                exec_id: String::new(),
+            },
+            client
+        );
+        let wait = tokio::spawn(async move { waiter.wait(wait_request).await });
+
+        containerd
+            .tasks()
+            .start(with_client!(
+                StartRequest {
+                    container_id: id.to_string(),
+                    exec_id: String::new(),
+                },
+                client
+            ))
+            .await
+            .with_context(|| "starting the task".to_string())?;
+
+        let exited = supervise(
+            wait,
+            true,
+            policy,
+            |signal| {
+                let (client, id) = (client.clone(), id.to_string());
+
+                tokio::spawn(async move {
+                    let _ = client
+                        .client()
+                        .tasks()
+                        .kill(with_client!(
+                            KillRequest {
+                                container_id: id,
+                                exec_id: String::new(),
+                                signal: signal as u32,
+                                all: true,
+                            },
+                            client
+                        ))
+                        .await;
+                });
+            },
+            || {},
+        )
+        .await?;
+
+        Ok::<_, Box<dyn Error>>(exited??.into_inner().exit_status)
+    }
+    .await;
+
+    let _ = exited.send(true);
+    let _ = stdout.await;
+    let _ = stderr.await;
+    let _ = containerd
+        .tasks()
+        .delete(with_client!(
+            DeleteTaskRequest {
+                container_id: id.to_string(),
+            },
+            client
+        ))
+        .await;
+
+    result
+}
+
+/// Runs an image that was pulled into containerd as a container and task of
+/// its own, on a snapshot of the unpacked image, instead of extracting it.
+/// Returns the exit status of the task
+pub async fn run_task(
+    args: &Run,
+    env: Vec<(String, String)>,
+    settings: &RegistrySettings,
+) -> Result<i32, Box<dyn Error>> {
+    let socket = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| "/run/containerd/containerd.sock".into());
+    let snapshotter = args.snapshotter.as_deref().unwrap_or(DEFAULT_SNAPSHOTTER);
+    let client =
+        Arc::new(LeasedClient::with_path("default".to_string(), socket.to_str().unwrap()).await?);
+    let containerd = client.client();
+
+    let (full_name, config) = read_image(&client, &args.image, settings)
+        .await
+        .with_context(|| format!("reading {} from containerd", args.image))?;
+    let parent = chain_ids(&config.rootfs.diff_ids)
+        .pop()
+        .ok_or("The image has no layers")?;
+
+    containerd
+        .snapshots()
+        .stat(with_client!(
+            StatSnapshotRequest {
+                snapshotter: snapshotter.to_string(),
+                key: parent.clone(),
+            },
+            client
+        ))
+        .await
+        .map_err(|_| {
+            format!(
+                "{} is not unpacked into {}, pull it with compose pull first",
+                full_name, snapshotter
+            )
+        })?;
+
+    let id = format!("ocitool-run-{}", std::process::id());
+    let mounts = containerd
+        .snapshots()
+        .prepare(with_client!(
+            PrepareSnapshotRequest {
+                snapshotter: snapshotter.to_string(),
+                key: id.clone(),
+                parent,
+                ..Default::default()
+            },
+            client
+        ))
+        .await?
+        .into_inner()
+        .mounts;
+
+    let result = async {
+        let dirs = layer_dirs(&mounts);
+        let layers: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        let runner = crate::build_runner(args, Path::new("rootfs"), &config.config, env);
+        let io_dir = tempfile::tempdir()?;
+        let resolv_conf = io_dir.path().join("resolv.conf");
+        std::fs::write(&resolv_conf, runner.resolv_conf())?;
+        let spec = runner.task_spec(&layers, &resolv_conf)?;
+
+        containerd
+            .containers()
+            .create(with_client!(
+                CreateContainerRequest {
+                    container: Some(Container {
+                        id: id.clone(),
+                        image: full_name.clone(),
+                        runtime: Some(Runtime {
+                            name: RUNTIME.to_string(),
+                            options: None,
+                        }),
+                        spec: Some(Any {
+                            type_url: SPEC_TYPE.to_string(),
+                            value: serde_json::to_vec(&spec)?,
+                        }),
+                        snapshotter: snapshotter.to_string(),
+                        snapshot_key: id.clone(),
+                        ..Default::default()
+                    }),
+                },
+                client
+            ))
+            .await
+            .with_context(|| "creating the container".to_string())?;
+
+        let stop_signal = config
+            .config
+            .as_ref()
+            .and_then(|config| config.stop_signal.as_deref());
+        let policy = StopPolicy::new(stop_signal, args.stop_timeout.map(Duration::from_secs))?;
+        let status = run(&client, &id, mounts, io_dir.path(), &policy).await;
+
+        let _ = containerd
+            .containers()
+            .delete(with_client!(
+                DeleteContainerRequest { id: id.clone() },
+                client
+            ))
+            .await;
+
+        status
+    }
+    .await;
+
+    let _ = containerd
+        .snapshots()
+        .remove(with_client!(
+            RemoveSnapshotRequest {
+                snapshotter: snapshotter.to_string(),
+                key: id.clone(),
+            },
+            client
+        ))
+        .await;
+    client.delete_lease().await;
+
+    Ok(result? as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_dirs() {
+        let overlay = Mount {
+            r#type: "overlay".to_string(),
+            source: "overlay".to_string(),
+            target: String::new(),
+            options: vec![
+                "index=off".to_string(),
+                "workdir=/snapshots/3/work".to_string(),
+                "upperdir=/snapshots/3/fs".to_string(),
+                "lowerdir=/snapshots/2/fs:/snapshots/1/fs".to_string(),
+            ],
+        };
+
+        assert_eq!(
+            layer_dirs(&[overlay]),
+            vec![
+                PathBuf::from("/snapshots/3/fs"),
+                PathBuf::from("/snapshots/2/fs"),
+                PathBuf::from("/snapshots/1/fs"),
+            ]
+        );
+
+        let bind = Mount {
+            r#type: "bind".to_string(),
+            source: "/snapshots/1/fs".to_string(),
+            target: String::new(),
+            options: vec!["rbind".to_string(), "rw".to_string()],
+        };
+
+        assert_eq!(layer_dirs(&[bind]), vec![PathBuf::from("/snapshots/1/fs")]);
+    }
+}
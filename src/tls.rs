@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use reqwest::{Certificate, Identity};
+
+/// The TLS settings of the registry clients, beyond the built-in roots
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// Certificate authorities trusted on top of the built-in ones
+    pub roots: Vec<Certificate>,
+    /// Certificate authorities trusted for single registry hosts, by host
+    pub host_roots: HashMap<String, Vec<Certificate>>,
+    /// Client certificates presented to registry hosts, by host
+    pub identities: HashMap<String, Identity>,
+}
+
+/// Parses a client certificate option, like
+/// registry.example.com=client.pem,client.key
+pub fn parse_client_cert(value: &str) -> Result<(String, PathBuf, Option<PathBuf>), String> {
+    let (host, files) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected HOST=CERT[,KEY], got: {}", value))?;
+
+    if host.is_empty() || files.is_empty() {
+        return Err(format!("Expected HOST=CERT[,KEY], got: {}", value));
+    }
+
+    let (cert, key) = match files.split_once(',') {
+        Some((cert, key)) => (cert, Some(PathBuf::from(key))),
+        None => (files, None),
+    };
+
+    Ok((host.to_string(), PathBuf::from(cert), key))
+}
+
+fn read_pem(path: &Path) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates = Certificate::from_pem_bundle(&read_pem(path)?).map_err(|e| {
+        io::Error::other(format!("Invalid certificates in {}: {}", path.display(), e))
+    })?;
+
+    if certificates.is_empty() {
+        return Err(io::Error::other(format!(
+            "No certificates found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certificates)
+}
+
+/// Loads the PEM files of --registry-ca and --registry-cert, and the
+/// certificate authorities of single hosts
+pub fn load(
+    cas: &[PathBuf],
+    host_cas: &[(String, PathBuf)],
+    client_certs: &[String],
+) -> io::Result<TlsOptions> {
+    let mut options = TlsOptions::default();
+
+    for path in cas {
+        options.roots.extend(read_certificates(path)?);
+    }
+
+    for (host, path) in host_cas {
+        options
+            .host_roots
+            .entry(host.clone())
+            .or_default()
+            .extend(read_certificates(path)?);
+    }
+
+    for value in client_certs {
+        let (host, cert, key) = parse_client_cert(value).map_err(io::Error::other)?;
+
+        // The certificate chain and the private key are read as one PEM
+        let mut pem = read_pem(&cert)?;
+
+        if let Some(key) = &key {
+            pem.push(b'\n');
+            pem.extend(read_pem(key)?);
+        }
+
+        let identity = Identity::from_pem(&pem).map_err(|e| {
+            io::Error::other(format!("Invalid client certificate for {}: {}", host, e))
+        })?;
+
+        options.identities.insert(host, identity);
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let encoded = BASE64_STANDARD.encode(der);
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+
+        format!(
+            "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+            label,
+            lines.join("\n"),
+            label
+        )
+    }
+
+    #[test]
+    fn test_parse_client_cert() {
+        assert_eq!(
+            parse_client_cert("registry.example.com=client.pem,client.key"),
+            Ok((
+                "registry.example.com".to_string(),
+                PathBuf::from("client.pem"),
+                Some(PathBuf::from("client.key"))
+            ))
+        );
+        assert_eq!(
+            parse_client_cert("registry.example.com=bundle.pem"),
+            Ok((
+                "registry.example.com".to_string(),
+                PathBuf::from("bundle.pem"),
+                None
+            ))
+        );
+        assert!(parse_client_cert("client.pem").is_err());
+        assert!(parse_client_cert("=client.pem").is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let certified =
+            rcgen::generate_simple_self_signed(vec!["registry.example.com".into()]).unwrap();
+        let (cert, key) = (dir.path().join("client.pem"), dir.path().join("client.key"));
+        fs::write(&cert, pem("CERTIFICATE", certified.cert.der())).unwrap();
+        fs::write(
+            &key,
+            pem("PRIVATE KEY", &certified.signing_key.serialize_der()),
+        )
+        .unwrap();
+
+        let options = load(
+            std::slice::from_ref(&cert),
+            &[("registry.example.com".to_string(), cert.clone())],
+            &[format!(
+                "registry.example.com={},{}",
+                cert.display(),
+                key.display()
+            )],
+        )
+        .unwrap();
+
+        assert_eq!(options.roots.len(), 1);
+        assert_eq!(options.host_roots["registry.example.com"].len(), 1);
+        assert!(options.identities.contains_key("registry.example.com"));
+
+        assert!(load(&[key], &[], &[]).is_err());
+        assert!(load(&[dir.path().join("missing.pem")], &[], &[]).is_err());
+    }
+}
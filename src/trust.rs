@@ -0,0 +1,502 @@
+use std::{collections::HashMap, fs};
+
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
+use bytes::Bytes;
+use reqwest::StatusCode;
+use rustls_pki_types::{pem::PemObject, CertificateDer, SignatureVerificationAlgorithm, UnixTime};
+use serde::Deserialize;
+use serde_json::Value;
+use webpki::{anchor_from_trusted_cert, EndEntityCert, KeyUsage};
+
+use crate::{
+    client::{ImagePermission, ImagePermissions, OciClient, OciClientError},
+    downloader::{OciDownloader, OciDownloaderError},
+    macros::{impl_error, impl_from_error},
+    parser::{FullImage, FullImageWithTag},
+    spec::plan::{ImagePlanTrustLevel, ImagePlanTrustPolicy},
+};
+
+impl_error!(TrustError);
+impl_from_error!(OciClientError, TrustError);
+impl_from_error!(OciDownloaderError, TrustError);
+impl_from_error!(reqwest::Error, TrustError);
+impl_from_error!(serde_json::Error, TrustError);
+impl_from_error!(base64::DecodeError, TrustError);
+impl_from_error!(std::io::Error, TrustError);
+impl_from_error!(webpki::Error, TrustError);
+
+const NOTARY_SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.cncf.notary.signature";
+const JWS_MEDIA_TYPE: &str = "application/jose+json";
+const OCI_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// id-kp-codeSigning (1.3.6.1.5.5.7.3.3), required on Notary v2 signing certificates
+const CODE_SIGNING_EKU: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x03];
+
+/// Algorithms accepted for the certificate chain itself
+static CHAIN_ALGORITHMS: &[&dyn SignatureVerificationAlgorithm] = &[
+    webpki::ring::ECDSA_P256_SHA256,
+    webpki::ring::ECDSA_P256_SHA384,
+    webpki::ring::ECDSA_P384_SHA256,
+    webpki::ring::ECDSA_P384_SHA384,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA256,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA384,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA512,
+    webpki::ring::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    webpki::ring::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    webpki::ring::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+#[derive(Deserialize)]
+struct JwsEnvelope {
+    payload: String,
+    protected: String,
+    header: JwsHeader,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct JwsHeader {
+    x5c: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JwsProtectedHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct NotaryPayload {
+    #[serde(rename = "targetArtifact")]
+    target_artifact: NotaryTargetArtifact,
+}
+
+#[derive(Deserialize)]
+struct NotaryTargetArtifact {
+    digest: String,
+}
+
+struct RegistryTrust {
+    level: ImagePlanTrustLevel,
+    roots: Vec<CertificateDer<'static>>,
+}
+
+/// Verifies Notary v2 signatures of base images against per-registry trust policies
+#[derive(Default)]
+pub struct TrustVerifier {
+    registries: HashMap<String, RegistryTrust>,
+}
+
+impl TrustVerifier {
+    /// Loads the root certificates of every policy, keyed by registry host
+    pub fn new(policies: &HashMap<String, ImagePlanTrustPolicy>) -> Result<Self, TrustError> {
+        let mut registries = HashMap::new();
+
+        for (registry, policy) in policies {
+            let mut roots = vec![];
+
+            for path in &policy.certificates {
+                let pem = fs::read(path)?;
+
+                for certificate in CertificateDer::pem_slice_iter(&pem) {
+                    roots.push(certificate.map_err(|e| {
+                        TrustError(format!("Invalid certificate in {}: {}", path, e))
+                    })?);
+                }
+            }
+
+            if policy.level == ImagePlanTrustLevel::Strict && roots.is_empty() {
+                return Err(TrustError(format!(
+                    "Strict trust policy for {} has no certificates",
+                    registry
+                )));
+            }
+
+            registries.insert(
+                registry.clone(),
+                RegistryTrust {
+                    level: policy.level,
+                    roots,
+                },
+            );
+        }
+
+        Ok(TrustVerifier { registries })
+    }
+
+    fn registry_trust(&self, image: &FullImage) -> Option<&RegistryTrust> {
+        let host = image
+            .registry
+            .trim_start_matches("https://")
+            .trim_start_matches("http://");
+
+        self.registries
+            .get(&image.service)
+            .or_else(|| self.registries.get(host))
+    }
+
+    /// Checks that the manifest with the given digest carries a trusted signature,
+    /// failing or warning depending on the policy of its registry
+    pub async fn verify(
+        &self,
+        downloader: &OciDownloader,
+        image: &FullImageWithTag,
+        digest: &str,
+    ) -> Result<(), TrustError> {
+        let Some(trust) = self.registry_trust(&image.image) else {
+            return Ok(());
+        };
+
+        if trust.level == ImagePlanTrustLevel::Skip {
+            return Ok(());
+        }
+
+        let result = async {
+            let envelopes = fetch_signature_envelopes(downloader, &image.image, digest).await?;
+
+            if envelopes.is_empty() {
+                return Err(TrustError("no Notary signatures found".to_string()));
+            }
+
+            let mut errors = vec![];
+
+            for envelope in envelopes {
+                match verify_envelope(&envelope, digest, &trust.roots) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => errors.push(e.0),
+                }
+            }
+
+            Err(TrustError(errors.join(", ")))
+        }
+        .await;
+
+        let name = image.name();
+
+        match (result, trust.level) {
+            (Ok(()), _) => {
+                println!("Verified signature of {} ({})", name, digest);
+                Ok(())
+            }
+            (Err(e), ImagePlanTrustLevel::Audit) => {
+                eprintln!("Warning: untrusted image {} ({}): {}", name, digest, e);
+                Ok(())
+            }
+            (Err(e), _) => Err(TrustError(format!(
+                "Untrusted image {} ({}): {}",
+                name, digest, e
+            ))),
+        }
+    }
+}
+
+async fn registry_get(
+    client: &OciClient,
+    image: &FullImage,
+    url: &str,
+    accept: &str,
+) -> Result<Option<Bytes>, TrustError> {
+    let permission = ImagePermission {
+        full_image: image.clone(),
+        permissions: ImagePermissions::Pull,
+    };
+    let response = client
+        .send_authorized(url, &permission, |client| {
+            client.get(url).header("Accept", accept)
+        })
+        .await?;
+
+    match response.status() {
+        StatusCode::OK => Ok(Some(response.bytes().await?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        code => Err(TrustError(format!("Failed to fetch {}: {}", url, code))),
+    }
+}
+
+/// Fetches the Notary signature envelopes of the manifests referring to a digest
+async fn fetch_signature_envelopes(
+    downloader: &OciDownloader,
+    image: &FullImage,
+    digest: &str,
+) -> Result<Vec<Bytes>, TrustError> {
+    let client = &downloader.client;
+    let referrers = downloader
+        .list_referrers(image.clone(), digest, Some(NOTARY_SIGNATURE_ARTIFACT_TYPE))
+        .await?;
+
+    let mut envelopes = vec![];
+
+    for referrer in referrers {
+        let manifest_url = format!("{}/manifests/{}", image.get_image_url(), referrer.digest);
+        let Some(manifest) =
+            registry_get(client, image, &manifest_url, OCI_MANIFEST_MEDIA_TYPE).await?
+        else {
+            continue;
+        };
+        let manifest: Value = serde_json::from_slice(&manifest)?;
+
+        for layer in manifest["layers"].as_array().into_iter().flatten() {
+            if layer["mediaType"] != JWS_MEDIA_TYPE {
+                continue;
+            }
+
+            if let Some(blob_digest) = layer["digest"].as_str() {
+                let blob_url = format!("{}/blobs/{}", image.get_image_url(), blob_digest);
+
+                if let Some(envelope) = registry_get(client, image, &blob_url, "*/*").await? {
+                    envelopes.push(envelope);
+                }
+            }
+        }
+    }
+
+    Ok(envelopes)
+}
+
+/// Converts a JWS ECDSA signature (fixed-width r || s) to the ASN.1 DER form webpki expects.
+/// The signature must be `length` bytes, 64 for P-256 and 96 for P-384
+fn ecdsa_fixed_to_der(signature: &[u8], length: usize) -> Result<Vec<u8>, TrustError> {
+    if signature.len() != length {
+        return Err(TrustError(format!(
+            "ECDSA signature is {} bytes instead of {}",
+            signature.len(),
+            length
+        )));
+    }
+
+    fn der_integer(bytes: &[u8]) -> Vec<u8> {
+        let start = bytes
+            .iter()
+            .position(|byte| *byte != 0)
+            .unwrap_or(bytes.len() - 1);
+        let bytes = &bytes[start..];
+        let mut integer = vec![0x02];
+
+        if bytes[0] & 0x80 != 0 {
+            integer.push(bytes.len() as u8 + 1);
+            integer.push(0);
+        } else {
+            integer.push(bytes.len() as u8);
+        }
+
+        integer.extend_from_slice(bytes);
+        integer
+    }
+
+    let (r, s) = signature.split_at(signature.len() / 2);
+    let mut body = der_integer(r);
+    body.extend(der_integer(s));
+
+    let mut der = vec![0x30];
+
+    if body.len() >= 0x80 {
+        der.push(0x81);
+    }
+
+    der.push(body.len() as u8);
+    der.extend(body);
+    Ok(der)
+}
+
+/// Verifies a Notary v2 JWS envelope: the signature must be made by the leaf certificate,
+/// the chain must lead to one of the trusted roots, and the payload must name the digest
+pub fn verify_envelope(
+    envelope: &[u8],
+    digest: &str,
+    roots: &[CertificateDer<'static>],
+) -> Result<(), TrustError> {
+    let envelope: JwsEnvelope = serde_json::from_slice(envelope)?;
+    let protected: JwsProtectedHeader =
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(&envelope.protected)?)?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(&envelope.signature)?;
+
+    let (algorithm, signature) = match protected.alg.as_str() {
+        "ES256" => (
+            webpki::ring::ECDSA_P256_SHA256,
+            ecdsa_fixed_to_der(&signature, 64)?,
+        ),
+        "ES384" => (
+            webpki::ring::ECDSA_P384_SHA384,
+            ecdsa_fixed_to_der(&signature, 96)?,
+        ),
+        "PS256" => (webpki::ring::RSA_PSS_2048_8192_SHA256_LEGACY_KEY, signature),
+        "PS384" => (webpki::ring::RSA_PSS_2048_8192_SHA384_LEGACY_KEY, signature),
+        "PS512" => (webpki::ring::RSA_PSS_2048_8192_SHA512_LEGACY_KEY, signature),
+        alg => {
+            return Err(TrustError(format!(
+                "unsupported signature algorithm {}",
+                alg
+            )))
+        }
+    };
+
+    let chain = envelope
+        .header
+        .x5c
+        .iter()
+        .map(|certificate| Ok(CertificateDer::from(BASE64_STANDARD.decode(certificate)?)))
+        .collect::<Result<Vec<_>, TrustError>>()?;
+    let (leaf, intermediates) = chain
+        .split_first()
+        .ok_or(TrustError("signature has no certificate chain".to_string()))?;
+    let leaf = EndEntityCert::try_from(leaf)?;
+
+    let signing_input = format!("{}.{}", envelope.protected, envelope.payload);
+    leaf.verify_signature(algorithm, signing_input.as_bytes(), &signature)?;
+
+    let anchors = roots
+        .iter()
+        .map(|root| anchor_from_trusted_cert(root).map(|anchor| anchor.to_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    leaf.verify_for_usage(
+        CHAIN_ALGORITHMS,
+        &anchors,
+        intermediates,
+        UnixTime::now(),
+        KeyUsage::required(CODE_SIGNING_EKU),
+        None,
+        None,
+    )?;
+
+    let payload: NotaryPayload =
+        serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(&envelope.payload)?)?;
+
+    if payload.target_artifact.digest != digest {
+        return Err(TrustError(format!(
+            "signature is for {}",
+            payload.target_artifact.digest
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+        PKCS_ECDSA_P256_SHA256,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+
+    struct Signer {
+        root: CertificateDer<'static>,
+        leaf: CertificateDer<'static>,
+        key: EcdsaKeyPair,
+    }
+
+    fn new_signer() -> Signer {
+        let root_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut root_params = CertificateParams::new(vec![]).unwrap();
+        root_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let root = root_params.self_signed(&root_key).unwrap();
+
+        let leaf_key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut leaf_params = CertificateParams::new(vec![]).unwrap();
+        leaf_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::CodeSigning];
+        let leaf = leaf_params
+            .signed_by(&leaf_key, &Issuer::new(root_params, root_key))
+            .unwrap();
+
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &leaf_key.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .unwrap();
+
+        Signer {
+            root: root.der().clone(),
+            leaf: leaf.der().clone(),
+            key,
+        }
+    }
+
+    fn envelope(signer: &Signer, digest: &str) -> Vec<u8> {
+        let protected = BASE64_URL_SAFE_NO_PAD
+            .encode(r#"{"alg":"ES256","cty":"application/vnd.cncf.notary.payload.v1+json"}"#);
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(format!(
+            r#"{{"targetArtifact":{{"mediaType":"{}","digest":"{}","size":1}}}}"#,
+            "application/vnd.oci.image.index.v1+json", digest
+        ));
+        let signature = signer
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .unwrap();
+
+        serde_json::to_vec(&serde_json::json!({
+            "payload": payload,
+            "protected": protected,
+            "header": { "x5c": [BASE64_STANDARD.encode(&signer.leaf)] },
+            "signature": BASE64_URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify_envelope() {
+        let signer = new_signer();
+        let other = new_signer();
+        let envelope = envelope(&signer, "sha256:abc");
+
+        assert!(
+            verify_envelope(&envelope, "sha256:abc", std::slice::from_ref(&signer.root)).is_ok()
+        );
+        assert!(
+            verify_envelope(&envelope, "sha256:def", std::slice::from_ref(&signer.root)).is_err()
+        );
+        assert!(
+            verify_envelope(&envelope, "sha256:abc", std::slice::from_ref(&other.root)).is_err()
+        );
+
+        let mut tampered: Value = serde_json::from_slice(&envelope).unwrap();
+        tampered["payload"] = Value::String(
+            BASE64_URL_SAFE_NO_PAD.encode(r#"{"targetArtifact":{"digest":"sha256:def"}}"#),
+        );
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        assert!(
+            verify_envelope(&tampered, "sha256:def", std::slice::from_ref(&signer.root)).is_err()
+        );
+
+        // Malformed signatures are errors, not panics
+        for signature in ["", "AAAA"] {
+            let mut malformed: Value = serde_json::from_slice(&envelope).unwrap();
+            malformed["signature"] = Value::String(signature.to_string());
+            let malformed = serde_json::to_vec(&malformed).unwrap();
+            assert!(
+                verify_envelope(&malformed, "sha256:abc", std::slice::from_ref(&signer.root))
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_ecdsa_fixed_to_der() {
+        let mut signature = vec![0u8; 64];
+        signature[31] = 0x01;
+        signature[32] = 0x80;
+
+        let der = ecdsa_fixed_to_der(&signature, 64).unwrap();
+
+        assert_eq!(&der[..5], &[0x30, 0x26, 0x02, 0x01, 0x01]);
+        assert_eq!(&der[5..8], &[0x02, 0x21, 0x00]);
+        assert_eq!(der.len(), 0x28);
+
+        // All-zero signatures are encoded as zero integers
+        let der = ecdsa_fixed_to_der(&[0u8; 96], 96).unwrap();
+        assert_eq!(der, [0x30, 0x06, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00]);
+
+        assert!(ecdsa_fixed_to_der(&[], 64).is_err());
+        assert!(ecdsa_fixed_to_der(&signature[..63], 64).is_err());
+        assert!(ecdsa_fixed_to_der(&signature, 96).is_err());
+    }
+}
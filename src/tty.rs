@@ -0,0 +1,224 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    process::Stdio,
+    time::Duration,
+};
+
+use nix::{
+    pty::{openpty, Winsize},
+    sys::termios::{self, LocalFlags, SetArg, Termios},
+};
+use tokio::{process::Command, sync::oneshot};
+
+/// How long the output of the container is still copied after it exited,
+/// in case a process it left behind keeps the terminal open
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn window_size(fd: RawFd) -> Option<Winsize> {
+    let mut size: Winsize = unsafe { std::mem::zeroed() };
+
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 => Some(size),
+        _ => None,
+    }
+}
+
+/// Puts the terminal into raw mode, so that keys such as Ctrl-C reach the
+/// container instead of ocitool, until dropped
+struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        let stdin = io::stdin();
+        let original = termios::tcgetattr(&stdin)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSADRAIN, &self.original);
+    }
+}
+
+/// Stops the terminal from echoing what is typed, until dropped
+struct NoEcho {
+    original: Termios,
+}
+
+impl NoEcho {
+    fn enter() -> io::Result<NoEcho> {
+        let stdin = io::stdin();
+        let original = termios::tcgetattr(&stdin)?;
+        let mut silent = original.clone();
+        silent.local_flags.remove(LocalFlags::ECHO);
+        silent.local_flags.insert(LocalFlags::ECHONL);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &silent)?;
+
+        Ok(NoEcho { original })
+    }
+}
+
+impl Drop for NoEcho {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original);
+    }
+}
+
+/// Reads a password, dropping the line break it ends with. Inner spaces
+/// and other whitespace are part of the password
+fn read_password(mut reader: impl Read) -> io::Result<String> {
+    let mut password = String::new();
+    reader.read_to_string(&mut password)?;
+
+    let password = password.trim_end_matches(['\n', '\r']);
+
+    match password.is_empty() {
+        true => Err(io::Error::other("The password is empty")),
+        false => Ok(password.to_string()),
+    }
+}
+
+/// Reads a password piped into stdin, as with --password-stdin
+pub fn read_password_stdin() -> io::Result<String> {
+    read_password(io::stdin().lock())
+}
+
+/// Asks for a password on the terminal, without showing it as it is typed
+pub fn prompt_password(prompt: &str) -> io::Result<String> {
+    let stdin = io::stdin();
+
+    if !stdin.is_terminal() {
+        return Err(io::Error::other(
+            "No terminal to ask for the password on, pass --password or --password-stdin",
+        ));
+    }
+
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    {
+        let _no_echo = NoEcho::enter()?;
+        stdin.lock().read_line(&mut line)?;
+    }
+
+    read_password(line.as_bytes())
+}
+
+/// A pseudo terminal the container runs on, attached to this one
+pub struct Pty {
+    master: OwnedFd,
+}
+
+impl Pty {
+    /// Opens a pseudo terminal of the size of this one and makes it the
+    /// stdio and controlling terminal of the command. The command holds the
+    /// other end of the terminal, and has to be dropped once spawned for
+    /// reads to end when the container exits
+    pub fn attach(command: &mut Command) -> io::Result<Pty> {
+        let stdin = io::stdin();
+
+        if !stdin.is_terminal() {
+            return Err(io::Error::other("--tty needs a terminal on stdin"));
+        }
+
+        let pty = openpty(window_size(stdin.as_raw_fd()).as_ref(), None)?;
+
+        command
+            .stdin(Stdio::from(pty.slave.try_clone()?))
+            .stdout(Stdio::from(pty.slave.try_clone()?))
+            .stderr(Stdio::from(pty.slave));
+
+        // A new session controlled by the terminal, for job control and for
+        // Ctrl-C to signal the foreground process
+        unsafe {
+            command.pre_exec(|| {
+                nix::unistd::setsid()?;
+
+                match libc::ioctl(0, libc::TIOCSCTTY, 0) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            });
+        }
+
+        Ok(Pty { master: pty.master })
+    }
+
+    /// Starts copying keys into the terminal and its output back out, with
+    /// this terminal in raw mode until the session is finished
+    pub fn start(self) -> io::Result<Session> {
+        let master = File::from(self.master);
+        let mut input = master.try_clone()?;
+        let mut output = master.try_clone()?;
+        let raw = RawMode::enter()?;
+
+        // Plain threads rather than blocking tasks, as reading stdin never
+        // ends and would keep the runtime from shutting down
+        std::thread::spawn(move || io::copy(&mut io::stdin().lock(), &mut input));
+
+        let (drained, drained_receiver) = oneshot::channel();
+        std::thread::spawn(move || {
+            let mut stdout = io::stdout();
+            let mut buf = [0; 8192];
+
+            // Reads fail with EIO once every process closed the terminal
+            while let Ok(n @ 1..) = output.read(&mut buf) {
+                if stdout.write_all(&buf[..n]).and(stdout.flush()).is_err() {
+                    break;
+                }
+            }
+
+            let _ = drained.send(());
+        });
+
+        Ok(Session {
+            master,
+            drained: drained_receiver,
+            _raw: raw,
+        })
+    }
+}
+
+pub struct Session {
+    master: File,
+    drained: oneshot::Receiver<()>,
+    _raw: RawMode,
+}
+
+impl Session {
+    /// Passes the size of this terminal on, after it changed
+    pub fn resize(&self) {
+        if let Some(size) = window_size(io::stdin().as_raw_fd()) {
+            unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        }
+    }
+
+    /// Copies what is left of the output once the container exited, and
+    /// gives the terminal back
+    pub async fn finish(self) {
+        let _ = tokio::time::timeout(DRAIN_TIMEOUT, self.drained).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_password() {
+        assert_eq!(read_password(&b"secret\n"[..]).unwrap(), "secret");
+        assert_eq!(read_password(&b"pass word\r\n"[..]).unwrap(), "pass word");
+        assert_eq!(read_password(&b" secret "[..]).unwrap(), " secret ");
+        assert!(read_password(&b"\n"[..]).is_err());
+    }
+}
@@ -1,58 +1,99 @@
 use crate::{
     client::{ImagePermission, ImagePermissions, OciClient, OciClientError},
+    digest::sha256_digest,
     execution::Blob,
+    format::format_size,
+    layout::OciLayout,
     macros::{impl_error, impl_from_error},
-    parser::{FullImage, FullImageWithTag},
+    parser::{FullImage, FullImageWithTag, ReferenceError},
+    profile::{self, Phase},
+    spec::{enums::MediaType, index::Manifest},
 };
 use reqwest::{
-    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE},
     StatusCode,
 };
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 pub struct OciUploader {
     client: Arc<OciClient>,
-    uploaded_blobs: HashSet<String>,
+    uploaded_blobs: Mutex<HashSet<String>>,
+    dry_run: bool,
+    layout: Option<OciLayout>,
+}
+
+/// How the upload of a blob started
+pub enum UploadStart {
+    /// The blob was mounted from another repository, there is nothing to upload
+    Mounted,
+    /// The blob is to be uploaded to this location
+    Session(String),
 }
 
 impl_error!(OciUploaderError);
 impl_from_error!(OciClientError, OciUploaderError);
 impl_from_error!(reqwest::Error, OciUploaderError);
+impl_from_error!(std::io::Error, OciUploaderError);
+impl_from_error!(ReferenceError, OciUploaderError);
 
 impl OciUploader {
     pub fn new(client: Arc<OciClient>) -> Self {
         OciUploader {
             client,
-            uploaded_blobs: HashSet::new(),
+            uploaded_blobs: Mutex::new(HashSet::new()),
+            dry_run: false,
+            layout: None,
         }
     }
 
-    async fn blob_exists(
-        &mut self,
+    /// Writes blobs and manifests into an image layout instead of a registry
+    pub fn with_layout(mut self, layout: Option<OciLayout>) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn layout(&self) -> Option<&OciLayout> {
+        self.layout.as_ref()
+    }
+
+    /// Only reports the blobs and manifests that would be uploaded
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub async fn blob_exists(
+        &self,
         image: FullImage,
-        blob: &Blob,
+        digest: &str,
     ) -> Result<bool, OciUploaderError> {
-        if self.uploaded_blobs.contains(&blob.digest) {
-            println!("Blob {} was already uploaded.", blob.digest);
+        if self.uploaded_blobs.lock().unwrap().contains(digest) {
+            println!("Blob {} was already uploaded.", digest);
             return Ok(true);
         }
 
-        println!("Checking blob {}...", blob.digest);
+        if let Some(layout) = &self.layout {
+            return Ok(layout.has_blob(digest)?);
+        }
+
+        println!("Checking blob {}...", digest);
 
-        let url = format!("{}/blobs/{}", image.get_image_url(), blob.digest);
+        let url = format!("{}/blobs/{}", image.get_image_url(), digest);
+        let permission = ImagePermission {
+            full_image: image,
+            permissions: ImagePermissions::Push,
+        };
         let response = self
             .client
-            .client
-            .head(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image,
-                        permissions: ImagePermissions::Push,
-                    })
-                    .await?,
-            )
-            .send()
+            .send_authorized(&url, &permission, |client| client.head(&url))
             .await?;
 
         let status = response.status();
@@ -67,41 +108,98 @@ impl OciUploader {
         let exists = status == StatusCode::OK;
 
         if exists {
-            self.uploaded_blobs.insert(blob.digest.clone());
+            self.uploaded_blobs
+                .lock()
+                .unwrap()
+                .insert(digest.to_string());
         }
 
         Ok(exists)
     }
 
-    pub async fn upload_blob(
-        &mut self,
-        image: FullImage,
-        blob: &Blob,
-    ) -> Result<(), OciUploaderError> {
-        let exists = self.blob_exists(image.clone(), &blob).await?;
+    pub async fn upload_blob(&self, image: FullImage, blob: &Blob) -> Result<(), OciUploaderError> {
+        let exists = self.blob_exists(image.clone(), &blob.digest).await?;
 
         if exists {
             println!("Blob {} already exists.", blob.digest);
             return Ok(());
         }
 
-        let url = format!("{}/blobs/uploads/", image.get_image_url());
+        if self.dry_run {
+            println!(
+                "Would upload blob {} ({}).",
+                blob.digest,
+                format_size(blob.data.len() as u64)
+            );
+            self.uploaded_blobs
+                .lock()
+                .unwrap()
+                .insert(blob.digest.clone());
+            return Ok(());
+        }
+
+        if let Some(layout) = &self.layout {
+            layout.write_blob(&blob.digest, &blob.data)?;
+            println!("Blob {} written.", blob.digest);
+            self.uploaded_blobs
+                .lock()
+                .unwrap()
+                .insert(blob.digest.clone());
+            return Ok(());
+        }
+
+        let mount_from = blob
+            .mount_from
+            .as_ref()
+            .filter(|source| self.can_mount(source, &image));
+
+        match self
+            .start_upload(image.clone(), &blob.digest, mount_from)
+            .await?
+        {
+            UploadStart::Mounted => Ok(()),
+            UploadStart::Session(location) => self.finish_upload(image, &location, blob).await,
+        }
+    }
+
+    /// Whether a blob of another repository can be mounted into an image
+    /// rather than uploaded to it
+    pub fn can_mount(&self, source: &FullImage, image: &FullImage) -> bool {
+        // Blobs copied from another repository on the same registry can be
+        // mounted from there, which skips transferring the data entirely
+        self.layout.is_none()
+            && source.registry == image.registry
+            && source.library_name != image.library_name
+    }
+
+    /// Starts the upload of a blob, mounting it from another repository
+    /// instead if one is given and the registry can
+    pub async fn start_upload(
+        &self,
+        image: FullImage,
+        digest: &str,
+        mount_from: Option<&FullImage>,
+    ) -> Result<UploadStart, OciUploaderError> {
+        let url = match mount_from {
+            Some(source) => format!(
+                "{}/blobs/uploads/?mount={}&from={}",
+                image.get_image_url(),
+                digest,
+                source.library_name
+            ),
+            None => format!("{}/blobs/uploads/", image.get_image_url()),
+        };
         let registry = image.registry.clone();
 
-        let headers = self
-            .client
-            .auth_headers(ImagePermission {
-                full_image: image,
-                permissions: ImagePermissions::Push,
-            })
-            .await?;
+        let permission = ImagePermission {
+            full_image: image,
+            permissions: ImagePermissions::Push,
+        };
 
+        let started = Instant::now();
         let response = self
             .client
-            .client
-            .post(&url)
-            .headers(headers.clone())
-            .send()
+            .send_authorized(&url, &permission, |client| client.post(&url))
             .await?;
 
         if !response.status().is_success() {
@@ -111,6 +209,17 @@ impl OciUploader {
             )));
         }
 
+        // Registries that cannot mount the blob fall back to a regular upload session
+        if mount_from.is_some() && response.status() == StatusCode::CREATED {
+            profile::record(Phase::Upload, started.elapsed(), 0);
+            println!("Blob {} mounted.", digest);
+            self.uploaded_blobs
+                .lock()
+                .unwrap()
+                .insert(digest.to_string());
+            return Ok(UploadStart::Mounted);
+        }
+
         let location = response
             .headers()
             .get("location")
@@ -124,64 +233,194 @@ impl OciUploader {
             location.to_string()
         };
 
+        Ok(UploadStart::Session(location))
+    }
+
+    /// Uploads a blob into the upload session started for it
+    pub async fn finish_upload(
+        &self,
+        image: FullImage,
+        location: &str,
+        blob: &Blob,
+    ) -> Result<(), OciUploaderError> {
+        let permission = ImagePermission {
+            full_image: image,
+            permissions: ImagePermissions::Push,
+        };
+
+        let started = Instant::now();
         let upload_url = if location.contains('?') {
             format!("{}&digest={}", location, blob.digest)
         } else {
             format!("{}?digest={}", location, blob.digest)
         };
 
-        let request = self
-            .client
+        let response = self
             .client
-            .put(upload_url)
-            .headers(headers)
-            .header(CONTENT_TYPE, "application/octet-stream")
-            .header(CONTENT_LENGTH, blob.data.len() as u64)
-            .body(blob.data.clone());
-
-        let response = request.send().await?;
+            .send_authorized(&upload_url, &permission, |client| {
+                client
+                    .put(&upload_url)
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(CONTENT_LENGTH, blob.data.len() as u64)
+                    .body(blob.data.clone())
+            })
+            .await?;
+        profile::record(Phase::Upload, started.elapsed(), blob.data.len() as u64);
 
         match response.status() {
             StatusCode::CREATED => {
                 println!("Blob {} uploaded.", blob.digest);
-                self.uploaded_blobs.insert(blob.digest.clone());
+                self.uploaded_blobs
+                    .lock()
+                    .unwrap()
+                    .insert(blob.digest.clone());
                 Ok(())
             }
             code => Err(OciUploaderError(format!("Failed to upload blob: {}", code))),
         }
     }
 
-    pub async fn upload_manifest(
+    pub async fn manifest_digest(
         &self,
         image: FullImageWithTag,
-        manifest_data: Vec<u8>,
         content_type: &str,
-    ) -> Result<(), OciUploaderError> {
+    ) -> Result<Option<String>, OciUploaderError> {
+        if let Some(layout) = &self.layout {
+            return Ok(layout.find(&image.tag)?.map(|manifest| manifest.digest));
+        }
+
         let url = format!("{}/manifests/{}", image.image.get_image_url(), image.tag);
 
-        println!("Uploading {}:{}...", image.image.image_name, image.tag);
+        let permission = ImagePermission {
+            full_image: image.image,
+            permissions: ImagePermissions::Push,
+        };
+        let response = self
+            .client
+            .send_authorized(&url, &permission, |client| {
+                client.head(&url).header("Accept", content_type)
+            })
+            .await?;
 
+        match response.status() {
+            StatusCode::OK => Ok(response
+                .headers()
+                .get("docker-content-digest")
+                .and_then(|digest| digest.to_str().ok())
+                .map(|digest| digest.to_string())),
+            StatusCode::NOT_FOUND => Ok(None),
+            code => Err(OciUploaderError(format!(
+                "Failed to check manifest: {}",
+                code
+            ))),
+        }
+    }
+
+    /// Deletes a manifest by digest, which also removes every tag pointing at it
+    pub async fn delete_manifest(
+        &self,
+        image: FullImage,
+        digest: &str,
+    ) -> Result<(), OciUploaderError> {
+        let url = format!("{}/manifests/{}", image.get_image_url(), digest);
+
+        if self.dry_run {
+            println!("Would delete {}@{}.", image.image_name, digest);
+            return Ok(());
+        }
+
+        let permission = ImagePermission {
+            full_image: image,
+            permissions: ImagePermissions::Delete,
+        };
         let response = self
             .client
+            .send_authorized(&url, &permission, |client| client.delete(&url))
+            .await?;
+
+        match response.status() {
+            StatusCode::OK | StatusCode::ACCEPTED => Ok(()),
+            code => Err(OciUploaderError(format!(
+                "Failed to delete manifest: {}",
+                code
+            ))),
+        }
+    }
+
+    pub async fn get_manifest(
+        &self,
+        image: FullImageWithTag,
+        content_type: &str,
+    ) -> Result<Option<Vec<u8>>, OciUploaderError> {
+        if let Some(layout) = &self.layout {
+            return match layout.find(&image.tag)? {
+                Some(manifest) => Ok(layout.read_blob(&manifest.digest)?),
+                None => Ok(None),
+            };
+        }
+
+        let url = format!("{}/manifests/{}", image.image.get_image_url(), image.tag);
+
+        let permission = ImagePermission {
+            full_image: image.image,
+            permissions: ImagePermissions::Push,
+        };
+        let response = self
             .client
-            .put(&url)
-            .headers(
-                self.client
-                    .auth_headers(ImagePermission {
-                        full_image: image.image,
-                        permissions: ImagePermissions::Push,
-                    })
-                    .await?,
-            )
-            .header("Content-Type", content_type)
-            .body(manifest_data)
-            .send()
+            .send_authorized(&url, &permission, |client| {
+                client.get(&url).header("Accept", content_type)
+            })
             .await?;
 
+        match response.status() {
+            StatusCode::OK => Ok(Some(response.bytes().await?.to_vec())),
+            StatusCode::NOT_FOUND => Ok(None),
+            code => Err(OciUploaderError(format!(
+                "Failed to fetch manifest: {}",
+                code
+            ))),
+        }
+    }
+
+    pub async fn upload_manifest(
+        &self,
+        image: FullImageWithTag,
+        manifest_data: Vec<u8>,
+        content_type: &str,
+    ) -> Result<HeaderMap, OciUploaderError> {
+        let url = format!("{}/manifests/{}", image.image.get_image_url(), image.tag);
+
+        if self.dry_run {
+            println!("Would upload {}:{}.", image.image.image_name, image.tag);
+            return Ok(HeaderMap::new());
+        }
+
+        if let Some(layout) = &self.layout {
+            self.write_manifest(layout, &image.tag, &manifest_data, content_type)?;
+            return Ok(HeaderMap::new());
+        }
+
+        println!("Uploading {}:{}...", image.image.image_name, image.tag);
+
+        let permission = ImagePermission {
+            full_image: image.image,
+            permissions: ImagePermissions::Push,
+        };
+        let response = profile::timed(
+            Phase::Upload,
+            self.client.send_authorized(&url, &permission, |client| {
+                client
+                    .put(&url)
+                    .header("Content-Type", content_type)
+                    .body(manifest_data.clone())
+            }),
+        )
+        .await?;
+
         match response.status() {
             StatusCode::CREATED => {
                 println!("Manifest uploaded successfully.");
-                Ok(())
+                Ok(response.headers().clone())
             }
             code => Err(OciUploaderError(format!(
                 "Failed to upload manifest: {}",
@@ -189,4 +428,37 @@ impl OciUploader {
             ))),
         }
     }
+
+    /// Writes a manifest into the image layout. Manifests pushed by digest
+    /// are only referenced by other manifests, so only tags are named in
+    /// index.json
+    fn write_manifest(
+        &self,
+        layout: &OciLayout,
+        tag: &str,
+        manifest_data: &[u8],
+        content_type: &str,
+    ) -> Result<(), OciUploaderError> {
+        let digest = sha256_digest(manifest_data);
+        layout.write_blob(&digest, manifest_data)?;
+
+        if tag.starts_with("sha256:") {
+            return Ok(());
+        }
+
+        layout.tag(
+            tag,
+            Manifest {
+                media_type: MediaType::from(content_type),
+                size: manifest_data.len() as u64,
+                digest,
+                platform: None,
+                artifact_type: None,
+                annotations: None,
+            },
+        )?;
+
+        println!("Wrote {} to {}.", tag, layout.root().display());
+        Ok(())
+    }
 }
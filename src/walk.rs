@@ -10,7 +10,11 @@ pub fn walk_with_filters(
 ) -> Vec<PathBuf> {
     let mut results = Vec::new();
 
-    for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+    for entry in WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
         let path = entry.into_path();
 
         if path.is_dir() {
@@ -0,0 +1,301 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{self, FileLock},
+    spec::config::ImageConfig,
+    state::{now, State},
+    Rm,
+};
+
+/// What a named container was created from, kept next to its root
+/// filesystem so that it can be run again without the registry
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceState {
+    pub name: String,
+    pub image: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    pub config: ImageConfig,
+}
+
+/// The directory of a container named with `run --name`, holding the
+/// extracted root filesystem and its state file
+pub struct Workspace {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+pub fn workspaces_dir() -> PathBuf {
+    cache::cache_dir().join("workspaces")
+}
+
+/// Where containers started with `run --detach` keep their pid, log and
+/// exit code files, apart from the root filesystem so that they outlive it
+pub fn detached_dir() -> PathBuf {
+    cache::cache_dir().join("detached")
+}
+
+/// When a process started, in clock ticks since boot, or None if there is
+/// no such process
+fn start_time(pid: Pid) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The name in parentheses may hold spaces, the fields after it do not.
+    // starttime is the 22nd field, the 20th after the name
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+impl Workspace {
+    pub fn new(name: &str) -> Result<Workspace, Box<dyn Error>> {
+        Workspace::in_dir(&workspaces_dir(), name)
+    }
+
+    pub fn in_dir(dir: &Path, name: &str) -> Result<Workspace, Box<dyn Error>> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+        if !valid {
+            return Err(format!(
+                "Invalid container name {}, use letters, digits, '-', '_' and '.'",
+                name
+            )
+            .into());
+        }
+
+        Ok(Workspace {
+            name: name.to_string(),
+            dir: dir.join(name),
+        })
+    }
+
+    pub fn rootfs(&self) -> PathBuf {
+        self.dir.join("rootfs")
+    }
+
+    /// Next to the directory rather than in it, so that it outlives discard
+    fn lock_path(&self) -> PathBuf {
+        self.dir.with_file_name(format!("{}.lock", self.name))
+    }
+
+    /// Locks the container for as long as the lock is held, failing if an
+    /// ocitool run or rm of the container already holds it
+    pub fn lock(&self) -> Result<FileLock, Box<dyn Error>> {
+        FileLock::try_acquire(&self.lock_path())?
+            .ok_or_else(|| format!("{} is in use by another ocitool", self.name).into())
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.dir.join("state.json")
+    }
+
+    pub fn pid_path(&self) -> PathBuf {
+        detached_dir().join(format!("{}.pid", self.name))
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        detached_dir().join(format!("{}.log", self.name))
+    }
+
+    pub fn exit_code_path(&self) -> PathBuf {
+        detached_dir().join(format!("{}.exit", self.name))
+    }
+
+    /// Why the detached ocitool failed, if it did
+    pub fn error_path(&self) -> PathBuf {
+        detached_dir().join(format!("{}.error", self.name))
+    }
+
+    /// Records this process as the ocitool running the container in the
+    /// background, with its start time to tell it from a later process
+    /// that got the same pid
+    pub fn record_pid(&self) -> io::Result<()> {
+        let pid = Pid::this();
+        let started = start_time(pid)
+            .ok_or_else(|| io::Error::other("cannot read the start time of ocitool"))?;
+
+        fs::write(self.pid_path(), format!("{} {}", pid, started))
+    }
+
+    /// The pid of the ocitool running the container in the background, if
+    /// it is still alive
+    pub fn running_pid(&self) -> Option<Pid> {
+        let contents = fs::read_to_string(self.pid_path()).ok()?;
+        let (pid, started) = contents.trim().split_once(' ')?;
+        let pid = Pid::from_raw(pid.parse().ok()?);
+
+        (start_time(pid)? == started.parse::<u64>().ok()?).then_some(pid)
+    }
+
+    /// The state of the container, or None if it was never kept
+    pub fn load(&self) -> Option<WorkspaceState> {
+        fs::read(self.state_path())
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+    }
+
+    /// Writes the state file once the root filesystem is complete, so that a
+    /// run that failed to extract the image is not taken up again
+    pub fn save(&self, image: &str, config: ImageConfig) -> io::Result<WorkspaceState> {
+        let state = WorkspaceState {
+            name: self.name.clone(),
+            image: image.to_string(),
+            created_at: now(),
+            config,
+        };
+
+        fs::write(self.state_path(), serde_json::to_vec_pretty(&state)?)?;
+        State::update(&State::path(), |host| {
+            host.record_workspace(&self.name, &self.dir, image, state.created_at)
+        })?;
+
+        Ok(state)
+    }
+
+    /// Deletes the root filesystem and state file of a container that was
+    /// never saved, leaving the files of a detached run in place
+    pub fn discard(&self) -> io::Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes the root filesystem and forgets the container
+    pub fn remove(&self) -> io::Result<()> {
+        self.discard()?;
+
+        for path in [
+            self.pid_path(),
+            self.log_path(),
+            self.exit_code_path(),
+            self.error_path(),
+        ] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        State::update(&State::path(), |host| {
+            host.workspaces.remove(&self.name);
+        })
+    }
+}
+
+/// Removes the named containers of a workspaces directory, or only says
+/// which would be removed on a dry run
+fn remove_workspaces(
+    names: &[String],
+    dir: &Path,
+    state: &State,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    for name in names {
+        let mut workspace = Workspace::in_dir(dir, name)?;
+
+        if let Some(record) = state.workspaces.get(name) {
+            workspace.dir = PathBuf::from(&record.path);
+        } else if !workspace.dir.exists() {
+            return Err(format!("No container named {}", name).into());
+        }
+
+        if workspace.running_pid().is_some() {
+            return Err(format!("{} is running, stop it with ocitool stop {}", name, name).into());
+        }
+
+        if dry_run {
+            println!("Would remove {}", name);
+            continue;
+        }
+
+        let _lock = workspace.lock()?;
+        workspace.remove()?;
+        println!("Removed {}", name);
+    }
+
+    Ok(())
+}
+
+pub fn rm_command(args: &Rm, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if args.names.is_empty() {
+        return Err("Name at least one container to remove".into());
+    }
+
+    let state = State::load(&State::path());
+    remove_workspaces(&args.names, &workspaces_dir(), &state, dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(Workspace::in_dir(dir.path(), "../etc").is_err());
+        assert!(Workspace::in_dir(dir.path(), "").is_err());
+        assert!(Workspace::in_dir(dir.path(), ".hidden").is_err());
+
+        let workspace = Workspace::in_dir(dir.path(), "debug-1.2").unwrap();
+        assert_eq!(workspace.rootfs(), dir.path().join("debug-1.2/rootfs"));
+        assert!(workspace.load().is_none());
+
+        fs::create_dir_all(workspace.rootfs()).unwrap();
+        fs::write(
+            workspace.state_path(),
+            r#"{"name":"debug-1.2","image":"alpine:3","createdAt":1000,"config":{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}}"#,
+        )
+        .unwrap();
+
+        let state = workspace.load().unwrap();
+        assert_eq!(state.image, "alpine:3");
+        assert_eq!(state.created_at, 1000);
+
+        // A container is only used by one ocitool at a time
+        let lock = workspace.lock().unwrap();
+        assert!(workspace.lock().is_err());
+        workspace.discard().unwrap();
+        assert!(workspace.lock().is_err());
+        drop(lock);
+        assert!(workspace.lock().is_ok());
+    }
+
+    #[test]
+    fn test_remove_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Workspace::in_dir(dir.path(), "dry-run").unwrap();
+        fs::create_dir_all(workspace.rootfs()).unwrap();
+
+        let names = vec!["dry-run".to_string()];
+        remove_workspaces(&names, dir.path(), &State::default(), true).unwrap();
+        assert!(workspace.rootfs().is_dir());
+        assert!(!workspace.lock_path().exists());
+
+        let names = vec!["missing".to_string()];
+        assert!(remove_workspaces(&names, dir.path(), &State::default(), true).is_err());
+    }
+
+    #[test]
+    fn test_start_time() {
+        let started = start_time(Pid::this()).unwrap();
+        assert!(started > 0);
+        assert_eq!(start_time(Pid::this()), Some(started));
+        assert_eq!(start_time(Pid::from_raw(i32::MAX)), None);
+    }
+}